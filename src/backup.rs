@@ -0,0 +1,182 @@
+// Rotated backups written on save (`:set backups=<n>`), and listing them for `:history`
+// notes.md keeps its previous versions as notes.md.~1~ (newest) up to notes.md.~n~
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub struct Generation {
+    pub number: usize,
+    pub path: PathBuf,
+    pub modified: Option<SystemTime>,
+}
+
+fn backup_path(path: &Path, number: usize) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.~{}~", name, number))
+}
+
+// Which generation `candidate` is a backup of `name` for, if it is one at all. Only
+// exactly "<name>.~<n>~" with a plain number (no sign, no leading zero, not 0) counts,
+// anything else in the directory must never be touched by pruning
+pub fn generation_of(candidate: &str, name: &str) -> Option<usize> {
+    let digits = candidate.strip_prefix(name)?.strip_prefix(".~")?.strip_suffix('~')?;
+    if digits.is_empty() || digits.starts_with('0') || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Every backup of the file that's there now, newest first. Only regular files count, a
+// directory or symlink that happens to have a matching name is left out
+pub fn generations(filename: &str) -> Vec<Generation> {
+    let path = Path::new(filename);
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else { return Vec::new(); };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new(); };
+
+    let mut found: Vec<Generation> = entries.flatten()
+        .filter_map(|entry| {
+            let number = generation_of(&entry.file_name().to_string_lossy(), &name)?;
+            let meta = fs::symlink_metadata(entry.path()).ok().filter(|m| m.is_file())?;
+            Some(Generation { number, path: backup_path(path, number), modified: meta.modified().ok() })
+        })
+        .collect();
+    found.sort_by_key(|g| g.number);
+    found
+}
+
+// Called before the file is overwritten: ~1~ becomes ~2~ and so on, the current file is
+// copied to ~1~, and generations past `keep` are removed. Nothing happens with keep at 0
+// (backups off) or when there's no file yet. A name in the way that isn't a backup (a
+// symlink, a directory) stops the rotation rather than being written through or replaced
+pub fn rotate(filename: &str, keep: usize) -> io::Result<()> {
+    let path = Path::new(filename);
+    if keep == 0 || !path.is_file() { return Ok(()); }
+
+    let existing = generations(filename);
+    for generation in existing.iter().filter(|g| g.number >= keep) {
+        fs::remove_file(&generation.path)?;
+    }
+    for generation in existing.iter().rev().filter(|g| g.number < keep) {
+        let target = backup_path(path, generation.number + 1);
+        check_free(&target)?;
+        fs::rename(&generation.path, target)?;
+    }
+
+    // Copied under a temp name first, so ~1~ is never a half-written file and a symlink
+    // planted there is replaced rather than followed
+    let first = backup_path(path, 1);
+    check_free(&first)?;
+    let temp = first.with_file_name(format!(".{}.vellum-{}", first.file_name().unwrap_or_default().to_string_lossy(), std::process::id()));
+    let _ = fs::remove_file(&temp);
+    let result = copy_new(path, &temp).and_then(|_| fs::rename(&temp, &first));
+    if result.is_err() { let _ = fs::remove_file(&temp); }
+    result
+}
+
+// Ok when nothing is at `target` or it's a regular file (a backup about to be replaced)
+fn check_free(target: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(target) {
+        Ok(meta) if !meta.is_file() => Err(io::Error::other(format!("{} is not a backup, leaving it alone", target.display()))),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Like fs::copy, but refuses to open anything that's already there
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut target = OpenOptions::new().write(true).create_new(true).open(to)?;
+    target.set_permissions(source.metadata()?.permissions())?;
+    io::copy(&mut source, &mut target)?;
+    target.sync_all()
+}
+
+// "5 min ago"-style age for the history list
+pub fn age(modified: Option<SystemTime>) -> String {
+    let Some(seconds) = modified.and_then(|m| m.elapsed().ok()).map(|d| d.as_secs()) else {
+        return "unknown time".to_string();
+    };
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} min ago", seconds / 60),
+        3600..86400 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vellum-backup-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn generation_of_only_matches_exact_names() {
+        assert_eq!(generation_of("notes.md.~1~", "notes.md"), Some(1));
+        assert_eq!(generation_of("notes.md.~12~", "notes.md"), Some(12));
+        for other in [
+            "notes.md", "notes.md.~~", "notes.md.~0~", "notes.md.~01~", "notes.md.~+1~",
+            "notes.md.~-1~", "notes.md.~1", "notes.md~1~", "notes.md.~1~~", "notes.md.~1~.bak",
+            "notes.md.~ 1~", "notes.md.~1a~", "notes.md.~١~", "xnotes.md.~1~", "notes.mdx.~1~",
+            "notes.md.~99999999999999999999999~",
+        ] {
+            assert_eq!(generation_of(other, "notes.md"), None, "{}", other);
+        }
+    }
+
+    #[test]
+    fn rotate_shifts_and_prunes() {
+        let dir = temp_dir("rotate");
+        let file = dir.join("notes.md");
+        let name = file.to_str().unwrap();
+        for version in 1..=4 {
+            fs::write(&file, format!("version {}", version)).unwrap();
+            rotate(name, 2).unwrap();
+        }
+        let numbers: Vec<usize> = generations(name).iter().map(|g| g.number).collect();
+        assert_eq!(numbers, [1, 2]);
+        assert_eq!(fs::read_to_string(backup_path(&file, 1)).unwrap(), "version 4");
+        assert_eq!(fs::read_to_string(backup_path(&file, 2)).unwrap(), "version 3");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_never_writes_through_symlinks() {
+        let dir = temp_dir("symlink");
+        let file = dir.join("notes.md");
+        let victim = dir.join("victim");
+        fs::write(&file, "text").unwrap();
+        fs::write(&victim, "precious").unwrap();
+        symlink(&victim, backup_path(&file, 1)).unwrap();
+
+        assert!(rotate(file.to_str().unwrap(), 3).is_err());
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_never_replaces_what_isnt_a_backup() {
+        let dir = temp_dir("in-the-way");
+        let file = dir.join("notes.md");
+        let name = file.to_str().unwrap();
+        fs::write(&file, "text").unwrap();
+        rotate(name, 3).unwrap();
+        fs::create_dir(backup_path(&file, 2)).unwrap();
+
+        assert!(rotate(name, 3).is_err());
+        assert!(backup_path(&file, 2).is_dir());
+        assert_eq!(fs::read_to_string(backup_path(&file, 1)).unwrap(), "text");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
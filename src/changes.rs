@@ -0,0 +1,146 @@
+// The session's edit log for :changes, fed by Document::update_tree with every change it
+// diffs. A burst of typing (or of Backspace) collapses into one entry, as does a whole
+// command, undo or redo
+use std::collections::VecDeque;
+use std::time::SystemTime;
+use tree_sitter::InputEdit;
+
+const MAX_ENTRIES: usize = 1000; // Oldest fall off first
+const EXCERPT: usize = 40; // Chars of changed text kept per entry
+
+#[derive(Clone, PartialEq)]
+pub enum ChangeKind {
+    Insert,
+    Delete,
+    Replace,
+    Command(String),
+    Undo,
+    Redo,
+}
+
+pub struct Change {
+    pub time: SystemTime,
+    pub kind: ChangeKind,
+    pub first_line: usize,
+    pub last_line: usize,
+    pub excerpt: String,
+    start_byte: usize, // Where the changed text sits now, so the next keystroke can extend it
+    end_byte: usize,
+}
+
+impl Change {
+    pub fn describe(&self) -> String {
+        let kind = match &self.kind {
+            ChangeKind::Insert => "insert".to_string(),
+            ChangeKind::Delete => "delete".to_string(),
+            ChangeKind::Replace => "replace".to_string(),
+            ChangeKind::Command(name) => format!(":{}", name),
+            ChangeKind::Undo => "undo".to_string(),
+            ChangeKind::Redo => "redo".to_string(),
+        };
+        let lines = if self.first_line == self.last_line {
+            format!("line {}", self.first_line + 1)
+        } else {
+            format!("lines {}-{}", self.first_line + 1, self.last_line + 1)
+        };
+        format!("{} {} {}: {}", crate::backup::age(Some(self.time)), kind, lines, self.excerpt)
+    }
+}
+
+#[derive(Default)]
+pub struct ChangeLog {
+    entries: VecDeque<Change>,
+    label: Option<ChangeKind>, // Set while a command/undo/redo runs, its edits share one entry
+    fresh: bool, // The next edit under `label` starts a new entry
+}
+
+impl ChangeLog {
+    pub fn entries(&self) -> impl Iterator<Item = &Change> {
+        self.entries.iter()
+    }
+
+    // Everything recorded until the next call belongs to `label` (None: plain editing),
+    // returns the label it replaces so it can be put back
+    pub fn label(&mut self, label: Option<ChangeKind>) -> Option<ChangeKind> {
+        self.fresh = true;
+        std::mem::replace(&mut self.label, label)
+    }
+
+    pub fn record(&mut self, edit: &InputEdit, old: &str, new: &str) {
+        let removed = &old[edit.start_byte..edit.old_end_byte];
+        let added = &new[edit.start_byte..edit.new_end_byte];
+        let first_line = edit.start_position.row;
+        let last_line = edit.new_end_position.row;
+        if removed.is_empty() && added.is_empty() { return; }
+
+        if let Some(label) = self.label.clone() {
+            if !self.fresh && let Some(last) = self.entries.back_mut() {
+                last.first_line = last.first_line.min(first_line);
+                last.last_line = last.last_line.max(last_line);
+                return;
+            }
+            self.fresh = false;
+            let text = if added.is_empty() { removed } else { added };
+            self.push(label, first_line, last_line, excerpt("", text), edit.start_byte, edit.new_end_byte);
+            return;
+        }
+
+        let kind = match (removed.is_empty(), added.is_empty()) {
+            (true, _) => ChangeKind::Insert,
+            (false, true) => ChangeKind::Delete,
+            (false, false) => ChangeKind::Replace,
+        };
+
+        // Typing on from the end of the last insert, or deleting on from the last delete
+        // (Backspace eats backwards, Delete stays put)
+        if let Some(last) = self.entries.back_mut() {
+            let extends = match kind {
+                ChangeKind::Insert => last.kind == kind && edit.start_byte == last.end_byte,
+                ChangeKind::Delete => last.kind == kind && (edit.old_end_byte == last.start_byte || edit.start_byte == last.start_byte),
+                _ => false,
+            };
+            if extends {
+                if kind == ChangeKind::Insert {
+                    last.excerpt = excerpt(&last.excerpt, added);
+                    last.end_byte = edit.new_end_byte;
+                } else if edit.old_end_byte == last.start_byte && edit.start_byte != last.start_byte {
+                    last.excerpt = excerpt(removed, &last.excerpt);
+                    last.start_byte = edit.start_byte;
+                    last.end_byte = edit.start_byte;
+                } else {
+                    last.excerpt = excerpt(&last.excerpt, removed);
+                }
+                last.time = SystemTime::now();
+                last.first_line = last.first_line.min(first_line);
+                last.last_line = last.last_line.max(last_line);
+                return;
+            }
+        }
+
+        let text = match kind {
+            ChangeKind::Delete => excerpt("", removed),
+            ChangeKind::Replace => format!("{} -> {}", excerpt("", removed), excerpt("", added)),
+            _ => excerpt("", added),
+        };
+        self.push(kind, first_line, last_line, text, edit.start_byte, edit.new_end_byte);
+    }
+
+    fn push(&mut self, kind: ChangeKind, first_line: usize, last_line: usize, excerpt: String, start_byte: usize, end_byte: usize) {
+        if self.entries.len() == MAX_ENTRIES { self.entries.pop_front(); }
+        self.entries.push_back(Change { time: SystemTime::now(), kind, first_line, last_line, excerpt, start_byte, end_byte });
+    }
+}
+
+// `a` followed by `b` with line breaks shown as ↵, cut to EXCERPT chars
+fn excerpt(a: &str, b: &str) -> String {
+    let mut text: String = a.chars().chain(b.chars())
+        .map(|c| if c == '\n' { '↵' } else { c })
+        .filter(|c| *c != '\r')
+        .take(EXCERPT + 1)
+        .collect();
+    if text.chars().count() > EXCERPT {
+        text = text.chars().take(EXCERPT - 1).collect();
+        text.push('…');
+    }
+    text
+}
@@ -1,28 +1,106 @@
-use crate::editor::{Editor, StatusMessage, Position};
+use crate::editor::{split_location, Context, Editor, StatusMessage, Position};
+use crate::document::{Document, View};
+use crate::options::NumberMode;
+use crate::changes::ChangeKind;
+use crate::location::Location;
+use crate::search::{Query, Search};
 
-pub fn execute_command(editor: &mut Editor, command: &str) -> Result<(), std::io::Error> {
-    // I want edits from commands to be able to be reversed/redone
-    editor.document.snapshot();
+const INTERRUPT_CHECK: usize = 1024; // Lines a long :s goes through between looks for Ctrl+C
 
-    // Search and replace logic (vim syntax so all one word not split by whitespace)
-    if command.starts_with("s/") {
-        let parts: Vec<&str> = command.split('/').collect();
+// Every command with its arguments, a one-line description and the key that does the same
+// thing in Normal Mode (if any). The palette is built from this, so new commands go here too
+pub(crate) struct CommandInfo {
+    pub(crate) name: &'static str,
+    pub(crate) args: &'static str, // Usage shown after the name, "" for none
+    pub(crate) needs_args: bool, // Ask for the arguments before running it from the palette
+    pub(crate) description: &'static str,
+    pub(crate) key: &'static str,
+}
 
-        // Expectation is ["s", "old_text", "new_text"]
-        if parts.len() >= 3 {
-            let target = parts[1];
-            let replacement = parts[2];
+const fn cmd(name: &'static str, args: &'static str, needs_args: bool, description: &'static str, key: &'static str) -> CommandInfo {
+    CommandInfo { name, args, needs_args, description, key }
+}
 
-            let count = editor.document.replace(target, replacement);
-            editor.status_message = StatusMessage::from(format!("Replaced '{}' in {} lines", target, count));
+pub(crate) const COMMANDS: &[CommandInfo] = &[
+    cmd("w", "", false, "Save the file", "Ctrl+S"),
+    cmd("!w", "<filename>", true, "Save as a new file", ""),
+    cmd("!", "<command>", true, "Run a shell command and show its output", ""),
+    cmd("q", "", false, "Quit Vellum (refuses with unsaved changes)", "Ctrl+Q"),
+    cmd("q!", "", false, "Quit without saving", ""),
+    cmd("wq", "", false, "Save and quit", ""),
+    cmd("x", "", false, "Save if anything changed, then quit", ""),
+    cmd("e", "<path>", true, "Open another file in place of this one (e! discards unsaved changes)", ""),
+    cmd("s/", "old/new[/gc]", true, "Search and replace, [range]s/ limits it to some lines", ""),
+    cmd("find", "[-b] [-w] [-r] [-c] [count] <text>", true, "Jump to the next match", "/"),
+    cmd("noh", "", false, "Stop highlighting search matches", ""),
+    cmd("ln", "", false, "Cycle line numbers between always, Normal Mode only and hidden", ""),
+    cmd("head", "<level>", true, "Turn the current line into a header", ""),
+    cmd("bold", "", false, "Wrap the current word in **", ""),
+    cmd("italic", "", false, "Wrap the current word in *", ""),
+    cmd("view", "save|load|list [name]", true, "Save or go back to a named cursor and scroll position", "Ctrl+6"),
+    cmd("reg", "", false, "List the registers that hold text", ""),
+    cmd("hardbreak", "", false, "Add or remove a markdown hard line break", ""),
+    cmd("hr", "", false, "Insert a --- horizontal rule below the current line", ""),
+    cmd("pagebreak", "", false, "Insert a page break (the pagebreak option) below the current line", ""),
+    cmd("t", "<count>", true, "Indent the current line by count spaces ([range]t indents a range)", ""),
+    cmd("wrap", "[width]", false, "Re-break the paragraph under the cursor at width (80) columns", "gq"),
+    cmd("dd", "", false, "Delete the current line", "dd"),
+    cmd("dup", "", false, "Copy the current line below itself", "Shift+Alt+Down"),
+    cmd("mv", "+n|-n", true, "Move the current line n lines down or up", "Alt+Down / Alt+Up"),
+    cmd("d", "<count>", true, "Delete words forward (with a range, as in 10,20d, delete lines)", ""),
+    cmd("db", "<count>", true, "Delete words backward", ""),
+    cmd("set", "<option>[=value]", true, "Change an option", ""),
+    cmd("export", "txt [path] [--underline]", true, "Write a plain-text copy without markdown syntax", ""),
+    cmd("goto", "<line>[:col] | <n>%", true, "Jump to a line (and column), or that far through the file", ""),
+    cmd("conflict", "ours|theirs|both", true, "Resolve the merge conflict under the cursor", ""),
+    cmd("tasks", "sort|archive", true, "Sort or archive the checked tasks", ""),
+    cmd("renumber", "", false, "Renumber the numbered list under the cursor from its first item", ""),
+    cmd("indentcheck", "", false, "Report whether the file mixes tabs and spaces", ""),
+    cmd("retab!", "", false, "Convert all indentation to the detected style", ""),
+    cmd("lint", "[fix]", false, "Put structural problems in the location list (or fix them)", ""),
+    cmd("lgrep", "<regex>", true, "Put every match in the location list", ""),
+    cmd("lopen", "", false, "Show the location list", ""),
+    cmd("lnext", "", false, "Jump to the next location", ""),
+    cmd("lprev", "", false, "Jump to the previous location", ""),
+    cmd("changes", "", false, "List the edits made this session", ""),
+    cmd("history", "[restore <n>]", false, "List the backups of this file, or restore one (undoable)", ""),
+    cmd("tutor", "", false, "Learn the basics in a practice copy of the tutorial", ""),
+    cmd("profile", "", false, "Show how long recent operations took", ""),
+    cmd("inspect", "", false, "Show the highlight spans of the current line", ""),
+    cmd("palette", "", false, "Pick a command from this list", "Ctrl+P"),
+];
 
-            // Saftey clamp for cursor (pulls back to end of line)
-            let current_len = editor.line_length(editor.cursor_position.y);
-            if editor.cursor_position.x > current_len {
-                editor.cursor_position.x = current_len;
-            }
-        } else {
-            editor.status_message = StatusMessage::from("Usage: s/old/new".to_string());
+pub fn execute_command(editor: &mut Editor, command: &str) -> Result<(), std::io::Error> {
+    // I want edits from commands to be able to be reversed/redone, however many steps they take
+    editor.document.begin_edit_group(editor.cursor_position);
+    // The command's name, without any line range in front
+    let rest = split_range(command).1;
+    let name = if rest.starts_with("s/") { "s" } else { rest.split_whitespace().next().unwrap_or(command) };
+    let label = editor.document.changes.label(Some(ChangeKind::Command(name.to_string())));
+    let result = editor.nested(Context::Command, |editor| run_command(editor, command));
+    editor.document.changes.label(label);
+    editor.document.end_edit_group();
+    result.map(|_| ())
+}
+
+fn run_command(editor: &mut Editor, command: &str) -> Result<(), std::io::Error> {
+    // A line range in front (:10,20d, :.,+5t 1, :%s/a/b), or s/ which is the whole file
+    // without one
+    let (range, rest) = split_range(command);
+    // A range on its own (:347, :$, :.+5) jumps to its last line
+    if !range.is_empty() && rest.trim().is_empty() {
+        let last = range.rsplit(',').next().unwrap_or(range);
+        let line = if last == "%" { Some(text_lines(editor) as isize) } else { address(last, editor.cursor_position.y + 1, text_lines(editor)) };
+        match line {
+            Some(line) => editor.goto(line.max(1) as usize, None),
+            None => editor.status_message = StatusMessage::from(format!("Invalid line: {}", range)),
+        }
+        return Ok(());
+    }
+    if !range.is_empty() || rest.starts_with("s/") {
+        match parse_range(editor, range) {
+            Ok(lines) => ranged_command(editor, lines, !range.is_empty(), rest)?,
+            Err(msg) => editor.status_message = StatusMessage::from(msg),
         }
         return Ok(());
     }
@@ -32,63 +110,226 @@ pub fn execute_command(editor: &mut Editor, command: &str) -> Result<(), std::io
     if parts.is_empty() { return Ok(());}
 
     match parts[0] {
-        "q" => editor.should_quit = true,
-        "w" => {
-            if let Err(e) = editor.document.save() {
-                editor.status_message = StatusMessage::from(format!("Error: {}", e));
-            } else {
-                editor.status_message = StatusMessage::from("File saved.".to_string());
-            }
-        },
+        "q" if editor.document.is_dirty() => {
+            editor.status_message = StatusMessage::from("Unsaved changes (:w to save, :q! to discard them)".to_string());
+        }
+        "q" | "q!" => editor.should_quit = true,
+        // Save and quit, :x only saves if something changed. A failed or aborted save doesn't quit
+        "wq" | "x" => {
+            if parts[0] == "wq" || editor.document.is_dirty() { editor.save()?; }
+            editor.should_quit = !editor.document.is_dirty();
+        }
+        // Unnamed buffers (e.g. piped in) need a name first
+        "w" if editor.document.filename.is_none() => editor.save()?,
+        "w" => editor.write_file(),
         "!w" => {
             if parts.len() > 1 {
                 let new_name = parts[1].to_string();
+                if editor.document.filename.is_none() {
+                    editor.locations.name_unnamed(&new_name);
+                }
                 editor.document.filename = Some(new_name);
-                editor.document.save()?;
-                editor.status_message = StatusMessage::from("File saved as new name.".to_string());
+                editor.write_file();
             } else {
                 editor.status_message = StatusMessage::from("Error: !w requires a filename".to_string());
             }
         },
+        "e" | "e!" => edit(editor, parts.get(1).copied(), parts[0] == "e!"),
+        // :!cmd args runs a shell command (apart from !w, Save As above, :! w runs w)
+        name if name.starts_with('!') => shell(editor, command[1..].trim())?,
         "head" => {
-            if parts.len() > 1 {
-                if let Ok(level) = parts[1].parse::<usize>() {
-                    editor.document.set_header(editor.cursor_position.y, level);
-                }
+            if let Some(Ok(level)) = parts.get(1).map(|p| p.parse::<usize>()) {
+                editor.document.set_header(editor.cursor_position.y, level);
             }
         },
         "bold" => wrap_word(editor, "**"),
         "italic" => wrap_word(editor, "*"),
         "t" => {
-            if parts.len() > 1 {
-                if let Ok(count) = parts[1].parse::<usize>() {
-                    editor.document.indent(editor.cursor_position.y, count);
-                    editor.cursor_position.x += count * 4;
-                }
+            if let Some(Ok(count)) = parts.get(1).map(|p| p.parse::<usize>()) {
+                editor.document.indent(editor.cursor_position.y, count);
+                editor.cursor_position.x += count * 4;
             }
         },
+        "wrap" => {
+            let width = match parts.get(1).map(|p| p.parse::<usize>()) {
+                None => Ok(80),
+                Some(Ok(width)) if width > 0 => Ok(width),
+                Some(_) => Err(format!("Invalid width: {}", parts[1])),
+            };
+            let message = width.and_then(|width| editor.wrap_paragraph(width))
+                .map_or_else(|e| e, |lines| format!("Wrapped into {} lines", lines));
+            editor.status_message = StatusMessage::from(message);
+        }
+        // find [-b] [-w] [-r] [-c] [count] <text>: flags for backward, whole word, regex
+        // and case sensitive, the count jumps that many matches ahead
         "find" => {
-            if parts.len() > 1 {
-                let query = parts[1];
-                find_next(editor, query);
+            let mut query = Query::new("");
+            query.regex = editor.options.regex;
+            let mut count = 1;
+            let mut rest = &parts[1..];
+            while let Some(arg) = rest.first() {
+                match *arg {
+                    "-b" => query.backward = true,
+                    "-w" => query.whole_word = true,
+                    "-r" => query.regex = true,
+                    "-c" => query.case_sensitive = true,
+                    _ => match arg.parse::<usize>() {
+                        Ok(n) if rest.len() > 1 && n > 0 => count = n,
+                        _ => break,
+                    },
+                }
+                rest = &rest[1..];
+            }
+
+            if rest.is_empty() {
+                editor.status_message = StatusMessage::from("Usage: find [-b] [-w] [-r] [-c] [count] <text>".to_string());
+            } else {
+                let pattern = rest.join(" ");
+                query.case_sensitive |= Query::new(&pattern).case_sensitive;
+                query.pattern = pattern;
+                editor.start_search(query, count);
             }
         },
-        "ln" => {
-            editor.show_line_numbers = !editor.show_line_numbers;
-            editor.status_message = StatusMessage::from(format!("Line numbers: {}", editor.show_line_numbers));
+        // Stop marking the last search's matches
+        "noh" => editor.highlight_search = false,
+        "set" => {
+            if parts.len() < 2 {
+                editor.status_message = StatusMessage::from("Usage: set <option>[=value]".to_string());
+            }
+            // A page break snippet can have spaces in it, so it takes the rest of the line
+            let args: Vec<&str> = match command.trim_start().strip_prefix("set") {
+                Some(rest) if rest.trim_start().starts_with("pagebreak=") => vec![rest.trim()],
+                _ => parts[1..].to_vec(),
+            };
+            for arg in &args {
+                editor.status_message = match editor.options.set(arg) {
+                    Ok(msg) | Err(msg) => StatusMessage::from(msg),
+                };
+            }
+            editor.document.long_line = editor.options.long_line;
+            editor.document.undo_levels = editor.options.undo_levels;
+            editor.document.patch_save = editor.options.patch_save;
+            editor.profiler.enabled = editor.options.profile;
+            editor.terminal.set_color(editor.options.color);
         },
-        "dd" => {
-            editor.document.delete_line(editor.cursor_position.y);
+        "indentcheck" => {
+            let report = crate::indent::analyze(&editor.document, editor.options.indent_skip_fences);
+            editor.detected_indent = report.style;
+            editor.detected_width = report.width;
+            editor.status_message = StatusMessage::from(report.summary());
+        },
+        "retab!" => {
+            if let Some(style) = editor.indent_style() {
+                let changed = editor.document.retab(style, editor.options.tabstop, editor.options.indent_skip_fences);
+                editor.detected_indent = Some(style);
+                editor.status_message = StatusMessage::from(format!("Retabbed {} lines to {}", changed, style.name()));
 
-            // Fix cursor if deleted bottom line
-            if editor.cursor_position.y >= editor.document.len() {
-                editor.cursor_position.y = editor.document.len().saturating_sub(1);
+                let current_len = editor.line_length(editor.cursor_position.y);
+                if editor.cursor_position.x > current_len {
+                    editor.cursor_position.x = current_len;
+                }
+            } else {
+                editor.status_message = StatusMessage::from("No indent style detected (use :set indentstyle=tabs|spaces)".to_string());
+            }
+        },
+        // :goto 50% goes halfway down the file
+        "goto" if parts.get(1).is_some_and(|arg| arg.ends_with('%')) => {
+            match parts[1].trim_end_matches('%').parse::<usize>() {
+                Ok(percent) => editor.goto(percent_line(percent, text_lines(editor)), None),
+                Err(_) => editor.status_message = StatusMessage::from("Usage: goto <n>%".to_string()),
             }
-            let current_len = editor.line_length(editor.cursor_position.y);
-            if editor.cursor_position.x > current_len {
-                editor.cursor_position.x = current_len;
+        }
+        "goto" => {
+            // :goto line[:col]
+            let target = parts.get(1).map(|arg| {
+                let mut nums = arg.splitn(2, ':').map(|n| n.parse::<usize>());
+                (nums.next(), nums.next())
+            });
+            match target {
+                Some((Some(Ok(line)), None)) => editor.goto(line, None),
+                Some((Some(Ok(line)), Some(Ok(col)))) => editor.goto(line, Some(col)),
+                _ => editor.status_message = StatusMessage::from("Usage: goto <line>[:col]".to_string()),
             }
         },
+        "export" => export(editor, &parts[1..]),
+        "conflict" => resolve_conflict(editor, parts.get(1).copied()),
+        "tasks" => tasks(editor, parts.get(1).copied()),
+        "renumber" => {
+            let message = match editor.renumber_list(editor.cursor_position.y) {
+                Some(0) => "List numbers are already in order".to_string(),
+                Some(1) => "Renumbered 1 line".to_string(),
+                Some(changed) => format!("Renumbered {} lines", changed),
+                None => "No numbered list under cursor".to_string(),
+            };
+            editor.status_message = StatusMessage::from(message);
+        }
+        "inspect" => inspect(editor),
+        "profile" => editor.status_message = StatusMessage::from(editor.profiler.report()),
+        "lint" => lint(editor, parts.get(1).copied())?,
+        "lgrep" => lgrep(editor, &parts[1..].join(" ")),
+        "lopen" => open_locations(editor, String::new())?,
+        "history" => history(editor, &parts[1..])?,
+        "tutor" => {
+            // The tutor gets the terminal to itself, this session waits and redraws after
+            editor.terminal.restore();
+            let result = crate::tutor::run(editor.options.color);
+            editor.terminal.resume()?;
+            if let Err(e) = result {
+                editor.status_message = StatusMessage::from(format!("Couldn't start the tutor: {}", e));
+            }
+        }
+        "changes" => {
+            let items = editor.document.changes.entries().map(|change| Location {
+                file: editor.document.filename.clone(),
+                line: change.first_line,
+                col: 0,
+                message: change.describe(),
+            }).collect::<Vec<_>>();
+            if items.is_empty() {
+                editor.status_message = StatusMessage::from("No changes yet".to_string());
+            } else {
+                let note = editor.locations.fill("changes", items);
+                open_locations(editor, note)?;
+            }
+        }
+        "lnext" | "lprev" => match editor.locations.step(parts[0] == "lnext") {
+            Some(i) => editor.jump_to_location(i),
+            None if editor.locations.items.is_empty() => editor.status_message = StatusMessage::from("Location list is empty".to_string()),
+            None => editor.status_message = StatusMessage::from(format!("No {} location", if parts[0] == "lnext" { "next" } else { "previous" })),
+        },
+        "ln" => {
+            // Cycles always -> normal mode only -> never
+            let (mode, name) = match editor.options.number_mode {
+                NumberMode::Always => (NumberMode::Normal, "normal mode only"),
+                NumberMode::Normal => (NumberMode::Never, "off"),
+                NumberMode::Never => (NumberMode::Always, "on"),
+            };
+            editor.options.number_mode = mode;
+            editor.status_message = StatusMessage::from(format!("Line numbers: {}", name));
+        },
+        "reg" => registers(editor),
+        "view" => view(editor, parts.get(1).copied(), parts.get(2).copied())?,
+        "hardbreak" => {
+            let y = editor.cursor_position.y;
+            editor.status_message = StatusMessage::from(match editor.document.toggle_hard_break(y) {
+                Some(true) => "Hard break added".to_string(),
+                Some(false) => "Hard break removed".to_string(),
+                None => "Nothing to break on an empty line".to_string(),
+            });
+            editor.cursor_position.x = std::cmp::min(editor.cursor_position.x, editor.line_length(y));
+        },
+        "palette" => palette(editor)?,
+        "hr" => insert_block(editor, "---", "horizontal rule"),
+        "pagebreak" => {
+            let snippet = editor.options.page_break.clone();
+            insert_block(editor, &snippet, "page break");
+        }
+        "dd" => editor.delete_lines(1),
+        "dup" => editor.duplicate_line(true),
+        "mv" => match parts.get(1).and_then(|n| n.parse::<isize>().ok()) {
+            Some(n) if n != 0 => editor.move_line(n > 0, n.unsigned_abs()),
+            _ => editor.status_message = StatusMessage::from("Usage: mv +n|-n".to_string()),
+        },
         "d" => {
             let count = if parts.len() > 1 { parts[1].parse::<usize>().unwrap_or(1) } else { 1 };
             delete_words(editor, count, true);
@@ -104,6 +345,32 @@ pub fn execute_command(editor: &mut Editor, command: &str) -> Result<(), std::io
 
 // Helper functions
 
+// Lists every command with its key right-aligned, runs the one picked (asking for its
+// arguments first when it needs them)
+pub(crate) fn palette(editor: &mut Editor) -> Result<(), std::io::Error> {
+    let width = (editor.terminal.size().width as usize).saturating_sub(2);
+    let items = COMMANDS.iter().map(|c| {
+        let left = format!("{:<12} {}", c.name, c.description);
+        let pad = width.saturating_sub(left.chars().count() + c.key.chars().count()).max(1);
+        format!("{}{}{}", left, " ".repeat(pad), c.key)
+    }).collect();
+
+    let Some(i) = editor.pick("Commands", items)? else { return Ok(()); };
+    let info = &COMMANDS[i];
+    let command = if info.needs_args {
+        let prompt = format!("{} {}: ", info.name, info.args);
+        match editor.prompt(&prompt)? {
+            // s/ takes its arguments without a space
+            Some(args) if info.name.ends_with('/') => format!("{}{}", info.name, args),
+            Some(args) => format!("{} {}", info.name, args),
+            None => return Ok(()),
+        }
+    } else {
+        info.name.to_string()
+    };
+    execute_command(editor, &command)
+}
+
 // Wrap word for bold and italics
 fn wrap_word(editor: &mut Editor, wrapper: &str) {
     let y = editor.cursor_position.y;
@@ -123,13 +390,7 @@ fn wrap_word(editor: &mut Editor, wrapper: &str) {
         }
         
         // Find end of word
-        let mut end = chars.len();
-        for i in x..chars.len() {
-            if chars[i] == ' ' {
-                end = i;
-                break;
-            }
-        }
+        let end = chars.iter().skip(x).position(|c| *c == ' ').map_or(chars.len(), |i| x + i);
 
         // Since we are mutating the line, document needs to be called
         editor.document.insert_str(&Position { x: end, y }, wrapper); // Suffex first so we don't mess with indices for prefix insertion
@@ -140,39 +401,647 @@ fn wrap_word(editor: &mut Editor, wrapper: &str) {
     }
 }
 
-fn find_next(editor: &mut Editor, query: &str) {
-    let start_y = editor.cursor_position.y;
-    let mut y = start_y;
+// :export txt [path] [--underline]
+fn export(editor: &mut Editor, args: &[&str]) {
+    if args.first() != Some(&"txt") {
+        editor.status_message = StatusMessage::from("Usage: export txt [path] [--underline]".to_string());
+        return;
+    }
+
+    let options = crate::export::TextOptions {
+        underline_headings: args.contains(&"--underline"),
+    };
+    let path = match args[1..].iter().find(|a| !a.starts_with("--")) {
+        Some(path) => path.to_string(),
+        None => match &editor.document.filename {
+            Some(name) => std::path::Path::new(name).with_extension("txt").to_string_lossy().to_string(),
+            None => {
+                editor.status_message = StatusMessage::from("Error: no filename, use export txt <path>".to_string());
+                return;
+            }
+        },
+    };
+
+    // Never clobber the document we're exporting from
+    if editor.document.filename.as_deref() == Some(path.as_str()) {
+        editor.status_message = StatusMessage::from(format!("Error: {} is the file being edited", path));
+        return;
+    }
+
+    let text = crate::export::to_plain_text(&editor.document.rope.to_string(), &options);
+    editor.status_message = match std::fs::write(&path, &text) {
+        Ok(()) => StatusMessage::from(format!("Exported plain text to {} ({} lines)", path, text.lines().count())),
+        Err(e) => StatusMessage::from(format!("Error exporting to {}: {}", path, e)),
+    };
+}
+
+// :lint puts what lint::check finds in the location list, :lint fix applies the fixable
+// ones as a single undo step
+fn lint(editor: &mut Editor, action: Option<&str>) -> Result<(), std::io::Error> {
+    let text = editor.document.rope.to_string();
+    let lines: Vec<&str> = text.lines().collect();
+
+    match action {
+        Some("fix") => {
+            let (fixed, count) = crate::lint::fix(&lines);
+            if count > 0 {
+                let mut new_text = fixed.join("\n");
+                new_text.push('\n');
+                let last = editor.document.len() - 1;
+                editor.document.replace_lines(0, last, &new_text);
+                editor.cursor_position.y = std::cmp::min(editor.cursor_position.y, editor.document.len().saturating_sub(1));
+                editor.cursor_position.x = std::cmp::min(editor.cursor_position.x, editor.line_length(editor.cursor_position.y));
+            }
+            let remaining = crate::lint::check(&fixed.iter().map(String::as_str).collect::<Vec<_>>()).len();
+            editor.status_message = StatusMessage::from(format!("Fixed {} problems, {} left", count, remaining));
+        }
+        None => {
+            let findings = crate::lint::check(&lines);
+            if findings.is_empty() {
+                editor.status_message = StatusMessage::from("No problems found".to_string());
+                return Ok(());
+            }
+            let items = findings.into_iter().map(|f| Location {
+                file: editor.document.filename.clone(),
+                line: f.line,
+                col: 0,
+                message: format!("{}{}", f.message, if f.fixable { " (fixable)" } else { "" }),
+            }).collect();
+            let note = editor.locations.fill("lint", items);
+            open_locations(editor, note)?;
+        }
+        Some(_) => editor.status_message = StatusMessage::from("Usage: lint [fix]".to_string()),
+    }
+    Ok(())
+}
+
+// :history lists the backups kept on save (newest first) and restores the one picked,
+// :history restore <n> restores generation n directly. Only the buffer changes, u takes it back
+fn history(editor: &mut Editor, args: &[&str]) -> Result<(), std::io::Error> {
+    let Some(filename) = editor.document.filename.clone() else {
+        editor.status_message = StatusMessage::from("No file name, so no backups".to_string());
+        return Ok(());
+    };
+    let generations = crate::backup::generations(&filename);
+
+    let chosen = match args {
+        [] => {
+            if generations.is_empty() {
+                let hint = if editor.options.backups == 0 { " (turn them on with :set backups=<n>)" } else { "" };
+                editor.status_message = StatusMessage::from(format!("No backups of {}{}", filename, hint));
+                return Ok(());
+            }
+            let items = generations.iter()
+                .map(|g| format!("~{}~  {}", g.number, crate::backup::age(g.modified)))
+                .collect();
+            match editor.pick(&format!("Backups of {}", filename), items)? {
+                Some(i) => generations[i].number,
+                None => return Ok(()),
+            }
+        }
+        ["restore", n] => match n.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                editor.status_message = StatusMessage::from(format!("Invalid generation: {}", n));
+                return Ok(());
+            }
+        },
+        _ => {
+            editor.status_message = StatusMessage::from("Usage: history [restore <n>]".to_string());
+            return Ok(());
+        }
+    };
+
+    let Some(generation) = generations.iter().find(|g| g.number == chosen) else {
+        editor.status_message = StatusMessage::from(format!("No backup ~{}~ of {}", chosen, filename));
+        return Ok(());
+    };
+    match std::fs::read_to_string(&generation.path) {
+        Ok(text) => {
+            editor.document.set_text(&text);
+            editor.cursor_position.y = std::cmp::min(editor.cursor_position.y, editor.document.len().saturating_sub(1));
+            editor.cursor_position.x = std::cmp::min(editor.cursor_position.x, editor.line_length(editor.cursor_position.y));
+            editor.status_message = StatusMessage::from(format!(
+                "Restored backup ~{}~ from {}, not saved yet (u to undo)", chosen, crate::backup::age(generation.modified)
+            ));
+        }
+        Err(e) => editor.status_message = StatusMessage::from(format!("Error reading {}: {}", generation.path.display(), e)),
+    }
+    Ok(())
+}
+
+// Puts a block (:hr, :pagebreak) on its own lines below the cursor's, with a blank line on
+// either side unless there's one already (markdown needs them around a ---), and moves the
+// cursor to the line after it
+fn insert_block(editor: &mut Editor, block: &str, what: &str) {
+    let y = editor.cursor_position.y;
+    let doc = &editor.document;
+    if doc.fence_lines().get(y) == Some(&true) {
+        editor.status_message = StatusMessage::from(format!("Can't insert a {} inside a code fence", what));
+        return;
+    }
 
-    loop {
-        if y < editor.document.len() {
-            let line = editor.document.rope.line(y).to_string();
-            if let Some(byte_idx) = line.find(query) {
+    let blank = |y: usize| doc.line_text(y).trim().is_empty();
+    let has_next = !doc.is_last_line(y);
+    let blank_before = blank(y);
+    let mut text = String::new();
+    if !blank_before { text.push('\n'); }
+    text.push_str(block);
+    text.push('\n');
+    if has_next && !blank(y + 1) { text.push('\n'); }
 
-                // Convert byte index to char index
-                let char_idx = line[..byte_idx].chars().count();
+    editor.document.insert_line(y + 1, &text);
+    let after = y + 1 + usize::from(!blank_before) + block.lines().count();
+    editor.cursor_position = Position { x: 0, y: std::cmp::min(after, editor.document.len().saturating_sub(1)) };
+    editor.status_message = StatusMessage::from(format!("Inserted a {}", what));
+}
 
-                // Check if found after current cursor position only if cursor on y = stary_y
-                if y != start_y || char_idx > editor.cursor_position.x {
-                    editor.cursor_position.x = char_idx;
-                    editor.cursor_position.y = y;
-                    editor.status_message = StatusMessage::from(format!("Found: {}", query));
-                    return;
+// :e <path> opens another file in place of this one (a new, empty one if it doesn't exist
+// yet, :w creates it). :e! throws away unsaved changes to do it, and on its own reloads the
+// file from disk. The path can carry a location like the command line does (notes.md:120:14)
+fn edit(editor: &mut Editor, path: Option<&str>, force: bool) {
+    let (path, location) = match path.map(split_location) {
+        Some((path, location)) => (Some(path), location),
+        None => (None, None),
+    };
+    let Some(path) = path.or_else(|| editor.document.filename.clone().filter(|_| force)) else {
+        editor.status_message = StatusMessage::from("Usage: e <path>".to_string());
+        return;
+    };
+    if editor.document.is_dirty() && !force {
+        editor.status_message = StatusMessage::from("Unsaved changes (use :e! to discard)".to_string());
+        return;
+    }
+
+    let (document, message) = match Document::open(&path) {
+        Ok(document) => {
+            let message = format!("\"{}\" {} lines", path, document.len());
+            (document, message)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut document = Document::default();
+            document.filename = Some(path.clone());
+            (document, format!("\"{}\" [New file]", path))
+        }
+        Err(e) => {
+            editor.status_message = StatusMessage::from(format!("Could not open {}: {}", path, e));
+            return;
+        }
+    };
+    let locked = document.locked_by.is_some();
+    // The lock question leaves its own message
+    if editor.open_document(document) {
+        if !locked { editor.status_message = StatusMessage::from(message); }
+        // Clamped into the file, with a note when it was out of range
+        if let Some((line, col)) = location { editor.goto(line, col); }
+    }
+}
+
+// Hands the terminal to a shell command until it's done, then reports how it exited
+fn shell(editor: &mut Editor, command: &str) -> Result<(), std::io::Error> {
+    if command.is_empty() {
+        editor.status_message = StatusMessage::from("Usage: !<command>".to_string());
+        return Ok(());
+    }
+    editor.terminal.clear_screen();
+    editor.terminal.cursor_position(0, 0);
+    editor.terminal.flush()?;
+    editor.terminal.restore();
+    let result = crate::shell::run(command);
+    editor.terminal.resume()?;
+    editor.status_message = StatusMessage::from(match result {
+        Ok(status) if status.success() => format!("!{} finished", command),
+        Ok(status) => format!("!{} failed: {}", command, status),
+        Err(e) => format!("Couldn't run {}: {}", command, e),
+    });
+    Ok(())
+}
+
+// The commands that take a line range. The cursor ends up at the start of the range when
+// one was given
+fn ranged_command(editor: &mut Editor, (first, last): (usize, usize), explicit: bool, command: &str) -> Result<(), std::io::Error> {
+    // Search and replace logic (vim syntax so all one word not split by whitespace)
+    if let Some(rest) = command.strip_prefix("s/") {
+        match parse_substitute(rest) {
+            Ok(sub) => substitute(editor, (first, last), sub)?,
+            Err(msg) => {
+                editor.status_message = StatusMessage::from(msg);
+                return Ok(());
+            }
+        }
+        if explicit { editor.cursor_position = Position { x: 0, y: first }; }
+        return Ok(());
+    }
+
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    let count = last - first + 1;
+    match parts.as_slice() {
+        // Deleted lines go in the unnamed register like dd's
+        ["d"] => {
+            editor.cursor_position = Position { x: 0, y: first };
+            editor.delete_lines(count);
+            editor.status_message = StatusMessage::from(format!("Deleted {} lines", count));
+        }
+        ["t", rest @ ..] => {
+            let Ok(levels) = rest.first().map_or(Ok(1), |n| n.parse::<usize>()) else {
+                editor.status_message = StatusMessage::from("Usage: [range]t [count]".to_string());
+                return Ok(());
+            };
+            for y in first..=last {
+                editor.document.indent(y, levels);
+            }
+            editor.cursor_position = Position { x: 0, y: first };
+            editor.status_message = StatusMessage::from(format!("Indented {} lines", count));
+        }
+        _ => editor.status_message = StatusMessage::from(format!("Not a command that takes a range: {}", command)),
+    }
+    Ok(())
+}
+
+// The 1-based line `percent` of the way down `lines`, anything over 100% is the last line
+fn percent_line(percent: usize, lines: usize) -> usize {
+    (percent.min(100) * lines).div_ceil(100).max(1)
+}
+
+// The range in front of a command and what comes after it
+fn split_range(command: &str) -> (&str, &str) {
+    let len = command.chars().take_while(|c| c.is_ascii_digit() || ".,$%+-".contains(*c)).count();
+    command.split_at(len)
+}
+
+// Lines (0-based, inclusive) a range covers: % or nothing for the whole file, or one or two
+// addresses joined by a comma. An address is a line number, . for the cursor line or $ for
+// the last one, plus or minus some lines (.+5, $-2, or +3 on its own for 3 below the cursor)
+fn parse_range(editor: &Editor, range: &str) -> Result<(usize, usize), String> {
+    let lines = text_lines(editor);
+    if range.is_empty() || range == "%" { return Ok((0, lines.saturating_sub(1))); }
+
+    let (first, last) = range.split_once(',').unwrap_or((range, range));
+    let (Some(first), Some(last)) = (address(first, editor.cursor_position.y + 1, lines), address(last, editor.cursor_position.y + 1, lines)) else {
+        return Err(format!("Invalid range: {}", range));
+    };
+    if first < 1 || last < 1 || first > lines as isize || last > lines as isize {
+        return Err(format!("Range {} is outside the file (1-{})", range, lines));
+    }
+    if first > last { return Err(format!("Backwards range: {}", range)); }
+    Ok((first as usize - 1, last as usize - 1))
+}
+
+// A 1-based line number given the cursor's line (.) and the last one ($), None if it doesn't
+// parse or runs past what an isize holds (:99999999999999999999+1). May be out of the file
+fn address(text: &str, current: usize, last: usize) -> Option<isize> {
+    let offset_at = text.find(['+', '-']).unwrap_or(text.len());
+    let (base, mut offsets) = text.split_at(offset_at);
+    let mut line = match base {
+        "" | "." => current as isize,
+        "$" => last as isize,
+        _ => base.parse::<isize>().ok()?,
+    };
+    // Each +n or -n in turn, a bare + or - is one line
+    while let Some(sign) = offsets.chars().next() {
+        let digits = offsets[1..].chars().take_while(char::is_ascii_digit).count();
+        let n = if digits == 0 { 1 } else { offsets[1..=digits].parse::<isize>().ok()? };
+        line = match sign {
+            '+' => line.checked_add(n)?,
+            '-' => line.checked_sub(n)?,
+            _ => return None,
+        };
+        offsets = &offsets[1 + digits..];
+    }
+    Some(line)
+}
+
+// `line` with the chosen replacements made
+fn replaced(line: &str, chosen: &[crate::search::Replacement]) -> String {
+    let mut text = String::new();
+    let mut end = 0;
+    for replacement in chosen {
+        text.push_str(&line[end..replacement.bytes.start]);
+        text.push_str(&replacement.text);
+        end = replacement.bytes.end;
+    }
+    text.push_str(&line[end..]);
+    text
+}
+
+// Lines of text, not counting the empty "line" after the file's last line break
+fn text_lines(editor: &Editor) -> usize {
+    let lines = editor.document.len();
+    if lines > 1 && editor.document.line_length(lines - 1) == 0 { lines - 1 } else { lines }
+}
+
+struct Substitute {
+    pattern: String,
+    replacement: String,
+    global: bool, // Every match on a line, not just the first
+    confirm: bool, // Ask before each one
+}
+
+// old/new[/flags] with \/ for a slash inside either side
+fn parse_substitute(text: &str) -> Result<Substitute, String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'/') => {
+                chars.next();
+                parts.last_mut().unwrap().push('/');
+            }
+            '/' => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+    if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() {
+        return Err("Usage: [range]s/old/new[/gc]".to_string());
+    }
+    let flags = parts.get(2).cloned().unwrap_or_default();
+    if let Some(flag) = flags.chars().find(|c| !"gc".contains(*c)) {
+        return Err(format!("Unknown flag: {}", flag));
+    }
+    Ok(Substitute {
+        pattern: parts[0].clone(),
+        replacement: parts[1].clone(),
+        global: flags.contains('g'),
+        confirm: flags.contains('c'),
+    })
+}
+
+// [range]s/old/new/gc: the first match on each line of the range (the whole file by default),
+// every one with g, asking y/n/a/q before each with c. A regex with :set regex. Goes line
+// by line so only the lines that change are touched, and it's all one undo step however
+// many questions it took
+fn substitute(editor: &mut Editor, (first, last): (usize, usize), sub: Substitute) -> Result<(), std::io::Error> {
+    let mut query = Query::new(&sub.pattern);
+    query.regex = editor.options.regex;
+    query.case_sensitive = true;
+    let search = match Search::new(query) {
+        Ok(search) => search,
+        Err(msg) => {
+            editor.status_message = StatusMessage::from(msg);
+            return Ok(());
+        }
+    };
+
+    // While asking, the matches light up like a search's and the cursor sits on the one in question
+    let origin = editor.cursor_position;
+    let previous = if sub.confirm {
+        Some((editor.search.replace(search.clone()), editor.highlight_search))
+    } else {
+        None
+    };
+    editor.highlight_search |= sub.confirm;
+
+    let mut changed = Vec::new();
+    let mut lines = 0;
+    let mut count = 0;
+    let mut ask = sub.confirm;
+    let mut quit = false;
+    let mut interrupted = false;
+    for y in first..=last {
+        // Ctrl+C stops a long run, keeping what was replaced so far
+        if !ask && (y - first) % INTERRUPT_CHECK == INTERRUPT_CHECK - 1 && editor.interrupted()? {
+            interrupted = true;
+            break;
+        }
+        let line: std::borrow::Cow<str> = editor.document.rope.line(y).into();
+        let line = line.trim_end_matches(['\n', '\r']).to_string();
+        let limit = if sub.global { usize::MAX } else { 1 };
+        let mut chosen = Vec::new();
+        let mut shift = 0isize; // How far the replacements made so far moved the rest of the line
+        for replacement in search.replacements(&line, &sub.replacement).into_iter().take(limit) {
+            if ask {
+                editor.cursor_position = Position { x: replacement.start.saturating_add_signed(shift), y };
+                let question = format!("Replace with '{}'?", replacement.text);
+                match editor.confirm(&question, &[('y', "yes"), ('n', "no"), ('a', "all"), ('q', "quit")])? {
+                    Some('y') => (),
+                    Some('n') => continue,
+                    Some('a') => ask = false,
+                    _ => {
+                        quit = true;
+                        break;
+                    }
                 }
             }
+            shift += replacement.text.chars().count() as isize - line[replacement.bytes.clone()].chars().count() as isize;
+            chosen.push(replacement);
+            // Each answer shows on screen before the next question
+            if ask { editor.document.set_lines(&[(y, replaced(&line, &chosen))]); }
+        }
+
+        if !chosen.is_empty() {
+            count += chosen.len();
+            lines += 1;
+            changed.push((y, replaced(&line, &chosen)));
+        }
+        if quit { break; }
+    }
+    editor.document.set_lines(&changed);
+    if let Some((search, highlight)) = previous {
+        editor.search = search;
+        editor.highlight_search = highlight;
+    }
+    let done = if interrupted { "Interrupted, replaced" } else { "Replaced" };
+    editor.status_message = StatusMessage::from(format!("{} '{}' {} times on {} lines", done, sub.pattern, count, lines));
+
+    // Saftey clamp for cursor (pulls back to end of line)
+    editor.cursor_position = origin;
+    let current_len = editor.line_length(editor.cursor_position.y);
+    if editor.cursor_position.x > current_len {
+        editor.cursor_position.x = current_len;
+    }
+    Ok(())
+}
+
+// :lgrep <regex> puts every match in the open file in the location list
+fn lgrep(editor: &mut Editor, pattern: &str) {
+    if pattern.is_empty() {
+        editor.status_message = StatusMessage::from("Usage: lgrep <regex>".to_string());
+        return;
+    }
+    let mut query = Query::new(pattern);
+    query.regex = true;
+    let search = match Search::new(query) {
+        Ok(search) => search,
+        Err(msg) => {
+            editor.status_message = StatusMessage::from(msg);
+            return;
+        }
+    };
+
+    let items = search.all_matches(&editor.document).iter().map(|m| Location {
+        file: editor.document.filename.clone(),
+        line: m.y,
+        col: m.start,
+        message: editor.document.line_text(m.y).trim().to_string(),
+    }).collect();
+    editor.status_message = StatusMessage::from(editor.locations.fill("grep", items));
+}
+
+// :lopen lists the location list, Enter jumps to an entry. `note` stays up if nothing is picked
+fn open_locations(editor: &mut Editor, note: String) -> Result<(), std::io::Error> {
+    if editor.locations.items.is_empty() {
+        editor.status_message = StatusMessage::from("Location list is empty".to_string());
+        return Ok(());
+    }
+    let title = format!("Locations from {}", editor.locations.source);
+    let rows = editor.locations.rows();
+    match editor.pick(&title, rows)? {
+        Some(i) => editor.jump_to_location(i),
+        None => editor.status_message = StatusMessage::from(note),
+    }
+    Ok(())
+}
+
+// Dumps what the highlighter sees on the current line
+fn inspect(editor: &mut Editor) {
+    let y = editor.cursor_position.y;
+    let doc = &editor.document;
+    let types = doc.get_highlights(y);
+
+    let path = doc.node_path(y);
+    let quote_depth = path.iter().filter(|kind| **kind == "block_quote").count();
+    let in_fence = doc.fence_lines().get(y) == Some(&true);
+
+    let mut lines = vec![
+        format!("Line {}: {} chars", y + 1, types.len()),
+        format!("Code fence: {}, blockquote depth: {}", if in_fence { "inside" } else { "no" }, quote_depth),
+        format!("Syntax: {}", if path.is_empty() { "(no tree)".to_string() } else { path.join(" > ") }),
+    ];
+    let spans = crate::highlighting::spans(&types);
+    if spans.is_empty() {
+        lines.push("No highlights".to_string());
+    }
+    for (start, end, t) in spans {
+        let text: String = doc.rope.line(y).chars().skip(start).take(std::cmp::min(end - start, 40)).collect();
+        lines.push(format!("  {}..{} {:?} {:?}", start, end, t, text.trim_end_matches('\n')));
+    }
+    editor.status_message = StatusMessage::from(lines.join("\n"));
+}
+
+// :tasks sort orders the task list under the cursor (open ones first), :tasks archive moves
+// every done task to an Archive section at the end
+fn tasks(editor: &mut Editor, action: Option<&str>) {
+    let text = editor.document.rope.to_string();
+    let lines: Vec<&str> = text.lines().collect();
+
+    let message = match action {
+        Some("sort") => match crate::tasks::sort(&lines, editor.cursor_position.y) {
+            Ok((first, last, sorted, total, done)) => {
+                editor.document.replace_lines(first, last, &sorted);
+                format!("Sorted {} tasks, {} done", total, done)
+            }
+            Err(msg) => msg,
+        },
+        Some("archive") => {
+            let (archived, count) = crate::tasks::archive(&lines);
+            if count > 0 {
+                let last = editor.document.len() - 1;
+                editor.document.replace_lines(0, last, &archived);
+            }
+            format!("Archived {} done tasks", count)
         }
+        _ => "Usage: tasks sort|archive".to_string(),
+    };
+    editor.status_message = StatusMessage::from(message);
 
-        y += 1;
-        // Wrap to top if hitting bottom
-        if y >= editor.document.len() {
-            y = 0;
+    // Lines moved around, keep the cursor on the page
+    if editor.cursor_position.y >= editor.document.len() {
+        editor.cursor_position.y = editor.document.len().saturating_sub(1);
+    }
+    let current_len = editor.line_length(editor.cursor_position.y);
+    if editor.cursor_position.x > current_len {
+        editor.cursor_position.x = current_len;
+    }
+}
+
+// :conflict ours|theirs|both resolves the block under the cursor
+fn resolve_conflict(editor: &mut Editor, side: Option<&str>) {
+    use crate::conflict::Side;
+    let side = match side {
+        Some("ours") => Side::Ours,
+        Some("theirs") => Side::Theirs,
+        Some("both") => Side::Both,
+        _ => {
+            editor.status_message = StatusMessage::from("Usage: conflict ours|theirs|both".to_string());
+            return;
         }
-        // If word not found in full wrapping of doc, stop
-        if y == start_y {
-            editor.status_message = StatusMessage::from(format!("Not found: {}", query));
+    };
+
+    // Refuse to touch anything if the markers don't parse cleanly
+    let blocks = match crate::conflict::blocks(&editor.document) {
+        Ok(blocks) => blocks,
+        Err((y, msg)) => {
+            editor.status_message = StatusMessage::from(format!("Malformed conflict at line {}: {}", y + 1, msg));
             return;
         }
+    };
+
+    let y = editor.cursor_position.y;
+    let Some(block) = blocks.iter().find(|b| b.start <= y && y <= b.end) else {
+        editor.status_message = StatusMessage::from("Cursor is not inside a conflict".to_string());
+        return;
+    };
+
+    let text = crate::conflict::resolution(&editor.document, block, &side);
+    editor.document.replace_lines(block.start, block.end, &text);
+    editor.cursor_position = Position { x: 0, y: block.start };
+    if editor.cursor_position.y >= editor.document.len() {
+        editor.cursor_position.y = editor.document.len().saturating_sub(1);
+    }
+
+    let remaining = blocks.len() - 1;
+    editor.status_message = StatusMessage::from(format!("Conflict resolved, {} remaining", remaining));
+}
+
+// view save|load <name>, view list
+fn view(editor: &mut Editor, action: Option<&str>, name: Option<&str>) -> Result<(), std::io::Error> {
+    let index = name.and_then(|name| editor.document.views.iter().position(|v| v.name == name));
+    match (action, name) {
+        (Some("save"), Some(name)) => {
+            let view = View { name: name.to_string(), cursor: editor.cursor_position, row_offset: editor.row_offset };
+            match index {
+                Some(i) => editor.document.views[i] = view,
+                None => editor.document.views.push(view),
+            }
+            editor.status_message = StatusMessage::from(format!("Saved view {}", name));
+        }
+        (Some("load"), Some(name)) => match index {
+            Some(i) => editor.load_view(i),
+            None => editor.status_message = StatusMessage::from(format!("No view named {}", name)),
+        },
+        (Some("list"), _) => {
+            if editor.document.views.is_empty() {
+                editor.status_message = StatusMessage::from("No saved views".to_string());
+                return Ok(());
+            }
+            let items = editor.document.views.iter()
+                .map(|v| format!("{:<16} line {}", v.name, v.cursor.y + 1))
+                .collect();
+            if let Some(i) = editor.pick("Views", items)? {
+                editor.load_view(i);
+            }
+        }
+        _ => editor.status_message = StatusMessage::from("Usage: view save|load <name>, view list".to_string()),
     }
+    Ok(())
+}
+
+// One line per non-empty register, the unnamed one first, cut to the screen width
+fn registers(editor: &mut Editor) {
+    let mut names: Vec<char> = editor.registers.keys().copied().filter(|c| *c != '"').collect();
+    names.sort();
+    if editor.registers.contains_key(&'"') { names.insert(0, '"'); }
+    if names.is_empty() {
+        editor.status_message = StatusMessage::from("All registers are empty".to_string());
+        return;
+    }
+
+    let width = editor.terminal.size().width as usize;
+    let lines: Vec<String> = names.iter().map(|name| {
+        let text = editor.registers[name].replace('\n', "^J").replace('\t', "^I");
+        format!("\"{}  {}", name, text).chars().take(width).collect()
+    }).collect();
+    editor.status_message = StatusMessage::from(lines.join("\n"));
 }
 
 fn delete_words(editor: &mut Editor, count: usize, forward: bool) {
@@ -222,4 +1091,50 @@ fn delete_words(editor: &mut Editor, count: usize, forward: bool) {
     editor.document.delete_char_range(start_idx, end_idx);
     editor.cursor_position.y = editor.document.rope.char_to_line(start_idx);
     editor.cursor_position.x = start_idx - editor.document.rope.line_to_char(editor.cursor_position.y);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address("12", 5, 40), Some(12));
+        assert_eq!(address(".", 5, 40), Some(5));
+        assert_eq!(address("", 5, 40), Some(5));
+        assert_eq!(address("$", 5, 40), Some(40));
+        assert_eq!(address(".+3", 5, 40), Some(8));
+        assert_eq!(address("$-2", 5, 40), Some(38));
+        assert_eq!(address("+", 5, 40), Some(6));
+        assert_eq!(address("--", 5, 40), Some(3));
+        assert_eq!(address("-10", 5, 40), Some(-5));
+        assert_eq!(address("x", 5, 40), None);
+    }
+
+    #[test]
+    fn address_overflow_is_invalid() {
+        assert_eq!(address(&format!("{}+1", isize::MAX), 1, 1), None);
+        assert_eq!(address(&format!("{}", isize::MAX), 1, 1), Some(isize::MAX));
+        assert_eq!(address(&format!("1-{}-{}", isize::MAX, isize::MAX), 1, 1), None);
+        assert_eq!(address(&format!(".+{}+", isize::MAX), 1, 1), None);
+        assert_eq!(address("99999999999999999999999", 1, 1), None);
+    }
+
+    #[test]
+    fn percent_lines() {
+        assert_eq!(percent_line(0, 200), 1);
+        assert_eq!(percent_line(50, 200), 100);
+        assert_eq!(percent_line(1, 150), 2);
+        assert_eq!(percent_line(100, 200), 200);
+        assert_eq!(percent_line(250, 200), 200);
+        assert_eq!(percent_line(usize::MAX, 200), 200);
+        assert_eq!(percent_line(50, 1), 1);
+    }
+
+    #[test]
+    fn split_range_stops_at_the_command() {
+        assert_eq!(split_range("10,20d"), ("10,20", "d"));
+        assert_eq!(split_range("%s/a/b/"), ("%", "s/a/b/"));
+        assert_eq!(split_range(".+5"), (".+5", ""));
+        assert_eq!(split_range("w"), ("", "w"));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::document::Document;
+use ropey::RopeSlice;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Marker {
+    Start, // <<<<<<< ours
+    Base,  // ||||||| base (diff3 style)
+    Split, // =======
+    End,   // >>>>>>> theirs
+}
+
+// Line numbers of one conflict block
+pub struct ConflictBlock {
+    pub start: usize,
+    pub base: Option<usize>,
+    pub split: usize,
+    pub end: usize,
+}
+
+pub enum Side {
+    Ours,
+    Theirs,
+    Both,
+}
+
+pub fn marker(line: RopeSlice) -> Option<Marker> {
+    let head: Vec<char> = line.chars().take(8).filter(|c| *c != '\n' && *c != '\r').collect();
+    if head.len() < 7 { return None; }
+    let run: String = head[..7].iter().collect();
+    let rest = &head[7..];
+    // Markers are exactly 7 chars, followed by a space (and a label) or nothing
+    if !rest.is_empty() && rest != [' '] { return None; }
+
+    match run.as_str() {
+        "<<<<<<<" => Some(Marker::Start),
+        "|||||||" => Some(Marker::Base),
+        "=======" if rest.is_empty() => Some(Marker::Split),
+        ">>>>>>>" => Some(Marker::End),
+        _ => None,
+    }
+}
+
+// Whether line y is a conflict marker that's really part of a block. A ======= is only one
+// between <<<<<<< and >>>>>>>, on its own it's a setext heading underline or a rule
+pub fn is_marker_line(doc: &Document, y: usize) -> bool {
+    match marker(doc.rope.line(y)) {
+        Some(Marker::Split) => {
+            let above = (0..y).rev().map(|i| marker(doc.rope.line(i))).find(|m| matches!(m, Some(Marker::Start | Marker::End)));
+            let below = (y + 1..doc.len()).map(|i| marker(doc.rope.line(i))).find(|m| matches!(m, Some(Marker::Start | Marker::End)));
+            above == Some(Some(Marker::Start)) && below == Some(Some(Marker::End))
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+pub fn count(doc: &Document) -> usize {
+    doc.rope.lines().filter(|line| marker(*line) == Some(Marker::Start)).count()
+}
+
+// Parses every conflict block, or reports the first malformed marker as (line, message)
+pub fn blocks(doc: &Document) -> Result<Vec<ConflictBlock>, (usize, String)> {
+    let mut found = Vec::new();
+    let mut open: Option<(usize, Option<usize>, Option<usize>)> = None; // (start, base, split)
+
+    for (y, line) in doc.rope.lines().enumerate() {
+        let Some(m) = marker(line) else { continue; };
+        open = match (m, open) {
+            (Marker::Start, None) => Some((y, None, None)),
+            (Marker::Start, Some(_)) => return Err((y, "nested conflict start".to_string())),
+            (Marker::Base, Some((start, None, None))) => Some((start, Some(y), None)),
+            (Marker::Split, Some((start, base, None))) => Some((start, base, Some(y))),
+            (Marker::End, Some((start, base, Some(split)))) => {
+                found.push(ConflictBlock { start, base, split, end: y });
+                None
+            }
+            // A lone ======= outside a block is just text (setext heading, etc.)
+            (Marker::Split, None) => None,
+            (Marker::End, Some(_)) => return Err((y, "conflict end without =======".to_string())),
+            (_, _) => return Err((y, "unexpected conflict marker".to_string())),
+        };
+    }
+
+    match open {
+        Some((start, _, _)) => Err((start, "conflict is never closed".to_string())),
+        None => Ok(found),
+    }
+}
+
+// The text a block resolves to for the chosen side
+pub fn resolution(doc: &Document, block: &ConflictBlock, side: &Side) -> String {
+    let lines = |from: usize, to: usize| -> String {
+        (from..to).map(|y| doc.rope.line(y).to_string()).collect()
+    };
+    let ours = lines(block.start + 1, block.base.unwrap_or(block.split));
+    let theirs = lines(block.split + 1, block.end);
+
+    match side {
+        Side::Ours => ours,
+        Side::Theirs => theirs,
+        Side::Both => ours + &theirs,
+    }
+}
+
+// Next/previous conflict start from line y, wrapping around the document
+pub fn find_start(doc: &Document, y: usize, forward: bool) -> Option<usize> {
+    let len = doc.len();
+    (1..=len)
+        .map(|step| if forward { (y + step) % len } else { (y + len * 2 - step) % len })
+        .find(|line| marker(doc.rope.line(*line)) == Some(Marker::Start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked(text: &str) -> Vec<usize> {
+        let doc = Document::from_text(text);
+        (0..doc.len()).filter(|y| is_marker_line(&doc, *y)).collect()
+    }
+
+    #[test]
+    fn lone_split_is_text() {
+        assert_eq!(marked("Title\n=======\n\ntext\n"), Vec::<usize>::new());
+        assert_eq!(marked("<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\n"), [0, 2, 4]);
+        // Before, after and between blocks it's text again
+        let text = "=======\n<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\nHead\n=======\n";
+        assert_eq!(marked(text), [1, 3, 5]);
+        assert_eq!(marked("<<<<<<< ours\na\n=======\nno end\n"), [0]);
+    }
+
+    #[test]
+    fn blocks_skip_lone_splits() {
+        let doc = Document::from_text("Head\n=======\n<<<<<<< ours\na\n||||||| base\nb\n=======\nc\n>>>>>>> theirs\n");
+        let blocks = blocks(&doc).ok().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!((blocks[0].start, blocks[0].base, blocks[0].split, blocks[0].end), (2, Some(4), 6, 8));
+        assert_eq!(resolution(&doc, &blocks[0], &Side::Theirs), "c\n");
+        assert_eq!(resolution(&doc, &blocks[0], &Side::Both), "a\nc\n");
+    }
+}
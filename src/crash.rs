@@ -0,0 +1,167 @@
+// Saving what we can when the editor panics
+// Everything in here is best effort, a second panic would lose the buffer for good
+use crate::document::Document;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Filled by the panic hook, read after the terminal has been restored
+static PANIC_MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+
+// Replaces the default hook, which would print into the raw-mode screen
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = match info.location() {
+            Some(location) => format!("{} at {}:{}", payload_text(info.payload()), location.file(), location.line()),
+            None => payload_text(info.payload()),
+        };
+        if let Ok(mut slot) = PANIC_MESSAGE.lock() {
+            *slot = Some(message);
+        }
+    }));
+}
+
+fn payload_text(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Writes the rescue file and crash log, returns the line to print for the user
+pub fn report(document: &Document) -> String {
+    let message = PANIC_MESSAGE.lock().ok().and_then(|slot| slot.clone()).unwrap_or_else(|| "unknown panic".to_string());
+    log(&message);
+
+    let rescued = if document.is_dirty() { rescue(document) } else { None };
+    match rescued {
+        Some(path) => format!("vellum crashed ({}), unsaved changes are in {}", message, path.display()),
+        None if document.is_dirty() => format!("vellum crashed ({}), unsaved changes could not be written", message),
+        None => format!("vellum crashed ({}), there were no unsaved changes", message),
+    }
+}
+
+// notes.md -> notes.md.vel-crash, then .vel-crash.1, .2... so earlier rescues survive
+fn rescue(document: &Document) -> Option<PathBuf> {
+    let base = match &document.filename {
+        Some(name) => PathBuf::from(format!("{}.vel-crash", name)),
+        None => std::env::temp_dir().join(format!("vellum-{}.vel-crash", std::process::id())),
+    };
+    let path = (0..1000)
+        .map(|n| if n == 0 { base.clone() } else { PathBuf::from(format!("{}.{}", base.display(), n)) })
+        .find(|p| !p.exists())?;
+
+    // create_new so a file that appeared in the meantime still isn't overwritten
+    let file = OpenOptions::new().write(true).create_new(true).open(&path).ok()?;
+    document.rope.write_to(std::io::BufWriter::new(file)).ok()?;
+    Some(path)
+}
+
+fn log(message: &str) {
+    let Some(dir) = cache_dir() else { return; };
+    if fs::create_dir_all(&dir).is_err() { return; }
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(dir.join("crash.log")) {
+        let _ = writeln!(file, "{} {}", seconds, message);
+    }
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(base.join("vellum"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::Position;
+    use std::process::Command;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vellum-crash-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Runs crashing_child in a process of its own, the hook is process-wide
+    fn crash(file: &Path, cache: &Path) -> String {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["crash::tests::crashing_child", "--exact", "--nocapture"])
+            .env("VELLUM_CRASH_CHILD", file)
+            .env("XDG_CACHE_HOME", cache)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success(), "{}", stderr);
+        stderr
+    }
+
+    // Does what main does around a panic mid-edit, only when started by crash() above
+    #[test]
+    fn crashing_child() {
+        let Some(path) = std::env::var_os("VELLUM_CRASH_CHILD") else { return; };
+        let mut document = Document::open(path.to_str().unwrap()).unwrap();
+        install_hook();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            document.insert_str(&Position { x: 0, y: 1 }, "unsaved ");
+            panic!("controlled crash");
+        }));
+        assert!(result.is_err());
+        eprintln!("{}", report(&document));
+    }
+
+    #[test]
+    fn a_panic_rescues_the_unsaved_text() {
+        let dir = temp_dir("panic");
+        let (file, cache) = (dir.join("notes.md"), dir.join("cache"));
+        fs::write(&file, "# Notes\nkept\n").unwrap();
+
+        let stderr = crash(&file, &cache);
+        let rescue = dir.join("notes.md.vel-crash");
+        assert!(stderr.contains("vellum crashed (controlled crash at src/crash.rs:"), "{}", stderr);
+        assert!(stderr.contains(&format!("unsaved changes are in {}\n", rescue.display())), "{}", stderr);
+        assert_eq!(fs::read_to_string(&rescue).unwrap(), "# Notes\nunsaved kept\n");
+        // The file itself is left as it was last saved
+        assert_eq!(fs::read_to_string(&file).unwrap(), "# Notes\nkept\n");
+        let log = fs::read_to_string(cache.join("vellum").join("crash.log")).unwrap();
+        assert!(log.contains(" controlled crash at src/crash.rs:"), "{}", log);
+
+        // An earlier rescue is kept, the next one goes beside it
+        fs::write(&rescue, "an earlier rescue").unwrap();
+        let stderr = crash(&file, &cache);
+        assert!(stderr.contains(&format!("unsaved changes are in {}.1\n", rescue.display())), "{}", stderr);
+        assert_eq!(fs::read_to_string(&rescue).unwrap(), "an earlier rescue");
+        assert_eq!(fs::read_to_string(dir.join("notes.md.vel-crash.1")).unwrap(), "# Notes\nunsaved kept\n");
+        assert_eq!(fs::read_to_string(cache.join("vellum").join("crash.log")).unwrap().lines().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rescue_numbers_past_existing_files() {
+        let dir = temp_dir("numbers");
+        let file = dir.join("draft.md");
+        fs::write(&file, "saved\n").unwrap();
+        let mut document = Document::open(file.to_str().unwrap()).unwrap();
+        document.insert_str(&Position { x: 0, y: 0 }, "un");
+
+        for (n, expected) in ["draft.md.vel-crash", "draft.md.vel-crash.1", "draft.md.vel-crash.2"].iter().enumerate() {
+            assert_eq!(rescue(&document), Some(dir.join(expected)), "rescue {}", n);
+        }
+        // A gap gets filled, nothing that's there is written over
+        fs::remove_file(dir.join("draft.md.vel-crash.1")).unwrap();
+        assert_eq!(rescue(&document), Some(dir.join("draft.md.vel-crash.1")));
+        assert_eq!(rescue(&document), Some(dir.join("draft.md.vel-crash.3")));
+        for name in ["draft.md.vel-crash", "draft.md.vel-crash.1", "draft.md.vel-crash.2", "draft.md.vel-crash.3"] {
+            assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), "unsaved\n", "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,210 @@
-use ropey::Rope;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Error};
+use ropey::{Rope, RopeSlice};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::changes::{ChangeKind, ChangeLog};
 use crate::editor::Position;
 use crate::highlighting::Type;
-use tree_sitter::{Parser, Tree, Query, QueryCursor};
+use crate::indent::IndentStyle;
+use crate::lock::Lock;
+use crate::patch::OnDisk;
+use tree_sitter::{InputEdit, Parser, Point, Query, QueryCursor, Tree};
+
+const TYPING_GROUP: Duration = Duration::from_millis(700); // A pause longer than this in typing starts a new undo step
+const SNAPSHOT_COALESCE: Duration = Duration::from_millis(150); // Enters closer than this are a held key, one undo step
+
+// What an Insert Mode edit does, for grouping them into undo steps
+#[derive(Clone, Copy, PartialEq)]
+pub enum EditKind {
+    Typing,
+    Deleting,
+    Newline, // Starts a new step (unless it's auto-repeat), typing straight after joins it
+}
+
+// What a successful save wrote
+pub struct SaveReport {
+    pub lines: usize,
+    pub bytes: usize,
+    pub patched: Option<usize>, // Bytes written in place by a patch save, None for a full save
+    pub duration: Duration,
+}
+
+impl SaveReport {
+    // "notes.md" 214 lines, 8,312 bytes written (4 ms)
+    // "notes.md" 214 lines, 8,312 bytes (1,024 patched in place) (4 ms)
+    pub fn summary(&self, filename: &str) -> String {
+        let written = match self.patched {
+            Some(patched) => format!("{} bytes ({} patched in place)", thousands(self.bytes), thousands(patched)),
+            None => format!("{} bytes written", thousands(self.bytes)),
+        };
+        format!("\"{}\" {} lines, {} ({} ms)", filename, thousands(self.lines), written, self.duration.as_millis())
+    }
+}
+
+// Full save: writes a temp file next to the real one, syncs it and renames it over the
+// original, so a failed or half-done write (e.g. a patch save that gave up) never leaves
+// the file truncated. Symlinks are followed so the link itself stays a link
+fn write_atomically(filename: &str, text: &Rope) -> Result<(), Error> {
+    let target = std::fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let dir = target.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = target.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    let temp = dir.join(format!(".{}.vellum-{}", name, std::process::id()));
+
+    let _ = std::fs::remove_file(&temp); // Left over from a crash
+    let result = (|| {
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        if let Ok(meta) = std::fs::metadata(&target) {
+            file.set_permissions(meta.permissions())?;
+        }
+        let mut writer = BufWriter::new(file);
+        text.write_to(&mut writer)?;
+        writer.flush()?; // Dropping would flush too, but swallow the error
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&temp, &target)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+// 8312 -> "8,312"
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) { out.push(','); }
+        out.push(c);
+    }
+    out
+}
 
 pub struct Document {
     pub rope: Rope,
     pub filename: Option<String>,
     dirty: bool,
-    undo_stack: Vec<Rope>, // Past states
-    redo_stack: Vec<Rope>, // Future states
+    undo_stack: Vec<UndoStep>, // Oldest first, edits are recorded into the last one
+    redo_stack: Vec<UndoStep>,
+    pub undo_levels: usize, // Steps kept (mirrors the undolevels option)
+    on_disk: Option<OnDisk>, // The text as last loaded or saved, for patch saves
+    pub patch_save: bool, // Mirrors the patchsave option
     pub parser: Parser,
     pub tree: Option<Tree>,
     pub query: Query,
     pub source_string: String,
+    pub long_line: usize, // Lines longer than this are not reparsed on edit (mirrors the longline option)
+    last_typing: Option<(Instant, EditKind)>, // The last Insert Mode edit, while its undo step is still open
+    _lock: Option<Lock>, // Held only for its Drop, which removes our lock file
+    pub locked_by: Option<String>, // Another live editor holding the lock when we opened
+    pub read_only: bool,
+    line_edits: Vec<LineEdit>, // Line-level changes since the editor last collected them
+    pub views: Vec<View>, // Saved with :view save, in the order they were made
+    pub last_view: Option<usize>, // Last one loaded, Ctrl+6 cycles on from here
+    pub changes: ChangeLog, // What was edited this session, for :changes
+    group_depth: usize, // Open begin_edit_group calls, snapshots inside one are skipped so it all undoes at once
+    fences: RefCell<Option<Rc<Vec<bool>>>>, // fence_lines, until the text changes
+    revision: u64, // Changes with every edit, see revision()
+}
+
+// One change to the rope, with the text involved so it can be undone or made again
+enum Delta {
+    Insert { at: usize, text: String },
+    Remove { at: usize, text: String },
+}
+
+// Everything between two snapshots, and where the cursor was when it started
+struct UndoStep {
+    deltas: Vec<Delta>,
+    cursor: Position,
+    parent: Option<u64>, // content_hash of the text the step was made on, taken with its first delta
+}
+
+impl UndoStep {
+    // Takes the step back (undo) or makes it again (redo). False when a delta doesn't fit
+    // the text (`rope` is then left half done, so it should be a copy)
+    fn apply(&self, rope: &mut Rope, forward: bool) -> bool {
+        let apply = |rope: &mut Rope, delta: &Delta, insert: bool| match delta {
+            Delta::Insert { at, text } | Delta::Remove { at, text } if insert => {
+                if *at > rope.len_chars() { return false; }
+                rope.insert(*at, text);
+                true
+            }
+            Delta::Insert { at, text } | Delta::Remove { at, text } => {
+                let end = *at + text.chars().count();
+                if end > rope.len_chars() || rope.slice(*at..end) != text.as_str() { return false; }
+                rope.remove(*at..end);
+                true
+            }
+        };
+        if forward {
+            self.deltas.iter().all(|delta| apply(rope, delta, matches!(delta, Delta::Insert { .. })))
+        } else {
+            self.deltas.iter().rev().all(|delta| apply(rope, delta, matches!(delta, Delta::Remove { .. })))
+        }
+    }
+}
+
+// What undo() or redo() did
+pub struct HistoryMove {
+    pub applied: usize, // Steps taken
+    pub cursor: Option<Position>, // Where the last change undone (or redone) was made
+    pub invalidated: bool, // A step didn't fit the text, so the whole history was dropped
+}
+
+fn next_revision() -> u64 {
+    static REVISION: AtomicU64 = AtomicU64::new(0);
+    REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
+// FNV-1a over the text's bytes, chunk boundaries don't matter
+fn content_hash(text: &Rope) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.chunks().flat_map(str::bytes) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// A parked cursor and scroll position
+pub struct View {
+    pub name: String,
+    pub cursor: Position,
+    pub row_offset: usize,
+}
+
+// A change in terms of whole lines: starting at `start`, `removed` old lines became `added` new ones
+// (a change within a single line has both at 0)
+#[derive(Clone, Copy)]
+pub struct LineEdit {
+    pub start: usize,
+    pub removed: usize,
+    pub added: usize,
+}
+
+impl LineEdit {
+    // Where a line that was at `y` before this edit is now
+    // Lines inside the replaced region land at its start
+    pub fn shift(&self, y: usize) -> usize {
+        if y <= self.start {
+            y
+        } else if y >= self.start + self.removed {
+            y + self.added - self.removed
+        } else {
+            self.start
+        }
+    }
+
+    // Same for a spot in the text: its line moves along, and a spot on a line that went away
+    // goes to the start of the line the region now begins on
+    pub fn shift_position(&self, at: Position) -> Position {
+        let y = self.shift(at.y);
+        let inside = at.y > self.start && at.y < self.start + self.removed;
+        Position { x: if inside { 0 } else { at.x }, y }
+    }
 }
 
 impl Default for Document {
@@ -39,10 +229,25 @@ impl Default for Document {
             dirty: false,
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            undo_levels: 1000,
+            on_disk: None,
+            patch_save: false,
             parser,
-            tree: tree,
+            tree,
             query,
             source_string: String::new(),
+            long_line: 10_000,
+            last_typing: None,
+            _lock: None,
+            locked_by: None,
+            read_only: false,
+            line_edits: Vec::new(),
+            views: Vec::new(),
+            last_view: None,
+            changes: ChangeLog::default(),
+            group_depth: 0,
+            fences: RefCell::default(),
+            revision: next_revision(),
         }
     }
 }
@@ -51,6 +256,7 @@ impl Document {
     pub fn open(filename: &str) -> Result<Self, Error> {
         let file = File::open(filename)?;
         let rope = Rope::from_reader(BufReader::new(file))?;
+        let on_disk = OnDisk::record(filename, &rope);
 
         let mut parser = Parser::new();
         parser.set_language(tree_sitter_markdown::language()).expect("Failed to load markdown grammar");
@@ -67,62 +273,287 @@ impl Document {
             (fenced_code_block) @string"
         ).unwrap();
 
+        let (lock, locked_by) = match crate::lock::acquire(filename) {
+            Ok(lock) => (lock, None),
+            Err(holder) => (None, Some(holder)),
+        };
+
         Ok(Self {
             rope,
             filename: Some(filename.to_string()),
             dirty: false,
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            undo_levels: 1000,
+            on_disk,
+            patch_save: false,
             parser,
-            tree: tree,
+            tree,
             query,
             source_string: text,
+            long_line: 10_000,
+            last_typing: None,
+            _lock: lock,
+            locked_by,
+            read_only: false,
+            line_edits: Vec::new(),
+            views: Vec::new(),
+            last_view: None,
+            changes: ChangeLog::default(),
+            group_depth: 0,
+            fences: RefCell::default(),
+            revision: next_revision(),
         })
     }
     
-    pub fn save(&mut self) -> Result<(), Error> {
-        if let Some(filename) = &self.filename {
-            let file = File::create(filename)?;
-            self.rope.write_to(BufWriter::new(file))?;
-            self.dirty = false;
+    // Unnamed document holding the given text (e.g. read from stdin)
+    pub fn from_text(text: &str) -> Self {
+        let mut doc = Self::default();
+        doc.rope = Rope::from_str(text);
+        doc.tree = doc.parser.parse(text, None);
+        doc.source_string = text.to_string();
+        doc.revision = next_revision();
+        doc
+    }
+
+    pub fn save(&mut self) -> Result<SaveReport, Error> {
+        if self.read_only {
+            return Err(Error::other("file was opened read-only"));
         }
-        Ok(())
+        let Some(filename) = &self.filename else {
+            return Err(Error::other("no file name"));
+        };
+
+        let start = Instant::now();
+        let patched = match &self.on_disk {
+            Some(on_disk) if self.patch_save => crate::patch::save(on_disk, filename, &self.rope),
+            _ => None,
+        };
+        if patched.is_none() {
+            write_atomically(filename, &self.rope)?;
+        }
+        self.on_disk = OnDisk::record(filename, &self.rope);
+        self.dirty = false;
+
+        // The empty "line" after a trailing newline isn't one
+        let ends_with_newline = self.rope.len_chars() > 0 && self.rope.char(self.rope.len_chars() - 1) == '\n';
+        let lines = if ends_with_newline { self.len() - 1 } else { self.len() };
+        Ok(SaveReport { lines, bytes: self.rope.len_bytes(), patched, duration: start.elapsed() })
     }
 
-    // Snapshotting
-    pub fn snapshot(&mut self) {
-        self.undo_stack.push(self.rope.clone());
-        if self.undo_stack.len() > 100 {
-            self.undo_stack.remove(0); // Limit undo history to 100
+    // Snapshotting: starts a new undo step, the cursor is where undo puts it back to
+    // Only the edits made after it are kept, never a copy of the text
+    pub fn snapshot(&mut self, cursor: Position) {
+        if self.group_depth > 0 { return; }
+        self.last_typing = None;
+        match self.undo_stack.last_mut() {
+            Some(step) if step.deltas.is_empty() => step.cursor = cursor,
+            _ => self.undo_stack.push(UndoStep { deltas: Vec::new(), cursor, parent: None }),
         }
+        // The oldest steps go first, all at once if undolevels was just lowered
+        let excess = self.undo_stack.len().saturating_sub(self.undo_levels);
+        self.undo_stack.drain(..excess);
+    }
+
+    // Every change to the rope goes through these two, so it lands in the open undo step
+    fn apply_insert(&mut self, at: usize, text: &str) {
+        if text.is_empty() { return; }
+        self.tag_step();
+        self.rope.insert(at, text);
         self.redo_stack.clear(); // Can't redo if you edit the past
+        let Some(step) = self.undo_stack.last_mut() else { return; };
+        // Typing carries on the last insert instead of adding one delta per key
+        if let Some(Delta::Insert { at: start, text: last }) = step.deltas.last_mut()
+            && *start + last.chars().count() == at
+        {
+            last.push_str(text);
+            return;
+        }
+        step.deltas.push(Delta::Insert { at, text: text.to_string() });
+    }
+
+    fn apply_remove(&mut self, range: std::ops::Range<usize>) {
+        if range.is_empty() { return; }
+        self.tag_step();
+        let text = self.rope.slice(range.clone()).to_string();
+        self.rope.remove(range.clone());
+        self.redo_stack.clear();
+        let Some(step) = self.undo_stack.last_mut() else { return; };
+        // Backspace runs grow the last removal backwards, Delete runs forwards
+        if let Some(Delta::Remove { at, text: last }) = step.deltas.last_mut() {
+            if range.end == *at {
+                last.insert_str(0, &text);
+                *at = range.start;
+                return;
+            }
+            if range.start == *at {
+                last.push_str(&text);
+                return;
+            }
+        }
+        step.deltas.push(Delta::Remove { at: range.start, text });
+    }
+
+    // The open step's first edit records the text it starts from, so undo can tell whether the
+    // step still fits (see undo)
+    fn tag_step(&mut self) {
+        if let Some(step) = self.undo_stack.last_mut() && step.parent.is_none() {
+            step.parent = Some(content_hash(&self.rope));
+        }
+    }
+
+    // Swaps in a whole new text, recording only the part that differs
+    fn apply_text(&mut self, text: &str) {
+        let old = self.rope.to_string();
+        let edit = text_edit(&old, text);
+        let start = self.rope.byte_to_char(edit.start_byte);
+        let end = self.rope.byte_to_char(edit.old_end_byte);
+        self.apply_remove(start..end);
+        self.apply_insert(start, &text[edit.start_byte..edit.new_end_byte]);
+    }
+
+    // A snapshot nothing was recorded into isn't a step to undo
+    fn drop_empty_step(&mut self) {
+        if self.undo_stack.last().is_some_and(|step| step.deltas.is_empty()) {
+            self.undo_stack.pop();
+        }
     }
 
+    // Called before each Insert Mode edit: edits of the same kind with no pause longer than
+    // TYPING_GROUP between them share one undo step, anything else starts a new one. Enter
+    // always starts one, except that holding it auto-repeats dozens of times a second, so
+    // Enters coming faster than SNAPSHOT_COALESCE fold into the previous one
+    pub fn snapshot_typing(&mut self, cursor: Position, kind: EditKind) {
+        let now = Instant::now();
+        let joins = self.last_typing.is_some_and(|(at, last)| match (last, kind) {
+            (EditKind::Newline, EditKind::Newline) => now - at < SNAPSHOT_COALESCE,
+            (_, EditKind::Newline) => false,
+            // Typing straight after an Enter carries on its step
+            (EditKind::Newline, EditKind::Typing) => now - at < TYPING_GROUP,
+            (last, kind) => last == kind && now - at < TYPING_GROUP,
+        });
+        if !joins { self.snapshot(cursor); }
+        self.last_typing = Some((now, kind));
+    }
+
+    // The next Insert Mode edit starts a new undo step (leaving Insert Mode, moving the cursor)
+    pub fn break_typing(&mut self) {
+        self.last_typing = None;
+    }
+
+    // Everything up to the matching end_edit_group undoes as one step (a command, a macro
+    // run). Groups nest, only the outermost one snapshots
+    pub fn begin_edit_group(&mut self, cursor: Position) {
+        if self.group_depth == 0 { self.snapshot(cursor); }
+        self.group_depth += 1;
+    }
+
+    pub fn end_edit_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        self.last_typing = None;
+    }
+
+    // Reparses incrementally: the changed byte range is found by trimming the common
+    // prefix/suffix against the last parsed text, so tree-sitter can reuse the rest
     pub fn update_tree(&mut self) {
-        self.source_string = self.rope.to_string();
-        self.tree = self.parser.parse(&self.source_string, None);
+        self.fences.take();
+        self.revision = next_revision();
+        let new_text = self.rope.to_string();
+        let old_tree = self.tree.take();
+
+        let edit = text_edit(&self.source_string, &new_text);
+        let (start, old_end, new_end) = (edit.start_position.row, edit.old_end_position.row, edit.new_end_position.row);
+        if old_end != start || new_end != start {
+            self.line_edits.push(LineEdit { start, removed: old_end - start, added: new_end - start });
+        }
+        self.changes.record(&edit, &self.source_string, &new_text);
+
+        if let Some(mut tree) = old_tree {
+            tree.edit(&edit);
+
+            // Edits inside a huge line only shift the tree, reparsing would relex the whole
+            // line and that line is drawn without highlighting anyway
+            let line = self.rope.byte_to_line(edit.start_byte);
+            if self.rope.line(line).len_chars() > self.long_line {
+                self.tree = Some(tree);
+            } else {
+                self.tree = self.parser.parse(&new_text, Some(&tree));
+            }
+        } else {
+            self.tree = self.parser.parse(&new_text, None);
+        }
+        self.source_string = new_text;
     }
 
-    pub fn undo(&mut self) -> bool {
-        if let Some(prev) = self.undo_stack.pop() {
-            self.redo_stack.push(self.rope.clone());
-            self.rope = prev;
-            self.dirty = true;
-            self.update_tree();
-            return true;
+    // Hands over the line changes made since the last call
+    pub fn take_line_edits(&mut self) -> Vec<LineEdit> {
+        std::mem::take(&mut self.line_edits)
+    }
+
+    // Steps back up to `count` states, reparsing once at the end however many were taken.
+    // The cursor position moves to the redo stack with the change. A step that doesn't
+    // take the text back to the one it was made on (it was changed some way the history
+    // didn't see, like a reload) isn't applied: the text stays as it is and the history,
+    // no longer true of it, is dropped
+    pub fn undo(&mut self, count: usize) -> HistoryMove {
+        let mut done = HistoryMove { applied: 0, cursor: None, invalidated: false };
+        self.drop_empty_step();
+        while done.applied < count && let Some(step) = self.undo_stack.pop() {
+            let mut text = self.rope.clone();
+            if !step.apply(&mut text, false) || step.parent != Some(content_hash(&text)) {
+                done.invalidated = true;
+                break;
+            }
+            self.rope = text;
+            done.cursor = Some(step.cursor);
+            self.redo_stack.push(step);
+            done.applied += 1;
         }
-        false
+        self.finish_history_move(&done, ChangeKind::Undo);
+        done
     }
 
-    pub fn redo(&mut self) -> bool {
-        if let Some(next) = self.redo_stack.pop() {
-            self.undo_stack.push(self.rope.clone());
-            self.rope = next;
-            self.dirty = true;
+    // Same as undo, a step is made again only on the text it was made on
+    pub fn redo(&mut self, count: usize) -> HistoryMove {
+        let mut done = HistoryMove { applied: 0, cursor: None, invalidated: false };
+        self.drop_empty_step();
+        while done.applied < count && let Some(step) = self.redo_stack.pop() {
+            let mut text = self.rope.clone();
+            if step.parent != Some(content_hash(&self.rope)) || !step.apply(&mut text, true) {
+                done.invalidated = true;
+                break;
+            }
+            self.rope = text;
+            done.cursor = Some(step.cursor);
+            self.undo_stack.push(step);
+            done.applied += 1;
+        }
+        self.finish_history_move(&done, ChangeKind::Redo);
+        done
+    }
+
+    fn finish_history_move(&mut self, done: &HistoryMove, kind: ChangeKind) {
+        if done.invalidated {
+            self.undo_stack.clear();
+            self.redo_stack.clear();
+        }
+        if done.applied > 0 {
+            self.dirty = !self.matches_disk();
+            let label = self.changes.label(Some(kind));
             self.update_tree();
-            return true;
+            self.changes.label(label);
         }
-        false
+    }
+
+    // Undoing (or redoing) back to the text as it was last loaded or saved leaves nothing
+    // unsaved. Unnamed and not yet saved documents have nothing to match
+    fn matches_disk(&self) -> bool {
+        self.on_disk.as_ref().is_some_and(|on_disk| on_disk.holds(&self.rope))
+    }
+
+    // States left to undo and to redo
+    pub fn history_depth(&self) -> (usize, usize) {
+        (self.undo_stack.len(), self.redo_stack.len())
     }
 
     // Info getters
@@ -138,6 +569,30 @@ impl Document {
         self.rope.len_lines()
     }
 
+    // Length of a line in chars, ignoring its line break
+    pub fn line_length(&self, y: usize) -> usize {
+        if y >= self.len() { return 0; }
+        let line = self.rope.line(y);
+        let mut len = line.len_chars();
+
+        // Index from the end rather than iterating, lines can be megabytes long
+        if len > 0 && line.char(len - 1) == '\n' { len -= 1; }
+        if len > 0 && line.char(len - 1) == '\r' { len -= 1; }
+
+        len
+    }
+
+    // The nearest spot to `at` that's really in the text
+    pub fn clamp(&self, at: Position) -> Position {
+        let y = std::cmp::min(at.y, self.len().saturating_sub(1));
+        Position { x: std::cmp::min(at.x, self.line_length(y)), y }
+    }
+
+    // The empty "line" after a trailing newline doesn't count
+    pub fn is_last_line(&self, y: usize) -> bool {
+        y + 1 >= self.len() || (y + 2 == self.len() && self.rope.line(y + 1).len_chars() == 0)
+    }
+
     // Helper to translate 2d cursor into 1d rope index
     fn get_char_index(&self, at: &Position) -> usize {
         let line_start = self.rope.line_to_char(at.y);
@@ -148,7 +603,7 @@ impl Document {
     pub fn insert(&mut self, at: &Position, c: char) {
         if at.y > self.len() { return; }
         let char_idx = self.get_char_index(at);
-        self.rope.insert_char(char_idx, c);
+        self.apply_insert(char_idx, &c.to_string());
         self.dirty = true;
         self.update_tree();
     }
@@ -156,58 +611,267 @@ impl Document {
     pub fn insert_str(&mut self, at: &Position, text: &str) {
         if at.y >= self.len() { return; }
         let char_idx = self.get_char_index(at);
-        self.rope.insert(char_idx, text);
+        self.apply_insert(char_idx, text);
         self.dirty = true;
         self.update_tree();
     }
 
+    // At the end of a line this joins the next one on, taking all of its line break (\r\n
+    // too, not just the \r)
     pub fn delete(&mut self, at: &Position) {
         let char_idx = self.get_char_index(at);
         // Don't delete past end of file
         if char_idx < self.rope.len_chars() {
-            self.rope.remove(char_idx..char_idx + 1);
+            let end = if at.y + 1 < self.len() && at.x >= self.line_length(at.y) {
+                self.rope.line_to_char(at.y + 1)
+            } else {
+                char_idx + 1
+            };
+            self.apply_remove(char_idx..end);
             self.dirty = true;
             self.update_tree();
         }
     }
 
-    pub fn delete_line(&mut self, y: usize) {
-        if y < self.len() {
-            let start_char = self.rope.line_to_char(y);
-            let end_char = if y + 1 < self.len() {
-                self.rope.line_to_char(y + 1)
+    // Removes `count` lines from y with their newlines (fewer near the end), returns the
+    // removed text (always ending in a newline)
+    pub fn delete_lines(&mut self, y: usize, count: usize) -> String {
+        if y >= self.len() || count == 0 { return String::new(); }
+        let end = std::cmp::min(y + count, self.len());
+
+        let first_char = self.rope.line_to_char(y);
+        let mut start_char = first_char;
+        let end_char = if end < self.len() {
+            self.rope.line_to_char(end)
+        } else {
+            // The last line has no newline of its own, take the one before it instead, both
+            // halves of a \r\n
+            if y > 0 { start_char -= 1; }
+            if y > 0 && start_char > 0 && self.rope.char(start_char - 1) == '\r' { start_char -= 1; }
+            self.rope.len_chars()
+        };
+
+        let mut removed = self.rope.slice(first_char..end_char).to_string();
+        if !removed.ends_with('\n') { removed.push('\n'); }
+        self.apply_remove(start_char..end_char);
+        self.dirty = true;
+        self.update_tree();
+        removed
+    }
+
+    // Joins line y with the next one: the line break and the next line's indentation become
+    // a single space (none when either side is empty). Returns the join column, None on the last line
+    pub fn join_lines(&mut self, y: usize) -> Option<usize> {
+        if self.is_last_line(y) { return None; }
+        let line = self.rope.line(y).to_string();
+        let body = line.trim_end_matches(['\n', '\r']);
+        let next = self.rope.line(y + 1).to_string();
+        let next_body = next.trim_end_matches(['\n', '\r']);
+        let rest = next_body.trim_start_matches([' ', '\t']);
+
+        let joined_at = body.chars().count();
+        let separator = if body.is_empty() || rest.is_empty() || body.ends_with([' ', '\t']) { "" } else { " " };
+
+        // From the end of line y's text up to where the next line's text starts
+        let from = self.rope.line_to_char(y) + joined_at;
+        let to = self.rope.line_to_char(y + 1) + (next_body.chars().count() - rest.chars().count());
+        self.apply_remove(from..to);
+        self.apply_insert(from, separator);
+        self.dirty = true;
+        self.update_tree();
+        Some(joined_at)
+    }
+
+    // Char indices covering a selection between two positions in either order, both ends
+    // included. A selection ending on a line break takes the break with it
+    pub fn selection_bounds(&self, a: &Position, b: &Position) -> (usize, usize) {
+        let (start, end) = if (a.y, a.x) <= (b.y, b.x) { (a, b) } else { (b, a) };
+        let len = self.rope.len_chars();
+        let clamp = |at: &Position| {
+            if at.y >= self.len() { return len; }
+            std::cmp::min(self.get_char_index(at), len)
+        };
+        (clamp(start), std::cmp::min(clamp(end) + 1, len))
+    }
+
+    pub fn text_range(&self, a: &Position, b: &Position) -> String {
+        let (start, end) = self.selection_bounds(a, b);
+        self.rope.slice(start..end).to_string()
+    }
+
+    // Removes a selection (see selection_bounds), returns what was removed
+    pub fn delete_range(&mut self, a: &Position, b: &Position) -> String {
+        let (start, end) = self.selection_bounds(a, b);
+        let removed = self.rope.slice(start..end).to_string();
+        self.delete_char_range(start, end);
+        removed
+    }
+
+    // Swaps the char at a position for another one in place
+    pub fn replace_char(&mut self, at: &Position, c: char) {
+        if at.y >= self.len() { return; }
+        let idx = self.get_char_index(at);
+        if idx >= self.rope.len_chars() { return; }
+        self.apply_remove(idx..idx + 1);
+        self.apply_insert(idx, &c.to_string());
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Flips the case of chars start..end (char indices), returns how many chars took their
+    // place: an uppercased ß is SS, so the text can grow
+    pub fn toggle_case(&mut self, start: usize, end: usize) -> usize {
+        let end = std::cmp::min(end, self.rope.len_chars());
+        if start >= end { return 0; }
+        let old = self.rope.slice(start..end).to_string();
+        let new: String = old.chars().flat_map(|c| {
+            let flipped: Vec<char> = if c.is_lowercase() {
+                c.to_uppercase().collect()
+            } else if c.is_uppercase() {
+                c.to_lowercase().collect()
             } else {
-                self.rope.len_chars()
+                vec![c]
             };
-            self.rope.remove(start_char..end_char);
+            flipped
+        }).collect();
+        if new != old {
+            self.apply_remove(start..end);
+            self.apply_insert(start, &new);
             self.dirty = true;
             self.update_tree();
         }
+        new.chars().count()
     }
 
     pub fn delete_char_range(&mut self, start: usize, end: usize) {
         if start < end && start <= self.rope.len_chars() {
             let actual_end = std::cmp::min(end, self.rope.len_chars());
-            self.rope.remove(start..actual_end);
+            self.apply_remove(start..actual_end);
             self.dirty = true;
             self.update_tree();
         }
     }
 
     // Comamnd helpers
-    pub fn replace(&mut self, target: &str, replacement: &str) -> usize {
-        // Convert to string, replace, and rebuild the rope
-        // Works for now but may get slow with larget files
-        let text = self.rope.to_string();
-        let count = text.matches(target).count();
-
-        if count > 0 {
-            let new_text = text.replace(target, replacement);
-            self.rope = ropey::Rope::from_str(&new_text);
-            self.dirty = true;
-            self.update_tree();
+    // Puts new text (without line breaks at the end) in place of the given lines, editing
+    // the rope line by line and reparsing once at the end
+    pub fn set_lines(&mut self, lines: &[(usize, String)]) {
+        // Bottom up, so a replacement with line breaks in it doesn't move the lines still to do
+        let unchanged = |y: usize, text: &str| self.rope.line(y).slice(..self.line_length(y)) == text;
+        let mut lines: Vec<&(usize, String)> = lines.iter().filter(|(y, text)| *y < self.len() && !unchanged(*y, text)).collect();
+        if lines.is_empty() { return; }
+        lines.sort_by_key(|(y, _)| std::cmp::Reverse(*y));
+        for (y, text) in lines {
+            let from = self.rope.line_to_char(*y);
+            self.apply_remove(from..from + self.line_length(*y));
+            self.apply_insert(from, text);
+        }
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Swaps lines start..=end for new text (which carries its own newlines)
+    pub fn replace_lines(&mut self, start: usize, end: usize, text: &str) {
+        if start > end || end >= self.len() { return; }
+        let from = self.rope.line_to_char(start);
+        let to = if end + 1 < self.len() { self.rope.line_to_char(end + 1) } else { self.rope.len_chars() };
+
+        // Don't add a trailing newline the file didn't have
+        let had_newline = to > from && self.rope.char(to - 1) == '\n';
+        let text = if had_newline { text } else { text.strip_suffix('\n').unwrap_or(text) };
+
+        self.apply_remove(from..to);
+        self.apply_insert(from, text);
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Replaces the whole text (restoring a backup), exactly as given
+    pub fn set_text(&mut self, text: &str) {
+        self.apply_text(text);
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Line y as it would be yanked, always ending in a newline
+    pub fn line_text(&self, y: usize) -> String {
+        let mut text = self.rope.line(y).to_string();
+        if !text.ends_with('\n') { text.push('\n'); }
+        text
+    }
+
+    // Puts a copy of line y right below it
+    pub fn duplicate_line(&mut self, y: usize) {
+        if y >= self.len() { return; }
+        let text = self.line_text(y);
+        self.insert_line(y + 1, &text);
+    }
+
+    // Swaps line y with the one below (or above) it in one edit, a file without a trailing
+    // newline still has none afterwards. Returns where line y ended up, None at either end
+    // (the empty "line" after a trailing newline isn't one to swap with)
+    pub fn move_line(&mut self, y: usize, down: bool) -> Option<usize> {
+        let phantom = |y: usize| y > 0 && y + 1 == self.len() && self.rope.line(y).len_chars() == 0;
+        if y >= self.len() || phantom(y) { return None; }
+        let other = if down { y + 1 } else { y.checked_sub(1)? };
+        if other >= self.len() || phantom(other) { return None; }
+
+        let (first, second) = (y.min(other), y.max(other));
+        let text = format!("{}{}", self.line_text(second), self.line_text(first));
+        self.replace_lines(first, second, &text);
+        Some(other)
+    }
+
+    // Inserts whole lines (text ending in a newline) so they start at line y,
+    // y == len() appends after the last line
+    pub fn insert_line(&mut self, y: usize, text: &str) {
+        if y > self.len() { return; }
+        let at = if y < self.len() { self.rope.line_to_char(y) } else { self.rope.len_chars() };
+        let ends_open = at == self.rope.len_chars() && at > 0 && self.rope.char(at - 1) != '\n';
+
+        if ends_open {
+            // The last line has no newline to insert after, so borrow the pasted one
+            self.apply_insert(at, &format!("\n{}", text.strip_suffix('\n').unwrap_or(text)));
+        } else {
+            self.apply_insert(at, text);
         }
-        count
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Shifts pasted lines so the first one lines up with line y's indentation,
+    // moving the rest by the same amount. Contents of code fences in the paste stay as they are
+    pub fn reindent_paste(&self, y: usize, text: &str) -> String {
+        let indent_of = |line: &str| line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        let target: String = self.rope.line(y).chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+        let Some(first) = text.lines().find(|line| !line.trim().is_empty()) else { return text.to_string(); };
+        let delta = target.chars().count() as isize - indent_of(first) as isize;
+        let pad = target.chars().next().unwrap_or(' ').to_string().repeat(delta.unsigned_abs());
+
+        let mut out = String::new();
+        let mut fence: Option<(char, usize)> = None;
+        for line in text.split_inclusive('\n') {
+            // The ``` lines move with everything else, only what's between them is left alone
+            let marker = fence_marker(line);
+            let closes = matches!((fence, marker), (Some((fc, flen)), Some((c, len))) if c == fc && len >= flen);
+            let inside = fence.is_some() && !closes;
+            if closes {
+                fence = None;
+            } else if fence.is_none() {
+                fence = marker;
+            }
+
+            if inside || line.trim().is_empty() {
+                out.push_str(line);
+            } else if delta >= 0 {
+                out.push_str(&pad);
+                out.push_str(line);
+            } else {
+                let strip = std::cmp::min(delta.unsigned_abs(), indent_of(line));
+                out.extend(line.chars().skip(strip));
+            }
+        }
+        out
     }
 
     pub fn set_header(&mut self, y: usize, level: usize) {
@@ -222,8 +886,8 @@ impl Document {
         let line_len = line.chars().count();
 
         // Remove old line and insert the formatted one
-        self.rope.remove(char_idx..(char_idx + line_len));
-        self.rope.insert(char_idx, &new_content);
+        self.apply_remove(char_idx..(char_idx + line_len));
+        self.apply_insert(char_idx, &new_content);
         self.dirty = true;
         self.update_tree();
     }
@@ -232,9 +896,114 @@ impl Document {
         if y > self.len() { return; }
         let char_idx = self.rope.line_to_char(y);
         let spaces = " ".repeat(count * 4);
-        self.rope.insert(char_idx, &spaces);
+        self.apply_insert(char_idx, &spaces);
+        self.dirty = true;
+        self.update_tree();
+    }
+
+    // Adds or removes the two trailing spaces that make a markdown hard line break
+    // Returns the new state, None for blank lines where a break means nothing
+    pub fn toggle_hard_break(&mut self, y: usize) -> Option<bool> {
+        if y >= self.len() { return None; }
+        let line = self.rope.line(y).to_string();
+        let body = line.trim_end_matches(['\n', '\r']);
+        let text = body.trim_end_matches(' ');
+        if text.trim().is_empty() { return None; }
+
+        let on = has_hard_break(self.rope.line(y));
+        let new_body = if on { text.to_string() } else { format!("{}  ", text) };
+        self.replace_lines(y, y, &format!("{}{}", new_body, &line[body.len()..]));
+        Some(!on)
+    }
+
+    // Takes `count` levels of indentation off (a tab, or up to 4 spaces each), lines with
+    // less than that lose what they have. Returns how many chars were removed
+    pub fn dedent(&mut self, y: usize, count: usize) -> usize {
+        if y >= self.len() { return 0; }
+        let line = self.rope.line(y);
+        let mut width = 0;
+        for _ in 0..count {
+            let level = if width < line.len_chars() && line.char(width) == '\t' {
+                1
+            } else {
+                line.chars().skip(width).take(4).take_while(|c| *c == ' ').count()
+            };
+            if level == 0 { break; }
+            width += level;
+        }
+        if width == 0 { return 0; }
+
+        let char_idx = self.rope.line_to_char(y);
+        self.apply_remove(char_idx..char_idx + width);
         self.dirty = true;
         self.update_tree();
+        width
+    }
+
+    // Tells text apart without looking at it, for whatever caches something worked out from
+    // it: a new value after every edit, and never the same for two documents
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // Which lines sit inside a fenced code block (the ``` lines themselves included)
+    // Worked out once per change to the text, it's needed on every redraw
+    pub fn fence_lines(&self) -> Rc<Vec<bool>> {
+        self.fences.borrow_mut().get_or_insert_with(|| Rc::new(self.scan_fences())).clone()
+    }
+
+    fn scan_fences(&self) -> Vec<bool> {
+        let mut lines = Vec::with_capacity(self.len());
+        let mut open: Option<(char, usize)> = None;
+
+        for y in 0..self.len() {
+            // Only the start of the line matters, don't copy huge lines
+            let head: String = self.rope.line(y).chars().take(256).collect();
+            let marker = fence_marker(&head);
+            match (open, marker) {
+                (None, Some(m)) => {
+                    open = Some(m);
+                    lines.push(true);
+                }
+                (Some((c, len)), Some((mc, mlen))) if c == mc && mlen >= len => {
+                    open = None;
+                    lines.push(true);
+                }
+                (Some(_), _) => lines.push(true),
+                (None, None) => lines.push(false),
+            }
+        }
+        lines
+    }
+
+    // Converts leading whitespace on every line to the given style
+    // Returns how many lines changed
+    pub fn retab(&mut self, style: IndentStyle, tabstop: usize, skip_fences: bool) -> usize {
+        let fences = if skip_fences { self.fence_lines() } else { Rc::default() };
+        let mut changed = 0;
+
+        for y in 0..self.len() {
+            if fences.get(y) == Some(&true) { continue; }
+
+            let (tabs, spaces) = crate::indent::leading_whitespace(self.rope.line(y));
+            let ws_len = tabs + spaces;
+            if ws_len == 0 { continue; }
+
+            let line_start = self.rope.line_to_char(y);
+            let old = self.rope.slice(line_start..line_start + ws_len).to_string();
+            let new = crate::indent::retab_leading(&old, style, tabstop);
+            if old != new {
+                self.apply_remove(line_start..line_start + ws_len);
+                self.apply_insert(line_start, &new);
+                changed += 1;
+            }
+        }
+
+        if changed > 0 {
+            self.dirty = true;
+            self.update_tree();
+        }
+        changed
     }
 
     pub fn get_highlights(&self, y: usize) -> Vec<crate::highlighting::Type> {
@@ -284,6 +1053,718 @@ impl Document {
                 }
             }
         }
+
+        // Merge conflict markers override everything else on the line
+        if crate::conflict::is_marker_line(self, y) {
+            colors.fill(Type::Conflict);
+        }
         colors
     }
-}
\ No newline at end of file
+
+    // Syntax node kinds from the root down to where the text of line y starts
+    // (past indentation and blockquote markers, so nested quotes show up)
+    pub fn node_path(&self, y: usize) -> Vec<&'static str> {
+        let Some(tree) = &self.tree else { return Vec::new(); };
+        let line = self.rope.line(y);
+        let indent = line.chars().take_while(|c| matches!(c, ' ' | '\t' | '>')).count();
+        let byte = self.rope.char_to_byte(self.rope.line_to_char(y) + indent);
+
+        let mut path = Vec::new();
+        let mut node = tree.root_node().descendant_for_byte_range(byte, byte);
+        while let Some(n) = node {
+            path.push(n.kind());
+            node = n.parent();
+        }
+        path.reverse();
+        path
+    }
+
+    // The blocks line y sits in, outermost first: the section's heading ("H2"), then
+    // quotes, lists and code from the syntax tree, nesting counted ("list 2")
+    pub fn block_context(&self, y: usize) -> Vec<String> {
+        let mut chain = Vec::new();
+        if let Some(level) = self.section_level(y) {
+            chain.push(format!("H{}", level));
+        }
+
+        let mut blocks: Vec<(&str, usize)> = Vec::new();
+        for kind in self.node_path(y) {
+            let name = match kind {
+                "block_quote" => "quote",
+                "list_item" => "list",
+                "fenced_code_block" | "indented_code_block" => "code",
+                "table" => "table",
+                _ => continue,
+            };
+            match blocks.last_mut() {
+                Some((last, depth)) if *last == name => *depth += 1,
+                _ => blocks.push((name, 1)),
+            }
+        }
+        chain.extend(blocks.into_iter().map(|(name, depth)| {
+            if depth > 1 { format!("{} {}", name, depth) } else { name.to_string() }
+        }));
+        chain
+    }
+
+    // Level of the last top-level heading at or above line y, headings inside code never
+    // count since the tree doesn't see them as headings
+    fn section_level(&self, y: usize) -> Option<usize> {
+        let tree = self.tree.as_ref()?;
+        let root = tree.root_node();
+        let mut cursor = root.walk();
+        let heading = root.named_children(&mut cursor)
+            .take_while(|node| node.start_position().row <= y)
+            .filter(|node| matches!(node.kind(), "atx_heading" | "setext_heading"))
+            .last()?;
+
+        let first = heading.child(0)?.kind();
+        if let Some(level) = first.strip_prefix("atx_h").and_then(|rest| rest.strip_suffix("_marker")) {
+            return level.parse().ok();
+        }
+        // Setext: the underline is the last child, === for 1 and --- for 2
+        let underline = heading.child(heading.child_count().checked_sub(1)?)?.kind();
+        Some(if underline.contains("h1") { 1 } else { 2 })
+    }
+}
+
+// Two or more trailing spaces after some text, a markdown hard line break. Reads the line
+// from the end, so huge lines cost no more than short ones
+pub fn has_hard_break(line: RopeSlice) -> bool {
+    let mut chars = line.chars_at(line.len_chars()).reversed().skip_while(|c| matches!(c, '\n' | '\r')).peekable();
+    let mut spaces = 0;
+    while chars.next_if_eq(&' ').is_some() { spaces += 1; }
+    spaces >= 2 && chars.any(|c| !c.is_whitespace())
+}
+
+// Returns the fence character and run length if the line opens or closes a code fence
+pub fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start_matches(' ');
+    let c = trimmed.chars().next()?;
+    if c != '`' && c != '~' { return None; }
+
+    let len = trimmed.chars().take_while(|ch| *ch == c).count();
+    if len >= 3 { Some((c, len)) } else { None }
+}
+
+// Describes the difference between two versions of the text as a single tree-sitter edit
+fn text_edit(old: &str, new: &str) -> InputEdit {
+    let (old_bytes, new_bytes) = (old.as_bytes(), new.as_bytes());
+
+    let mut prefix = old_bytes.iter().zip(new_bytes).take_while(|(a, b)| a == b).count();
+    while !new.is_char_boundary(prefix) || !old.is_char_boundary(prefix) { prefix -= 1; }
+
+    let max_suffix = std::cmp::min(old_bytes.len(), new_bytes.len()) - prefix;
+    let mut suffix = old_bytes.iter().rev().zip(new_bytes.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !new.is_char_boundary(new.len() - suffix) || !old.is_char_boundary(old.len() - suffix) { suffix -= 1; }
+
+    let start_position = point_at(new, prefix, Point { row: 0, column: 0 }, 0);
+    InputEdit {
+        start_byte: prefix,
+        old_end_byte: old.len() - suffix,
+        new_end_byte: new.len() - suffix,
+        start_position,
+        old_end_position: point_at(old, old.len() - suffix, start_position, prefix),
+        new_end_position: point_at(new, new.len() - suffix, start_position, prefix),
+    }
+}
+
+// Row/column of `byte`, counting forward from a known point at `from`
+fn point_at(text: &str, byte: usize, from: Point, from_byte: usize) -> Point {
+    let span = &text.as_bytes()[from_byte..byte];
+    match span.iter().rposition(|b| *b == b'\n') {
+        Some(last) => Point {
+            row: from.row + span.iter().filter(|b| **b == b'\n').count(),
+            column: span.len() - last - 1,
+        },
+        None => Point { row: from.row, column: from.column + span.len() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A fresh directory per test, so lock and temp files can't collide
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vellum-document-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open(path: &Path) -> Document {
+        Document::open(path.to_str().unwrap()).unwrap()
+    }
+
+    // Small deterministic generator, so a failure can be replayed from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize % n.max(1)
+        }
+    }
+
+    fn text(doc: &Document) -> String {
+        doc.rope.to_string()
+    }
+
+    // One random edit through the public editing calls, always one that changes the text
+    fn random_edit(doc: &mut Document, rng: &mut Rng) {
+        let before = text(doc);
+        while text(doc) == before {
+            let y = rng.below(doc.len());
+            let x = rng.below(doc.line_length(y) + 1);
+            let at = Position { x, y };
+            let pieces = ["a", "é", "漢", " ", "\n", "\r\n", "**", "- "];
+            match rng.below(6) {
+                0 => doc.insert_str(&at, pieces[rng.below(pieces.len())]),
+                1 => doc.delete(&at),
+                2 => {
+                    let start = rng.below(doc.rope.len_chars());
+                    doc.delete_char_range(start, start + 1 + rng.below(8));
+                }
+                3 => { doc.join_lines(y); }
+                4 if doc.len() > 2 => { doc.delete_lines(y, 1 + rng.below(2)); }
+                _ => doc.set_lines(&[(y, format!("line {}", rng.below(100)))]),
+            }
+        }
+    }
+
+    // Undo and redo replayed against the simplest possible history: a full copy of the text
+    // after every step, and which one we're at
+    #[test]
+    fn undo_matches_full_text_history() {
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let mut doc = Document::from_text("# Title\n\nsome text\n- item\n");
+            doc.undo_levels = 12;
+            let mut states = vec![text(&doc)];
+            let mut current = 0;
+            for step in 0..300 {
+                match rng.below(5) {
+                    0 => {
+                        let count = 1 + rng.below(3);
+                        let applied = doc.undo(count).applied;
+                        assert_eq!(applied, count.min(current), "seed {}", seed);
+                        current -= applied;
+                    }
+                    1 => {
+                        let count = 1 + rng.below(3);
+                        let applied = doc.redo(count).applied;
+                        assert_eq!(applied, count.min(states.len() - 1 - current), "seed {}", seed);
+                        current += applied;
+                    }
+                    _ => {
+                        doc.snapshot(Position { x: 0, y: 0 });
+                        random_edit(&mut doc, &mut rng);
+                        states.truncate(current + 1);
+                        states.push(text(&doc));
+                        // Past undolevels the oldest steps are forgotten
+                        let excess = states.len().saturating_sub(doc.undo_levels + 1);
+                        states.drain(..excess);
+                        current = states.len() - 1;
+                    }
+                }
+                assert_eq!(text(&doc), states[current], "seed {}", seed);
+                // The incremental reparse ends up where parsing from scratch does (checked now
+                // and then, parsing is slow in debug builds)
+                if step % 25 == 0 {
+                    let fresh = Document::from_text(&states[current]);
+                    let sexp = |doc: &Document| doc.tree.as_ref().map(|tree| tree.root_node().to_sexp());
+                    assert_eq!(sexp(&doc), sexp(&fresh), "seed {}", seed);
+                }
+                assert_eq!(doc.history_depth(), (current, states.len() - 1 - current), "seed {}", seed);
+            }
+        }
+    }
+
+    // Two steps, "one" -> "one two" -> "one two three"
+    fn two_steps() -> Document {
+        let mut doc = Document::from_text("one");
+        doc.snapshot(Position { x: 3, y: 0 });
+        doc.insert_str(&Position { x: 3, y: 0 }, " two");
+        doc.snapshot(Position { x: 7, y: 0 });
+        doc.insert_str(&Position { x: 7, y: 0 }, " three");
+        doc
+    }
+
+    #[test]
+    fn undo_refuses_steps_made_on_other_text() {
+        // The text changed without the history seeing it (what a reload does)
+        let mut doc = two_steps();
+        doc.rope = Rope::from_str("one two three, reloaded");
+        let done = doc.undo(1);
+        assert!(done.invalidated);
+        assert_eq!(done.applied, 0);
+        assert_eq!(text(&doc), "one two three, reloaded");
+        assert_eq!(doc.history_depth(), (0, 0));
+
+        // Same length and shape, only the content differs: the deltas fit, the hash doesn't
+        let mut doc = two_steps();
+        doc.rope = Rope::from_str("one two THREE");
+        assert!(doc.undo(1).invalidated);
+        assert_eq!(text(&doc), "one two THREE");
+    }
+
+    #[test]
+    fn undo_refuses_a_corrupted_parent() {
+        let mut doc = two_steps();
+        doc.undo_stack[0].parent = Some(0);
+        let done = doc.undo(2);
+        // The newer step still fits and is undone, the corrupted one stops it cleanly
+        assert_eq!((done.applied, done.invalidated), (1, true));
+        assert_eq!(text(&doc), "one two");
+        assert_eq!(doc.history_depth(), (0, 0));
+    }
+
+    #[test]
+    fn redo_refuses_steps_made_on_other_text() {
+        let mut doc = two_steps();
+        assert_eq!(doc.undo(2).applied, 2);
+        doc.rope = Rope::from_str("two");
+        let done = doc.redo(1);
+        assert_eq!((done.applied, done.invalidated), (0, true));
+        assert_eq!(text(&doc), "two");
+
+        let mut doc = two_steps();
+        assert_eq!(doc.undo(2).applied, 2);
+        let done = doc.redo(2);
+        assert_eq!((done.applied, done.invalidated), (2, false));
+        assert_eq!(text(&doc), "one two three");
+    }
+
+    #[test]
+    fn block_chain_fixture() {
+        let doc = Document::from_text(concat!(
+            "intro\n",                  // 0
+            "# One\n",                  // 1
+            "text\n",                   // 2
+            "> quote\n",                // 3
+            "> > deeper\n",             // 4
+            "\n",                       // 5
+            "- item\n",                 // 6
+            "  - nested\n",             // 7
+            "    > quoted in a list\n", // 8
+            "\n",                       // 9
+            "```\n",                    // 10
+            "# not a heading\n",        // 11
+            "```\n",                    // 12
+            "\n",                       // 13
+            "Two\n",                    // 14
+            "---\n",                    // 15
+            "\n",                       // 16
+            "    indented code\n",      // 17
+            "\n",                       // 18
+            "### Three\n",              // 19
+            "> - listed quote\n",       // 20
+        ));
+        let chain = |y: usize| doc.block_context(y).join(" › ");
+        let expected = [
+            (0, ""),
+            (1, "H1"),
+            (2, "H1"),
+            (3, "H1 › quote"),
+            (4, "H1 › quote 2"),
+            (6, "H1 › list"),
+            (7, "H1 › list 2"),
+            (8, "H1 › list 2 › quote"),
+            (11, "H1 › code"),
+            (12, "H1 › code"),
+            (14, "H2"),
+            (17, "H2 › code"),
+            (19, "H3"),
+            (20, "H3 › quote › list"),
+        ];
+        for (y, want) in expected {
+            assert_eq!(chain(y), want, "line {}", y);
+        }
+    }
+
+    #[test]
+    fn hard_breaks() {
+        let doc = Document::from_text("text  \nnone \n   \ncrlf  \r\ntab\t  \n  \n");
+        let breaks: Vec<bool> = (0..doc.len()).map(|y| has_hard_break(doc.rope.line(y))).collect();
+        assert_eq!(breaks, [true, false, false, true, true, false, false]);
+    }
+
+    #[test]
+    fn fence_lines_follow_edits() {
+        let mut doc = Document::from_text("a\n```\ncode\n```\nb\n");
+        assert_eq!(*doc.fence_lines(), [false, true, true, true, false, false]);
+        doc.delete_lines(3, 1);
+        assert_eq!(*doc.fence_lines(), [false, true, true, true, true]);
+        doc.snapshot(Position { x: 0, y: 0 });
+        doc.insert_str(&Position { x: 0, y: 0 }, "~~~\n");
+        assert_eq!(*doc.fence_lines(), [true, true, true, true, true, true]);
+        doc.undo(1);
+        assert_eq!(*doc.fence_lines(), [false, true, true, true, true]);
+    }
+
+    #[test]
+    fn delete_at_line_end_takes_the_whole_break() {
+        let mut doc = Document::from_text("one\r\ntwo\r\nthree\n");
+        doc.delete(&Position { x: 3, y: 0 });
+        assert_eq!(doc.rope.to_string(), "onetwo\r\nthree\n");
+        doc.delete(&Position { x: 6, y: 0 });
+        assert_eq!(doc.rope.to_string(), "onetwothree\n");
+        doc.delete(&Position { x: 0, y: 0 });
+        assert_eq!(doc.rope.to_string(), "netwothree\n");
+    }
+
+    #[test]
+    fn delete_last_lines_takes_the_whole_break() {
+        for (before, y, count, left, removed) in [
+            ("a\r\nb", 1, 1, "a", "b\n"),
+            ("a\r\nb\r\nc", 1, 2, "a", "b\r\nc\n"),
+            ("a\r\nb\r\nc", 1, 5, "a", "b\r\nc\n"),
+            ("a\nb", 1, 1, "a", "b\n"),
+            ("a\r\nb\r\n", 1, 1, "a\r\n", "b\r\n"),
+            ("\r\nb", 1, 1, "", "b\n"),
+            ("a\r\nb", 0, 2, "", "a\r\nb\n"),
+        ] {
+            let mut doc = Document::from_text(before);
+            assert_eq!(doc.delete_lines(y, count), removed, "{:?}", before);
+            assert_eq!(text(&doc), left, "{:?}", before);
+            assert!(!text(&doc).ends_with('\r'));
+        }
+    }
+
+    #[test]
+    fn thousands_groups_digits_in_threes() {
+        for (n, expected) in [
+            (0, "0"),
+            (7, "7"),
+            (999, "999"),
+            (1000, "1,000"),
+            (8312, "8,312"),
+            (99999, "99,999"),
+            (100000, "100,000"),
+            (1234567, "1,234,567"),
+        ] {
+            assert_eq!(thousands(n), expected);
+        }
+    }
+
+    #[test]
+    fn save_summary_reads_like_the_examples() {
+        let mut report = SaveReport { lines: 214, bytes: 8312, patched: None, duration: Duration::from_millis(4) };
+        assert_eq!(report.summary("notes.md"), "\"notes.md\" 214 lines, 8,312 bytes written (4 ms)");
+        report.patched = Some(1024);
+        assert_eq!(report.summary("notes.md"), "\"notes.md\" 214 lines, 8,312 bytes (1,024 patched in place) (4 ms)");
+    }
+
+    #[test]
+    fn reindent_paste_moves_lines_by_the_first_ones_step() {
+        let doc = Document::from_text("top\n    - four\n  - two\n\t- tab\n");
+        for (y, pasted, expected) in [
+            // Deeper: every line gains the same amount, blank lines stay empty
+            (1, "- a\n  - b\n\n- c\n", "    - a\n      - b\n\n    - c\n"),
+            // Shallower: the difference comes off, never more than a line has
+            (2, "      - a\n        - b\n    - c\n", "  - a\n    - b\n- c\n"),
+            (0, "    - a\n  - b\n", "- a\n- b\n"),
+            // Leading blank lines don't count as the first line
+            (2, "\n- a\n", "\n  - a\n"),
+            // Tab indentation pads with tabs
+            (3, "- a\n  - b\n", "\t- a\n\t  - b\n"),
+            // Already lined up, or nothing to line up
+            (1, "    - a\n", "    - a\n"),
+            (0, "\n\n", "\n\n"),
+            // Without a final newline
+            (1, "- a\n- b", "    - a\n    - b"),
+        ] {
+            assert_eq!(doc.reindent_paste(y, pasted), expected, "{:?} at {}", pasted, y);
+        }
+    }
+
+    #[test]
+    fn reindent_paste_leaves_fence_contents_alone() {
+        let doc = Document::from_text("    - item\n");
+        let pasted = "- code:\n  ```\nfn main() {\n    body\n}\n  ```\n- after\n";
+        let expected = "    - code:\n      ```\nfn main() {\n    body\n}\n      ```\n    - after\n";
+        assert_eq!(doc.reindent_paste(0, pasted), expected);
+
+        // Going the other way too, and a longer closing fence or ~~~ inside doesn't confuse it
+        let doc = Document::from_text("x\n");
+        let pasted = "  ~~~~\n  ```\n    kept\n  ~~~~~\n  out\n";
+        assert_eq!(doc.reindent_paste(0, pasted), "~~~~\n  ```\n    kept\n~~~~~\nout\n");
+    }
+
+    #[test]
+    fn line_edits_carry_a_viewport_along() {
+        // The viewport starts at line 10 with the cursor on line 12, a mark above it on line 9
+        let (top, cursor, mark) = (10, Position { x: 4, y: 12 }, Position { x: 2, y: 9 });
+        for (start, removed, added, expected_top, expected_cursor, expected_mark) in [
+            // Above the viewport: everything moves by the difference
+            (2, 0, 3, 13, (4, 15), (2, 12)),
+            (2, 4, 0, 6, (4, 8), (2, 5)),
+            (2, 4, 1, 7, (4, 9), (2, 6)),
+            // Below it: nothing moves
+            (20, 0, 5, 10, (4, 12), (2, 9)),
+            (13, 4, 0, 10, (4, 12), (2, 9)),
+            // Starting on a line keeps that line where it is
+            (10, 0, 2, 10, (4, 14), (2, 9)),
+            (12, 3, 0, 10, (4, 12), (2, 9)),
+            // Spanning the viewport: lines that went away land at the start of the region
+            (8, 10, 1, 8, (0, 8), (0, 8)),
+            (8, 10, 0, 8, (0, 8), (0, 8)),
+            (11, 1, 0, 10, (4, 11), (2, 9)),
+            // Spanning it but ending before the cursor line: the cursor keeps its column
+            (5, 7, 2, 5, (4, 7), (0, 5)),
+        ] {
+            let edit = LineEdit { start, removed, added };
+            let moved = (
+                edit.shift(top),
+                edit.shift_position(cursor),
+                edit.shift_position(mark),
+            );
+            let label = format!("{} lines at {} became {}", removed, start, added);
+            assert_eq!(moved.0, expected_top, "top, {}", label);
+            assert_eq!((moved.1.x, moved.1.y), expected_cursor, "cursor, {}", label);
+            assert_eq!((moved.2.x, moved.2.y), expected_mark, "mark, {}", label);
+        }
+    }
+
+    #[test]
+    fn edits_are_recorded_as_whole_lines() {
+        let mut doc = Document::from_text("a\nb\nc\nd\n");
+        let edits = |doc: &mut Document| {
+            doc.take_line_edits().iter().map(|edit| (edit.start, edit.removed, edit.added)).collect::<Vec<_>>()
+        };
+
+        // Typing within a line isn't a line edit
+        doc.insert(&Position { x: 1, y: 1 }, 'x');
+        assert_eq!(edits(&mut doc), []);
+
+        doc.insert(&Position { x: 0, y: 1 }, '\n');
+        assert_eq!(edits(&mut doc), [(1, 0, 1)]);
+
+        doc.delete_lines(1, 2);
+        assert_eq!(edits(&mut doc), [(1, 2, 0)]);
+        assert_eq!(text(&doc), "a\nc\nd\n");
+    }
+
+    #[test]
+    fn undo_back_to_disk_is_clean() {
+        let dir = temp_dir("dirty");
+        let path = dir.join("notes.md");
+        fs::write(&path, "one\n").unwrap();
+        let mut doc = open(&path);
+        assert!(!doc.is_dirty());
+
+        doc.snapshot(Position { x: 0, y: 0 });
+        doc.insert_str(&Position { x: 3, y: 0 }, " two");
+        assert!(doc.is_dirty());
+        doc.undo(1);
+        assert!(!doc.is_dirty(), "undone back to what's on disk");
+        doc.redo(1);
+        assert!(doc.is_dirty());
+
+        doc.save().unwrap();
+        assert!(!doc.is_dirty());
+        doc.undo(1);
+        assert!(doc.is_dirty(), "the saved text is the new reference");
+        doc.redo(1);
+        assert!(!doc.is_dirty());
+
+        // Typing the same text back by hand counts as unsaved, only undo and redo compare
+        doc.snapshot(Position { x: 0, y: 0 });
+        doc.delete(&Position { x: 0, y: 0 });
+        doc.insert(&Position { x: 0, y: 0 }, 'o');
+        assert!(doc.is_dirty());
+        drop(doc);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unnamed_stays_dirty_after_undo() {
+        let mut doc = Document::from_text("one\n");
+        doc.snapshot(Position { x: 0, y: 0 });
+        doc.insert(&Position { x: 0, y: 0 }, 'x');
+        doc.undo(1);
+        assert_eq!(text(&doc), "one\n");
+        assert!(doc.is_dirty(), "nothing on disk to match");
+    }
+
+    #[test]
+    fn full_save_replaces_the_file() {
+        let dir = temp_dir("full-save");
+        let path = dir.join("notes.md");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let mut doc = open(&path);
+        doc.insert_str(&Position { x: 0, y: 1 }, "and ");
+        let report = doc.save().unwrap();
+
+        assert_eq!(report.patched, None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\nand two\n");
+        assert!(!doc.is_dirty());
+        // Nothing left behind next to it but our lock
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert!(names.iter().all(|name| name == "notes.md" || name.to_string_lossy().contains("lock")), "{:?}", names);
+        drop(doc);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn full_save_keeps_permissions_and_symlinks() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+        let dir = temp_dir("symlink");
+        let real = dir.join("real.md");
+        let link = dir.join("link.md");
+        fs::write(&real, "text\n").unwrap();
+        fs::set_permissions(&real, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink(&real, &link).unwrap();
+
+        let mut doc = open(&link);
+        doc.insert_str(&Position { x: 0, y: 0 }, "more ");
+        doc.save().unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&real).unwrap(), "more text\n");
+        assert_eq!(fs::metadata(&real).unwrap().permissions().mode() & 0o777, 0o640);
+        drop(doc);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_save_leaves_the_file_alone() {
+        let dir = temp_dir("failed-save");
+        let path = dir.join("notes.md");
+        fs::write(&path, "keep me\n").unwrap();
+        let mut doc = open(&path);
+        doc.insert_str(&Position { x: 0, y: 0 }, "x");
+        // A directory where the temp file would go makes the write fail before the rename
+        let temp = dir.join(format!(".notes.md.vellum-{}", std::process::id()));
+        fs::create_dir(&temp).unwrap();
+        fs::write(temp.join("blocker"), "").unwrap();
+
+        assert!(doc.save().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me\n");
+        assert!(doc.is_dirty());
+        drop(doc);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Types `text` one char at a time the way Insert Mode does
+    fn type_text(doc: &mut Document, at: &mut Position, text: &str) {
+        for c in text.chars() {
+            let kind = if c == '\n' { EditKind::Newline } else { EditKind::Typing };
+            doc.snapshot_typing(*at, kind);
+            doc.insert(at, c);
+            *at = if c == '\n' { Position { x: 0, y: at.y + 1 } } else { Position { x: at.x + 1, y: at.y } };
+        }
+    }
+
+    #[test]
+    fn typing_undoes_a_burst_at_a_time() {
+        let mut doc = Document::from_text("");
+        let mut at = Position { x: 0, y: 0 };
+        type_text(&mut doc, &mut at, "one two\nthree");
+        // Enter starts a step, the typing straight after it joins that one
+        assert!(doc.undo(1).cursor == Some(Position { x: 7, y: 0 }));
+        assert_eq!(text(&doc), "one two");
+        doc.undo(1);
+        assert_eq!(text(&doc), "");
+        doc.redo(2);
+        assert_eq!(text(&doc), "one two\nthree");
+    }
+
+    #[test]
+    fn held_enter_is_one_step() {
+        let mut doc = Document::from_text("a");
+        let mut at = Position { x: 1, y: 0 };
+        type_text(&mut doc, &mut at, "\n\n\n\n\n");
+        type_text(&mut doc, &mut at, "b");
+        doc.undo(1);
+        assert_eq!(text(&doc), "a");
+
+        // An Enter after a pause longer than SNAPSHOT_COALESCE is a step of its own
+        let mut at = Position { x: 1, y: 0 };
+        type_text(&mut doc, &mut at, "\n");
+        doc.last_typing = doc.last_typing.map(|(at, kind)| (at - SNAPSHOT_COALESCE * 2, kind));
+        type_text(&mut doc, &mut at, "\n");
+        assert_eq!(text(&doc), "a\n\n");
+        doc.undo(1);
+        assert_eq!(text(&doc), "a\n");
+    }
+
+    #[test]
+    fn typing_splits_on_kind_pause_and_break() {
+        let mut doc = Document::from_text("");
+        let mut at = Position { x: 0, y: 0 };
+        type_text(&mut doc, &mut at, "abcd");
+
+        // Backspaces are their own step
+        for _ in 0..2 {
+            doc.snapshot_typing(at, EditKind::Deleting);
+            at.x -= 1;
+            doc.delete(&at);
+        }
+        assert_eq!(text(&doc), "ab");
+
+        // A pause longer than TYPING_GROUP
+        type_text(&mut doc, &mut at, "x");
+        doc.last_typing = doc.last_typing.map(|(at, kind)| (at - TYPING_GROUP * 2, kind));
+        type_text(&mut doc, &mut at, "y");
+
+        // Leaving Insert Mode or moving the cursor
+        doc.break_typing();
+        type_text(&mut doc, &mut at, "z");
+        assert_eq!(text(&doc), "abxyz");
+
+        for expected in ["abxy", "abx", "ab", "abcd", ""] {
+            doc.undo(1);
+            assert_eq!(text(&doc), expected);
+        }
+    }
+
+    #[test]
+    fn edit_group_undoes_at_once() {
+        let mut doc = Document::from_text("one\ntwo\n");
+        doc.begin_edit_group(Position { x: 1, y: 0 });
+        doc.snapshot(Position { x: 0, y: 0 });
+        doc.insert_str(&Position { x: 0, y: 0 }, "- ");
+        doc.snapshot(Position { x: 0, y: 1 });
+        doc.insert_str(&Position { x: 0, y: 1 }, "- ");
+        // Groups nest, the inner one doesn't end the outer
+        doc.begin_edit_group(Position { x: 0, y: 0 });
+        doc.join_lines(0);
+        doc.end_edit_group();
+        let mut at = Position { x: 0, y: 1 };
+        type_text(&mut doc, &mut at, "end");
+        doc.end_edit_group();
+        assert_eq!(text(&doc), "- one - two\nend");
+
+        // Typing after the group is a step of its own
+        let mut at = Position { x: 3, y: 1 };
+        type_text(&mut doc, &mut at, "!");
+        doc.undo(1);
+        assert_eq!(text(&doc), "- one - two\nend");
+        let undone = doc.undo(1);
+        assert!(undone.applied == 1 && undone.cursor == Some(Position { x: 1, y: 0 }));
+        assert_eq!(text(&doc), "one\ntwo\n");
+        doc.redo(1);
+        assert_eq!(text(&doc), "- one - two\nend");
+    }
+
+    #[test]
+    fn unbalanced_end_edit_group_is_harmless() {
+        let mut doc = Document::from_text("a");
+        doc.end_edit_group();
+        doc.snapshot(Position { x: 1, y: 0 });
+        doc.insert_str(&Position { x: 1, y: 0 }, "b");
+        doc.snapshot(Position { x: 2, y: 0 });
+        doc.insert_str(&Position { x: 2, y: 0 }, "c");
+        doc.undo(1);
+        assert_eq!(text(&doc), "ab");
+    }
+}
@@ -1,14 +1,22 @@
-use crate::terminal::Terminal;
-use crate::document::Document;
+use crate::terminal::{Input, Terminal};
+use crate::document::{Document, EditKind};
+use crate::indent::IndentStyle;
+use crate::location::LocationList;
+use crate::options::{NumberMode, Options};
+use crate::profile::Profiler;
+use crate::search::{Query, Search};
+use crate::history::History;
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use std::{
+    collections::{HashMap, VecDeque},
     env,
-    time::Instant
+    time::{Duration, Instant}
 };
 
 pub(crate) const WRAP_PREFIX: &str = " >"; // Visual indicator for wrapped text (will not show in saved files)
 
 // Cursor coordinates, non-negative
+#[derive(Clone, Copy, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -18,9 +26,28 @@ pub struct Position {
 pub enum Mode {
     Normal,
     Insert,
+    Replace, // Typing overwrites the character under the cursor (R)
+    Visual { anchor: Position }, // Selection runs from the anchor to the cursor, both included
+    VisualLine { anchor: usize }, // Whole lines from the anchor line to the cursor's
     Command(String), //Holds the command being typed
 }
 
+// Nested input loops running on top of the main one, innermost last (Editor::contexts).
+// Each knows what may start inside it, so a key sequence can't run a command in the
+// middle of a question or a macro inside itself
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Context {
+    Command, // A : command (the palette runs the one picked inside its own)
+    Prompt, // Reading a line of text
+    Confirm, // Waiting for one of a few keys
+    Pick, // The picker list
+    Macro, // Playing back @x
+    Repeat, // Replaying the last edit for .
+}
+
+const MAX_NESTING: usize = 8; // Deeper than this is a loop, not a real use
+const QUIT_AGAIN: Duration = Duration::from_secs(3); // A second Ctrl+Q this soon quits without saving
+
 // Main state of the editor
 // keeps track of terminal size and where user is looking
 pub struct Editor {
@@ -30,13 +57,120 @@ pub struct Editor {
     pub(crate) document: Document,
     pub(crate) status_message: StatusMessage,
     pub(crate) mode: Mode,
-    pub(crate) show_line_numbers: bool,
     pub(crate) row_offset: usize,
+    pub(crate) options: Options,
+    pub(crate) detected_indent: Option<IndentStyle>, // From the last indent analysis
+    pub(crate) detected_width: Option<usize>, // Spaces per level in the document, same analysis
+    pub(crate) auto_break: Option<Position>, // Cursor spot right after the last auto-wrap, for backspacing over it
+    pub(crate) auto_pair: Option<Position>, // Cursor spot inside the pair autopair just typed, a Backspace straight after takes both
+    pub(crate) pending_prefix: Option<char>, // First key of a two-key normal mode command
+    pub(crate) pending_count: Option<usize>, // Digits typed before a normal mode command (5u)
+    pub(crate) pending_operator: Option<PendingOperator>, // d, y or c waiting for its motion
+    pub(crate) locations: LocationList, // Filled by :lint and :lgrep, walked with :lnext / :lprev
+    pub(crate) marks: HashMap<char, Position>, // m{a-z}, plus ' for where the last jump started
+    pub(crate) macros: HashMap<char, Vec<KeyEvent>>, // Recorded with q{register}, played with @{register}
+    pub(crate) recording: Option<(char, Vec<KeyEvent>)>, // Register and keys so far while q is recording
+    pub(crate) playback: VecDeque<KeyEvent>, // Keys of the macro being played, read before anything else
+    pub(crate) contexts: Vec<Context>, // Nested loops in progress, see Context
+    pub(crate) desired_x: Option<(usize, Position)>, // Screen column up/down aim for (usize::MAX after $), while the cursor is still where they left it
+    pub(crate) last_macro: Option<char>, // For @@
+    pub(crate) motion_failed: bool, // A motion or search went nowhere, stops macro playback
+    pub(crate) replaced: Vec<ReplaceStep>, // What this Replace Mode session did, undone step by step with Backspace
+    pub(crate) insert_after_command: bool, // Ctrl+O from insert mode, go back to it after one command
+    pub(crate) edit_keys: Vec<KeyEvent>, // Keys of the normal mode command (and insert session) in progress
+    pub(crate) last_edit: Option<LastEdit>, // Replayed by `.`
+    pub(crate) input_queue: VecDeque<Input>, // Keys and pastes read ahead (typed during slow work), replayed in order
+    pub(crate) picker: Option<Picker>, // List drawn over the text while pick() waits for a choice
+    pub(crate) registers: HashMap<char, String>, // Yanked/deleted text by register, '"' is the unnamed one
+    pub(crate) pending_register: Option<char>, // Picked with "a, used by the next yank, delete or paste
+    pub(crate) profiler: Profiler,
+    pub(crate) centered_at: Option<(usize, usize)>, // Cursor (x, y) typewriter mode last centered on
+    pub(crate) search: Option<Search>, // Last search, repeated by n/N
+    pub(crate) highlight_search: bool, // Matches of the last search are marked until :noh
+    pub(crate) block_context: Option<(usize, String)>, // Status bar block chain and the line it's for, dropped on edits
+    pub(crate) command_history: History, // Commands run from the : line, Up/Down brings them back
+    pub(crate) search_history: History, // Patterns searched for with / and ?
+    pub(crate) quit_warned: Option<Instant>, // When Ctrl+Q last refused over unsaved changes
+}
+
+// The keys of the last change (count, command, and everything typed if it entered insert
+// mode), replayed through the normal handlers so undo treats it like the original
+pub(crate) struct LastEdit {
+    pub(crate) keys: Vec<KeyEvent>,
+}
+
+// An operator (d, y or c) waiting for the motion that says what it acts on
+#[derive(Clone, Copy)]
+pub(crate) struct PendingOperator {
+    pub(crate) op: char,
+    pub(crate) count: Option<usize>, // Typed before the operator, multiplies one typed after it (2d3w)
+}
+
+// What an operator acts on: chars from one position up to (not including) another, or
+// whole lines
+enum OperatorRange {
+    Chars(Position, Position),
+    Lines(usize, usize),
+}
+
+// One key's worth of Replace Mode, so Backspace can put things back the way they were
+pub(crate) enum ReplaceStep {
+    Overwrote(char), // The character that was there
+    Appended, // Typed past the end of the line
+    NextLine { x: usize, added: bool }, // Enter from column x, `added` if it had to make a new last line
+}
+
+// A list to choose from, drawn in place of the document
+pub(crate) struct Picker {
+    pub(crate) title: String,
+    pub(crate) items: Vec<String>,
+    pub(crate) filter: String,
+    pub(crate) matches: Vec<usize>, // Indices of the items the filter keeps, best first
+    pub(crate) selected: usize, // Index into matches
+}
+
+impl Picker {
+    fn new(title: &str, items: Vec<String>) -> Self {
+        let matches = (0..items.len()).collect();
+        Self { title: title.to_string(), items, filter: String::new(), matches, selected: 0 }
+    }
+
+    fn refilter(&mut self) {
+        let mut scored: Vec<(usize, usize)> = self.items.iter().enumerate()
+            .filter_map(|(i, item)| fuzzy_score(&self.filter, item).map(|score| (score, i)))
+            .collect();
+        scored.sort_by_key(|(score, _)| *score);
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = 0;
+    }
+}
+
+// Whether the filter's chars all appear in order (ignoring case), lower scores are better:
+// a plain substring beats a scattered match, and earlier beats later
+fn fuzzy_score(filter: &str, item: &str) -> Option<usize> {
+    let filter = filter.to_lowercase();
+    let item = item.to_lowercase();
+    if let Some(at) = item.find(&filter) {
+        return Some(item[..at].chars().count());
+    }
+
+    let chars: Vec<char> = item.chars().collect();
+    let mut first = None;
+    let mut i = 0;
+    for c in filter.chars() {
+        while i < chars.len() && chars[i] != c { i += 1; }
+        if i == chars.len() { return None; }
+        first.get_or_insert(i);
+        i += 1;
+    }
+    Some(chars.len() + first.unwrap_or(0))
 }
 
 pub(crate) struct StatusMessage {
     pub(crate) text: String,
     pub(crate) time: Instant,
+    pub(crate) expand: bool, // Long/multi-line text gets extra rows and a "press any key" pause
+    pub(crate) choices: Vec<(char, String)>, // Keys drawn highlighted after the text, from confirm()
 }
 
 impl StatusMessage {
@@ -44,19 +178,41 @@ impl StatusMessage {
         Self {
             time: Instant::now(),
             text:message,
+            expand: true,
+            choices: Vec::new(),
+        }
+    }
+
+    // For text the user is typing into (prompts), which should never pause
+    pub(crate) fn input(message: String) -> Self {
+        Self {
+            expand: false,
+            ..Self::from(message)
         }
     }
 }
 
 impl Editor {
     // Initialize the editor with default values
-    pub fn default() -> Self {
+    // `piped` is text read from stdin (`vellum -`), opened as an unnamed buffer
+    pub fn new(piped: Option<String>) -> Self {
+
+        let args: Vec<String> = env::args().filter(|a| a != "--no-color").collect();
+        let mut initial_status = "Normal Mode - Press 'i' to insert".to_string();
 
-        let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from("Normal Mode - Press 'i' to insert".to_string());
+        // Accept tool-style locations like notes.md:120:14
+        let (filename, location) = match args.get(1) {
+            Some(arg) if arg != "-" => {
+                let (path, location) = split_location(arg);
+                (Some(path), location)
+            }
+            _ => (None, None),
+        };
 
-        let document = if args.len() > 1 {
-            let filename = &args[1];
+        let document = if let Some(text) = &piped {
+            initial_status = "Read from stdin (unnamed buffer)".to_string();
+            Document::from_text(text)
+        } else if let Some(filename) = &filename {
             let doc = Document::open(filename);
             if let Ok(doc) = doc {
                 doc
@@ -68,45 +224,299 @@ impl Editor {
             Document::default()
         };
 
-        let mut editor = Self {
-            should_quit: false,
-            terminal: Terminal::default().expect("Failed to initialize terminal"),
-            cursor_position: Position { x: 0, y: 0 },
-            document,
-            status_message: StatusMessage::from(initial_status.to_string()),
-            mode: Mode::Normal,
-            show_line_numbers: true,
-            row_offset: 0,
-        };
+        let terminal = Terminal::default().expect("Failed to initialize terminal");
+        let mut editor = Self::with(terminal, document, History::load("command_history"), History::load("search_history"));
 
+        // https://no-color.org: any non-empty NO_COLOR turns colors off, the config can still
+        // turn them back on with `set color`
+        let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) || env::args().any(|a| a == "--no-color");
+        editor.options.color = !no_color;
         editor.load_config();
+        editor.terminal.set_color(editor.options.color);
+        editor.document.undo_levels = editor.options.undo_levels;
+        editor.document.patch_save = editor.options.patch_save;
+
+        // Warn up front if the file mixes tabs and spaces
+        if !editor.document.is_empty() {
+            let report = crate::indent::analyze(&editor.document, editor.options.indent_skip_fences);
+            editor.detected_indent = report.style;
+            editor.detected_width = report.width;
+            if report.is_mixed() {
+                initial_status = report.summary();
+            }
+        }
 
         // Reset startup message so it doesn't just show the last command from the config
         editor.status_message = StatusMessage::from(initial_status);
 
+        if let Some((line, col)) = location {
+            editor.goto(line, col);
+        }
+
+        if !editor.check_lock() { editor.should_quit = true; }
+
+        editor
+    }
+
+    // Everything at its starting state around a terminal and a document
+    fn with(terminal: Terminal, document: Document, command_history: History, search_history: History) -> Self {
+    Self {
+        should_quit: false,
+        terminal,
+        cursor_position: Position { x: 0, y: 0 },
+        document,
+        status_message: StatusMessage::from(String::new()),
+        mode: Mode::Normal,
+        row_offset: 0,
+        options: Options::default(),
+        detected_indent: None,
+        detected_width: None,
+        auto_break: None,
+        auto_pair: None,
+        pending_prefix: None,
+        pending_operator: None,
+        locations: LocationList::default(),
+        marks: HashMap::new(),
+        macros: HashMap::new(),
+        recording: None,
+        playback: VecDeque::new(),
+        contexts: Vec::new(),
+        desired_x: None,
+        last_macro: None,
+        motion_failed: false,
+        replaced: Vec::new(),
+        pending_count: None,
+        insert_after_command: false,
+        edit_keys: Vec::new(),
+        last_edit: None,
+        input_queue: VecDeque::new(),
+        picker: None,
+        registers: HashMap::new(),
+        pending_register: None,
+        profiler: Profiler::default(),
+        centered_at: None,
+        search: None,
+        highlight_search: false,
+        block_context: None,
+        command_history,
+        search_history,
+        quit_warned: None,
+    }
+    }
+
+    // An editor for tests: draws into a terminal made by Terminal::to_writer and reads the
+    // input scripted there (or put in input_queue / playback). No config, no history files,
+    // no lock check, and crossterm's global color switch is left alone
+    #[cfg(test)]
+    pub(crate) fn for_test(terminal: Terminal, document: Document) -> Self {
+        let mut editor = Self::with(terminal, document, History::default(), History::default());
+        editor.options.color = editor.terminal.color();
+        editor.document.undo_levels = editor.options.undo_levels;
+        editor.document.patch_save = editor.options.patch_save;
+        if !editor.document.is_empty() {
+            let report = crate::indent::analyze(&editor.document, editor.options.indent_skip_fences);
+            (editor.detected_indent, editor.detected_width) = (report.style, report.width);
+        }
         editor
     }
 
+    // The main loop of run() until the script runs out or the editor quits, so a test goes
+    // through rendering, keypresses and follow_edits the way a session does
+    #[cfg(test)]
+    pub(crate) fn run_script(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            crate::ui::refresh_screen(self)?;
+            if self.should_quit { return Ok(()); }
+            match self.process_keypress() {
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            self.follow_edits();
+        }
+    }
+
+    // Someone else has the file open, like vim's swap file warning. False if the user would
+    // rather not open it after all
+    fn check_lock(&mut self) -> bool {
+        let Some(holder) = self.document.locked_by.clone() else { return true; };
+        let name = self.document.filename.clone().unwrap_or_default();
+        let message = format!("{} is being edited by {}:", name, holder);
+        match self.confirm(&message, &[('r', "read-only"), ('e', "edit anyway"), ('a', "abort")]) {
+            Ok(Some('r')) => {
+                self.document.read_only = true;
+                self.status_message = StatusMessage::from("Opened read-only".to_string());
+            }
+            Ok(Some('e')) => self.status_message = StatusMessage::from("Editing anyway, saves may overwrite theirs".to_string()),
+            _ => return false,
+        }
+        true
+    }
+
+    // Swaps in another document (:e). Everything pointing into the old text (cursor, scroll,
+    // marks, locations) starts over. False if the lock question was aborted, then the old
+    // document stays
+    pub(crate) fn open_document(&mut self, document: Document) -> bool {
+        let previous = std::mem::replace(&mut self.document, document);
+        if !self.check_lock() {
+            self.document = previous;
+            return false;
+        }
+        self.document.long_line = self.options.long_line;
+        self.document.undo_levels = self.options.undo_levels;
+        self.document.patch_save = self.options.patch_save;
+        self.cursor_position = Position { x: 0, y: 0 };
+        self.row_offset = 0;
+        self.desired_x = None;
+        self.auto_break = None;
+        self.auto_pair = None;
+        self.centered_at = None;
+        self.block_context = None;
+        self.replaced.clear();
+        self.marks.clear();
+        self.locations.forget_unnamed();
+        (self.detected_indent, self.detected_width) = if self.document.is_empty() {
+            (None, None)
+        } else {
+            let report = crate::indent::analyze(&self.document, self.options.indent_skip_fences);
+            (report.style, report.width)
+        };
+        true
+    }
+
     // Helper to calculate gutter width
+    // Depends on the mode with `numbermode=normal`, so the text area resizes on mode switches
+    pub(crate) fn show_line_numbers(&self) -> bool {
+        match self.options.number_mode {
+            NumberMode::Always => true,
+            NumberMode::Normal => !matches!(self.mode, Mode::Insert | Mode::Replace),
+            NumberMode::Never => false,
+        }
+    }
+
     pub(crate) fn gutter_width(&self) -> usize {
-        if !self.show_line_numbers {
+        if !self.show_line_numbers() {
             return 0;
         }
 
-        // Adds 2 for padding and pipe
-        self.document.len().to_string().len() + 2
+        let options = &self.options;
+        self.document.len().to_string().len() + options.gutter_padding + options.gutter_separator.chars().count()
+    }
+
+    // Jumps to a 1-based line (and column), clamping to the document
+    pub(crate) fn goto(&mut self, line: usize, col: Option<usize>) {
+        self.remember_jump();
+        let last = self.document.len().saturating_sub(1);
+        let y = std::cmp::min(line.saturating_sub(1), last);
+        let len = self.line_length(y);
+        let x = std::cmp::min(col.unwrap_or(1).saturating_sub(1), len);
+
+        if line.saturating_sub(1) > last || col.is_some_and(|c| c.saturating_sub(1) > len) {
+            self.status_message = StatusMessage::from(format!("{}:{} is out of range, moved to {}:{}", line, col.unwrap_or(1), y + 1, x + 1));
+        }
+        self.cursor_position = Position { x, y };
+    }
+
+    // Keeps the cursor in the ' mark before a jump (G, gg, searches, :goto, marks) so '' can
+    // come back
+    pub(crate) fn remember_jump(&mut self) {
+        self.marks.insert('\'', self.cursor_position);
+    }
+
+    // Clamped in case the document got shorter than the mark, then scrolled into view
+    fn jump_to_mark(&mut self, name: char, exact: bool) {
+        let Some(&mark) = self.marks.get(&name) else {
+            self.motion_failed = true;
+            self.status_message = StatusMessage::from(format!("Mark {} not set", name));
+            return;
+        };
+        self.remember_jump();
+        self.cursor_position.y = std::cmp::min(mark.y, self.line_target(None, true));
+        if exact {
+            self.cursor_position.x = std::cmp::min(mark.x, self.line_length(self.cursor_position.y));
+        } else {
+            self.cursor_position = self.first_non_blank();
+        }
+        self.scroll();
+    }
+
+    // Makes entry `index` of the location list the active one and moves to it
+    pub(crate) fn jump_to_location(&mut self, index: usize) {
+        let Some(item) = self.locations.items.get(index) else { return; };
+        let total = self.locations.items.len();
+
+        // Entries in other files open them in place of this one, like :e. A jump that doesn't
+        // happen leaves the active entry where it was, so :lnext after a :w tries it again
+        if let Some(file) = item.file.clone() && item.file != self.document.filename {
+            if self.document.is_dirty() {
+                self.status_message = StatusMessage::from(format!("({}/{}) Unsaved changes, :w before jumping to {}", index + 1, total, file));
+                return;
+            }
+            let document = match Document::open(&file) {
+                Ok(document) => document,
+                Err(e) => {
+                    self.status_message = StatusMessage::from(format!("({}/{}) Could not open {}: {}", index + 1, total, file, e));
+                    return;
+                }
+            };
+            let previous = self.locations.current.replace(index);
+            if !self.open_document(document) {
+                self.locations.current = previous;
+                return;
+            }
+        } else {
+            self.locations.current = Some(index);
+        }
+
+        // Opening a file drops the unnamed buffer's entries, which can move this one up
+        let Some(index) = self.locations.current else { return; };
+        let total = self.locations.items.len();
+        let item = &self.locations.items[index];
+        let (line, col, message) = (item.line, item.col, item.message.clone());
+        self.goto(line + 1, Some(col + 1));
+        self.status_message = StatusMessage::from(format!("({}/{}) {}", index + 1, total, message));
+    }
+
+    // Rows left for text after the status bar and message area
+    pub(crate) fn text_area_height(&self) -> usize {
+        let message_rows = std::cmp::max(1, crate::ui::expanded_message(self).len());
+        (self.terminal.size().height as usize).saturating_sub(1 + message_rows)
+    }
+
+    // Declared indent style wins over the detected one
+    pub(crate) fn indent_style(&self) -> Option<IndentStyle> {
+        self.options.indent_style.or(self.detected_indent)
+    }
+
+    // How far Tab and soft-tab Backspace go when indenting with spaces: whatever the document
+    // already steps by, tabstop if it doesn't say
+    pub(crate) fn soft_tab_width(&self) -> usize {
+        self.detected_width.unwrap_or(self.options.tabstop)
     }
 
 
     // Helper to get length of a line, ignoring newlines
     pub(crate) fn line_length(&self, y: usize) -> usize {
-        if y >= self.document.len() { return 0; }
-        let line = self.document.rope.line(y);
-        let mut len = line.len_chars();
+        self.document.line_length(y)
+    }
 
-        if line.chars().last() == Some('\n') { len = len.saturating_sub(1); }
-        if line.chars().nth(len.saturating_sub(1)) == Some('\r') { len = len.saturating_sub(1); }
+    // Screen column of char x on line y (before wrapping), a tab reaches to the next tab stop.
+    // Very long lines count one column per char, they're drawn plain anyway
+    pub(crate) fn display_col(&self, y: usize, x: usize) -> usize {
+        if y >= self.document.len() || self.line_length(y) > self.options.long_line { return x; }
+        let tabstop = self.options.tabstop;
+        self.document.rope.line(y).chars().take(x)
+            .fold(0, |col, c| if c == '\t' { crate::indent::next_tab_stop(col, tabstop) } else { col + 1 })
+    }
 
+    // The char on line y that covers screen column col, or the end of the line
+    fn char_at_col(&self, y: usize, col: usize) -> usize {
+        let len = self.line_length(y);
+        if len > self.options.long_line { return std::cmp::min(col, len); }
+        let mut width = 0;
+        for (x, c) in self.document.rope.line(y).chars().take(len).enumerate() {
+            width = if c == '\t' { crate::indent::next_tab_stop(width, self.options.tabstop) } else { width + 1 };
+            if width > col { return x; }
+        }
         len
     }
 
@@ -116,202 +526,1515 @@ impl Editor {
     // 3. Process the keypress
     pub fn run(&mut self) {
         loop {
+            let start = self.profiler.start();
             if let Err(e) = crate::ui::refresh_screen(self) {
                 self.die(&e);
             }
+            self.profiler.record("render", start);
             if self.should_quit {
                 break;
             }
             if let Err(e) = self.process_keypress() {
                 self.die(&e);
             }
+            self.follow_edits();
+        }
+    }
+
+    // The one place everything holding a position into the text hears about edits. Edits
+    // above the viewport (undo, :s, :tasks archive...) would otherwise slide the text under
+    // the user, so row_offset moves along with the line it pointed at, and so do marks,
+    // saved views, the visual anchor and location list entries. Whatever still ends up past
+    // the end of a line or the document (undoing a big paste a mark was in) is pulled back
+    // onto the text, so nothing indexes the rope out of bounds later
+    fn follow_edits(&mut self) {
+        let edits = self.document.take_line_edits();
+        if edits.is_empty() { return; }
+        self.block_context = None;
+
+        let open_file = self.document.filename.clone();
+        for edit in &edits {
+            self.row_offset = edit.shift(self.row_offset);
+            for mark in self.marks.values_mut() {
+                *mark = edit.shift_position(*mark);
+            }
+            for view in &mut self.document.views {
+                view.cursor = edit.shift_position(view.cursor);
+                view.row_offset = edit.shift(view.row_offset);
+            }
+            match &mut self.mode {
+                Mode::Visual { anchor } => *anchor = edit.shift_position(*anchor),
+                Mode::VisualLine { anchor } => *anchor = edit.shift(*anchor),
+                _ => {}
+            }
+            self.auto_break = self.auto_break.map(|at| edit.shift_position(at));
+            for item in self.locations.items.iter_mut().filter(|item| item.file.is_none() || item.file == open_file) {
+                item.line = edit.shift(item.line);
+            }
+        }
+
+        let document = &mut self.document;
+        let last = document.len().saturating_sub(1);
+        self.cursor_position = document.clamp(self.cursor_position);
+        self.row_offset = std::cmp::min(self.row_offset, last);
+        for mark in self.marks.values_mut() {
+            *mark = document.clamp(*mark);
+        }
+        for i in 0..document.views.len() {
+            document.views[i].cursor = document.clamp(document.views[i].cursor);
+            document.views[i].row_offset = std::cmp::min(document.views[i].row_offset, last);
+        }
+        match &mut self.mode {
+            Mode::Visual { anchor } => *anchor = document.clamp(*anchor),
+            Mode::VisualLine { anchor } => *anchor = std::cmp::min(*anchor, last),
+            _ => {}
         }
+        self.auto_break = self.auto_break.filter(|at| *at == document.clamp(*at));
+        for item in &mut self.locations.items {
+            item.line = std::cmp::min(item.line, last);
+        }
+    }
+
+    // "¶ H2 › list › code" for the cursor line, worked out once per line
+    pub(crate) fn block_context(&mut self) -> &str {
+        let y = self.cursor_position.y;
+        if self.block_context.as_ref().is_none_or(|(line, _)| *line != y) {
+            let chain = self.document.block_context(y);
+            let text = if chain.is_empty() { String::new() } else { format!("¶ {}", chain.join(" › ")) };
+            self.block_context = Some((y, text));
+        }
+        self.block_context.as_ref().map_or("", |(_, text)| text)
     }
 
     // Reads a single key event and updates state
     fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
-        
-        match &self.mode {
-            Mode::Normal => self.process_normal_mode(pressed_key),
-            Mode::Insert => self.process_insert_mode(pressed_key),
-            Mode::Command(_) => self.process_command_mode(pressed_key),
+        // A long message is waiting to be read. Keys typed before it showed up wait their
+        // turn, only a fresh one dismisses it
+        if self.playback.is_empty() && !crate::ui::expanded_message(self).is_empty() {
+            self.queue_pending_input()?;
+            if let Some(input) = self.terminal.read_input()? {
+                self.status_message = StatusMessage::from(String::new());
+                // A paste isn't an answer, it still goes where it was meant to
+                if let Input::Paste(_) = input { self.input_queue.push_back(input); }
+            }
+            return Ok(());
+        }
+
+        let pressed_key = match self.next_input()? {
+            Some(Input::Key(key)) => key,
+            Some(Input::Paste(text)) => return self.paste(&text),
+            None => return Ok(()),
+        };
+
+        // Esc and the next key sent close together (a slow link) arrive as one Alt+key
+        if let Some(key) = self.split_alt(pressed_key) {
+            self.process_key(KeyEvent::from(KeyCode::Esc))?;
+            return self.process_key(key);
+        }
+        self.process_key(pressed_key)
+    }
+
+    // Alt+char as the Esc and char it most likely was (`:set altesc`), None when the key is
+    // meant as is: no Alt, a key with an Alt binding, or on the command line
+    fn split_alt(&self, key: KeyEvent) -> Option<KeyEvent> {
+        if !self.options.alt_esc || !key.modifiers.contains(KeyModifiers::ALT) { return None; }
+        let KeyCode::Char(c) = key.code else { return None; };
+        if key.modifiers.contains(KeyModifiers::CONTROL) || matches!(self.mode, Mode::Command(_)) { return None; }
+        if self.mode == Mode::Insert && self.options.emacs_keys && matches!(c, 'f' | 'b') { return None; }
+        Some(KeyEvent::new(key.code, key.modifiers - KeyModifiers::ALT))
+    }
+
+    fn process_key(&mut self, pressed_key: KeyEvent) -> Result<(), std::io::Error> {
+        if let Mode::Command(_) = self.mode {
+            // Whatever a command prompted for isn't part of an edit
+            self.process_command_mode(pressed_key)?;
+            self.edit_keys.clear();
+            return Ok(());
+        }
+        // Before handling it, a prompt it opens (d/) reads keys that have to come after it
+        self.edit_keys.push(pressed_key);
+        self.process_edit_key(pressed_key)?;
+        self.finish_edit_keys();
+        Ok(())
+    }
+
+    // Normal and insert mode keys, shared with `.` replays
+    fn process_edit_key(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
+        match self.mode {
+            Mode::Normal => {
+                self.process_normal_mode(key)?;
+                if self.insert_after_command { self.finish_one_shot(key); }
+            }
+            Mode::Insert => self.process_insert_mode(key)?,
+            Mode::Replace => self.process_replace_mode(key),
+            Mode::Visual { anchor } => self.process_visual_mode(key, anchor)?,
+            Mode::VisualLine { anchor } => self.process_visual_line_mode(key, anchor)?,
+            Mode::Command(_) => (),
+        }
+        Ok(())
+    }
+
+    // Collects keys until a command is complete and we're back in normal mode, then keeps
+    // them as the last edit if that command changed the text. A register picked with "a
+    // waits for its command, so `.` repeats "add with the register
+    fn finish_edit_keys(&mut self) {
+        match self.mode {
+            Mode::Command(_) => self.edit_keys.clear(),
+            Mode::Normal if self.pending_prefix.is_none() && self.pending_count.is_none() && self.pending_operator.is_none()
+                && self.pending_register.is_none() && !self.insert_after_command =>
+            {
+                let keys = std::mem::take(&mut self.edit_keys);
+                if is_repeatable(&keys) {
+                    self.last_edit = Some(LastEdit { keys });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // `.`: runs the last edit again at the cursor, a count replaces the original one
+    fn repeat_last_edit(&mut self, count: Option<usize>) -> Result<(), std::io::Error> {
+        let Some(edit) = &self.last_edit else {
+            self.status_message = StatusMessage::from("Nothing to repeat".to_string());
+            return Ok(());
+        };
+
+        let keys: Vec<KeyEvent> = match count {
+            Some(count) => count.to_string().chars()
+                .map(|c| KeyEvent::from(KeyCode::Char(c)))
+                .chain(edit.keys.iter().copied().skip_while(is_count_key))
+                .collect(),
+            None => edit.keys.clone(),
+        };
+
+        // Through the playback queue so prompts in it (d/) get their answers from it too.
+        // Ahead of whatever a playing macro has left
+        self.nested(Context::Repeat, |editor| {
+            let remaining = editor.playback.len();
+            for key in keys.into_iter().rev() {
+                editor.playback.push_front(key);
+            }
+            while editor.playback.len() > remaining {
+                editor.process_keypress()?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Runs `f` as a nested loop in `context`, or refuses with a message and returns None
+    // when that can't start from inside the loops already running. The context is left
+    // again however `f` ends, errors included, so Esc or a failure unwinds one level only
+    pub(crate) fn nested<T>(&mut self, context: Context, f: impl FnOnce(&mut Self) -> Result<T, std::io::Error>) -> Result<Option<T>, std::io::Error> {
+        if let Err(reason) = self.can_enter(context) {
+            self.status_message = StatusMessage::from(reason);
+            self.motion_failed = true; // Stops a macro that tried it
+            return Ok(None);
         }
+        self.contexts.push(context);
+        let result = f(self);
+        self.contexts.pop();
+        result.map(Some)
+    }
+
+    fn can_enter(&self, context: Context) -> Result<(), String> {
+        if self.contexts.len() >= MAX_NESTING {
+            return Err("Too many nested commands, stopped".to_string());
+        }
+        let inside = |c: Context| self.contexts.contains(&c);
+        let asking = matches!(self.contexts.last(), Some(Context::Prompt | Context::Confirm | Context::Pick));
+        match context {
+            // Whatever is being asked has to be answered (or Esc'd) first
+            Context::Command | Context::Macro | Context::Repeat if asking => Err("Finish or cancel the prompt first".to_string()),
+            Context::Macro if inside(Context::Macro) => Err("A macro can't play another macro".to_string()),
+            Context::Repeat if inside(Context::Repeat) => Err("Can't repeat from inside a repeat".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // Back to insert mode once the Ctrl+O command is complete (a count or prefix key alone
+    // isn't). Commands that switch modes themselves cancel the return
+    fn finish_one_shot(&mut self, key: KeyEvent) {
+        if self.pending_prefix.is_some() || self.pending_count.is_some() || self.pending_operator.is_some() { return; }
+        self.insert_after_command = false;
+        if self.mode != Mode::Normal { return; }
+
+        // $ means past the last character when typing resumes, like vim
+        if key.code == KeyCode::Char('$') {
+            self.cursor_position.x = self.line_length(self.cursor_position.y);
+        }
+        self.mode = Mode::Insert;
+        self.status_message = StatusMessage::from("Insert Mode".to_string());
     }
 
     fn process_normal_mode(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
+        if let Some(operator) = self.pending_operator {
+            return self.process_operator(operator, key);
+        }
+
+        // Second key of a two-key command
+        if let Some(prefix) = self.pending_prefix.take() {
+            let count = self.pending_count.take();
+            return self.process_prefixed(prefix, key, count);
+        }
+
+        // Count prefix, a leading 0 isn't a count
+        if let KeyCode::Char(c @ '0'..='9') = key.code
+            && !key.modifiers.contains(KeyModifiers::CONTROL)
+            && (c != '0' || self.pending_count.is_some())
+        {
+            let digit = c.to_digit(10).unwrap_or(0) as usize;
+            self.pending_count = Some(self.pending_count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            return Ok(());
+        }
+        let count = self.pending_count.take();
+
         match key {
 
+            // Redo, a count steps that many states at once
+            KeyEvent { code: KeyCode::Char('r'), modifiers: KeyModifiers::CONTROL, .. } => {
+                self.undo_steps(count.unwrap_or(1), true);
+                self.skip_repeats(key)?;
+            }
+
+            // Half a screen down (Ctrl+D) or up (Ctrl+U), view and cursor together
+            KeyEvent { code: KeyCode::Char(c @ ('d' | 'u')), modifiers: KeyModifiers::CONTROL, .. } => {
+                let rows = self.text_area_height() / 2;
+                self.repeat_motion(count, |editor| editor.page(c == 'd', rows));
+            }
+
+            // Scroll the view a line down (Ctrl+E) or up (Ctrl+Y), the cursor only moves to stay on screen
+            KeyEvent { code: KeyCode::Char(c @ ('e' | 'y')), modifiers: KeyModifiers::CONTROL, .. } => {
+                for _ in 0..count.unwrap_or(1) {
+                    if !self.nudge(c == 'e') { break; }
+                }
+            }
+
+            // Cycle through saved views (Ctrl+6, like vim's alternate file)
+            KeyEvent { code: KeyCode::Char('6' | '^'), modifiers: KeyModifiers::CONTROL, .. } => {
+                match self.document.last_view {
+                    _ if self.document.views.is_empty() => self.status_message = StatusMessage::from("No saved views".to_string()),
+                    Some(i) => self.load_view((i + 1) % self.document.views.len()),
+                    None => self.load_view(0),
+                }
+            }
+
+            // Every command in a filterable list
+            KeyEvent { code: KeyCode::Char('p'), modifiers: KeyModifiers::CONTROL, .. } => crate::commands::palette(self)?,
+
+            // Macros: q{register} starts recording, q stops it, @{register} plays one
+            KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::NONE, .. } if self.recording.is_some() => self.stop_recording(),
+            KeyEvent { code: KeyCode::Char(c @ ('q' | '@')), modifiers: KeyModifiers::NONE, .. } => {
+                self.pending_prefix = Some(c);
+                self.pending_count = count;
+            }
+
+            // Operators, the motion typed next says what they act on
+            KeyEvent { code: KeyCode::Char(op @ ('d' | 'y' | 'c')), modifiers: KeyModifiers::NONE, .. } => {
+                self.pending_operator = Some(PendingOperator { op, count });
+            }
+
+            // Keys that wait for a second key, the count carries over to it
+            KeyEvent { code: KeyCode::Char(c @ (']' | '[' | 'g' | 'r' | '"' | 'm' | '\'' | '`' | '>' | '<' | 'z')), .. } => {
+                self.pending_prefix = Some(c);
+                self.pending_count = count;
+            }
+
             // Enter insert mode
             KeyEvent { code: KeyCode::Char('i'), .. } => {
-                self.document.snapshot();
+                self.document.break_typing();
+                self.mode = Mode::Insert;
+                self.status_message = StatusMessage::from("Insert Mode".to_string());
+            }
+
+            // Overtype from the cursor
+            KeyEvent { code: KeyCode::Char('R'), .. } => {
+                self.document.snapshot(self.cursor_position);
+                self.replaced.clear();
+                self.mode = Mode::Replace;
+                self.status_message = StatusMessage::from("Replace Mode".to_string());
+            }
+
+            // Start selecting from the cursor
+            KeyEvent { code: KeyCode::Char('v'), .. } => {
+                self.mode = Mode::Visual { anchor: self.cursor_position };
+                self.status_message = StatusMessage::from("-- VISUAL --".to_string());
+            }
+
+            // Start selecting whole lines
+            KeyEvent { code: KeyCode::Char('V'), .. } => {
+                self.mode = Mode::VisualLine { anchor: self.cursor_position.y };
+                self.status_message = StatusMessage::from("-- VISUAL LINE --".to_string());
+            }
+
+            // Open a new line below (o) or above (O) and start typing on it
+            KeyEvent { code: KeyCode::Char(c @ ('o' | 'O')), .. } => {
+                self.document.snapshot(self.cursor_position);
+                let y = if c == 'o' { self.cursor_position.y + 1 } else { self.cursor_position.y };
+                self.document.insert_line(y, "\n");
+                self.cursor_position = Position { x: 0, y };
                 self.mode = Mode::Insert;
                 self.status_message = StatusMessage::from("Insert Mode".to_string());
             }
 
             // Enter command mode
             KeyEvent { code: KeyCode::Char(':'), .. } => {
+                self.command_history.reset();
                 self.mode = Mode::Command(String::new());
                 self.status_message = StatusMessage::from("Command: ".to_string());
             }
 
-            // Quick escape on ctrl + q
-            KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL, .. } => self.should_quit = true,
+            // Quick escape on ctrl + q, with unsaved changes it takes a second press
+            KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL, .. } => {
+                let again = self.quit_warned.take().is_some_and(|at| at.elapsed() < QUIT_AGAIN);
+                if !self.document.is_dirty() || again {
+                    self.should_quit = true;
+                } else {
+                    self.quit_warned = Some(Instant::now());
+                    self.status_message = StatusMessage::from("Unsaved changes, press Ctrl+Q again to quit without saving".to_string());
+                }
+            }
 
             // Save with Ctrl+S (keeping for now, not 100% sure w and !w work as I want yet)
             KeyEvent {
                 code: KeyCode::Char('s'),
                 modifiers: KeyModifiers::CONTROL,
                 ..
-            } => {
-                if self.document.filename.is_none() {
-                    let new_name = self.prompt("Save as: ")?;
-                    if let Some(name) = new_name {
-                        self.document.filename = Some(name);
-                    } else {
-                        self.status_message = StatusMessage::from("Save aborted.".to_string());
-                        return Ok(());
-                    }
+            } => self.save()?,
+
+            // Delete the character under the cursor (count of them), never the line break
+            KeyEvent { code: KeyCode::Char('x') | KeyCode::Delete, .. } if self.cursor_position.x < self.line_length(self.cursor_position.y) => {
+                self.document.snapshot(self.cursor_position);
+                let available = self.line_length(self.cursor_position.y) - self.cursor_position.x;
+                let start = self.document.rope.line_to_char(self.cursor_position.y) + self.cursor_position.x;
+                let end = start + std::cmp::min(count.unwrap_or(1), available);
+                self.store_register(self.document.rope.slice(start..end).to_string());
+                self.document.delete_char_range(start, end);
+                let len = self.line_length(self.cursor_position.y);
+                if self.cursor_position.x >= len {
+                    self.cursor_position.x = len.saturating_sub(1);
                 }
-                
-                if self.document.save().is_ok() {
-                    self.status_message = StatusMessage::from("File saved successfully.".to_string());
+            }
+
+            // Delete (D) or change (C) from the cursor to the end of the line, the line break
+            // stays. Nothing to delete on an empty line, C still starts typing there
+            KeyEvent { code: KeyCode::Char(c @ ('D' | 'C')), .. } => {
+                let Position { x, y } = self.cursor_position;
+                let len = self.line_length(y);
+                if x < len {
+                    self.document.snapshot(self.cursor_position);
+                    let removed = self.document.delete_range(&self.cursor_position, &Position { x: len - 1, y });
+                    self.store_register(removed);
+                }
+                if c == 'C' {
+                    self.document.break_typing();
+                    self.mode = Mode::Insert;
+                    self.status_message = StatusMessage::from("Insert Mode".to_string());
                 } else {
-                    self.status_message = StatusMessage::from("Error writing file!".to_string());
+                    self.cursor_position.x = std::cmp::min(x, self.line_length(y).saturating_sub(1));
                 }
             }
 
-            // Copy (yank) current line
-            KeyEvent { code: KeyCode::Char('y'), .. } => {
-                if self.cursor_position.y < self.document.len() {
-                    let line = self.document.rope.line(self.cursor_position.y).to_string();
-                    // Init clipboard and set text
-                    if let Ok(mut clipboard) = arboard::Clipboard::new() {
-                        let _ = clipboard.set_text(line);
-                        self.status_message = crate::editor::StatusMessage::from("Line copied!".to_string());
-                    } else {
-                        self.status_message = crate::editor::StatusMessage::from("Clipboard error".to_string());
-                    }
-                }
+            // Past the last character (End, arrows) Delete joins the next line on, like in Insert Mode
+            KeyEvent { code: KeyCode::Delete, .. } if self.cursor_position.y + 1 < self.document.len() => {
+                self.document.snapshot(self.cursor_position);
+                self.document.delete(&self.cursor_position);
             }
 
-            // Paste from clipboard
-            KeyEvent { code: KeyCode::Char('p'), .. } => {
-                if let Ok(mut clipboard) = arboard::Clipboard::new() {
-                    if let Ok(text) = clipboard.get_text() {
-                        self.document.snapshot(); // Snapshot to allow undo
-
-                        // Insert char by char to handle newlines
-                        for c in text.chars() {
-                            self.document.insert(&self.cursor_position, c);
-                            if c == '\n' {
-                                self.cursor_position.y += 1;
-                                self.cursor_position.x = 0;
-                            } else {
-                                self.cursor_position.x += 1;
-                            }
-                        }
-                        self.status_message = crate::editor::StatusMessage::from("Pasted!".to_string());
-                    }
-                }
+            // Toggle the case of the character under the cursor (count of them) and move past
+            // it, stopping at the end of the line
+            KeyEvent { code: KeyCode::Char('~'), .. } if self.cursor_position.x < self.line_length(self.cursor_position.y) => {
+                self.document.snapshot(self.cursor_position);
+                let available = self.line_length(self.cursor_position.y) - self.cursor_position.x;
+                let start = self.document.rope.line_to_char(self.cursor_position.y) + self.cursor_position.x;
+                let toggled = self.document.toggle_case(start, start + std::cmp::min(count.unwrap_or(1), available));
+                let len = self.line_length(self.cursor_position.y);
+                self.cursor_position.x = std::cmp::min(self.cursor_position.x + toggled, len.saturating_sub(1));
             }
 
-            // Undo to last snapshot
-            KeyEvent { code: KeyCode::Char('u'), .. } => {
-                if self.document.undo() {
-                    self.status_message = StatusMessage::from("Undo".to_string());
-                }
+            // Alt+Up/Down move the line (count times), Shift+Alt+Up/Down copy it above/below
+            KeyEvent { code: code @ (KeyCode::Up | KeyCode::Down), modifiers, .. } if modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT => {
+                self.duplicate_line(code == KeyCode::Down);
+            }
+            KeyEvent { code: code @ (KeyCode::Up | KeyCode::Down), modifiers: KeyModifiers::ALT, .. } => {
+                self.move_line(code == KeyCode::Down, count.unwrap_or(1));
             }
 
-            // Redo to future snapshot in stack
-            KeyEvent { code: KeyCode::Char('r'), .. } => {
-                if self.document.redo() {
-                    self.status_message = StatusMessage::from("Redo".to_string());
-                }
+            // Paste the register below (p) or above (P) the current line
+            KeyEvent { code: KeyCode::Char(c @ ('p' | 'P')), .. } if self.paste_lines(c == 'p', self.options.smart_paste) => {
+                self.status_message = crate::editor::StatusMessage::from("Pasted!".to_string());
             }
-            
-            // Delegate movement logic
-            KeyEvent {
-                code: KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right,
-                ..
-            } => self.move_cursor(key.code),
-            
-            _ => (),
-        }
-        Ok(())
-    }
 
-    fn process_insert_mode(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
-        match key {
+            // Search forward (/) or backward (?) for a regex
+            KeyEvent { code: KeyCode::Char(c @ ('/' | '?')), .. } => self.search_prompt(c == '?')?,
 
-            // Exit into normal mode
-            KeyEvent { code: KeyCode::Esc, .. } => {
-                self.mode = Mode::Normal;
-                self.status_message = StatusMessage::from("Normal Mode".to_string());
+            // Repeat the last search, N goes the other way
+            KeyEvent { code: KeyCode::Char(c @ ('n' | 'N')), .. } => {
+                if self.search.is_some() {
+                    self.highlight_search = true;
+                    self.search_next(1, c == 'N');
+                } else {
+                    self.status_message = StatusMessage::from("No previous search".to_string());
+                }
             }
 
-            // Typing logic (moved from process_normal_mode)
-            // Handle Enter
-            KeyEvent { code: KeyCode::Enter, .. } => {
-                self.document.snapshot();
-                self.document.insert(&self.cursor_position, '\n');
-                self.cursor_position.y += 1;
-                self.cursor_position.x = 0;
+            // Undo, a count (5u) steps that many states at once
+            KeyEvent { code: KeyCode::Char('u'), .. } => {
+                self.undo_steps(count.unwrap_or(1), false);
+                self.skip_repeats(key)?;
             }
-
-            // Save state every space
-            KeyEvent { code: KeyCode::Char(' '), .. } => {
-                self.document.snapshot();
-                self.document.insert(&self.cursor_position, ' ');
-                self.cursor_position.x += 1;
+            
+            // Join the next line onto this one
+            KeyEvent { code: KeyCode::Char('J'), .. } if !self.document.is_last_line(self.cursor_position.y) => {
+                self.document.snapshot(self.cursor_position);
+                if let Some(x) = self.document.join_lines(self.cursor_position.y) {
+                    self.cursor_position.x = x;
+                }
             }
 
-            // Handle Character insertion
-            KeyEvent { code: KeyCode::Char(c), .. } => {
-                self.document.insert(&self.cursor_position, c);
-                self.cursor_position.x += 1;
+            // Repeat the last edit
+            KeyEvent { code: KeyCode::Char('.'), .. } => self.repeat_last_edit(count)?,
+
+            // Line motions: start, last character, first non-blank
+            KeyEvent { code: KeyCode::Char(c @ ('0' | '$' | '^')), .. } => {
+                self.cursor_position = match c {
+                    '0' => self.line_start(),
+                    '$' => self.line_end(),
+                    _ => self.first_non_blank(),
+                };
+                // After $, moving up or down sticks to the end of each line
+                if c == '$' { self.desired_x = Some((usize::MAX, self.cursor_position)); }
             }
 
-            // Handle Backspace
-            KeyEvent { code: KeyCode::Backspace, .. } => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    if self.cursor_position.x > 0 {
-                        self.cursor_position.x -= 1;
-                    } else {
-                        // Moving back a line
-                        self.cursor_position.y -= 1;
-                        self.cursor_position.x = self.line_length(self.cursor_position.y);
-                    }
-                    self.document.delete(&self.cursor_position);
-                }
+            // Delegate movement logic, hjkl map onto the arrows
+            KeyEvent {
+                code: code @ (KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right | KeyCode::Char('h' | 'j' | 'k' | 'l')
+                    | KeyCode::Home | KeyCode::End | KeyCode::PageUp | KeyCode::PageDown),
+                ..
+            } => {
+                let code = match code {
+                    KeyCode::Char('h') => KeyCode::Left,
+                    KeyCode::Char('j') => KeyCode::Down,
+                    KeyCode::Char('k') => KeyCode::Up,
+                    KeyCode::Char('l') => KeyCode::Right,
+                    _ => code,
+                };
+                self.repeat_motion(count, |editor| editor.move_cursor(code));
             }
-            
-            // Movement logic
-            KeyEvent { code: KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right, .. } => {
-                self.move_cursor(key.code);
+
+            // Last line, or line `count`
+            KeyEvent { code: KeyCode::Char('G'), .. } => {
+                self.remember_jump();
+                self.cursor_position.y = self.line_target(count, true);
+                self.cursor_position = self.first_non_blank();
             }
 
+            // Word motions
+            KeyEvent { code: KeyCode::Char('w'), .. } => self.repeat_motion(count, Self::next_word_start),
+            KeyEvent { code: KeyCode::Char('b'), .. } => self.repeat_motion(count, Self::word_back),
+
+            // Drop a register picked with "a
+            KeyEvent { code: KeyCode::Esc, .. } => self.pending_register = None,
+            
             _ => (),
         }
         Ok(())
     }
 
-    fn process_command_mode(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
-        let mut command = if let Mode::Command(s) = &self.mode { s.clone() } else { String::new() };
+    // The cursor goes back to where the change was made, clamped in case the lines it was
+    // on are gone
+    fn undo_steps(&mut self, count: usize, redo: bool) {
+        let done = if redo { self.document.redo(count) } else { self.document.undo(count) };
+        let applied = done.applied;
+        if let Some(at) = done.cursor {
+            self.cursor_position.y = std::cmp::min(at.y, self.document.len().saturating_sub(1));
+            self.cursor_position.x = std::cmp::min(at.x, self.line_length(self.cursor_position.y));
+        }
+        let (older, newer) = self.document.history_depth();
+        let (verb, remaining, side) = if redo { ("Redo", newer, "newer") } else { ("Undo", older, "older") };
 
-        match key {
-            // Execute command
-            KeyEvent { code: KeyCode::Enter, .. } => {
-                crate::commands::execute_command(self, &command)?;
+        let text = if done.invalidated {
+            "undo history invalidated by reload".to_string()
+        } else if applied == 0 {
+            format!("Nothing to {}", verb.to_lowercase())
+        } else if applied < count {
+            format!("{} {} of {}, no {} states left", verb, applied, count, side)
+        } else {
+            format!("{} ({} {} states remain)", verb, remaining, side)
+        };
+        self.status_message = StatusMessage::from(text);
+    }
+
+    // Drops copies of `key` that queued up while it was being handled (a held-down key),
+    // so each frame applies one step. The first different key is kept for the next loop
+    fn skip_repeats(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
+        while self.input_queue.front() == Some(&Input::Key(key)) {
+            self.input_queue.pop_front();
+        }
+        if !self.input_queue.is_empty() { return Ok(()); }
+
+        while let Some(next) = self.terminal.poll_input()? {
+            if next != Input::Key(key) {
+                self.input_queue.push_back(next);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Replayed keys first, then the terminal
+    // None means the window was resized and only needs a redraw
+    fn next_input(&mut self) -> Result<Option<Input>, std::io::Error> {
+        if let Some(key) = self.playback.pop_front() {
+            return Ok(Some(Input::Key(key)));
+        }
+        let input = match self.input_queue.pop_front() {
+            Some(input) => Some(input),
+            None => self.terminal.read_input()?,
+        };
+        match &input {
+            Some(Input::Key(key)) => self.record_macro_key(*key),
+            // A macro only holds keys, so a paste goes in as the keys that would type it
+            Some(Input::Paste(text)) => for key in paste_keys(text) { self.record_macro_key(key); },
+            None => (),
+        }
+        Ok(input)
+    }
+
+    // Keys for prompts, pickers and questions: a playing macro first, then keys typed ahead,
+    // then the terminal. They're part of the edit in progress too, so . can answer the
+    // prompt the same way. A paste has nowhere to go here and is dropped
+    fn read_key(&mut self) -> Result<KeyEvent, std::io::Error> {
+        loop {
+            match self.next_input()? {
+                Some(Input::Key(key)) => {
+                    self.edit_keys.push(key);
+                    return Ok(key);
+                }
+                Some(Input::Paste(_)) => (),
+                None => crate::ui::refresh_screen(self)?,
+            }
+        }
+    }
+
+    // read_key for the prompts and the picker filter, which take a paste as typed text.
+    // Only its first line: a line break would end the prompt
+    fn read_text_input(&mut self) -> Result<Input, std::io::Error> {
+        loop {
+            match self.next_input()? {
+                Some(Input::Key(key)) => {
+                    self.edit_keys.push(key);
+                    return Ok(Input::Key(key));
+                }
+                Some(Input::Paste(text)) => {
+                    let line: String = text.split(['\r', '\n']).next().unwrap_or_default().chars().filter(|c| !c.is_control()).collect();
+                    self.edit_keys.extend(paste_keys(&line));
+                    return Ok(Input::Paste(line));
+                }
+                None => crate::ui::refresh_screen(self)?,
+            }
+        }
+    }
+
+    // For slow work to check every so often: true once Ctrl+C was pressed. Everything else
+    // typed meanwhile, before or after the Ctrl+C, is queued to run when the work is done
+    pub(crate) fn interrupted(&mut self) -> Result<bool, std::io::Error> {
+        let mut interrupted = false;
+        while let Some(input) = self.terminal.poll_input()? {
+            match input {
+                Input::Key(KeyEvent { code: KeyCode::Char('c'), modifiers: KeyModifiers::CONTROL, .. }) => interrupted = true,
+                input => self.input_queue.push_back(input),
+            }
+        }
+        Ok(interrupted)
+    }
+
+    // Moves everything typed so far into the queue, so a prompt that shows up next
+    // only sees keys pressed after it was drawn
+    fn queue_pending_input(&mut self) -> Result<(), std::io::Error> {
+        while let Some(input) = self.terminal.poll_input()? {
+            self.input_queue.push_back(input);
+        }
+        Ok(())
+    }
+
+    fn process_prefixed(&mut self, prefix: char, key: KeyEvent, count: Option<usize>) -> Result<(), std::io::Error> {
+        // Anything not listed here (or Esc) just cancels
+        match (prefix, key.code) {
+            // Jump between merge conflicts
+            (']' | '[', KeyCode::Char('x')) => {
+                match crate::conflict::find_start(&self.document, self.cursor_position.y, prefix == ']') {
+                    Some(y) => {
+                        self.cursor_position.y = y;
+                        self.cursor_position.x = 0;
+                    }
+                    None => self.status_message = StatusMessage::from("No conflicts".to_string()),
+                }
+            }
+            // First line, or line `count`
+            ('g', KeyCode::Char('g')) => {
+                self.remember_jump();
+                self.cursor_position.y = self.line_target(count, false);
+                self.cursor_position = self.first_non_blank();
+            }
+            // Scroll so the cursor's row sits in the middle (zz), at the top (zt) or at the
+            // bottom (zb) of the screen, counting wrapped lines by the rows they take
+            ('z', KeyCode::Char(c @ ('z' | 't' | 'b'))) => {
+                let height = self.text_area_height();
+                let rows = match c {
+                    'z' => height / 2,
+                    't' => 0,
+                    _ => height.saturating_sub(1),
+                };
+                self.row_offset = self.centered_offset(rows);
+                self.centered_at = Some((self.cursor_position.x, self.cursor_position.y)); // Typewriter mode keeps this scroll
+            }
+            // Re-break the paragraph at textwidth (80 when that's off), like :wrap
+            ('g', KeyCode::Char('q')) => {
+                self.document.snapshot(self.cursor_position);
+                let width = if self.options.textwidth > 0 { self.options.textwidth } else { 80 };
+                if let Err(message) = self.wrap_paragraph(width) {
+                    self.status_message = StatusMessage::from(message);
+                }
+            }
+            // Pick the register for the next yank, delete or paste ("a), the count waits for it too
+            ('"', KeyCode::Char(c)) if c.is_ascii_alphanumeric() => {
+                self.pending_register = Some(c);
+                self.pending_count = count;
+            }
+            // Set a mark
+            ('m', KeyCode::Char(c)) if c.is_ascii_lowercase() => {
+                self.marks.insert(c, self.cursor_position);
+                self.status_message = StatusMessage::from(format!("Mark {} set", c));
+            }
+            // Jump to a mark's line ('a) or its exact spot (`a), '' goes back to before the last jump
+            ('\'' | '`', KeyCode::Char(c @ ('a'..='z' | '\'' | '`'))) => {
+                let name = if c == '`' { '\'' } else { c };
+                self.jump_to_mark(name, prefix == '`');
+            }
+            // Replace the character under the cursor
+            ('r', KeyCode::Char(c)) if self.cursor_position.x < self.line_length(self.cursor_position.y) => {
+                self.document.snapshot(self.cursor_position);
+                self.document.replace_char(&self.cursor_position, c);
+            }
+            // Paste lines re-indented to fit under the current line
+            (']', KeyCode::Char('p')) if self.paste_lines(true, true) => {
+                self.status_message = StatusMessage::from("Pasted!".to_string());
+            }
+            // Indent (>>) or dedent (<<) this line and count - 1 below it by one level, the
+            // cursor stays on the same character
+            ('>', KeyCode::Char('>')) | ('<', KeyCode::Char('<')) => {
+                self.document.snapshot(self.cursor_position);
+                let y = self.cursor_position.y;
+                let last = std::cmp::min(y + count.unwrap_or(1), self.document.len()) - 1;
+                for line in y..=last {
+                    // Blank lines stay blank rather than gaining trailing spaces
+                    if self.line_length(line) == 0 { continue; }
+                    let shift = if prefix == '>' { self.document.indent(line, 1); 4 } else { self.document.dedent(line, 1) };
+                    if line == y {
+                        self.cursor_position.x = if prefix == '>' { self.cursor_position.x + shift } else { self.cursor_position.x.saturating_sub(shift) };
+                    }
+                }
+            }
+            // Start recording a macro
+            ('q', KeyCode::Char(c)) if c.is_ascii_alphanumeric() => {
+                self.recording = Some((c, Vec::new()));
+                self.status_message = StatusMessage::from(format!("recording @{}", c));
+            }
+            // Play a macro, @@ plays the last one again
+            ('@', KeyCode::Char(c)) if c.is_ascii_alphanumeric() || c == '@' => self.play_macro(c, count.unwrap_or(1))?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Feeds the macro's keys back through process_keypress `count` times as one undo step,
+    // stopping early once something in it fails (a search that finds nothing, a motion that
+    // can't move)
+    fn play_macro(&mut self, register: char, count: usize) -> Result<(), std::io::Error> {
+        let register = match (register, self.last_macro) {
+            ('@', Some(last)) => last,
+            ('@', None) => {
+                self.status_message = StatusMessage::from("No macro played yet".to_string());
+                return Ok(());
+            }
+            (c, _) => c,
+        };
+        let Some(keys) = self.macros.get(&register).cloned() else {
+            self.status_message = StatusMessage::from(format!("No macro in register {}", register));
+            return Ok(());
+        };
+        self.last_macro = Some(register);
+
+        self.document.begin_edit_group(self.cursor_position);
+        self.motion_failed = false;
+        let runs = self.nested(Context::Macro, |editor| {
+            let mut runs = 0;
+            while runs < count && !editor.motion_failed && !editor.should_quit {
+                editor.playback = keys.iter().copied().collect();
+                while !editor.playback.is_empty() && !editor.motion_failed && !editor.should_quit {
+                    editor.process_keypress()?;
+                    editor.follow_edits();
+                }
+                runs += 1;
+            }
+            Ok(runs)
+        });
+        self.playback.clear();
+        self.document.end_edit_group();
+        let Some(runs) = runs? else { return Ok(()); };
+
+        if self.motion_failed && count > 1 {
+            self.status_message = StatusMessage::from(format!("@{} stopped during run {} of {}", register, runs, count));
+        }
+        Ok(())
+    }
+
+    // Every key read while recording goes into the macro, playback keys don't (they're
+    // already in one)
+    fn record_macro_key(&mut self, key: KeyEvent) {
+        if let Some((_, keys)) = self.recording.as_mut() {
+            keys.push(key);
+        }
+    }
+
+    // The q that stopped the recording was recorded too, it isn't part of the macro
+    fn stop_recording(&mut self) {
+        let Some((register, mut keys)) = self.recording.take() else { return; };
+        keys.pop();
+        self.status_message = StatusMessage::from(format!("Recorded @{} ({} keys)", register, keys.len()));
+        self.macros.insert(register, keys);
+    }
+
+    // Second half of d/y/c: a motion picks the range (the operator again means whole lines,
+    // dd), anything else cancels without touching the text
+    fn process_operator(&mut self, operator: PendingOperator, key: KeyEvent) -> Result<(), std::io::Error> {
+        let prefix = self.pending_prefix.take();
+
+        // Digits after the operator are a count too (d3w)
+        if let KeyCode::Char(c @ '0'..='9') = key.code
+            && prefix.is_none()
+            && key.modifiers.is_empty()
+            && (c != '0' || self.pending_count.is_some())
+        {
+            let digit = c.to_digit(10).unwrap_or(0) as usize;
+            self.pending_count = Some(self.pending_count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            return Ok(());
+        }
+
+        self.pending_operator = None;
+        let count = match (operator.count, self.pending_count.take()) {
+            (None, None) => None,
+            (before, after) => Some(before.unwrap_or(1).saturating_mul(after.unwrap_or(1))),
+        };
+        let here = self.cursor_position;
+        let range = match (prefix, key.code) {
+            // gg and text objects (iw, i*) need one more key
+            (None, KeyCode::Char(c @ ('g' | 'i'))) => {
+                self.pending_operator = Some(PendingOperator { op: operator.op, count });
+                self.pending_prefix = Some(c);
+                return Ok(());
+            }
+            (Some('g'), KeyCode::Char('g')) => OperatorRange::Lines(here.y, self.line_target(count, false)),
+            (Some('i'), KeyCode::Char(c @ ('w' | '"' | '\'' | '`' | '*' | '(' | ')' | '[' | ']'))) => {
+                let found = if c == 'w' {
+                    crate::textobject::inner_word(&self.document, here)
+                } else {
+                    crate::textobject::inner_delimited(&self.document, here, c)
+                };
+                match found {
+                    Some((start, end)) => OperatorRange::Chars(start, end),
+                    None => {
+                        let what = if c == 'w' { "No word under the cursor".to_string() } else { format!("Not inside {}", c) };
+                        self.status_message = StatusMessage::from(format!("{}i{} cancelled: {}", operator.op, c, what));
+                        return Ok(());
+                    }
+                }
+            }
+            (None, KeyCode::Char('G')) => OperatorRange::Lines(here.y, self.line_target(count, true)),
+            (None, KeyCode::Char(c)) if c == operator.op => {
+                let last = self.document.len().saturating_sub(1);
+                OperatorRange::Lines(here.y, std::cmp::min(here.y + count.unwrap_or(1) - 1, last))
+            }
+            (None, KeyCode::Char('j')) => {
+                let last = self.line_target(None, true);
+                OperatorRange::Lines(here.y, std::cmp::min(here.y + count.unwrap_or(1), last))
+            }
+            (None, KeyCode::Char('k')) => OperatorRange::Lines(here.y, here.y.saturating_sub(count.unwrap_or(1))),
+            (None, KeyCode::Char('w')) => {
+                let mut end = self.motion_target(|editor| editor.repeat_motion(count, Self::next_word_start));
+                // Stopping at the start of a later line means up to the end of the one before
+                // it, so dw on a line's last word doesn't join the next line on
+                if end.x == 0 && end.y > here.y {
+                    end = Position { x: self.line_length(end.y - 1), y: end.y - 1 };
+                }
+                // cw leaves the space after the word alone (vim's ce)
+                if operator.op == 'c' {
+                    let line = self.document.rope.line(end.y);
+                    while end.x > 0 && (end.y, end.x) > (here.y, here.x) && matches!(line.char(end.x - 1), ' ' | '\t') {
+                        end.x -= 1;
+                    }
+                }
+                OperatorRange::Chars(here, end)
+            }
+            (None, KeyCode::Char('b')) => {
+                OperatorRange::Chars(self.motion_target(|editor| editor.repeat_motion(count, Self::word_back)), here)
+            }
+            (None, KeyCode::Char('$')) => OperatorRange::Chars(here, Position { x: self.line_length(here.y), y: here.y }),
+            (None, KeyCode::Char('0')) => OperatorRange::Chars(self.line_start(), here),
+            (None, KeyCode::Char('^')) => {
+                let start = self.first_non_blank();
+                if start.x < here.x { OperatorRange::Chars(start, here) } else { OperatorRange::Chars(here, start) }
+            }
+            // A search as the motion, up to (not including) the match. It doesn't become the
+            // last search, and Esc at the prompt drops the operator too
+            (None, KeyCode::Char(c @ ('/' | '?'))) => {
+                let Some(pattern) = self.prompt(&format!("{}{}", operator.op, c))? else { return Ok(()); };
+                let mut query = Query::new(&pattern);
+                query.backward = c == '?';
+                query.regex = true;
+                let found = match Search::new(query) {
+                    Ok(search) => search.find(&self.document, here.x, here.y, count.unwrap_or(1), false),
+                    Err(msg) => {
+                        self.status_message = StatusMessage::from(msg);
+                        return Ok(());
+                    }
+                };
+                match found {
+                    Some((m, _, _)) => {
+                        let target = Position { x: m.start, y: m.y };
+                        if (target.y, target.x) < (here.y, here.x) { OperatorRange::Chars(target, here) } else { OperatorRange::Chars(here, target) }
+                    }
+                    None => {
+                        self.status_message = StatusMessage::from(format!("{}{}{} cancelled, not found", operator.op, c, pattern));
+                        return Ok(());
+                    }
+                }
+            }
+            (_, KeyCode::Esc) => return Ok(()),
+            _ => {
+                let mut typed: String = prefix.into_iter().collect();
+                if let KeyCode::Char(c) = key.code { typed.push(c); }
+                let what = if prefix == Some('i') { "not a text object" } else { "not a motion" };
+                self.status_message = StatusMessage::from(format!("{}{} cancelled, {}", operator.op, typed, what));
+                return Ok(());
+            }
+        };
+        self.apply_operator(operator.op, range);
+        Ok(())
+    }
+
+    // Deletes (d), copies (y) or replaces (c) a range as one undo step, the cursor ends up at
+    // its start
+    fn apply_operator(&mut self, op: char, range: OperatorRange) {
+        match range {
+            OperatorRange::Lines(a, b) => {
+                let (first, last) = (std::cmp::min(a, b), std::cmp::max(a, b));
+                if first >= self.document.len() { return; }
+                let count = last - first + 1;
+                self.cursor_position.y = first;
+                match op {
+                    'y' => {
+                        let text: String = (first..=last).map(|y| self.document.line_text(y)).collect();
+                        self.store_register(text.clone());
+                        self.copy_to_clipboard(text, if count == 1 { "Line copied!" } else { "Lines copied!" });
+                    }
+                    'd' => {
+                        self.document.snapshot(self.cursor_position);
+                        self.delete_lines(count);
+                    }
+                    // One empty line is left to type the replacement on
+                    _ => {
+                        self.document.snapshot(self.cursor_position);
+                        let start = self.document.rope.line_to_char(first);
+                        let end = self.document.rope.line_to_char(last) + self.line_length(last);
+                        self.store_register(format!("{}\n", self.document.rope.slice(start..end)));
+                        self.document.delete_char_range(start, end);
+                        self.cursor_position.x = 0;
+                        self.mode = Mode::Insert;
+                        self.status_message = StatusMessage::from("Insert Mode".to_string());
+                    }
+                }
+                self.cursor_position.x = std::cmp::min(self.cursor_position.x, self.line_length(first));
+            }
+            OperatorRange::Chars(from, to) => {
+                let rope = &self.document.rope;
+                let start = rope.line_to_char(from.y) + from.x;
+                let end = std::cmp::max(rope.line_to_char(to.y) + to.x, start);
+                let text = self.document.rope.slice(start..end).to_string();
+                self.cursor_position = from;
+                if op == 'y' {
+                    self.store_register(text.clone());
+                    self.copy_to_clipboard(text, "Copied!");
+                    return;
+                }
+
+                self.document.snapshot(self.cursor_position);
+                self.store_register(text);
+                self.document.delete_char_range(start, end);
+                if op == 'c' {
+                    self.mode = Mode::Insert;
+                    self.status_message = StatusMessage::from("Insert Mode".to_string());
+                } else {
+                    // Normal mode sits on a character, not past the last one
+                    let len = self.line_length(from.y);
+                    self.cursor_position.x = std::cmp::min(from.x, len.saturating_sub(1));
+                }
+            }
+        }
+    }
+
+    // Motions (and counts for them) move the cursor end of the selection, d/x and y act on it
+    fn process_visual_mode(&mut self, key: KeyEvent, anchor: Position) -> Result<(), std::io::Error> {
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('v') => {
+                self.pending_count = None;
+                self.mode = Mode::Normal;
+                self.status_message = StatusMessage::from("Normal Mode".to_string());
+            }
+            KeyCode::Char(c @ ('d' | 'x' | 'y' | '~')) => {
+                if c == '~' {
+                    let (start, end) = self.document.selection_bounds(&anchor, &self.cursor_position);
+                    self.document.snapshot(self.cursor_position);
+                    self.document.toggle_case(start, end);
+                } else if c == 'y' {
+                    let text = self.document.text_range(&anchor, &self.cursor_position);
+                    self.store_register(text.clone());
+                    self.copy_to_clipboard(text, "Selection copied!");
+                } else {
+                    self.document.snapshot(self.cursor_position);
+                    let removed = self.document.delete_range(&anchor, &self.cursor_position);
+                    self.store_register(removed);
+                }
+
+                // Back to the start of the selection
+                let start = if (anchor.y, anchor.x) < (self.cursor_position.y, self.cursor_position.x) { anchor } else { self.cursor_position };
+                self.cursor_position.y = std::cmp::min(start.y, self.document.len().saturating_sub(1));
+                self.cursor_position.x = std::cmp::min(start.x, self.line_length(self.cursor_position.y));
+                self.pending_count = None;
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+            | KeyCode::Home | KeyCode::End | KeyCode::PageUp | KeyCode::PageDown
+            | KeyCode::Char('h' | 'j' | 'k' | 'l' | 'w' | 'b' | '0'..='9' | '$' | '^') => {
+                // The normal mode handlers know the motions, only the mode has to survive them
+                self.process_normal_mode(key)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Up/down extend the range of lines, d/y/>/< act on all of them as one undo step
+    fn process_visual_line_mode(&mut self, key: KeyEvent, anchor: usize) -> Result<(), std::io::Error> {
+        let y = self.cursor_position.y;
+        let (first, last) = (std::cmp::min(anchor, y), std::cmp::max(anchor, y));
+        let count = last - first + 1;
+
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('V') => {
+                self.pending_count = None;
+                self.mode = Mode::Normal;
+                self.status_message = StatusMessage::from("Normal Mode".to_string());
+                return Ok(());
+            }
+            KeyCode::Up | KeyCode::Down | KeyCode::Char('j' | 'k' | '0'..='9') => {
+                return self.process_normal_mode(key);
+            }
+            KeyCode::Char('d') => {
+                self.document.snapshot(self.cursor_position);
+                self.cursor_position.y = first;
+                self.delete_lines(count);
+            }
+            KeyCode::Char('y') => {
+                let text: String = (first..=last).map(|y| self.document.line_text(y)).collect();
+                self.store_register(text.clone());
+                self.copy_to_clipboard(text, if count == 1 { "Line copied!" } else { "Lines copied!" });
+                self.cursor_position.y = first;
+            }
+            KeyCode::Char('~') => {
+                self.document.snapshot(self.cursor_position);
+                let start = self.document.rope.line_to_char(first);
+                let end = self.document.rope.line_to_char(last) + self.line_length(last);
+                self.document.toggle_case(start, end);
+                self.cursor_position.y = first;
+            }
+            KeyCode::Char(c @ ('>' | '<')) => {
+                self.document.snapshot(self.cursor_position);
+                // Blank lines stay blank rather than gaining trailing spaces
+                for y in first..=last {
+                    if self.line_length(y) == 0 { continue; }
+                    if c == '>' { self.document.indent(y, 1); } else { self.document.dedent(y, 1); }
+                }
+                self.cursor_position.y = first;
+            }
+            _ => return Ok(()),
+        }
+
+        self.cursor_position.x = std::cmp::min(self.cursor_position.x, self.line_length(self.cursor_position.y));
+        self.pending_count = None;
+        self.mode = Mode::Normal;
+        Ok(())
+    }
+
+    fn process_insert_mode(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
+        // Only the key straight after autopair typed a pair can act on it, anything else
+        // (typing, moving, an emacs key) ends it
+        let auto_pair = self.auto_pair.take();
+        if self.options.emacs_keys && self.process_emacs_key(key) {
+            return Ok(());
+        }
+
+        // Undo steps: a run of typing or of deleting is one (until a pause), each Enter starts
+        // a new one, and leaving Insert Mode or moving the cursor ends the current one
+        let ctrl_o = key.code == KeyCode::Char('o') && key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            // Their own step, taken once they know there's something to delete
+            KeyCode::Char('w' | 'u') if key.modifiers == KeyModifiers::CONTROL => self.document.break_typing(),
+            KeyCode::Char(_) | KeyCode::Tab if !ctrl_o => self.document.snapshot_typing(self.cursor_position, EditKind::Typing),
+            KeyCode::Backspace | KeyCode::Delete => self.document.snapshot_typing(self.cursor_position, EditKind::Deleting),
+            KeyCode::Enter => self.document.snapshot_typing(self.cursor_position, EditKind::Newline),
+            KeyCode::BackTab => self.document.snapshot(self.cursor_position),
+            _ => self.document.break_typing(),
+        }
+
+        match key {
+
+            // Exit into normal mode
+            KeyEvent { code: KeyCode::Esc, .. } => {
+                self.mode = Mode::Normal;
+                self.auto_break = None;
+                self.status_message = StatusMessage::from("Normal Mode".to_string());
+            }
+
+            // Delete the word before the cursor (Ctrl+W) or everything before it on the line (Ctrl+U)
+            KeyEvent { code: KeyCode::Char(c @ ('w' | 'u')), modifiers: KeyModifiers::CONTROL, .. } => {
+                self.auto_break = None;
+                self.delete_back(c == 'w');
+            }
+
+            // Run one normal mode command, then carry on typing
+            KeyEvent { code: KeyCode::Char('o'), modifiers: KeyModifiers::CONTROL, .. } => {
+                self.mode = Mode::Normal;
+                self.insert_after_command = true;
+                self.auto_break = None;
+                self.status_message = StatusMessage::from("-- (insert) --".to_string());
+            }
+
+            // Carry on a list or quote (`:set autolist`)
+            KeyEvent { code: KeyCode::Enter, .. } if self.options.auto_list && self.continue_list() => self.auto_break = None,
+
+            // Typing logic (moved from process_normal_mode)
+            // Handle Enter, the new line gets the indentation before the cursor (autoindent)
+            KeyEvent { code: KeyCode::Enter, .. } => {
+                self.auto_break = None;
+                let y = self.cursor_position.y;
+                let mut indent = String::new();
+                if self.options.auto_indent && y < self.document.len() {
+                    let len = self.line_length(y);
+                    let line = self.document.rope.line(y);
+                    indent = line.chars().take(self.cursor_position.x).take_while(|c| *c == ' ' || *c == '\t').collect();
+
+                    // A line of nothing but indentation is left empty rather than carried on
+                    if len > 0 && line.chars().take(len).all(|c| c == ' ' || c == '\t') {
+                        let start = self.document.rope.line_to_char(y);
+                        self.document.delete_char_range(start, start + len);
+                        self.cursor_position.x = 0;
+                        indent.clear();
+                    }
+                }
+                self.document.insert(&self.cursor_position, '\n');
+                self.cursor_position.y += 1;
+                self.cursor_position.x = 0;
+                if !indent.is_empty() {
+                    self.document.insert_str(&self.cursor_position, &indent);
+                    self.cursor_position.x = indent.chars().count();
+                }
+            }
+
+            // A space never triggers the auto-wrap, the next word does
+            KeyEvent { code: KeyCode::Char(' '), .. } => {
+                self.document.insert(&self.cursor_position, ' ');
+                self.cursor_position.x += 1;
+            }
+
+            // Closing pairs (`:set autopair`)
+            KeyEvent { code: KeyCode::Char(c), .. } if self.options.auto_pair && self.type_pair(c, auto_pair) => (),
+
+            // Handle Character insertion
+            KeyEvent { code: KeyCode::Char(c), .. } => {
+                self.document.insert(&self.cursor_position, c);
+                self.cursor_position.x += 1;
+                self.auto_wrap();
+            }
+
+            // Backspacing over an auto-wrap puts the line back together
+            KeyEvent { code: KeyCode::Backspace, .. }
+                if self.auto_break.as_ref().is_some_and(|p| p.x == self.cursor_position.x && p.y == self.cursor_position.y) =>
+            {
+                self.undo_auto_break();
+            }
+
+            // Backspace inside a pair autopair just typed takes both halves, as long as they're
+            // still there
+            KeyEvent { code: KeyCode::Backspace, .. } if auto_pair == Some(self.cursor_position) && self.around_pair() => {
+                let start = self.document.rope.line_to_char(self.cursor_position.y) + self.cursor_position.x - 1;
+                self.document.delete_char_range(start, start + 2);
+                self.cursor_position.x -= 1;
+            }
+
+            // Soft tabs: back to the previous tab stop while in the indentation
+            KeyEvent { code: KeyCode::Backspace, .. } if self.options.soft_tabs && self.cursor_position.x > 0 && self.in_leading_spaces() => {
+                let x = self.cursor_position.x;
+                let stop = crate::indent::prev_tab_stop(x, self.soft_tab_width());
+                let start = self.document.rope.line_to_char(self.cursor_position.y) + stop;
+                self.document.delete_char_range(start, start + x - stop);
+                self.cursor_position.x = stop;
+            }
+
+            // Handle Backspace
+            KeyEvent { code: KeyCode::Backspace, .. } if self.cursor_position.x > 0 || self.cursor_position.y > 0 => {
+                if self.cursor_position.x > 0 {
+                    self.cursor_position.x -= 1;
+                } else {
+                    // Moving back a line
+                    self.cursor_position.y -= 1;
+                    self.cursor_position.x = self.line_length(self.cursor_position.y);
+                }
+                self.document.delete(&self.cursor_position);
+            }
+            
+            // Tab: spaces up to the next tab stop with expandtab (or in the indentation with soft
+            // tabs), a tab character otherwise. Documents indented with tabs keep getting tabs
+            KeyEvent { code: KeyCode::Tab, .. } => {
+                self.auto_break = None;
+                let x = self.cursor_position.x;
+                let expand = self.options.expand_tab && self.indent_style() != Some(IndentStyle::Tabs);
+                if expand || (self.options.soft_tabs && self.in_leading_spaces()) {
+                    let spaces = crate::indent::next_tab_stop(x, self.soft_tab_width()) - x;
+                    self.document.insert_str(&self.cursor_position, &" ".repeat(spaces));
+                    self.cursor_position.x += spaces;
+                } else {
+                    self.document.insert(&self.cursor_position, '\t');
+                    self.cursor_position.x += 1;
+                }
+            }
+
+            // Shift+Tab takes one level of indentation off the line, the cursor stays on its text
+            KeyEvent { code: KeyCode::BackTab, .. } => {
+                self.auto_break = None;
+                let removed = self.document.dedent(self.cursor_position.y, 1);
+                self.cursor_position.x = self.cursor_position.x.saturating_sub(removed);
+            }
 
+            // Forward delete, at the end of a line it pulls the next one up (nothing happens at
+            // the end of the document)
+            KeyEvent { code: KeyCode::Delete, .. } => {
+                self.auto_break = None;
+                self.document.delete(&self.cursor_position);
+            }
+
+            // Movement logic
+            KeyEvent { code: KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+                | KeyCode::Home | KeyCode::End | KeyCode::PageUp | KeyCode::PageDown, .. } => {
+                self.auto_break = None;
+                self.move_cursor(key.code);
+            }
+
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Typed characters overwrite instead of pushing the text along (past the end of the line
+    // they're appended), Enter goes on to the next line, Backspace undoes one key at a time
+    fn process_replace_mode(&mut self, key: KeyEvent) {
+        let Position { x, y } = self.cursor_position;
+        match key.code {
+            KeyCode::Esc => {
+                self.replaced.clear();
                 self.mode = Mode::Normal;
+                self.status_message = StatusMessage::from("Normal Mode".to_string());
+            }
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                if x < self.line_length(y) {
+                    let old = self.document.rope.line(y).char(x);
+                    self.document.replace_char(&self.cursor_position, c);
+                    self.replaced.push(ReplaceStep::Overwrote(old));
+                } else {
+                    self.document.insert(&self.cursor_position, c);
+                    self.replaced.push(ReplaceStep::Appended);
+                }
+                self.cursor_position.x += 1;
+            }
+            KeyCode::Enter => {
+                let added = self.document.is_last_line(y);
+                if added {
+                    self.document.insert(&Position { x: self.line_length(y), y }, '\n');
+                }
+                self.replaced.push(ReplaceStep::NextLine { x, added });
+                self.cursor_position = Position { x: 0, y: y + 1 };
+            }
+            KeyCode::Backspace => match self.replaced.pop() {
+                Some(ReplaceStep::Overwrote(old)) => {
+                    self.cursor_position.x -= 1;
+                    self.document.replace_char(&self.cursor_position, old);
+                }
+                Some(ReplaceStep::Appended) => {
+                    self.cursor_position.x -= 1;
+                    self.document.delete(&self.cursor_position);
+                }
+                Some(ReplaceStep::NextLine { x, added }) => {
+                    self.cursor_position = Position { x, y: y - 1 };
+                    if added {
+                        self.document.delete(&Position { x: self.line_length(y - 1), y: y - 1 });
+                    }
+                }
+                // Before anything typed this session it only moves
+                None => self.cursor_position.x = x.saturating_sub(1),
+            },
+            // Moving starts a fresh run, Backspace won't reach back past it
+            KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right => {
+                self.replaced.clear();
+                self.move_cursor(key.code);
+            }
+            _ => (),
+        }
+    }
+
+    // Whether everything before the cursor on its line is spaces (soft tabs only act there)
+    fn in_leading_spaces(&self) -> bool {
+        let y = self.cursor_position.y;
+        if y >= self.document.len() { return false; }
+        let line = self.document.rope.line(y);
+        (0..self.cursor_position.x).all(|i| i < line.len_chars() && line.char(i) == ' ')
+    }
+
+    // Emacs bindings for insert mode (`:set emacskeys`), returns false if the key isn't one
+    fn process_emacs_key(&mut self, key: KeyEvent) -> bool {
+        let KeyCode::Char(c) = key.code else { return false; };
+        let ctrl = key.modifiers == KeyModifiers::CONTROL;
+        let alt = key.modifiers == KeyModifiers::ALT;
+
+        match c {
+            'a' if ctrl => self.move_cursor(KeyCode::Home),
+            'e' if ctrl => self.move_cursor(KeyCode::End),
+            'f' if ctrl => self.move_cursor(KeyCode::Right),
+            'b' if ctrl => self.move_cursor(KeyCode::Left),
+            'f' if alt => self.word_forward(),
+            'b' if alt => self.word_back(),
+            'k' if ctrl => self.kill_line(),
+            'y' if ctrl => {
+                if let Some(text) = self.clipboard_text() {
+                    self.document.snapshot(self.cursor_position);
+                    self.insert_at_cursor(&text);
+                }
+            }
+            _ => return false,
+        }
+        self.auto_break = None;
+        true
+    }
+
+    // Removes `count` lines from the cursor's down into the register
+    pub(crate) fn delete_lines(&mut self, count: usize) {
+        let y = self.cursor_position.y;
+        let removed = self.document.delete_lines(y, count);
+        self.store_register(removed);
+
+        // Fix cursor if deleted bottom line
+        if self.cursor_position.y >= self.document.len() {
+            self.cursor_position.y = self.document.len().saturating_sub(1);
+        }
+        let current_len = self.line_length(self.cursor_position.y);
+        if self.cursor_position.x > current_len {
+            self.cursor_position.x = current_len;
+        }
+    }
+
+    // Ctrl+K: cut to the end of the line, or the line break itself when already there
+    fn kill_line(&mut self) {
+        let y = self.cursor_position.y;
+        if y >= self.document.len() { return; }
+
+        let start = self.document.rope.line_to_char(y) + self.cursor_position.x;
+        let len = self.line_length(y);
+        let end = if self.cursor_position.x < len {
+            self.document.rope.line_to_char(y) + len
+        } else {
+            // At the end already, join with the next line
+            self.document.rope.line_to_char(y + 1)
+        };
+        if start >= end { return; }
+
+        let killed = self.document.rope.slice(start..end).to_string();
+        self.document.snapshot(self.cursor_position);
+        self.document.delete_char_range(start, end);
+        self.copy_to_clipboard(killed, "Killed");
+    }
+
+    // Deletes back from the cursor to the start of the line, or with `word` just over the
+    // blanks and then the word (or run of punctuation) before it, like a shell's Ctrl+W.
+    // Never reaches into the line above
+    fn delete_back(&mut self, word: bool) {
+        let Position { x, y } = self.cursor_position;
+        if x == 0 || y >= self.document.len() { return; }
+        let line = self.document.rope.line(y);
+        let mut start = x;
+        if word {
+            while start > 0 && line.char(start - 1).is_whitespace() { start -= 1; }
+            let in_word = start > 0 && is_word_char(line.char(start - 1));
+            while start > 0 && !line.char(start - 1).is_whitespace() && is_word_char(line.char(start - 1)) == in_word {
+                start -= 1;
+            }
+        } else {
+            start = 0;
+        }
+
+        let line_start = self.document.rope.line_to_char(y);
+        self.document.snapshot(self.cursor_position);
+        self.document.delete_char_range(line_start + start, line_start + x);
+        self.cursor_position.x = start;
+    }
+
+    fn copy_to_clipboard(&mut self, text: String, message: &str) {
+        // Init clipboard and set text
+        if let Ok(mut clipboard) = arboard::Clipboard::new() {
+            let _ = clipboard.set_text(text);
+            self.status_message = StatusMessage::from(message.to_string());
+        } else {
+            self.status_message = StatusMessage::from("Clipboard error".to_string());
+        }
+    }
+
+    // Yanked or deleted text always goes to the unnamed register, and to the picked one if any
+    fn store_register(&mut self, text: String) {
+        if text.is_empty() { return; }
+        if let Some(name) = self.pending_register.take() {
+            self.registers.insert(name, text.clone());
+        }
+        self.registers.insert('"', text);
+    }
+
+    // Pastes the register (or the clipboard when nothing has been yanked) as whole lines
+    // below or above the cursor line, optionally shifted to the cursor line's indentation
+    // Text that isn't whole lines goes in at the cursor instead
+    fn paste_lines(&mut self, below: bool, reindent: bool) -> bool {
+        let text = match self.pending_register.take() {
+            Some(name) => match self.registers.get(&name) {
+                Some(text) => Some(text.clone()),
+                None => {
+                    self.status_message = StatusMessage::from(format!("Register {} is empty", name));
+                    return false;
+                }
+            },
+            None => match self.registers.get(&'"') {
+                Some(text) => Some(text.clone()),
+                None => self.clipboard_text(),
+            },
+        };
+        let Some(text) = text else { return false; };
+        if !text.ends_with('\n') {
+            self.document.snapshot(self.cursor_position);
+            self.insert_at_cursor(&text);
+            return true;
+        }
+
+        let y = self.cursor_position.y;
+        let text = if reindent { self.document.reindent_paste(y, &text) } else { text };
+        let target = if below { y + 1 } else { y };
+        self.document.snapshot(self.cursor_position);
+        self.document.insert_line(target, &text);
+
+        self.cursor_position.y = std::cmp::min(target, self.document.len().saturating_sub(1));
+        self.cursor_position.x = 0;
+        true
+    }
+
+    fn clipboard_text(&mut self) -> Option<String> {
+        arboard::Clipboard::new().ok()?.get_text().ok()
+    }
+
+    // One insert for the whole text (reparsed once), the cursor ends up right after it
+    fn insert_at_cursor(&mut self, text: &str) {
+        self.document.insert_str(&self.cursor_position, text);
+        match text.rsplit_once('\n') {
+            Some((before, last)) => {
+                self.cursor_position.y += before.matches('\n').count() + 1;
+                self.cursor_position.x = last.chars().count();
+            }
+            None => self.cursor_position.x += text.chars().count(),
+        }
+    }
+
+    // Bracketed paste: the whole text goes in at once as a single undo step, instead of
+    // running every char through the keypress handling (a space or Enter each making a
+    // new step, a redraw per char). Normal mode puts it after the cursor like `p`, the
+    // command line takes it as one line
+    fn paste(&mut self, text: &str) -> Result<(), std::io::Error> {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        if text.is_empty() { return Ok(()); }
+
+        match &mut self.mode {
+            Mode::Command(command) => {
+                command.push_str(&text.replace('\n', " "));
+                return Ok(());
+            }
+            // Overwriting has to go char by char, so Backspace can put each one back
+            Mode::Replace => {
+                for key in paste_keys(&text) {
+                    self.edit_keys.push(key);
+                    self.process_replace_mode(key);
+                }
+                return Ok(());
+            }
+            Mode::Visual { .. } | Mode::VisualLine { .. } => self.mode = Mode::Normal,
+            Mode::Normal | Mode::Insert => (),
+        }
+
+        self.document.snapshot(self.cursor_position);
+        self.auto_pair = None;
+        if self.mode == Mode::Insert {
+            // `.` replays the insert session key by key, the paste included
+            self.edit_keys.extend(paste_keys(&text));
+            self.insert_at_cursor(&text);
+            self.document.break_typing();
+        } else {
+            if self.line_length(self.cursor_position.y) > 0 { self.cursor_position.x += 1; }
+            self.insert_at_cursor(&text);
+            self.cursor_position.x = self.cursor_position.x.saturating_sub(1);
+        }
+        self.auto_break = None;
+        self.scroll();
+        Ok(())
+    }
+
+    fn process_command_mode(&mut self, key: KeyEvent) -> Result<(), std::io::Error> {
+        let mut command = if let Mode::Command(s) = &self.mode { s.clone() } else { String::new() };
+
+        match key {
+            // Execute command
+            KeyEvent { code: KeyCode::Enter, .. } => {
+                // Clear the prompt first so whatever the command reports stays visible
                 self.status_message = StatusMessage::from(String::new());
+                self.mode = Mode::Normal;
+                self.command_history.push(&command);
+                let start = self.profiler.start();
+                crate::commands::execute_command(self, &command)?;
+                self.profiler.record("command", start);
             }
             // Cancel command
             KeyEvent { code: KeyCode::Esc, .. } => {
                 self.mode = Mode::Normal;
                 self.status_message = StatusMessage::from(String::new());
             }
+            // Step through the commands run before, Down past the newest gives back what was typed
+            KeyEvent { code: KeyCode::Up, .. } => {
+                if let Some(older) = self.command_history.older(&command) { self.mode = Mode::Command(older); }
+            }
+            KeyEvent { code: KeyCode::Down, .. } => {
+                if let Some(newer) = self.command_history.newer() { self.mode = Mode::Command(newer); }
+            }
             // Edit command string
             KeyEvent { code: KeyCode::Backspace, .. } => {
                 command.pop();
@@ -321,31 +2044,360 @@ impl Editor {
                 command.push(c);
                 self.mode = Mode::Command(command);
             }
-            _ => (),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Enter on a list item or quote line starts the next one with the same marker (the next
+    // number for ordered lists). On a line that's nothing but the marker, the marker is
+    // cleared instead, which ends the list. Returns false when Enter should act as usual
+    fn continue_list(&mut self) -> bool {
+        let Position { x, y } = self.cursor_position;
+        if y >= self.document.len() || self.document.fence_lines().get(y) == Some(&true) { return false; }
+        let line = self.document.rope.line(y).to_string();
+        // A lazy list (1. 1. 1.) carries on with the same number and isn't renumbered
+        let lazy = self.list_block(y).is_some_and(|(first, lines)| {
+            crate::renumber::is_lazy(&lines.iter().map(String::as_str).collect::<Vec<_>>(), y - first)
+        });
+        let Some((prefix_len, next)) = crate::reflow::next_item(&line, lazy) else { return false; };
+        if x < prefix_len { return false; }
+
+        let len = self.line_length(y);
+        let line_start = self.document.rope.line_to_char(y);
+        if line.chars().take(len).skip(prefix_len).all(char::is_whitespace) {
+            self.document.delete_char_range(line_start, line_start + len);
+            self.cursor_position.x = 0;
+            return true;
+        }
+
+        self.document.insert_str(&self.cursor_position, &format!("\n{}", next));
+        self.cursor_position = Position { x: next.chars().count(), y: y + 1 };
+        // A numbered item added in the middle pushes the ones after it up by one
+        if !lazy && next.trim_start_matches([' ', '\t', '>']).starts_with(|c: char| c.is_ascii_digit()) {
+            self.renumber_list(y + 1);
+        }
+        true
+    }
+
+    // Re-breaks the paragraph around the cursor so its lines fit in `width` (reflow::fill),
+    // the cursor stays on the same word. A paragraph ends at blank lines, headings, code
+    // fences and the start of another list item. Returns how many lines it has now
+    pub(crate) fn wrap_paragraph(&mut self, width: usize) -> Result<usize, String> {
+        let fences = self.document.fence_lines();
+        let line = |i: usize| -> String { self.document.rope.line(i).chars().take(self.line_length(i)).collect() };
+        let is_break = |i: usize| {
+            let text = line(i);
+            text.trim().is_empty() || fences.get(i) == Some(&true) || crate::export::heading(&text).is_some()
+        };
+        let y = self.cursor_position.y;
+        if y >= self.document.len() || is_break(y) { return Err("No paragraph under cursor".to_string()); }
+
+        let mut first = y;
+        while first > 0 && !crate::reflow::is_list_item(&line(first)) && !is_break(first - 1) { first -= 1; }
+        let mut last = y;
+        while last + 1 < self.document.len() && !is_break(last + 1) && !crate::reflow::is_list_item(&line(last + 1)) { last += 1; }
+        let lines: Vec<String> = (first..=last).map(line).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        // The word the cursor is on (or the last one before it) and how far into it
+        let cursor = (y - first, self.cursor_position.x);
+        let starts = crate::reflow::word_starts(&lines);
+        let word = starts.iter().rposition(|start| *start <= cursor);
+        let offset = word.map_or(0, |i| {
+            let (sy, sx) = starts[i];
+            let len = lines[sy].chars().skip(sx).take_while(|c| !c.is_whitespace()).count();
+            if sy == cursor.0 { std::cmp::min(cursor.1 - sx, len.saturating_sub(1)) } else { 0 }
+        });
+
+        let (filled, new_starts) = crate::reflow::fill(&lines, width);
+        let text: String = filled.iter().map(|l| format!("{}\n", l)).collect();
+        self.document.replace_lines(first, last, &text);
+        self.cursor_position = match word.and_then(|i| new_starts.get(i)) {
+            Some((ny, nx)) => Position { x: nx + offset, y: first + ny },
+            None => Position { x: 0, y: first },
+        };
+        Ok(filled.len())
+    }
+
+    // The lines between the blank lines around y and where they start, for renumber. Only the
+    // block itself is handed over, it ends at the first blank line either way
+    fn list_block(&self, y: usize) -> Option<(usize, Vec<String>)> {
+        let blank = |document: &crate::document::Document, i: usize| document.rope.line(i).chars().all(char::is_whitespace);
+        if y >= self.document.len() || blank(&self.document, y) { return None; }
+        let mut first = y;
+        while first > 0 && !blank(&self.document, first - 1) { first -= 1; }
+        let mut last = y;
+        while last + 1 < self.document.len() && !blank(&self.document, last + 1) { last += 1; }
+        Some((first, (first..=last).map(|i| self.document.rope.line(i).chars().take(self.line_length(i)).collect()).collect()))
+    }
+
+    // Renumbers the numbered lists in the block around line y (see renumber::renumber), the
+    // cursor stays on the same text. Returns how many lines changed, None without a list
+    pub(crate) fn renumber_list(&mut self, y: usize) -> Option<usize> {
+        let (first, lines) = self.list_block(y)?;
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        let (from, to, text, changed) = crate::renumber::renumber(&lines, y - first)?;
+        if changed > 0 {
+            let cursor_y = self.cursor_position.y;
+            let before = self.line_length(cursor_y);
+            self.document.replace_lines(first + from, first + to, &text);
+            if (first + from..=first + to).contains(&cursor_y) && self.cursor_position.x > 0 {
+                self.cursor_position.x = (self.cursor_position.x + self.line_length(cursor_y)).saturating_sub(before);
+            }
+        }
+        Some(changed)
+    }
+
+    // Autopair for a typed char: an opener gets its closer too with the cursor in between, a
+    // closer that's already next is stepped over. Returns false to type `c` as usual
+    // `pair` is where the last pair was typed, if that was the key before this one
+    fn type_pair(&mut self, c: char, pair: Option<Position>) -> bool {
+        let Position { x, y } = self.cursor_position;
+        if y >= self.document.len() { return false; }
+        let len = self.line_length(y);
+        let line = self.document.rope.line(y);
+        let prev = if x > 0 { Some(line.char(x - 1)) } else { None };
+        let next = if x < len { Some(line.char(x)) } else { None };
+        let fresh = pair == Some(self.cursor_position);
+
+        // * inside a fresh *|* makes it **|** for bold. At the start of a line the first * was
+        // left alone (it could have been a list item), the second one shows it wasn't
+        let line_start = (0..x.saturating_sub(1)).all(|i| line.char(i).is_whitespace());
+        let bold_at_start = c == '*' && prev == Some('*') && line_start && next.is_none_or(char::is_whitespace);
+        let bold = bold_at_start || (c == '*' && fresh && prev == Some('*') && next == Some('*'));
+        if !bold && matches!(c, ')' | ']' | '"' | '*') && next == Some(c) {
+            self.cursor_position.x += 1;
+            return true;
+        }
+
+        let closer = match c {
+            '(' => ')',
+            '[' => ']',
+            '"' | '*' => c,
+            _ => return false,
+        };
+        // Only where nothing would be swallowed: before a blank, a closer or punctuation
+        if !next.is_none_or(|n| n.is_whitespace() || ")]}\"*.,;:!?".contains(n)) { return false; }
+        if !bold && matches!(c, '"' | '*') {
+            let before: Vec<char> = line.chars().take(x).collect();
+            if prev.is_some_and(|p| p.is_alphanumeric()) { return false; }
+            // An odd count of quotes before the cursor means this one closes an open quote
+            if c == '"' && before.iter().filter(|b| **b == '"').count() % 2 == 1 { return false; }
+            // Inside an emphasis span (**bold |text**) or starting a list item (* )
+            if c == '*' && (in_emphasis(&before) || before.iter().all(|b| b.is_whitespace())) { return false; }
+        }
+
+        let closer = if bold_at_start { "**".to_string() } else { closer.to_string() };
+        self.document.insert_str(&self.cursor_position, &format!("{}{}", c, closer));
+        self.cursor_position.x += 1;
+        self.auto_pair = Some(self.cursor_position);
+        true
+    }
+
+    // Whether the cursor sits between an opener and its closer, `(|)` or `*|*`
+    fn around_pair(&self) -> bool {
+        let Position { x, y } = self.cursor_position;
+        if x == 0 || x >= self.line_length(y) { return false; }
+        let line = self.document.rope.line(y);
+        matches!((line.char(x - 1), line.char(x)), ('(', ')') | ('[', ']') | ('"', '"') | ('*', '*'))
+    }
+
+    // Breaks the cursor line at the last space before textwidth, carrying list/quote prefixes
+    fn auto_wrap(&mut self) {
+        let width = self.options.textwidth;
+        let y = self.cursor_position.y;
+        let len = self.line_length(y);
+        if width == 0 || len <= width || len > self.options.long_line { return; }
+        if self.document.fence_lines().get(y) == Some(&true) { return; }
+
+        let line = self.document.rope.line(y).to_string();
+        let chars: Vec<char> = line.chars().take(self.line_length(y)).collect();
+        let (prefix_len, cont) = crate::reflow::prefixes(&line);
+
+        let Some(at) = crate::reflow::break_point(&chars, width, prefix_len) else { return; };
+        let mut end = at;
+        while end < chars.len() && chars[end] == ' ' { end += 1; }
+
+        // Swap the run of spaces for a newline and the continuation prefix
+        let line_start = self.document.rope.line_to_char(y);
+        self.document.delete_char_range(line_start + at, line_start + end);
+        self.document.insert_str(&Position { x: at, y }, &format!("\n{}", cont));
+
+        let cont_len = cont.chars().count();
+        if self.cursor_position.x >= end {
+            self.cursor_position.y += 1;
+            self.cursor_position.x = cont_len + (self.cursor_position.x - end);
+        }
+        self.auto_break = Some(Position { x: cont_len, y: y + 1 });
+    }
+
+    // Joins an auto-wrapped line back onto the previous one with a single space
+    fn undo_auto_break(&mut self) {
+        let y = self.cursor_position.y;
+        if y == 0 { return; }
+        let prev_len = self.line_length(y - 1);
+        let start = self.document.rope.line_to_char(y - 1) + prev_len;
+        let end = self.document.rope.line_to_char(y) + self.cursor_position.x;
+
+        self.document.delete_char_range(start, end);
+        self.document.insert_str(&Position { x: prev_len, y: y - 1 }, " ");
+        self.cursor_position.y = y - 1;
+        self.cursor_position.x = prev_len + 1;
+        self.auto_break = None;
+    }
+
+    pub fn scroll(&mut self) {
+        let visible_height = self.text_area_height();
+
+        // Typewriter mode keeps the cursor line in the middle, but only re-centers once the
+        // cursor has moved so a manual scroll position holds until then
+        let cursor = (self.cursor_position.x, self.cursor_position.y);
+        if self.options.typewriter && self.centered_at != Some(cursor) {
+            self.centered_at = Some(cursor);
+            self.row_offset = self.centered_offset(visible_height / 2);
+            return;
+        }
+
+        // Move offset up if cursor goes above visible screen
+        if self.cursor_position.y < self.row_offset {
+            self.row_offset = self.cursor_position.y;
+        }
+        // Move offset down if cursor goes below visible screen
+        else if self.cursor_position.y >= self.row_offset + visible_height {
+            self.row_offset = self.cursor_position.y.saturating_sub(visible_height).saturating_add(1);
+        }
+        // Wrapped lines above it can still push the cursor's row off the bottom
+        if self.cursor_screen_row() >= visible_height {
+            self.row_offset = self.centered_offset(visible_height.saturating_sub(1));
+        }
+    }
+
+    // Screen rows between the top of the text area and the cursor's row. Stops counting past a
+    // screenful, anything that far down is off screen either way
+    pub(crate) fn cursor_screen_row(&self) -> usize {
+        let text_width = (self.terminal.size().width as usize).saturating_sub(self.gutter_width());
+        let lines = (self.row_offset..self.cursor_position.y).take(self.terminal.size().height as usize);
+        let above: usize = lines.map(|y| self.visual_height(y, text_width)).sum();
+        above + crate::ui::cursor_wrap_row(self.display_col(self.cursor_position.y, self.cursor_position.x), text_width)
+    }
+
+    // After a resize the cursor keeps its place in the document and lands about as far down the
+    // screen as it was, wrap points are worked out again for the new width
+    pub(crate) fn keep_cursor_row(&mut self, row: usize, old_height: usize) {
+        let height = self.text_area_height();
+        if old_height == 0 || height == 0 { return; }
+        let row = std::cmp::min(row, old_height - 1) * height / old_height;
+        self.row_offset = self.centered_offset(row);
+        self.centered_at = None; // Typewriter mode centers again for the new height
+    }
+
+    // First line to draw so that `rows` visual rows sit above the cursor's row
+    // (fewer near the top of the document)
+    fn centered_offset(&self, rows: usize) -> usize {
+        let text_width = (self.terminal.size().width as usize).saturating_sub(self.gutter_width());
+        let mut above = crate::ui::cursor_wrap_row(self.display_col(self.cursor_position.y, self.cursor_position.x), text_width);
+        let mut offset = self.cursor_position.y;
+        while offset > 0 && above + self.visual_height(offset - 1, text_width) <= rows {
+            above += self.visual_height(offset - 1, text_width);
+            offset -= 1;
+        }
+        offset
+    }
+
+    // Scrolls the view by whole lines worth about `rows` screen rows (wrapped lines count for
+    // each row they take) and moves the cursor by as many lines. At the top or bottom of the
+    // document the cursor goes the rest of the way to the first or last line
+    pub(crate) fn page(&mut self, down: bool, rows: usize) {
+        let text_width = (self.terminal.size().width as usize).saturating_sub(self.gutter_width());
+        let last = self.document.len().saturating_sub(1);
+        let (mut y, mut used, mut lines) = (self.row_offset, 0, 0);
+        while used < rows.max(1) {
+            if down && y < last {
+                used += self.visual_height(y, text_width);
+                y += 1;
+            } else if !down && y > 0 {
+                y -= 1;
+                used += self.visual_height(y, text_width);
+            } else {
+                break;
+            }
+            lines += 1;
+        }
+
+        self.row_offset = y;
+        self.cursor_position.y = match (down, lines) {
+            (true, 0) => last,
+            (false, 0) => 0,
+            (true, _) => std::cmp::min(self.cursor_position.y + lines, last),
+            (false, _) => self.cursor_position.y.saturating_sub(lines),
+        };
+        self.cursor_position.x = std::cmp::min(self.cursor_position.x, self.line_length(self.cursor_position.y));
+        self.centered_at = None;
+    }
+
+    // Moves the view one line, returns false at the end of the document. A cursor that
+    // would scroll out of view is dragged along to the nearest line still on screen
+    pub(crate) fn nudge(&mut self, down: bool) -> bool {
+        if down {
+            if self.row_offset + 1 >= self.document.len() { return false; }
+            self.row_offset += 1;
+            self.cursor_position.y = std::cmp::max(self.cursor_position.y, self.row_offset);
+        } else {
+            if self.row_offset == 0 { return false; }
+            self.row_offset -= 1;
+            while self.cursor_position.y > self.row_offset && self.cursor_screen_row() >= self.text_area_height() {
+                self.cursor_position.y -= 1;
+            }
         }
-        Ok(())
+        self.cursor_position.x = std::cmp::min(self.cursor_position.x, self.line_length(self.cursor_position.y));
+        self.centered_at = Some((self.cursor_position.x, self.cursor_position.y)); // Typewriter mode keeps this scroll
+        true
     }
 
-    pub fn scroll(&mut self) {
-        let terminal_height = self.terminal.size().height as usize;
-        let visible_height = terminal_height.saturating_sub(2);
+    // Screen rows line y takes up once soft-wrapped
+    pub(crate) fn visual_height(&self, y: usize, text_width: usize) -> usize {
+        if y >= self.document.len() { return 1; }
+        let len = self.display_col(y, self.line_length(y));
+        if len <= text_width { return 1; }
 
-        // Move offset up if cursor goes above visible screen
-        if self.cursor_position.y < self.row_offset {
-            self.row_offset = self.cursor_position.y;
+        let wrap_width = std::cmp::max(1, text_width.saturating_sub(WRAP_PREFIX.len()));
+        1 + (len - text_width).div_ceil(wrap_width)
+    }
+
+    // Copies the current line below itself, the cursor goes with the copy if `onto_copy`
+    pub(crate) fn duplicate_line(&mut self, onto_copy: bool) {
+        self.document.snapshot(self.cursor_position);
+        self.document.duplicate_line(self.cursor_position.y);
+        if onto_copy { self.cursor_position.y += 1; }
+    }
+
+    // Moves the current line `count` lines down (or up), the cursor stays on it. Stops at
+    // the top or bottom of the file
+    pub(crate) fn move_line(&mut self, down: bool, count: usize) {
+        self.document.snapshot(self.cursor_position);
+        let mut moved = 0;
+        while moved < count && let Some(y) = self.document.move_line(self.cursor_position.y, down) {
+            self.cursor_position.y = y;
+            moved += 1;
         }
-        // Move offset down if cursor goes below visible screen
-        else if self.cursor_position.y >= self.row_offset + visible_height {
-            self.row_offset = self.cursor_position.y.saturating_sub(visible_height).saturating_add(1);
+        if moved < count {
+            self.motion_failed = true;
+            self.status_message = StatusMessage::from(format!("Can't move the line further {}", if down { "down" } else { "up" }));
         }
+        self.cursor_position.x = std::cmp::min(self.cursor_position.x, self.line_length(self.cursor_position.y));
+        self.scroll();
     }
 
-
     // Simplifying cursor movement, takes in key code and translates to movement
     fn move_cursor(&mut self, key: KeyCode) {
         let y = self.cursor_position.y;
         let x = self.cursor_position.x;
         let current_len = self.line_length(y);
+        // Up and down work in screen columns, so tabs don't throw off the wrap math
+        let col = self.display_col(y, x);
+        let current_width = self.display_col(y, current_len);
 
         let term_width = self.terminal.size().width as usize;
         let gutter = self.gutter_width();
@@ -355,58 +2407,54 @@ impl Editor {
         // Prevent divide by zero if it somehow gets to that
         let safe_wrap = std::cmp::max(1, text_width.saturating_sub(p_len));
 
-        // Figure out how many visual lines the current logical line takes up
-        let v_total = if current_len <= text_width { 1 } else { 2 + (current_len.saturating_sub(text_width)) / safe_wrap };
+        // Figure out how many visual lines the current logical line takes up. A window too
+        // narrow to show any text (it only has the too-small notice) has no wraps to step
+        // through, up and down go by whole lines
+        let wraps = text_width > p_len;
+        let v_total = if current_width <= text_width || !wraps { 1 } else { 2 + (current_width.saturating_sub(text_width)) / safe_wrap };
         // Figure out while visual line the cursor is on
-        let v_current = if x < text_width { 0 } else { 1 + (x - text_width) / safe_wrap };
+        let v_current = if col < text_width || !wraps { 0 } else { 1 + (col - text_width) / safe_wrap };
 
         // Calc current visual screen column
-        let screen_col = if x < text_width { x } else { p_len + (x - text_width) % safe_wrap };
+        let screen_col = if col < text_width || !wraps { col } else { p_len + (col - text_width) % safe_wrap };
+        // Up and down aim for the screen column the cursor had before it met a shorter
+        // line, as long as it hasn't moved any other way since
+        let desired = match self.desired_x {
+            Some((desired, at)) if at == self.cursor_position => desired,
+            _ => screen_col,
+        };
+        // Display column of screen column `d` on visual row `row` of a line, never past
+        // that row (the end of line stickiness asks for the far right)
+        let col_at = |row: usize, d: usize| match row {
+            0 if !wraps => d,
+            0 => std::cmp::min(d, text_width.saturating_sub(1)),
+            _ => text_width + (row - 1) * safe_wrap + std::cmp::min(d.saturating_sub(p_len), safe_wrap - 1),
+        };
 
         match key {
-            KeyCode::Up | KeyCode::Char('w') => {
+            KeyCode::Up => {
                 if v_current > 0 {
                     // Move up to the previous visual line in the same logical line
-                    if v_current == 1 {
-                        self.cursor_position.x = p_len + (x - text_width);
-                    } else {
-                        self.cursor_position.x = x.saturating_sub(safe_wrap);
-                    }
+                    self.cursor_position.x = self.char_at_col(y, col_at(v_current - 1, desired));
                 } else if y > 0 {
                     // Move up to the previous logical line
                     self.cursor_position.y -= 1;
-                    let l_prev = self.line_length(self.cursor_position.y);
-                    let v_total_prev = if l_prev <= text_width { 1 } else { 2 + (l_prev.saturating_sub(text_width + 1)) / safe_wrap};
-
-                    if v_total_prev == 1 {
-                        self.cursor_position.x = std::cmp::min(screen_col, l_prev);
-                    } else {
-                        let last_line_start = text_width + (v_total_prev - 2) * safe_wrap;
-                        let target_x = if screen_col < p_len {
-                            last_line_start
-                        } else {
-                            last_line_start + (screen_col - p_len)
-                        };
-                        self.cursor_position.x = std::cmp::min(target_x, l_prev);
-                    }
+                    let l_prev = self.display_col(self.cursor_position.y, self.line_length(self.cursor_position.y));
+                    let v_total_prev = if l_prev <= text_width || !wraps { 1 } else { 2 + (l_prev.saturating_sub(text_width + 1)) / safe_wrap};
+                    self.cursor_position.x = self.char_at_col(self.cursor_position.y, col_at(v_total_prev - 1, desired));
                 }
             }
-            KeyCode::Down | KeyCode::Char('s') => {
+            KeyCode::Down => {
                 if v_current + 1 < v_total {
                     // Move down to the next visual line in the same logical line
-                    let target_x = if v_current == 0 {
-                        if x < p_len { text_width } else { text_width + (x - p_len) }
-                    } else {
-                        x + safe_wrap
-                    };
-                    self.cursor_position.x = std::cmp::min(target_x, current_len);
+                    self.cursor_position.x = self.char_at_col(y, col_at(v_current + 1, desired));
                 } else if y < self.document.len().saturating_sub(1) {
                     // Move down to next logical line
                     self.cursor_position.y += 1;
-                    self.cursor_position.x = std::cmp::min(screen_col, self.line_length(self.cursor_position.y));
+                    self.cursor_position.x = self.char_at_col(self.cursor_position.y, desired);
                 }
             }
-            KeyCode::Left | KeyCode::Char('a') => {
+            KeyCode::Left => {
                 if x > 0 {
                     self.cursor_position.x -= 1;
                 } else if y > 0 {
@@ -415,7 +2463,7 @@ impl Editor {
                     self.cursor_position.x = self.line_length(self.cursor_position.y);
                 }
             },
-            KeyCode::Right | KeyCode::Char('d') => {
+            KeyCode::Right => {
                 if x < current_len {
                     self.cursor_position.x += 1;
                 } else if y < self.document.len().saturating_sub(1) {
@@ -424,6 +2472,11 @@ impl Editor {
                     self.cursor_position.x = 0;
                 }
             }
+            // Home again from column 0 goes on to the first non-blank
+            KeyCode::Home if x == 0 => self.cursor_position.x = self.first_non_blank().x,
+            KeyCode::Home => self.cursor_position.x = 0,
+            KeyCode::End => self.cursor_position.x = current_len,
+            KeyCode::PageUp | KeyCode::PageDown => self.page(key == KeyCode::PageDown, self.text_area_height()),
             _ => (),
         }
 
@@ -433,22 +2486,359 @@ impl Editor {
         if self.cursor_position.x > new_len {
             self.cursor_position.x = new_len;
         }
+
+        if matches!(key, KeyCode::Up | KeyCode::Down | KeyCode::Char('w' | 's')) {
+            // Sticking to the end of the line lands on the last character, as $ does
+            if desired == usize::MAX && self.mode != Mode::Insert && self.cursor_position.x == new_len {
+                self.cursor_position.x = new_len.saturating_sub(1);
+            }
+            self.desired_x = Some((desired, self.cursor_position));
+        }
+    }
+
+    // Runs a motion `count` times, stopping early once it stops moving (at the edge of the
+    // document), so huge counts clamp instead of spinning
+    fn repeat_motion(&mut self, count: Option<usize>, motion: impl Fn(&mut Self)) {
+        for i in 0..count.unwrap_or(1) {
+            let before = (self.cursor_position.x, self.cursor_position.y);
+            motion(self);
+            if (self.cursor_position.x, self.cursor_position.y) == before {
+                if i == 0 { self.motion_failed = true; }
+                break;
+            }
+        }
+    }
+
+    // Where a motion would move the cursor, leaving the cursor where it is
+    fn motion_target(&mut self, motion: impl FnOnce(&mut Self)) -> Position {
+        let from = self.cursor_position;
+        motion(self);
+        std::mem::replace(&mut self.cursor_position, from)
+    }
+
+    // G / gg: line `count` when there is one, otherwise the last / first line
+    fn line_target(&self, count: Option<usize>, last: bool) -> usize {
+        // Not counting the empty line after a trailing newline
+        let mut lines = self.document.len().max(1);
+        if lines > 1 && self.document.is_last_line(lines - 2) { lines -= 1; }
+        match count {
+            Some(n) => std::cmp::min(n, lines).saturating_sub(1),
+            None if last => lines - 1,
+            None => 0,
+        }
+    }
+
+    // Line motions (0, $, ^), returned rather than applied so operators can use them as targets
+    pub(crate) fn line_start(&self) -> Position {
+        Position { x: 0, y: self.cursor_position.y }
+    }
+
+    // The last character, or column 0 on an empty line
+    pub(crate) fn line_end(&self) -> Position {
+        let y = self.cursor_position.y;
+        Position { x: self.line_length(y).saturating_sub(1), y }
+    }
+
+    // Past leading spaces and tabs, the end of the line if that's all there is
+    pub(crate) fn first_non_blank(&self) -> Position {
+        let y = self.cursor_position.y;
+        let len = self.line_length(y);
+        if y >= self.document.len() { return Position { x: 0, y }; }
+        let line = self.document.rope.line(y);
+        let x = (0..len).find(|i| !matches!(line.char(*i), ' ' | '\t')).unwrap_or(len.saturating_sub(1));
+        Position { x, y }
+    }
+
+
+
+    // Moves to the end of the next word, crossing lines
+    fn word_forward(&mut self) {
+        let rope = &self.document.rope;
+        let mut idx = rope.line_to_char(self.cursor_position.y) + self.cursor_position.x;
+        while idx < rope.len_chars() && !is_word_char(rope.char(idx)) { idx += 1; }
+        while idx < rope.len_chars() && is_word_char(rope.char(idx)) { idx += 1; }
+        self.set_cursor_index(idx);
+    }
+
+    // Moves to the start of the next word, crossing lines (vim's w)
+    fn next_word_start(&mut self) {
+        let rope = &self.document.rope;
+        let mut idx = rope.line_to_char(self.cursor_position.y) + self.cursor_position.x;
+        while idx < rope.len_chars() && is_word_char(rope.char(idx)) { idx += 1; }
+        while idx < rope.len_chars() && !is_word_char(rope.char(idx)) { idx += 1; }
+        self.set_cursor_index(idx);
+    }
+
+    // Moves to the start of the previous word, crossing lines
+    fn word_back(&mut self) {
+        let rope = &self.document.rope;
+        let mut idx = rope.line_to_char(self.cursor_position.y) + self.cursor_position.x;
+        while idx > 0 && !is_word_char(rope.char(idx - 1)) { idx -= 1; }
+        while idx > 0 && is_word_char(rope.char(idx - 1)) { idx -= 1; }
+        self.set_cursor_index(idx);
+    }
+
+    // Places the cursor at a char index into the rope
+    fn set_cursor_index(&mut self, idx: usize) {
+        let idx = std::cmp::min(idx, self.document.rope.len_chars());
+        let y = self.document.rope.char_to_line(idx);
+        // The index past a trailing newline belongs to a line the cursor can't sit on
+        self.cursor_position.y = std::cmp::min(y, self.document.len().saturating_sub(1));
+        let x = idx - self.document.rope.line_to_char(self.cursor_position.y);
+        self.cursor_position.x = std::cmp::min(x, self.line_length(self.cursor_position.y));
+    }
+
+    // Saves the document, asking for a name first if it doesn't have one
+    pub(crate) fn save(&mut self) -> Result<(), std::io::Error> {
+        if self.document.filename.is_none() {
+            let new_name = self.prompt("Save as: ")?;
+            let overwrite_ok = match &new_name {
+                Some(name) if std::path::Path::new(name).exists() => {
+                    let message = format!("{} already exists, overwrite?", name);
+                    self.confirm(&message, &[('y', "yes"), ('n', "no")])? == Some('y')
+                }
+                Some(_) => true,
+                None => false,
+            };
+            match new_name {
+                Some(name) if overwrite_ok => {
+                    self.locations.name_unnamed(&name);
+                    self.document.filename = Some(name);
+                }
+                _ => {
+                    self.status_message = StatusMessage::from("Save aborted.".to_string());
+                    return Ok(());
+                }
+            }
+        }
+
+        self.write_file();
+        Ok(())
+    }
+
+    // Writes to the current file name, reporting what was written or why it failed
+    pub(crate) fn write_file(&mut self) {
+        let name = self.document.filename.clone().unwrap_or_default();
+        // The old version is copied aside first, a failed backup doesn't stop the save
+        let backup = if self.document.read_only { Ok(()) } else { crate::backup::rotate(&name, self.options.backups) };
+        self.status_message = StatusMessage::from(match (self.document.save(), backup) {
+            (Ok(report), Ok(())) => report.summary(&name),
+            (Ok(report), Err(e)) => format!("{} (backup failed: {})", report.summary(&name), e),
+            (Err(e), _) => format!("Error writing {}: {}", name, e),
+        });
+    }
+
+    // Asks a one-key question in the message bar, returns the chosen key or None on Esc
+    // Keys that aren't one of the choices are ignored
+    pub(crate) fn confirm(&mut self, message: &str, choices: &[(char, &str)]) -> Result<Option<char>, std::io::Error> {
+        Ok(self.nested(Context::Confirm, |editor| editor.confirm_loop(message, choices))?.flatten())
+    }
+
+    fn confirm_loop(&mut self, message: &str, choices: &[(char, &str)]) -> Result<Option<char>, std::io::Error> {
+        self.status_message = StatusMessage {
+            choices: choices.iter().map(|(key, label)| (*key, label.to_string())).collect(),
+            ..StatusMessage::input(message.to_string())
+        };
+
+        // Whatever was typed before the question appeared isn't an answer to it, nor is it
+        // run as commands afterwards. A playing macro still answers from its own keys
+        self.queue_pending_input()?;
+        self.input_queue.clear();
+        let answer = loop {
+            crate::ui::refresh_screen(self)?;
+            match self.read_key()? {
+                KeyEvent { code: KeyCode::Esc, .. } => break None,
+                KeyEvent { code: KeyCode::Char(c), .. } if choices.iter().any(|(key, _)| *key == c) => break Some(c),
+                _ => (),
+            }
+        };
+        self.status_message = StatusMessage::from(String::new());
+        Ok(answer)
+    }
+
+    // Shows a list over the document and blocks until one is picked (Enter) or Esc
+    // Returns the index of the chosen item
+    pub(crate) fn pick(&mut self, title: &str, items: Vec<String>) -> Result<Option<usize>, std::io::Error> {
+        if items.is_empty() { return Ok(None); }
+        Ok(self.nested(Context::Pick, |editor| editor.pick_loop(title, items))?.flatten())
+    }
+
+    fn pick_loop(&mut self, title: &str, items: Vec<String>) -> Result<Option<usize>, std::io::Error> {
+        let page = self.text_area_height().saturating_sub(1).max(1);
+        self.picker = Some(Picker::new(title, items));
+        self.status_message = StatusMessage::input("Type to filter, Up/Down to move, Enter to choose, Esc to close".to_string());
+
+        let choice = loop {
+            crate::ui::refresh_screen(self)?;
+            let key = match self.read_text_input()? {
+                Input::Key(key) => key,
+                Input::Paste(text) => {
+                    if let Some(picker) = self.picker.as_mut() {
+                        picker.filter.push_str(&text);
+                        picker.refilter();
+                    }
+                    continue;
+                }
+            };
+            let Some(picker) = self.picker.as_mut() else { break None; };
+            let last = picker.matches.len().saturating_sub(1);
+            match key.code {
+                KeyCode::Up => picker.selected = picker.selected.saturating_sub(1),
+                KeyCode::Down => picker.selected = std::cmp::min(picker.selected + 1, last),
+                KeyCode::PageUp => picker.selected = picker.selected.saturating_sub(page),
+                KeyCode::PageDown => picker.selected = std::cmp::min(picker.selected + page, last),
+                KeyCode::Enter if !picker.matches.is_empty() => break Some(picker.matches[picker.selected]),
+                KeyCode::Esc => break None,
+                KeyCode::Backspace => {
+                    picker.filter.pop();
+                    picker.refilter();
+                }
+                KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    picker.filter.push(c);
+                    picker.refilter();
+                }
+                _ => (),
+            }
+        };
+        self.picker = None;
+        self.status_message = StatusMessage::from(String::new());
+        Ok(choice)
+    }
+
+    // Restores a saved view, clamping to the document if it has shrunk since
+    pub(crate) fn load_view(&mut self, index: usize) {
+        let Some(view) = self.document.views.get(index) else { return; };
+        let (name, cursor, row_offset) = (view.name.clone(), view.cursor, view.row_offset);
+        self.document.last_view = Some(index);
+
+        let last = self.document.len().saturating_sub(1);
+        self.row_offset = std::cmp::min(row_offset, last);
+        self.goto(cursor.y + 1, Some(cursor.x + 1));
+        if cursor.y > last {
+            self.status_message = StatusMessage::from(format!("View {}: line {} no longer exists, moved to {}", name, cursor.y + 1, last + 1));
+        } else {
+            self.status_message = StatusMessage::from(format!("View {} ({}/{})", name, index + 1, self.document.views.len()));
+        }
+    }
+
+    // Compiles the query and jumps to its `count`th match
+    pub(crate) fn start_search(&mut self, query: Query, count: usize) {
+        match Search::new(query) {
+            Ok(search) => {
+                self.search = Some(search);
+                self.highlight_search = true;
+                self.search_next(count, false);
+            }
+            Err(msg) => {
+                self.status_message = StatusMessage::from(msg);
+                self.motion_failed = true;
+            }
+        }
+    }
+
+    // Moves to the `count`th match of the last search, `reverse` flips its direction
+    pub(crate) fn search_next(&mut self, count: usize, reverse: bool) {
+        let Some(search) = &self.search else { return; };
+        let Position { x, y } = self.cursor_position;
+        match search.find(&self.document, x, y, count, reverse) {
+            Some((found, index, total)) => {
+                // Went past the end of the document (the start going backward) and came round
+                let backward = search.query.backward != reverse;
+                let wrapped = if backward { (found.y, found.start) >= (y, x) } else { (found.y, found.start) <= (y, x) };
+                let wrapped = if wrapped { " (search wrapped)" } else { "" };
+                self.marks.insert('\'', self.cursor_position);
+                self.cursor_position = Position { x: found.start, y: found.y };
+                self.status_message = StatusMessage::from(format!("Found: {} [{}/{}]{}", search.query.pattern, index, total, wrapped));
+            }
+            None => {
+                self.status_message = StatusMessage::from(format!("Not found: {}", search.query.pattern));
+                self.motion_failed = true;
+            }
+        }
     }
 
+    // The / and ? prompt. With incsearch the cursor jumps to the nearest match and every match
+    // lights up while the pattern is typed, Enter keeps the match and Esc puts the cursor, the
+    // view and the last search back as they were
+    fn search_prompt(&mut self, backward: bool) -> Result<(), std::io::Error> {
+        let query = |pattern: &str| {
+            let mut query = Query::new(pattern);
+            query.backward = backward;
+            query.regex = true;
+            query
+        };
+        let (origin, row_offset, highlight) = (self.cursor_position, self.row_offset, self.highlight_search);
+        let previous = self.search.take();
+        let prompt = if backward { "?" } else { "/" };
+
+        let mut history = std::mem::take(&mut self.search_history);
+        let pattern = if self.options.incsearch {
+            self.prompt_with(prompt, Some(&mut history), &mut |editor, pattern| {
+                editor.cursor_position = origin;
+                editor.row_offset = row_offset;
+                // A half-typed regex ("(a") just shows no matches until it's complete
+                editor.search = Search::new(query(pattern)).ok();
+                editor.highlight_search = editor.search.is_some();
+                if let Some(found) = editor.search.as_ref().and_then(|search| search.nearest(&editor.document, origin.x, origin.y, backward)) {
+                    editor.cursor_position = Position { x: found.start, y: found.y };
+                }
+            })
+        } else {
+            self.prompt_with(prompt, Some(&mut history), &mut |_, _| ())
+        };
+        if let Ok(Some(pattern)) = &pattern { history.push(pattern); }
+        self.search_history = history;
+        self.cursor_position = origin;
+        self.row_offset = row_offset;
 
+        match pattern? {
+            Some(pattern) => self.start_search(query(&pattern), 1),
+            None => {
+                self.search = previous;
+                self.highlight_search = highlight;
+            }
+        }
+        Ok(())
+    }
 
     // "Save As" implementation (roughly)
-    fn prompt(&mut self, prompt: &str) -> Result<Option<String>, std::io::Error> {
+    pub(crate) fn prompt(&mut self, prompt: &str) -> Result<Option<String>, std::io::Error> {
+        self.prompt_with(prompt, None, &mut |_, _| ())
+    }
+
+    // A prompt that calls `on_change` with the text after every keystroke that changes it,
+    // Up/Down step through `history` if there is one
+    pub(crate) fn prompt_with(&mut self, prompt: &str, history: Option<&mut History>, on_change: &mut dyn FnMut(&mut Self, &str)) -> Result<Option<String>, std::io::Error> {
+        Ok(self.nested(Context::Prompt, |editor| editor.prompt_loop(prompt, history, on_change))?.flatten())
+    }
+
+    fn prompt_loop(&mut self, prompt: &str, mut history: Option<&mut History>, on_change: &mut dyn FnMut(&mut Self, &str)) -> Result<Option<String>, std::io::Error> {
         let mut result = String::new();
+        if let Some(history) = history.as_mut() { history.reset(); }
 
         loop {
-            self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
+            self.status_message = StatusMessage::input(format!("{}{}", prompt, result));
             crate::ui::refresh_screen(self)?;
 
-            match Terminal::read_key()? {
+            let key = match self.read_text_input()? {
+                Input::Key(key) => key,
+                Input::Paste(text) => {
+                    result.push_str(&text);
+                    on_change(self, &result);
+                    continue;
+                }
+            };
+            match key {
                 KeyEvent {code: KeyCode::Backspace, .. } => {
-                    if !result.is_empty() {
-                        result.pop();
+                    result.pop();
+                    on_change(self, &result);
+                }
+                KeyEvent { code: code @ (KeyCode::Up | KeyCode::Down), .. } => {
+                    let Some(history) = history.as_mut() else { continue; };
+                    let entry = if code == KeyCode::Up { history.older(&result) } else { history.newer() };
+                    if let Some(entry) = entry {
+                        result = entry;
+                        on_change(self, &result);
                     }
                 }
                 KeyEvent { code: KeyCode::Enter, .. } => {
@@ -458,10 +2848,9 @@ impl Editor {
                     self.status_message = StatusMessage::from(String::new());
                     return Ok(Some(result));
                 }
-                KeyEvent { code: KeyCode::Char(c), .. } => {
-                    if !c.is_control() {
-                        result.push(c);
-                    }
+                KeyEvent { code: KeyCode::Char(c), .. } if !c.is_control() => {
+                    result.push(c);
+                    on_change(self, &result);
                 }
                 KeyEvent { code: KeyCode::Esc, .. } => {
                     self.status_message = StatusMessage::from(String::new());
@@ -494,4 +2883,714 @@ impl Editor {
         self.terminal.clear_screen();
         panic!("{}", e);
     }
-}
\ No newline at end of file
+}
+
+// Whether a finished normal mode key sequence changed the text (and so `.` should repeat it)
+// Counts are skipped to find the command key
+fn is_repeatable(keys: &[KeyEvent]) -> bool {
+    // "a picks a register and can sit on either side of the count
+    let mut commands = keys.iter().copied().skip_while(is_count_key).peekable();
+    if commands.peek().is_some_and(|k| k.code == KeyCode::Char('"')) {
+        commands.nth(1);
+    }
+    let mut commands = commands.skip_while(is_count_key);
+    let first = commands.next().map(|k| (k.code, k.modifiers));
+    let second = commands.next().map(|k| k.code);
+    match first {
+        Some((KeyCode::Delete, _)) => true,
+        Some((KeyCode::Char('x' | 'J' | 'p' | 'P' | 'o' | 'O' | 'i' | 'R' | 'd' | 'c' | 'r' | '~' | 'D' | 'C'), KeyModifiers::NONE | KeyModifiers::SHIFT)) => true,
+        Some((KeyCode::Char(']'), _)) => second == Some(KeyCode::Char('p')),
+        Some((KeyCode::Char(c @ ('>' | '<')), _)) => second == Some(KeyCode::Char(c)),
+        _ => false,
+    }
+}
+
+fn is_count_key(key: &KeyEvent) -> bool {
+    matches!(key.code, KeyCode::Char('0'..='9')) && key.modifiers.is_empty()
+}
+
+// The keys that would type `text`, for the places that only deal in keys (macros, `.`)
+fn paste_keys(text: &str) -> Vec<KeyEvent> {
+    text.chars().map(|c| match c {
+        '\n' => KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        c => KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+    }).collect()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Whether the end of `text` is inside an unclosed *emphasis* or **bold** span. A run of
+// asterisks opens a span when text follows it, and closes the open one of the same length
+// when it follows text
+fn in_emphasis(text: &[char]) -> bool {
+    let mut open = None;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != '*' { i += 1; continue; }
+        let start = i;
+        while i < text.len() && text[i] == '*' { i += 1; }
+        let run = i - start;
+        let after_text = text.get(i).is_none_or(|c| !c.is_whitespace());
+        let before_text = start > 0 && !text[start - 1].is_whitespace();
+        if open == Some(run) && before_text {
+            open = None;
+        } else if open.is_none() && after_text {
+            open = Some(run);
+        }
+    }
+    open.is_some()
+}
+
+// Splits "path:line[:col]" into the path and location
+// A path that exists as typed always wins, so files with colons in the name still open
+pub(crate) fn split_location(arg: &str) -> (String, Option<(usize, Option<usize>)>) {
+    if std::path::Path::new(arg).exists() {
+        return (arg.to_string(), None);
+    }
+
+    let mut parts = arg.rsplitn(3, ':');
+    let last = parts.next().and_then(|p| p.parse::<usize>().ok());
+    let middle = parts.next();
+    let rest = parts.next();
+
+    match (rest, middle.and_then(|p| p.parse::<usize>().ok()), last) {
+        (Some(path), Some(line), Some(col)) if !path.is_empty() => (path.to_string(), Some((line, Some(col)))),
+        (_, _, Some(line)) => match arg.rsplit_once(':') {
+            Some((path, _)) if !path.is_empty() => (path.to_string(), Some((line, None))),
+            _ => (arg.to_string(), None),
+        },
+        _ => (arg.to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::capture::{self, Screen};
+
+    fn keys(typed: &str) -> Vec<KeyEvent> {
+        typed.chars().map(|c| KeyEvent::from(KeyCode::Char(c))).collect()
+    }
+
+    // An editor on `text` in a width x height terminal, with what it draws readable from
+    // the Screen
+    fn editor_sized(text: &str, width: u16, height: u16) -> (Editor, Screen) {
+        let screen = Screen::default();
+        let terminal = Terminal::to_writer(Box::new(screen.clone()), width, height, false);
+        (Editor::for_test(terminal, Document::from_text(text)), screen)
+    }
+
+    fn editor(text: &str) -> (Editor, Screen) {
+        editor_sized(text, 80, 24)
+    }
+
+    fn text(editor: &Editor) -> String {
+        editor.document.rope.to_string()
+    }
+
+    const CHOICES: [(char, &str); 2] = [('y', "yes"), ('n', "no")];
+
+    #[test]
+    fn confirm_takes_a_listed_key() {
+        let (mut editor, screen) = editor("text");
+        editor.terminal.script(capture::keys("n"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), Some('n'));
+        // The question was on screen while it waited, and is gone after
+        assert!(screen.take().contains("Really?"));
+        assert!(editor.status_message.text.is_empty());
+        assert!(editor.contexts.is_empty());
+    }
+
+    #[test]
+    fn confirm_ignores_unrecognized_keys() {
+        let (mut editor, _) = editor("text");
+        editor.terminal.script(capture::keys("xq<CR><Up>Y y"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), Some('y'));
+        // Nothing but the answer was taken
+        assert!(editor.terminal.read_input().is_err());
+        assert_eq!(text(&editor), "text");
+    }
+
+    #[test]
+    fn confirm_ignores_keys_typed_before_it() {
+        let (mut editor, _) = editor("text");
+        editor.terminal.script(capture::typed("y"));
+        editor.terminal.script(capture::keys("n"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), Some('n'));
+        assert!(editor.input_queue.is_empty());
+    }
+
+    #[test]
+    fn confirm_esc_is_no_answer() {
+        let (mut editor, _) = editor("text");
+        editor.terminal.script(capture::keys("<Esc>y"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), None);
+        assert!(editor.status_message.text.is_empty());
+    }
+
+    #[test]
+    fn confirm_redraws_on_resize() {
+        let (mut editor, screen) = editor("text");
+        editor.terminal.script([capture::Scripted::Resize(40, 10)]);
+        editor.terminal.script(capture::keys("y"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), Some('y'));
+        assert_eq!(editor.terminal.size().width, 40);
+        // Drawn once before the resize and again after it
+        assert_eq!(screen.take().matches("Really?").count(), 2);
+    }
+
+    #[test]
+    fn confirm_answers_from_a_playing_macro() {
+        let (mut editor, _) = editor("text");
+        editor.playback.extend(keys("zn"));
+        assert_eq!(editor.confirm("Really?", &CHOICES).unwrap(), Some('n'));
+        assert!(editor.playback.is_empty());
+    }
+
+    // Each question of :s///c is one confirm, a stray key in between doesn't count
+    #[test]
+    fn substitute_asks_through_confirm() {
+        let (mut editor, screen) = editor("a a a a\na a");
+        editor.terminal.script(capture::keys(":%s/a/b/gc<CR>yxna"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "b a b b\nb b");
+        assert!(screen.take().contains("Replace with 'b'?"));
+        assert!(editor.status_message.text.starts_with("Replaced 'a' 5 times on 2 lines"));
+        assert!(editor.mode == Mode::Normal);
+    }
+
+    #[test]
+    fn confirm_when_the_script_runs_out() {
+        let (mut editor, _) = editor("text");
+        assert!(editor.confirm("Really?", &CHOICES).is_err());
+        // Leaving by error still closes the question's context
+        assert!(editor.contexts.is_empty());
+    }
+
+    #[test]
+    fn repeatable_commands() {
+        for typed in ["x", "dd", "3dd", "\"add", "\"a3dd", "3\"add", "\"ap", ">>", "]p", "cwnew"] {
+            assert!(is_repeatable(&keys(typed)), "{}", typed);
+        }
+        for typed in ["j", "\"a", "\"ayy", "3j", "yy", "><", "]x"] {
+            assert!(!is_repeatable(&keys(typed)), "{}", typed);
+        }
+    }
+
+    // Types in Insert Mode key by key, the way process_keypress hands them over
+    fn insert(editor: &mut Editor, typed: &str) {
+        editor.mode = Mode::Insert;
+        for key in capture::key_events(typed) {
+            editor.process_insert_mode(key).unwrap();
+        }
+    }
+
+    fn wrapping(text: &str, at: Position) -> Editor {
+        let (mut editor, _) = editor(text);
+        editor.options.textwidth = 20;
+        editor.cursor_position = at;
+        editor
+    }
+
+    #[test]
+    fn auto_wrap_breaks_at_textwidth() {
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, "the quick brown fox jumps over the lazy dog");
+        assert_eq!(text(&editor), "the quick brown fox\njumps over the lazy\ndog");
+        assert!(editor.cursor_position == Position { x: 3, y: 2 });
+
+        // Spaces alone never break, the next word does
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, "the quick brown fox     ");
+        assert_eq!(text(&editor), "the quick brown fox     ");
+    }
+
+    #[test]
+    fn auto_wrap_hangs_under_the_prefix() {
+        for (typed, wrapped) in [
+            ("- item one two three four five", "- item one two three\n  four five"),
+            ("12. item one two three four", "12. item one two\n    three four"),
+            ("> quoted one two three four", "> quoted one two\n> three four"),
+            ("> - both one two three four", "> - both one two\n>   three four"),
+        ] {
+            let mut editor = wrapping("", Position { x: 0, y: 0 });
+            insert(&mut editor, typed);
+            assert_eq!(text(&editor), wrapped, "{}", typed);
+        }
+    }
+
+    #[test]
+    fn auto_wrap_leaves_fences_alone() {
+        let mut editor = wrapping("```\n\n```", Position { x: 0, y: 1 });
+        insert(&mut editor, "let words = one two three four five;");
+        assert_eq!(text(&editor), "```\nlet words = one two three four five;\n```");
+        assert!(editor.auto_break.is_none());
+    }
+
+    #[test]
+    fn auto_wrap_keeps_a_long_url_whole() {
+        let url = "https://example.com/a/very/long/path/to/something";
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, &format!("see {} ok", url));
+        assert_eq!(text(&editor), format!("see\n{} ok", url));
+
+        // With nowhere to break it stays as long as it is
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, url);
+        assert_eq!(text(&editor), url);
+    }
+
+    #[test]
+    fn backspace_over_an_auto_wrap_joins_the_lines() {
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, "the quick brown fox j");
+        assert_eq!(text(&editor), "the quick brown fox\nj");
+        insert(&mut editor, "<BS>");
+        assert!(editor.auto_break == Some(editor.cursor_position));
+        insert(&mut editor, "<BS>");
+        assert_eq!(text(&editor), "the quick brown fox ");
+        assert!(editor.cursor_position == Position { x: 20, y: 0 });
+        assert!(editor.auto_break.is_none());
+
+        // Backspacing the whole moved word brings it back to the break too
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, "the quick brown fox jumps<BS><BS><BS><BS><BS><BS>");
+        assert_eq!(text(&editor), "the quick brown fox ");
+
+        // Once the cursor has moved off, Backspace is an ordinary one again
+        let mut editor = wrapping("", Position { x: 0, y: 0 });
+        insert(&mut editor, "the quick brown fox jumps<Left><Right><BS><BS><BS><BS><BS><BS>");
+        assert_eq!(text(&editor), "the quick brown fox");
+    }
+
+    // The char the cursor should be drawn on, a blank past the end of the line
+    fn char_at_cursor(editor: &Editor) -> String {
+        let Position { x, y } = editor.cursor_position;
+        let line = editor.document.line_text(y);
+        line.chars().nth(x).filter(|c| *c != '\n').unwrap_or(' ').to_string()
+    }
+
+    // Runs what's scripted and replays the frames onto a screen of the terminal's size
+    fn frame(editor: &mut Editor, screen: &Screen) -> capture::Grid {
+        editor.run_script().unwrap();
+        let size = editor.terminal.size();
+        let mut grid = capture::Grid::new(size.width, size.height);
+        grid.feed(&screen.take());
+        grid
+    }
+
+    #[test]
+    fn resizing_keeps_the_cursor_on_its_char() {
+        let lines: Vec<String> = (0..80).map(|y| match y % 7 {
+            // Wraps, but still fits the smallest window: the view can't start partway into a line
+            3 => format!("{} {}", y, "a long line that wraps on narrow windows, ".repeat(2).trim_end()),
+            5 => String::new(),
+            _ => format!("line {} has a few words in it", y),
+        }).collect();
+        let (mut editor, screen) = editor(&lines.join("\n"));
+        let sizes = [(30, 8), (120, 40), (24, 9), (45, 12), (80, 24), (25, 30)];
+        let moves = ["j", "3w", "10j", "$", "k", "G", "gg", "20j", "^", "<C-d>", "zt", "/narrow<CR>", "$", "zz", "12k", "b", "<C-e>", "40G", "<C-u>"];
+        for (i, keys) in moves.iter().enumerate() {
+            let (width, height) = sizes[i % sizes.len()];
+            editor.terminal.script([capture::Scripted::Resize(width, height)]);
+            let grid = frame(&mut editor, &screen);
+            let shown: Vec<String> = (0..grid.height()).map(|y| grid.row(y)).collect();
+            assert_eq!(grid.under_cursor(), char_at_cursor(&editor), "after resizing to {}x{} before {}, {:?} {:?}", width, height, keys, grid.cursor, shown);
+            assert!(grid.cursor.1 < editor.text_area_height());
+
+            editor.terminal.script(capture::keys(keys));
+            let grid = frame(&mut editor, &screen);
+            let shown: Vec<String> = (0..grid.height()).map(|y| grid.row(y)).collect();
+            assert_eq!(grid.under_cursor(), char_at_cursor(&editor), "after {} at {}x{}, {:?} {:?}", keys, width, height, grid.cursor, shown);
+        }
+    }
+
+    // Alt+j from a slow link is Esc then j
+    #[test]
+    fn alt_key_leaves_insert_mode() {
+        let (mut editor, _) = editor("abc\ndef");
+        editor.terminal.script(capture::keys("ix<A-j>"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "xabc\ndef");
+        assert!(editor.mode == Mode::Normal);
+        assert_eq!(editor.cursor_position.y, 1);
+
+        // In Normal Mode the Esc does nothing and the key still runs
+        editor.terminal.script(capture::keys("0<A-x>"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "xabc\nef");
+    }
+
+    #[test]
+    fn emacs_alt_keys_stay_bound() {
+        let (mut editor, _) = editor("one two three");
+        editor.options.emacs_keys = true;
+        editor.terminal.script(capture::keys("i<End><A-b>X<A-b><A-b><A-f>Y"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "one twoY Xthree");
+        assert!(editor.mode == Mode::Insert);
+        // Other Alt keys still split
+        editor.terminal.script(capture::keys("<A-k>"));
+        editor.run_script().unwrap();
+        assert!(editor.mode == Mode::Normal);
+    }
+
+    #[test]
+    fn noaltesc_keeps_alt_keys_whole() {
+        let (mut editor, _) = editor("abc\ndef");
+        editor.options.alt_esc = false;
+        editor.terminal.script(capture::keys("i<A-j>"));
+        editor.run_script().unwrap();
+        assert!(editor.mode == Mode::Insert);
+        assert_eq!(editor.cursor_position.y, 0);
+
+        // And the command line never splits them
+        let (mut prompt, _) = editor_sized("abc", 80, 24);
+        prompt.terminal.script(capture::keys(":<A-j>"));
+        prompt.run_script().unwrap();
+        assert!(matches!(prompt.mode, Mode::Command(_)));
+    }
+
+    // Undoing a 200 line paste takes away the lines everything below points into. Each
+    // holder either moves up with its line or, if its line went away, lands where the
+    // paste was
+    #[test]
+    fn undoing_a_big_paste_moves_everything_along() {
+        use crate::terminal::capture::Scripted;
+        let lines: Vec<String> = (0..10).map(|y| format!("line {}", y)).collect();
+        let (mut editor, _) = editor(&lines.join("\n"));
+        let pasted: String = (0..200).map(|y| format!("p {}\n", y)).collect();
+        editor.terminal.script(capture::keys("3Gi"));
+        editor.terminal.script([Scripted::Pressed(Input::Paste(pasted))]);
+        editor.terminal.script(capture::keys(
+            "<Esc>152G3lma210G4lmb209G:view save end<CR>:lgrep ^(p 15|line 8)<CR>/p 19<CR>181Gv3j"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.len(), 210);
+        assert!(matches!(editor.mode, Mode::Visual { .. }));
+        assert_eq!(editor.locations.items.len(), 12);
+        // 181G jumped from the search match, in the paste
+        assert_eq!(editor.marks[&'\''].y, 21);
+
+        // The undo has to happen with the selection up, which no key allows
+        editor.document.undo(1);
+        editor.follow_edits();
+        assert_eq!(text(&editor), lines.join("\n"));
+        let last = editor.document.len() - 1;
+
+        assert!(editor.marks[&'a'] == Position { x: 0, y: 2 });
+        assert!(editor.marks[&'b'] == Position { x: 4, y: 9 });
+        assert!(editor.marks[&'\''] == Position { x: 0, y: 2 });
+        let view = &editor.document.views[0];
+        assert!(view.cursor.y == 8 && view.row_offset <= last);
+        let Mode::Visual { anchor } = editor.mode else { panic!("the selection is gone") };
+        assert!(anchor == Position { x: 0, y: 2 });
+        assert!(editor.cursor_position == editor.document.clamp(editor.cursor_position));
+        assert!(editor.row_offset <= last);
+        let found: Vec<usize> = editor.locations.items.iter().map(|item| item.line).collect();
+        assert_eq!(found, [2; 11].into_iter().chain([8]).collect::<Vec<_>>());
+
+        // Everything still works from where it landed: drawing, the search (its cached
+        // matches are for the old text), the marks, views and list
+        editor.terminal.script(capture::keys("<Esc>n`a`b''<C-6>:lnext<CR>:lnext<CR>Gn"));
+        editor.run_script().unwrap();
+        assert!(editor.cursor_position == editor.document.clamp(editor.cursor_position));
+    }
+
+    // The palette's pick opens s/, whose argument prompt leads to a confirm inside its own
+    // command. Esc at the question ends only the substitute, the palette is already done
+    #[test]
+    fn nested_prompts_unwind_one_at_a_time() {
+        let (mut editor, screen) = editor("a a\na");
+        editor.terminal.script(capture::keys("<C-p>s/<CR>a/b/gc<CR>y<Esc>"));
+        editor.run_script().unwrap();
+        let shown = screen.take();
+        assert!(shown.contains("Commands") && shown.contains("s/ old/new[/gc]: ") && shown.contains("Replace with 'b'?"));
+        assert_eq!(text(&editor), "b a\na");
+        assert!(editor.status_message.text.starts_with("Replaced 'a' 1 times"));
+        assert!(editor.contexts.is_empty());
+        assert!(editor.mode == Mode::Normal);
+
+        // Esc at the argument prompt closes it and the palette with it
+        editor.terminal.script(capture::keys("<C-p>s/<CR>a/c/g<Esc>"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "b a\na");
+        assert!(editor.contexts.is_empty());
+
+        // And the keys after all that are plain Normal Mode ones again
+        editor.terminal.script(capture::keys("x"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), " a\na");
+    }
+
+    // A macro holding a command that asks: the macro's own keys answer, Esc included
+    #[test]
+    fn macro_answers_its_command_questions() {
+        let (mut editor, _) = editor("a a a");
+        editor.terminal.script(capture::keys("qq:s/a/b/gc<CR>yn<Esc>qu"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "a a a");
+        assert!(editor.contexts.is_empty());
+
+        editor.terminal.script(capture::keys("@q"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "b a a");
+        assert!(editor.contexts.is_empty());
+        assert!(editor.playback.is_empty());
+        assert!(editor.mode == Mode::Normal);
+    }
+
+    // The palette picking itself over and over: each pick runs its command one level down,
+    // the pick after MAX_NESTING of them is one too many
+    #[test]
+    fn nesting_too_deep_is_refused() {
+        let (mut editor, screen) = editor("text");
+        editor.terminal.script(capture::keys(&format!("<C-p>{}", "palette<CR>".repeat(MAX_NESTING))));
+        editor.run_script().unwrap();
+        assert!(screen.take().contains("Too many nested commands, stopped"));
+        assert!(editor.picker.is_none());
+        editor.terminal.script(capture::keys("x"));
+        editor.run_script().unwrap();
+        assert!(editor.contexts.is_empty());
+        // Refused straight away, the x after it ran in Normal Mode
+        assert_eq!(text(&editor), "ext");
+        assert!(editor.mode == Mode::Normal);
+    }
+
+    #[test]
+    fn what_may_start_where() {
+        let (mut editor, _) = editor("");
+        for (inside, context, allowed) in [
+            (vec![], Context::Macro, true),
+            (vec![Context::Prompt], Context::Command, false),
+            (vec![Context::Prompt], Context::Macro, false),
+            (vec![Context::Confirm], Context::Repeat, false),
+            (vec![Context::Pick], Context::Confirm, true),
+            (vec![Context::Command, Context::Prompt], Context::Confirm, true),
+            (vec![Context::Macro, Context::Command], Context::Macro, false),
+            (vec![Context::Repeat], Context::Repeat, false),
+            (vec![Context::Macro], Context::Repeat, true),
+            (vec![Context::Command; MAX_NESTING - 1], Context::Confirm, true),
+            (vec![Context::Command; MAX_NESTING], Context::Confirm, false),
+        ] {
+            editor.contexts = inside.clone();
+            assert_eq!(editor.can_enter(context).is_ok(), allowed, "{:?} inside {:?}", context, inside);
+        }
+
+        // A refused one doesn't run, says why and stops a macro
+        editor.contexts = vec![Context::Prompt];
+        assert!(editor.nested(Context::Command, |_| -> Result<(), std::io::Error> { panic!("ran") }).unwrap().is_none());
+        assert_eq!(editor.status_message.text, "Finish or cancel the prompt first");
+        assert!(editor.motion_failed);
+        assert_eq!(editor.contexts, [Context::Prompt]);
+    }
+
+    // Keys typed ahead of a question were meant for whatever came before it, not as its
+    // answer: they're dropped and the question waits for a fresh key
+    #[test]
+    fn type_ahead_is_no_answer() {
+        let (mut editor, _) = editor("a a a");
+        editor.terminal.script(capture::keys(":s/a/b/gc<CR>"));
+        editor.terminal.script(capture::typed("yyyx"));
+        editor.terminal.script(capture::keys("n<Esc>"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "a a a");
+        assert!(editor.input_queue.is_empty());
+        assert!(editor.mode == Mode::Normal);
+    }
+
+    // Without a question in the way, keys typed ahead run in the order they were typed,
+    // all at once or while something slow was going on
+    #[test]
+    fn type_ahead_runs_in_order() {
+        let (mut editor, _) = editor("one two");
+        editor.terminal.script(capture::typed("wiX<Esc>0dw$iY"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), "XtwYo");
+
+        // A :s long enough to look for Ctrl+C queues what it finds, the keys run after it
+        let (mut editor, _) = editor_sized(&"a\n".repeat(3000), 80, 24);
+        editor.terminal.script(capture::keys(":%s/a/b/<CR>"));
+        editor.terminal.script(capture::typed("ggix<Esc>jdd"));
+        editor.run_script().unwrap();
+        let lines: Vec<String> = text(&editor).lines().map(String::from).collect();
+        assert_eq!(lines.len(), 2999);
+        assert_eq!(lines[0], "xb");
+        assert!(lines[1..].iter().all(|line| line == "b"));
+
+        // Ctrl+C among them stops the :s but not the others
+        let (mut editor, _) = editor_sized(&"a\n".repeat(3000), 80, 24);
+        editor.terminal.script(capture::keys(":%s/a/b/<CR>"));
+        editor.terminal.script(capture::typed("G<C-c>ix<Esc>"));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor).matches('b').count(), 1023);
+        assert!(text(&editor).ends_with("a\nxa\n"));
+    }
+
+    // A held-down key repeats faster than the screen redraws: held space or Enter is still
+    // one undo step, and a held u goes back one state per frame instead of through them all
+    #[test]
+    fn held_keys_take_bounded_undo_steps() {
+        let (mut editor, _) = editor("start\n");
+        let depth = editor.document.history_depth().0;
+        editor.terminal.script(capture::keys("$i"));
+        editor.terminal.script(capture::typed(&" ".repeat(200)));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor), format!("star{}t\n", " ".repeat(200)));
+        assert!(editor.document.history_depth().0 - depth <= 2, "{:?}", editor.document.history_depth());
+
+        let depth = editor.document.history_depth().0;
+        editor.terminal.script(capture::typed(&"<CR>".repeat(200)));
+        editor.run_script().unwrap();
+        assert_eq!(text(&editor).lines().count(), 201);
+        assert!(editor.document.history_depth().0 - depth <= 2, "{:?}", editor.document.history_depth());
+
+        // Thirty separate deletes, then u held down over them
+        let (mut editor, _) = editor_sized(&"x".repeat(40), 80, 24);
+        editor.terminal.script(capture::keys(&"x".repeat(30)));
+        editor.run_script().unwrap();
+        let (older, _) = editor.document.history_depth();
+        assert!(older >= 30);
+        editor.terminal.script(capture::typed(&"u".repeat(200)));
+        editor.terminal.script(capture::typed("j"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.history_depth(), (older - 1, 1));
+        assert_eq!(text(&editor), "x".repeat(11));
+
+        // Presses that each get a frame of their own are each a step
+        editor.terminal.script(capture::keys("uuu"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.history_depth(), (older - 4, 4));
+    }
+
+    // Typewriter mode keeps the cursor's row in the middle of the text area, as near as
+    // whole lines allow, until zz / zt / zb put it somewhere else
+    #[test]
+    fn typewriter_keeps_the_cursor_row_centered() {
+        let lines: Vec<String> = (0..100).map(|y| match y % 5 {
+            2 => format!("{} {}", y, "words that wrap across several rows ".repeat(3)),
+            _ => format!("line {}", y),
+        }).collect();
+        let (mut editor, screen) = editor_sized(&lines.join("\n"), 40, 14);
+        editor.options.typewriter = true;
+        let half = editor.text_area_height() / 2;
+        let text_width = 40 - editor.gutter_width();
+        // As close to `target` as whole lines allow: one more line above wouldn't fit
+        let near = |editor: &Editor, row: usize, target: usize| {
+            row <= target && (editor.row_offset == 0 || row + editor.visual_height(editor.row_offset - 1, text_width) > target)
+        };
+
+        for keys in ["j", "20j", "$", "5k", "3j", "$", "G", "gg", "j", "50G", "k"] {
+            editor.terminal.script(capture::keys(keys));
+            let grid = frame(&mut editor, &screen);
+            assert_eq!(grid.under_cursor(), char_at_cursor(&editor), "after {}", keys);
+            assert!(near(&editor, grid.cursor.1, half), "row {} after {}", grid.cursor.1, keys);
+        }
+
+        // zt puts the cursor line at the top, and that holds until the cursor moves
+        editor.terminal.script(capture::keys("30Gzt"));
+        assert_eq!(frame(&mut editor, &screen).cursor.1, 0);
+        editor.terminal.script(capture::keys("<Esc>:set typewriter<CR>"));
+        assert_eq!(frame(&mut editor, &screen).cursor.1, 0);
+        editor.terminal.script(capture::keys("zb"));
+        let row = frame(&mut editor, &screen).cursor.1;
+        assert!(row > half && near(&editor, row, editor.text_area_height() - 1));
+        editor.terminal.script(capture::keys("j"));
+        let row = frame(&mut editor, &screen).cursor.1;
+        assert!(near(&editor, row, half));
+    }
+
+    // :lnext through entries in two files: it opens the other one, the list survives the
+    // switch, and unsaved changes hold it up until they're written
+    #[test]
+    fn location_list_walks_across_files() {
+        let dir = std::env::temp_dir().join(format!("vellum-editor-{}-locations", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.md"), dir.join("b.md"));
+        std::fs::write(&a, "alpha\none\ntwo\n").unwrap();
+        std::fs::write(&b, "beta\nthree\nfour\n").unwrap();
+        let (a, b) = (a.to_str().unwrap().to_string(), b.to_str().unwrap().to_string());
+
+        let screen = Screen::default();
+        let terminal = Terminal::to_writer(Box::new(screen.clone()), 80, 24, false);
+        let mut editor = Editor::for_test(terminal, Document::open(&a).unwrap());
+        let entry = |file: &str, line: usize| crate::location::Location { file: Some(file.to_string()), line, col: 1, message: String::new() };
+        editor.locations.fill("test", vec![entry(&a, 1), entry(&b, 2), entry(&a, 0)]);
+
+        editor.terminal.script(capture::keys(":lnext<CR>"));
+        editor.run_script().unwrap();
+        assert!(editor.cursor_position == Position { x: 1, y: 1 });
+        editor.terminal.script(capture::keys(":lnext<CR>"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.filename.as_deref(), Some(b.as_str()));
+        assert!(editor.cursor_position == Position { x: 1, y: 2 });
+        assert_eq!(editor.locations.items.len(), 3);
+        assert_eq!(editor.locations.current, Some(1));
+
+        // A change to b has to be saved before going back to a
+        editor.terminal.script(capture::keys("x:lnext<CR>"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.filename.as_deref(), Some(b.as_str()));
+        assert!(editor.status_message.text.starts_with("(3/3) Unsaved changes"));
+        assert_eq!(editor.locations.current, Some(1));
+
+        // The message is longer than the window, the Esc puts it away
+        editor.terminal.script(capture::keys("<Esc>:w<CR>:lnext<CR>"));
+        editor.run_script().unwrap();
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "beta\nthree\nfur\n");
+        assert_eq!(editor.document.filename.as_deref(), Some(a.as_str()));
+        assert!(editor.cursor_position == Position { x: 1, y: 0 });
+        assert_eq!(editor.locations.current, Some(2));
+
+        // :lprev goes back the same way
+        editor.terminal.script(capture::keys(":lprev<CR>"));
+        editor.run_script().unwrap();
+        assert_eq!(editor.document.filename.as_deref(), Some(b.as_str()));
+        assert_eq!(editor.locations.items.len(), 3);
+        drop(editor);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn split_location_reads_tool_style_suffixes() {
+        for (arg, path, location) in [
+            ("a.md", "a.md", None),
+            ("a.md:12", "a.md", Some((12, None))),
+            ("a.md:12:3", "a.md", Some((12, Some(3)))),
+            ("dir/a.md:1:1", "dir/a.md", Some((1, Some(1)))),
+            (":12", ":12", None),
+            // Anything not a number stays part of the name
+            ("a.md:", "a.md:", None),
+            ("a.md:x", "a.md:x", None),
+            ("a.md:-1", "a.md:-1", None),
+            ("a.md:12:x", "a.md:12:x", None),
+            ("a.md:x:3", "a.md:x", Some((3, None))),
+            ("a.md:1.5", "a.md:1.5", None),
+        ] {
+            let (got_path, got_location) = split_location(arg);
+            assert_eq!((got_path.as_str(), got_location), (path, location), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn split_location_prefers_a_file_that_exists() {
+        let dir = std::env::temp_dir().join(format!("vellum-editor-{}-colons", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let weird = dir.join("weird:name.md");
+        std::fs::write(&weird, "text\n").unwrap();
+        let weird = weird.to_str().unwrap();
+
+        assert_eq!(split_location(weird), (weird.to_string(), None));
+        assert_eq!(split_location(&format!("{}:4", weird)), (weird.to_string(), Some((4, None))));
+        assert_eq!(split_location(&format!("{}:4:2", weird)), (weird.to_string(), Some((4, Some(2)))));
+        // A file named like a location wins over reading it as one
+        let numbered = dir.join("notes.md:12");
+        std::fs::write(&numbered, "text\n").unwrap();
+        let numbered = numbered.to_str().unwrap();
+        assert_eq!(split_location(numbered), (numbered.to_string(), None));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,284 @@
+// Markdown -> plain text, used by `:export txt` and `vellum --cat`
+use crate::document::fence_marker;
+
+pub struct TextOptions {
+    pub underline_headings: bool, // Follow headings with a row of = (level 1) or - (others)
+}
+
+pub fn to_plain_text(source: &str, options: &TextOptions) -> String {
+    let mut out = String::new();
+    let mut fence: Option<(char, usize)> = None;
+
+    for line in source.lines() {
+        // Code fences keep their contents verbatim but lose the ``` lines
+        if let Some(marker) = fence_marker(line) {
+            match fence {
+                None => {
+                    fence = Some(marker);
+                    continue;
+                }
+                Some((c, len)) if c == marker.0 && marker.1 >= len => {
+                    fence = None;
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        if fence.is_some() {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+
+        // Blockquotes become indentation
+        let (depth, rest) = strip_blockquote(line);
+        let indent = "    ".repeat(depth);
+
+        if is_thematic_break(rest) {
+            out.push_str(&indent);
+            out.push_str(&"-".repeat(20));
+            out.push('\n');
+            continue;
+        }
+
+        if let Some((level, title)) = heading(rest) {
+            let title = strip_inline(title);
+            out.push_str(&indent);
+            out.push_str(&title);
+            out.push('\n');
+            if options.underline_headings {
+                let c = if level == 1 { "=" } else { "-" };
+                out.push_str(&indent);
+                out.push_str(&c.repeat(title.chars().count()));
+                out.push('\n');
+            }
+            continue;
+        }
+
+        out.push_str(&indent);
+        out.push_str(&normalize_bullet(rest));
+        out.push('\n');
+    }
+    out
+}
+
+// Returns (number of > markers, rest of the line)
+fn strip_blockquote(line: &str) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line;
+    loop {
+        let trimmed = rest.trim_start_matches(' ');
+        match trimmed.strip_prefix('>') {
+            Some(after) => {
+                depth += 1;
+                rest = after.strip_prefix(' ').unwrap_or(after);
+            }
+            None => return (depth, rest),
+        }
+    }
+}
+
+fn is_thematic_break(line: &str) -> bool {
+    let compact: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    compact.len() >= 3
+        && ['-', '*', '_'].iter().any(|m| compact.chars().all(|c| c == *m))
+}
+
+pub fn heading(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    let level = trimmed.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 { return None; }
+
+    let rest = &trimmed[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') { return None; }
+    // Closing hashes are optional decoration
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+// "* item" and "+ item" become "- item", ordered lists keep their numbers
+fn normalize_bullet(line: &str) -> String {
+    let indent_len = line.len() - line.trim_start_matches(' ').len();
+    let (indent, rest) = line.split_at(indent_len);
+
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(item) = rest.strip_prefix(marker) {
+            return format!("{}- {}", indent, strip_inline(item));
+        }
+    }
+    format!("{}{}", indent, strip_inline(rest))
+}
+
+// Removes emphasis/code markers and flattens links to "text (url)"
+pub fn strip_inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if i + 1 < chars.len() && chars[i + 1].is_ascii_punctuation() => {
+                out.push(chars[i + 1]);
+                i += 2;
+            }
+            '`' => {
+                // Code spans are copied as-is
+                let ticks = chars[i..].iter().take_while(|ch| **ch == '`').count();
+                let body_start = i + ticks;
+                match find_run(&chars, body_start, '`', ticks) {
+                    Some(end) => {
+                        out.extend(&chars[body_start..end]);
+                        i = end + ticks;
+                    }
+                    None => {
+                        out.extend(&chars[i..body_start]);
+                        i = body_start;
+                    }
+                }
+            }
+            '!' if chars.get(i + 1) == Some(&'[') => {
+                match link_at(&chars, i + 1) {
+                    Some((label, url, end)) => {
+                        out.push_str(&format_link(&label, &url));
+                        i = end;
+                    }
+                    None => {
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            '[' => {
+                match link_at(&chars, i) {
+                    Some((label, url, end)) => {
+                        out.push_str(&format_link(&label, &url));
+                        i = end;
+                    }
+                    None => {
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            '*' | '_' | '~' => {
+                let run = chars[i..].iter().take_while(|ch| **ch == c).count();
+                let before = if i > 0 { Some(chars[i - 1]) } else { None };
+                let after = chars.get(i + run).copied();
+                if is_emphasis_run(c, run, before, after) {
+                    i += run;
+                } else {
+                    out.extend(&chars[i..i + run]);
+                    i += run;
+                }
+            }
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn format_link(label: &str, url: &str) -> String {
+    let label = strip_inline(label);
+    if label.is_empty() || label == url {
+        url.to_string()
+    } else {
+        format!("{} ({})", label, url)
+    }
+}
+
+// Parses "[label](url)" starting at the '[', returns (label, url, index after the closing paren)
+fn link_at(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let mut depth = 0;
+    let mut close = None;
+    for (j, ch) in chars.iter().enumerate().skip(start) {
+        match ch {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(j);
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+    let close = close?;
+    if chars.get(close + 1) != Some(&'(') { return None; }
+    let paren_end = chars.iter().skip(close + 2).position(|ch| *ch == ')')? + close + 2;
+
+    let label: String = chars[start + 1..close].iter().collect();
+    let target: String = chars[close + 2..paren_end].iter().collect();
+    // Drop an optional "title" after the url
+    let url = target.split_whitespace().next().unwrap_or("").trim_matches(|c| c == '<' || c == '>').to_string();
+    Some((label, url, paren_end + 1))
+}
+
+fn find_run(chars: &[char], from: usize, c: char, len: usize) -> Option<usize> {
+    let mut j = from;
+    while j + len <= chars.len() {
+        if chars[j..j + len].iter().all(|ch| *ch == c)
+            && chars.get(j + len) != Some(&c)
+        {
+            return Some(j);
+        }
+        j += 1;
+    }
+    None
+}
+
+// A marker run only counts as emphasis when it hugs text on at least one side,
+// so "2 * 3" and snake_case survive
+fn is_emphasis_run(c: char, run: usize, before: Option<char>, after: Option<char>) -> bool {
+    if c == '~' && run < 2 { return false; }
+    let left_flanking = after.is_some_and(|a| !a.is_whitespace());
+    let right_flanking = before.is_some_and(|b| !b.is_whitespace());
+    if c == '_' && before.is_some_and(|b| b.is_alphanumeric()) && after.is_some_and(|a| a.is_alphanumeric()) {
+        return false;
+    }
+    left_flanking || right_flanking
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // Every tests/export/<name>.md has the expected rendering next to it in <name>.txt.
+    // Files whose name starts with "underline" are rendered with underlined headings
+    #[test]
+    fn corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/export");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "md") { continue; }
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let options = TextOptions { underline_headings: name.starts_with("underline") };
+            let source = std::fs::read_to_string(&path).unwrap();
+            let expected = std::fs::read_to_string(path.with_extension("txt")).unwrap();
+            assert_eq!(to_plain_text(&source, &options), expected, "{}", name);
+            checked += 1;
+        }
+        assert!(checked >= 6, "only {} corpus files found", checked);
+    }
+
+    #[test]
+    fn inline_edge_cases() {
+        assert_eq!(strip_inline("[](https://x.org)"), "https://x.org");
+        assert_eq!(strip_inline("unclosed `tick"), "unclosed `tick");
+        assert_eq!(strip_inline("lone * star"), "lone * star");
+        assert_eq!(strip_inline("~single~ tilde"), "~single~ tilde");
+    }
+
+    #[test]
+    fn headings_need_a_space() {
+        assert_eq!(heading("## Title ##"), Some((2, "Title")));
+        assert_eq!(heading("#"), Some((1, "")));
+        assert_eq!(heading("#hashtag"), None);
+        assert_eq!(heading("####### seven"), None);
+    }
+}
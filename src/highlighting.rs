@@ -1,6 +1,7 @@
-use crossterm::style::Color;
+use crossterm::style::{Attribute, Color};
 
-#[derive(PartialEq, Clone, Copy)]
+#[allow(dead_code)] // Number/Comment are kept for future grammars
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Type {
     None,
     Number,
@@ -12,9 +13,18 @@ pub enum Type {
     Bold,
     Italic,
     List,
+    // Diagnostics
+    IndentWarning,
+    Conflict,
+    Selection,
 }
 
 impl Type {
+    // Drawn as a background block instead of colored text
+    pub fn is_background(self) -> bool {
+        matches!(self, Type::Match | Type::IndentWarning | Type::Conflict | Type::Selection)
+    }
+
     pub fn to_color(self) -> Color {
         match self {
             Type::Number => Color::Cyan,
@@ -25,7 +35,32 @@ impl Type {
             Type::Bold => Color::White,
             Type::Italic => Color::Yellow, 
             Type::List => Color::Cyan,
+            Type::IndentWarning => Color::Red,
+            Type::Conflict => Color::Magenta,
+            Type::Selection => Color::Grey,
             _ => Color::White,
         }
     }
-}
\ No newline at end of file
+
+    // What's left to tell text apart when colors are off (backgrounds become reverse video)
+    pub fn to_attribute(self) -> Option<Attribute> {
+        match self {
+            Type::Header | Type::Bold => Some(Attribute::Bold),
+            Type::Italic => Some(Attribute::Italic),
+            _ => None,
+        }
+    }
+}
+
+// Collapses per-char types into (start, end, type) runs, skipping unhighlighted text
+pub fn spans(types: &[Type]) -> Vec<(usize, usize, Type)> {
+    let mut runs: Vec<(usize, usize, Type)> = Vec::new();
+    for (i, t) in types.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if run.1 == i && run.2 == *t => run.1 = i + 1,
+            _ => runs.push((i, i + 1, *t)),
+        }
+    }
+    runs.retain(|run| run.2 != Type::None);
+    runs
+}
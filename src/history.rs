@@ -0,0 +1,86 @@
+// What was typed on the command line and in the / and ? prompts, for Up/Down to bring back.
+// Each kind is kept in its own file under $XDG_STATE_HOME/vellum (~/.local/state/vellum)
+// so it survives restarts
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAX_ENTRIES: usize = 100; // Oldest fall off first
+
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>, // Oldest first
+    file: Option<PathBuf>,
+    position: Option<usize>, // Entry Up/Down is showing, None while typing a new line
+    draft: String, // What was typed before Up, given back by Down past the newest entry
+}
+
+impl History {
+    // An empty history if the file doesn't exist yet or can't be read
+    pub fn load(name: &str) -> Self {
+        let file = state_dir().map(|dir| dir.join(name));
+        let entries = file.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().filter(|l| !l.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        Self { entries, file, ..Self::default() }
+    }
+
+    // Records a line that was run and starts over from the newest entry. Running the same
+    // thing twice in a row keeps one copy
+    pub fn push(&mut self, entry: &str) {
+        self.reset();
+        if entry.is_empty() || self.entries.last().is_some_and(|last| last == entry) { return; }
+        self.entries.push(entry.to_string());
+        if self.entries.len() > MAX_ENTRIES { self.entries.remove(0); }
+        self.save();
+    }
+
+    // Back to typing a new line, for when the prompt closes or opens
+    pub fn reset(&mut self) {
+        self.position = None;
+        self.draft.clear();
+    }
+
+    // The entry before the one showing (`current` is remembered when leaving the new line),
+    // None at the oldest
+    pub fn older(&mut self, current: &str) -> Option<String> {
+        let position = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(i) => i - 1,
+        };
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+
+    // The entry after the one showing, past the newest it's what was being typed before
+    pub fn newer(&mut self) -> Option<String> {
+        let position = self.position?;
+        if position + 1 < self.entries.len() {
+            self.position = Some(position + 1);
+            return Some(self.entries[position + 1].clone());
+        }
+        self.position = None;
+        Some(std::mem::take(&mut self.draft))
+    }
+
+    // Losing the history isn't worth interrupting anyone over, so errors are ignored
+    fn save(&self) {
+        let Some(file) = &self.file else { return; };
+        if let Some(dir) = file.parent() && fs::create_dir_all(dir).is_err() { return; }
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        let _ = fs::write(file, text);
+    }
+}
+
+fn state_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    Some(base.join("vellum"))
+}
@@ -0,0 +1,181 @@
+use crate::document::Document;
+use crate::highlighting::Type;
+use ropey::RopeSlice;
+use std::collections::HashMap;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces,
+}
+
+impl IndentStyle {
+    pub fn name(self) -> &'static str {
+        match self {
+            IndentStyle::Tabs => "tabs",
+            IndentStyle::Spaces => "spaces",
+        }
+    }
+}
+
+// Result of scanning the leading whitespace of every line
+pub struct IndentReport {
+    pub tab_lines: usize,   // Indented with tabs only
+    pub space_lines: usize, // Indented with spaces only
+    pub mixed_lines: usize, // Tabs and spaces in the same indent
+    pub style: Option<IndentStyle>, // Whichever style most lines use
+    pub width: Option<usize>, // Most common step between space-indented lines
+}
+
+impl IndentReport {
+    pub fn is_mixed(&self) -> bool {
+        self.mixed_lines > 0 || (self.tab_lines > 0 && self.space_lines > 0)
+    }
+
+    pub fn summary(&self) -> String {
+        let style = self.style.map(|s| s.name()).unwrap_or("none");
+        if self.is_mixed() {
+            format!(
+                "Mixed indentation: {} lines use tabs, {} use spaces, {} mix both (style: {}) - :retab! to fix",
+                self.tab_lines, self.space_lines, self.mixed_lines, style
+            )
+        } else {
+            format!("Indentation OK (style: {})", style)
+        }
+    }
+}
+
+// Counts (tabs, spaces) in the leading whitespace of a line
+// Whitespace-only lines count as (0, 0) since they don't affect how anything lines up
+pub fn leading_whitespace(line: RopeSlice) -> (usize, usize) {
+    let mut tabs = 0;
+    let mut spaces = 0;
+    for c in line.chars() {
+        match c {
+            '\t' => tabs += 1,
+            ' ' => spaces += 1,
+            '\n' | '\r' => return (0, 0),
+            _ => return (tabs, spaces),
+        }
+    }
+    (0, 0)
+}
+
+pub fn analyze(doc: &Document, skip_fences: bool) -> IndentReport {
+    let fences = if skip_fences { doc.fence_lines() } else { std::rc::Rc::default() };
+    let mut report = IndentReport { tab_lines: 0, space_lines: 0, mixed_lines: 0, style: None, width: None };
+    let mut steps: HashMap<usize, usize> = HashMap::new();
+    let mut previous = 0; // Space indent of the last non-blank line
+
+    for y in 0..doc.len() {
+        if fences.get(y) == Some(&true) { continue; }
+
+        let line = doc.rope.line(y);
+        match leading_whitespace(line) {
+            (0, 0) => (),
+            (_, 0) => report.tab_lines += 1,
+            (0, _) => report.space_lines += 1,
+            _ => report.mixed_lines += 1,
+        }
+
+        // Only lines with text (and no tabs) say anything about how far each level goes in
+        if line.chars().all(char::is_whitespace) { continue; }
+        if let (0, spaces) = leading_whitespace(line) {
+            if spaces > previous {
+                *steps.entry(spaces - previous).or_default() += 1;
+            }
+            previous = spaces;
+        }
+    }
+
+    // Ties go to the smaller step, so 2 and 4 in equal measure gives 2
+    report.width = steps.into_iter().max_by_key(|&(step, count)| (count, std::cmp::Reverse(step))).map(|(step, _)| step);
+
+    if report.tab_lines > report.space_lines {
+        report.style = Some(IndentStyle::Tabs);
+    } else if report.space_lines > 0 {
+        report.style = Some(IndentStyle::Spaces);
+    }
+    report
+}
+
+// A line is offending if its indent mixes both characters or uses the one the style doesn't
+pub fn is_offending(line: RopeSlice, style: Option<IndentStyle>) -> bool {
+    match (leading_whitespace(line), style) {
+        ((0, 0), _) => false,
+        ((tabs, spaces), _) if tabs > 0 && spaces > 0 => true,
+        ((tabs, _), Some(IndentStyle::Spaces)) => tabs > 0,
+        ((_, spaces), Some(IndentStyle::Tabs)) => spaces > 0,
+        _ => false,
+    }
+}
+
+// Paints the leading whitespace of an offending line with the warning color
+pub fn mark_warnings(line: RopeSlice, style: Option<IndentStyle>, highlights: &mut [Type]) {
+    if !is_offending(line, style) { return; }
+    let (tabs, spaces) = leading_whitespace(line);
+    for hl in highlights.iter_mut().take(tabs + spaces) {
+        *hl = Type::IndentWarning;
+    }
+}
+
+// Soft tab stops (`:set softtabs`): the column Tab moves to from `col`, and the one
+// Backspace goes back to
+pub fn next_tab_stop(col: usize, tabstop: usize) -> usize {
+    (col / tabstop + 1) * tabstop
+}
+
+pub fn prev_tab_stop(col: usize, tabstop: usize) -> usize {
+    col.saturating_sub(1) / tabstop * tabstop
+}
+
+// Rebuilds a run of leading whitespace in the given style, keeping its visual width
+pub fn retab_leading(whitespace: &str, style: IndentStyle, tabstop: usize) -> String {
+    let mut width = 0;
+    for c in whitespace.chars() {
+        if c == '\t' {
+            width += tabstop - (width % tabstop);
+        } else {
+            width += 1;
+        }
+    }
+
+    match style {
+        IndentStyle::Spaces => " ".repeat(width),
+        IndentStyle::Tabs => format!("{}{}", "\t".repeat(width / tabstop), " ".repeat(width % tabstop)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tab_stops_on_and_off_stops() {
+        assert_eq!(next_tab_stop(0, 4), 4);
+        assert_eq!(next_tab_stop(3, 4), 4);
+        assert_eq!(next_tab_stop(4, 4), 8);
+        assert_eq!(next_tab_stop(5, 2), 6);
+
+        assert_eq!(prev_tab_stop(0, 4), 0);
+        assert_eq!(prev_tab_stop(1, 4), 0);
+        assert_eq!(prev_tab_stop(4, 4), 0);
+        assert_eq!(prev_tab_stop(6, 4), 4);
+        assert_eq!(prev_tab_stop(8, 4), 4);
+        assert_eq!(prev_tab_stop(3, 2), 2);
+    }
+
+    fn width_of(text: &str) -> Option<usize> {
+        analyze(&Document::from_text(text), false).width
+    }
+
+    #[test]
+    fn detects_indent_width() {
+        assert_eq!(width_of("- a\n  - b\n    - c\n  - d\n"), Some(2));
+        assert_eq!(width_of("a\n    b\n        c\n\n    d\n"), Some(4));
+        // A blank line in between doesn't reset the level
+        assert_eq!(width_of("a\n  b\n\n  c\n    d\n"), Some(2));
+        assert_eq!(width_of("a\n\tb\n"), None);
+        assert_eq!(width_of("plain\ntext\n"), None);
+    }
+}
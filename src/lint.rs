@@ -0,0 +1,273 @@
+// Structural checks for `:lint`, and the fixes `:lint fix` knows how to make
+use crate::document::fence_marker;
+use crate::export::heading;
+
+pub struct Finding {
+    pub line: usize,
+    pub message: String,
+    pub fixable: bool,
+}
+
+// Lines between ``` markers (the markers themselves included), plus the opening line
+// of a fence that never closes
+fn fences(lines: &[&str]) -> (Vec<bool>, Option<(usize, char, usize)>) {
+    let mut inside = Vec::with_capacity(lines.len());
+    let mut open: Option<(usize, char, usize)> = None;
+    for (y, line) in lines.iter().enumerate() {
+        match (open, fence_marker(line)) {
+            (None, Some((c, len))) => open = Some((y, c, len)),
+            (Some((_, c, len)), Some((mc, mlen))) if c == mc && mlen >= len => {
+                inside.push(true);
+                open = None;
+                continue;
+            }
+            _ => (),
+        }
+        inside.push(open.is_some());
+    }
+    (inside, open)
+}
+
+// GitHub style anchor: lowercase, punctuation dropped, spaces to dashes
+pub fn slug(title: &str) -> String {
+    title.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+// Start (char index) and end of every http(s) URL on the line that isn't already
+// a link target, an autolink or inside a code span
+fn bare_urls(line: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut found = Vec::new();
+    let mut in_code = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '`' { in_code = !in_code; }
+        let rest: String = chars[i..std::cmp::min(i + 8, chars.len())].iter().collect();
+        if !in_code && (rest.starts_with("http://") || rest.starts_with("https://")) {
+            let mut end = i;
+            while end < chars.len() && !chars[end].is_whitespace() { end += 1; }
+            // Sentence punctuation after a URL isn't part of it
+            while end > i && matches!(chars[end - 1], '.' | ',' | ';' | ':' | '!' | '?' | ')') { end -= 1; }
+
+            let before = if i > 0 { Some(chars[i - 1]) } else { None };
+            if !matches!(before, Some('(' | '<' | '[' | '"' | '\'')) {
+                found.push((i, end));
+            }
+            i = end.max(i + 1);
+            continue;
+        }
+        i += 1;
+    }
+    found
+}
+
+fn trailing_spaces(line: &str) -> usize {
+    line.chars().rev().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+// Two or more spaces ending a line of text that's followed by more text: markdown's hard
+// line break. It may well be meant, so fixing leaves it alone
+fn hard_break(lines: &[&str], y: usize) -> bool {
+    let line = lines[y];
+    let spaces = line.chars().rev().take_while(|c| *c == ' ').count();
+    spaces >= 2 && spaces == trailing_spaces(line) && !line.trim().is_empty()
+        && lines.get(y + 1).is_some_and(|next| !next.trim().is_empty())
+}
+
+// Emphasis markers (** or __) left open at the end of each paragraph
+// Returns the line of the last unmatched marker
+fn unclosed_emphasis(lines: &[&str], fenced: &[bool]) -> Vec<(usize, &'static str)> {
+    let mut found = Vec::new();
+    let mut counts: [(usize, usize); 2] = [(0, 0); 2]; // (count, last line) for ** and __
+    let markers = ["**", "__"];
+
+    for y in 0..=lines.len() {
+        let paragraph_over = y == lines.len() || lines[y].trim().is_empty() || fenced[y];
+        if paragraph_over {
+            for (i, (count, last)) in counts.iter().enumerate() {
+                if count % 2 == 1 { found.push((*last, markers[i])); }
+            }
+            counts = [(0, 0); 2];
+            continue;
+        }
+
+        // Code spans don't count
+        let text: String = lines[y].split('`').step_by(2).collect::<Vec<_>>().join(" ");
+        for (i, marker) in markers.iter().enumerate() {
+            let n = text.matches(marker).count();
+            if n > 0 {
+                counts[i].0 += n;
+                counts[i].1 = y;
+            }
+        }
+    }
+    found
+}
+
+pub fn check(lines: &[&str]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let (fenced, open_fence) = fences(lines);
+    let mut previous_level = None;
+    let mut slugs: Vec<(String, usize)> = Vec::new();
+
+    for (y, line) in lines.iter().enumerate() {
+        if fenced[y] { continue; }
+
+        if let Some((level, title)) = heading(line) {
+            if let Some(prev) = previous_level
+                && level > prev + 1
+            {
+                findings.push(Finding { line: y, message: format!("Heading jumps from level {} to {}", prev, level), fixable: true });
+            }
+            previous_level = Some(level);
+
+            let slug = slug(title);
+            match slugs.iter().find(|(s, _)| *s == slug) {
+                Some((_, first)) => findings.push(Finding {
+                    line: y,
+                    message: format!("Duplicate heading anchor #{} (first at line {})", slug, first + 1),
+                    fixable: false,
+                }),
+                None => slugs.push((slug, y)),
+            }
+        }
+
+        for (start, end) in bare_urls(line) {
+            let url: String = line.chars().skip(start).take(end - start).collect();
+            findings.push(Finding { line: y, message: format!("Bare URL {}", url), fixable: true });
+        }
+
+        if hard_break(lines, y) {
+            findings.push(Finding { line: y, message: "Trailing spaces make a hard line break".to_string(), fixable: false });
+        } else if trailing_spaces(line) > 0 {
+            findings.push(Finding { line: y, message: "Trailing whitespace".to_string(), fixable: true });
+        }
+    }
+
+    for (y, marker) in unclosed_emphasis(lines, &fenced) {
+        findings.push(Finding { line: y, message: format!("Unclosed {} emphasis", marker), fixable: false });
+    }
+    if let Some((y, _, _)) = open_fence {
+        findings.push(Finding { line: y, message: "Code fence is never closed".to_string(), fixable: true });
+    }
+
+    findings.sort_by_key(|f| f.line);
+    findings
+}
+
+// Applies every fixable finding, returns the new lines and how many fixes were made
+pub fn fix(lines: &[&str]) -> (Vec<String>, usize) {
+    let (fenced, open_fence) = fences(lines);
+    let mut out = Vec::with_capacity(lines.len());
+    let mut fixes = 0;
+    let mut previous_level = None;
+
+    for (y, line) in lines.iter().enumerate() {
+        if fenced[y] {
+            out.push(line.to_string());
+            continue;
+        }
+        let mut text = if hard_break(lines, y) { line.to_string() } else { line.trim_end_matches([' ', '\t']).to_string() };
+        if text.len() != line.len() { fixes += 1; }
+
+        // Levels are compared with the previous heading as fixed, so ## #### ##### becomes
+        // ## ### ####
+        if let Some((level, _)) = heading(&text) {
+            let mut level = level;
+            if let Some(prev) = previous_level
+                && level > prev + 1
+            {
+                let indent = text.len() - text.trim_start().len();
+                text = format!("{}{}", "#".repeat(prev + 1), &text[indent + level..]);
+                level = prev + 1;
+                fixes += 1;
+            }
+            previous_level = Some(level);
+        }
+
+        // Wrap bare URLs in <> so they become autolinks, back to front to keep indices valid
+        let urls = bare_urls(&text);
+        if !urls.is_empty() {
+            let mut chars: Vec<char> = text.chars().collect();
+            for (start, end) in urls.iter().rev() {
+                chars.insert(*end, '>');
+                chars.insert(*start, '<');
+                fixes += 1;
+            }
+            text = chars.into_iter().collect();
+        }
+        out.push(text);
+    }
+
+    if let Some((_, c, len)) = open_fence {
+        out.push(c.to_string().repeat(len));
+        fixes += 1;
+    }
+    (out, fixes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<(usize, String, bool)> {
+        let lines: Vec<&str> = text.lines().collect();
+        check(&lines).into_iter().map(|f| (f.line, f.message, f.fixable)).collect()
+    }
+
+    fn fixed(text: &str) -> String {
+        let lines: Vec<&str> = text.lines().collect();
+        fix(&lines).0.join("\n")
+    }
+
+    #[test]
+    fn heading_jumps() {
+        assert_eq!(messages("# A\n### B"), [(1, "Heading jumps from level 1 to 3".to_string(), true)]);
+        assert!(messages("# A\n## B\n# C\n## D").is_empty());
+        assert!(messages("```\n# A\n### B\n```").is_empty());
+    }
+
+    #[test]
+    fn heading_fix_follows_the_fixed_levels() {
+        assert_eq!(fixed("# A\n#### B\n##### C\n## D"), "# A\n## B\n### C\n## D");
+        assert_eq!(fixed("## A\n#### B\n###### C"), "## A\n### B\n#### C");
+    }
+
+    #[test]
+    fn duplicate_anchors() {
+        let found = messages("# Intro\ntext\n## intro!");
+        assert_eq!(found, [(2, "Duplicate heading anchor #intro (first at line 1)".to_string(), false)]);
+    }
+
+    #[test]
+    fn bare_urls_get_wrapped() {
+        assert_eq!(messages("see https://example.com.").len(), 1);
+        assert!(messages("[x](https://example.com) <https://example.com> `https://example.com`").is_empty());
+        assert_eq!(fixed("see https://example.com."), "see <https://example.com>.");
+    }
+
+    #[test]
+    fn trailing_whitespace_keeps_hard_breaks() {
+        let text = "first line  \nsecond line \n\nend\t";
+        assert_eq!(messages(text), [
+            (0, "Trailing spaces make a hard line break".to_string(), false),
+            (1, "Trailing whitespace".to_string(), true),
+            (3, "Trailing whitespace".to_string(), true),
+        ]);
+        assert_eq!(fixed(text), "first line  \nsecond line\n\nend");
+        // Not a break when nothing follows in the paragraph
+        assert_eq!(fixed("last  \n\nnext"), "last\n\nnext");
+    }
+
+    #[test]
+    fn unclosed_emphasis_and_fences() {
+        assert_eq!(messages("some **bold\n\nfine **bold**"), [(0, "Unclosed ** emphasis".to_string(), false)]);
+        assert_eq!(messages("```\ncode"), [(0, "Code fence is never closed".to_string(), true)]);
+        assert_eq!(fixed("```\ncode"), "```\ncode\n```");
+    }
+}
@@ -0,0 +1,107 @@
+// The location list: places to walk through (lint findings, :lgrep matches), filled by one
+// producer at a time and stepped through with :lnext / :lprev
+pub(crate) struct Location {
+    pub(crate) file: Option<String>,
+    pub(crate) line: usize, // 0-based, like the cursor
+    pub(crate) col: usize,
+    pub(crate) message: String,
+}
+
+#[derive(Default)]
+pub(crate) struct LocationList {
+    pub(crate) items: Vec<Location>,
+    pub(crate) current: Option<usize>,
+    pub(crate) source: String, // What filled it, for the :lopen title
+}
+
+impl LocationList {
+    // Replaces whatever was there before, returns the note for the status bar
+    pub(crate) fn fill(&mut self, source: &str, items: Vec<Location>) -> String {
+        self.items = items;
+        self.current = None;
+        self.source = source.to_string();
+        format!("location list: {} items from {}", self.items.len(), source)
+    }
+
+    // The entry after (or before) the current one, None past either end
+    pub(crate) fn step(&self, forward: bool) -> Option<usize> {
+        match (self.current, forward) {
+            _ if self.items.is_empty() => None,
+            (None, true) => Some(0),
+            (None, false) => Some(self.items.len() - 1),
+            (Some(i), true) => (i + 1 < self.items.len()).then_some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+        }
+    }
+
+    // Saving the unnamed buffer under a name gives its entries that name too
+    pub(crate) fn name_unnamed(&mut self, file: &str) {
+        for item in self.items.iter_mut().filter(|item| item.file.is_none()) {
+            item.file = Some(file.to_string());
+        }
+    }
+
+    // Entries without a file belong to the unnamed buffer, so they go when another file
+    // is opened in its place. The active entry stays active if it's kept
+    pub(crate) fn forget_unnamed(&mut self) {
+        let current = self.current.filter(|&i| self.items[i].file.is_some());
+        self.current = current.map(|i| self.items[..i].iter().filter(|item| item.file.is_some()).count());
+        self.items.retain(|item| item.file.is_some());
+    }
+
+    // One row per entry for :lopen, the active one marked
+    pub(crate) fn rows(&self) -> Vec<String> {
+        self.items.iter().enumerate().map(|(i, item)| {
+            let marker = if self.current == Some(i) { '>' } else { ' ' };
+            let file = item.file.as_deref().unwrap_or("[No Name]");
+            format!("{} {}:{}:{}: {}", marker, file, item.line + 1, item.col + 1, item.message)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(file: Option<&str>, line: usize) -> Location {
+        Location { file: file.map(String::from), line, col: 0, message: String::new() }
+    }
+
+    #[test]
+    fn fill_replaces_and_steps_from_either_end() {
+        let mut list = LocationList::default();
+        assert_eq!(list.step(true), None);
+        list.fill("grep", vec![at(Some("a.md"), 0)]);
+        assert_eq!(list.fill("lint", vec![at(Some("a.md"), 1), at(Some("b.md"), 2)]), "location list: 2 items from lint");
+        assert_eq!(list.items.len(), 2);
+
+        assert_eq!(list.step(true), Some(0));
+        assert_eq!(list.step(false), Some(1));
+        list.current = Some(1);
+        assert_eq!(list.step(true), None);
+        assert_eq!(list.step(false), Some(0));
+        list.current = Some(0);
+        assert_eq!(list.step(false), None);
+    }
+
+    #[test]
+    fn forget_unnamed_keeps_the_active_entry() {
+        let mut list = LocationList::default();
+        list.fill("lint", vec![at(None, 0), at(Some("a.md"), 1), at(None, 2), at(Some("b.md"), 3)]);
+        list.current = Some(3);
+        list.forget_unnamed();
+        assert_eq!(list.items.iter().map(|item| item.line).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(list.current, Some(1));
+
+        list.fill("lint", vec![at(None, 0), at(Some("a.md"), 1)]);
+        list.current = Some(0);
+        list.forget_unnamed();
+        assert_eq!(list.current, None);
+        assert_eq!(list.rows(), vec!["  a.md:2:1: ".to_string()]);
+
+        list.fill("grep", vec![at(None, 0), at(Some("a.md"), 1)]);
+        list.name_unnamed("new.md");
+        list.forget_unnamed();
+        assert_eq!(list.items.iter().map(|item| item.file.as_deref()).collect::<Vec<_>>(), vec![Some("new.md"), Some("a.md")]);
+    }
+}
@@ -0,0 +1,169 @@
+// Advisory lock files so two editors don't silently fight over one file
+// notes.md gets a .notes.md.vel-lock next to it holding "pid\nhostname"
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct Lock {
+    path: PathBuf,
+}
+
+// Removing the file here covers clean exits, replaced documents and panics alike
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn lock_path(filename: &str) -> PathBuf {
+    let path = Path::new(filename);
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.vel-lock", name))
+}
+
+// Takes the lock, or describes who holds it ("pid 123 on host") if that process is still alive
+// A lock left behind by a dead process is replaced without asking
+pub fn acquire(filename: &str) -> Result<Option<Lock>, String> {
+    let path = lock_path(filename);
+    let contents = format!("{}\n{}\n", std::process::id(), hostname());
+
+    // create_new makes taking the lock one step, so two editors opening the file at once
+    // can't both find it free and both write it. Whoever loses reads the winner's lock
+    for _ in 0..3 {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                // Not being able to write a lock (full disk, etc.) shouldn't stop editing
+                if file.write_all(contents.as_bytes()).is_err() {
+                    drop(file);
+                    let _ = fs::remove_file(&path);
+                    return Ok(None);
+                }
+                return Ok(Some(Lock { path }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if let Some(holder) = holder(&path) { return Err(holder); }
+                // Stale (or our own), clear it and try again
+                if fs::remove_file(&path).is_err() { return Ok(None); }
+            }
+            // Read-only directory and the like
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+// Who holds the lock at `path`, None if it's gone, unreadable or its process has died
+fn holder(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|p| p.trim().parse::<u32>().ok())?;
+    let host = lines.next().unwrap_or("").trim().to_string();
+    if pid == std::process::id() || !is_alive(pid, &host) { return None; }
+    Some(format!("pid {} on {}", pid, if host.is_empty() { "unknown host" } else { &host }))
+}
+
+// A pid on another machine can't be checked, so assume it's still running
+fn is_alive(pid: u32, host: &str) -> bool {
+    if host != hostname() { return true; }
+    process_exists(pid)
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    // Signal 0 only checks the process is there, EPERM means it is but belongs to someone else
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 { return String::new(); }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    fn temp_file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("vellum-lock-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("notes.md").to_string_lossy().into_owned()
+    }
+
+    fn write_lock(file: &str, pid: u32, host: &str) {
+        fs::write(lock_path(file), format!("{}\n{}\n", pid, host)).unwrap();
+    }
+
+    #[test]
+    fn lock_sits_next_to_the_file() {
+        assert_eq!(lock_path("/tmp/x/notes.md"), PathBuf::from("/tmp/x/.notes.md.vel-lock"));
+        assert_eq!(lock_path("notes.md"), PathBuf::from(".notes.md.vel-lock"));
+    }
+
+    #[test]
+    fn takes_a_free_lock_and_drops_it() {
+        let file = temp_file("free");
+        let lock = acquire(&file).unwrap().unwrap();
+        let contents = fs::read_to_string(lock_path(&file)).unwrap();
+        assert_eq!(contents, format!("{}\n{}\n", std::process::id(), hostname()));
+
+        // Our own lock doesn't lock us out
+        assert!(acquire(&file).unwrap().is_some());
+        drop(lock);
+        assert!(!lock_path(&file).exists());
+    }
+
+    #[test]
+    fn replaces_a_stale_lock() {
+        let file = temp_file("stale");
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        write_lock(&file, dead, &hostname());
+
+        let _lock = acquire(&file).unwrap().unwrap();
+        assert!(fs::read_to_string(lock_path(&file)).unwrap().starts_with(&format!("{}\n", std::process::id())));
+
+        // Garbage counts as stale too
+        let file = temp_file("garbage");
+        fs::write(lock_path(&file), "").unwrap();
+        assert!(acquire(&file).unwrap().is_some());
+    }
+
+    #[test]
+    fn refuses_a_live_lock() {
+        let file = temp_file("live");
+        let mut child = Command::new("sleep").arg("10").stdout(Stdio::null()).spawn().unwrap();
+        write_lock(&file, child.id(), &hostname());
+        let result = acquire(&file);
+        let _ = child.kill();
+        let _ = child.wait();
+
+        assert_eq!(result.err(), Some(format!("pid {} on {}", child.id(), hostname())));
+        // The holder's lock is left alone
+        assert!(fs::read_to_string(lock_path(&file)).unwrap().starts_with(&format!("{}\n", child.id())));
+    }
+
+    #[test]
+    fn assumes_other_hosts_are_alive() {
+        let file = temp_file("remote");
+        write_lock(&file, std::process::id() + 1, "some-other-host.invalid");
+        assert_eq!(acquire(&file).err(), Some(format!("pid {} on some-other-host.invalid", std::process::id() + 1)));
+    }
+}
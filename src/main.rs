@@ -1,13 +1,143 @@
 mod editor;
+mod export;
 mod terminal;
 mod document;
 mod highlighting;
 mod ui;
 mod commands;
+mod backup;
+mod changes;
+mod conflict;
+mod crash;
+mod history;
+mod indent;
+mod lint;
+mod location;
+mod lock;
+mod options;
+mod patch;
+mod profile;
+mod reflow;
+mod renumber;
+mod search;
+mod shell;
+mod tasks;
+mod textobject;
+mod tutor;
 
 use editor::Editor;
+use std::env;
+use std::io::{IsTerminal, Read};
 
 fn main() {
-    let mut editor = Editor::default();
-    editor.run();
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().filter(|a| a != "--no-color").collect();
+
+    // Write or print a plain-text rendering and exit, --cat is the printing one for pipes
+    match args.get(1).map(String::as_str) {
+        Some("--cat") => std::process::exit(export_txt(&args[2..], true)),
+        Some("--export") if args.get(2).map(String::as_str) == Some("txt") => {
+            let stdout = args.iter().any(|a| a == "--stdout");
+            std::process::exit(export_txt(&args[3..], stdout));
+        }
+        Some("--export") => {
+            eprintln!("usage: vellum --export txt [--underline] [--stdout] <file>...");
+            std::process::exit(2);
+        }
+        _ => (),
+    }
+
+    // `vellum -`, or plain `vellum` at the end of a pipe, edits whatever came in on stdin
+    let piped = args.get(1).map(String::as_str) == Some("-")
+        || (args.len() < 2 && !std::io::stdin().is_terminal());
+    let text = if piped {
+        match read_stdin() {
+            Ok(text) => Some(text),
+            Err(e) => {
+                eprintln!("vellum: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut editor = Editor::new(text);
+
+    // A panic mid-edit still gets the terminal back and the buffer saved somewhere
+    crash::install_hook();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| editor.run()));
+    if result.is_err() {
+        editor.terminal.clear_screen();
+        editor.terminal.cursor_position(0, 0);
+    }
+    editor.terminal.restore();
+    if result.is_err() {
+        eprintln!("{}", crash::report(&editor.document));
+        drop(editor);
+        std::process::exit(101);
+    }
+}
+
+// Reads all of stdin, checking first that there's a terminal left to take keys from
+fn read_stdin() -> Result<String, String> {
+    terminal_available()?;
+    let mut bytes = Vec::new();
+    std::io::stdin().read_to_end(&mut bytes).map_err(|e| format!("reading stdin: {}", e))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Once stdin is a pipe, keys come from the controlling terminal instead
+#[cfg(unix)]
+fn terminal_available() -> Result<(), String> {
+    std::fs::File::open("/dev/tty")
+        .map(|_| ())
+        .map_err(|e| format!("no terminal to edit in (/dev/tty: {})", e))
+}
+
+#[cfg(not(unix))]
+fn terminal_available() -> Result<(), String> {
+    Err("reading from stdin is only supported on Unix".to_string())
+}
+
+// vellum --export txt [--underline] [--stdout] <file>... writes notes.txt next to notes.md,
+// with --stdout (or as vellum --cat) it's printed instead. A file named - is stdin
+fn export_txt(args: &[String], stdout: bool) -> i32 {
+    let options = export::TextOptions {
+        underline_headings: args.iter().any(|a| a == "--underline"),
+    };
+    let files: Vec<&String> = args.iter().filter(|a| *a == "-" || !a.starts_with("--")).collect();
+    if files.is_empty() {
+        eprintln!("usage: vellum --export txt [--underline] [--stdout] <file>... (or --cat <file>...)");
+        return 2;
+    }
+
+    for file in files {
+        let text = if file == "-" {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text).map(|_| text)
+        } else {
+            std::fs::read_to_string(file)
+        };
+        let text = match text {
+            Ok(text) => export::to_plain_text(&text, &options),
+            Err(e) => {
+                eprintln!("vellum: {}: {}", file, e);
+                return 1;
+            }
+        };
+        if stdout || file == "-" {
+            print!("{}", text);
+            continue;
+        }
+        let path = std::path::Path::new(file).with_extension("txt");
+        if path == std::path::Path::new(file) {
+            eprintln!("vellum: {}: would overwrite itself, use --stdout", file);
+            return 1;
+        }
+        if let Err(e) = std::fs::write(&path, text) {
+            eprintln!("vellum: {}: {}", path.display(), e);
+            return 1;
+        }
+    }
+    0
+}
@@ -0,0 +1,213 @@
+use crate::indent::IndentStyle;
+use unicode_width::UnicodeWidthChar;
+
+// When the line number gutter is drawn
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum NumberMode {
+    Always,
+    Normal, // Hidden while typing in insert mode
+    Never,
+}
+
+// Toggles and values changed with `:set` (and from ~/.vellumrc)
+pub(crate) struct Options {
+    pub(crate) indent_warnings: bool, // Highlight leading whitespace that doesn't match the indent style
+    pub(crate) indent_skip_fences: bool, // Leave code fences out of indent checks (tabs may be intentional there)
+    pub(crate) indent_style: Option<IndentStyle>, // Declared style, None means use whatever was detected
+    pub(crate) tabstop: usize,
+    pub(crate) textwidth: usize, // Auto-wrap column while typing, 0 turns it off
+    pub(crate) long_line: usize, // Lines longer than this (in chars) skip highlighting and other per-char work
+    pub(crate) emacs_keys: bool, // Ctrl+A/E/F/B/K/Y and Alt+F/B in insert mode
+    pub(crate) smart_paste: bool, // `p` re-indents pasted lines like `]p`
+    pub(crate) profile: bool, // Collect timings for `:profile`
+    pub(crate) typewriter: bool, // Keep the cursor line in the middle of the screen
+    pub(crate) soft_tabs: bool, // Tab and Backspace in the indentation move between tab stops with spaces
+    pub(crate) expand_tab: bool, // Tab inserts spaces up to the next tab stop (unless the document indents with tabs)
+    pub(crate) page_break: String, // What :pagebreak inserts
+    pub(crate) auto_indent: bool, // Enter starts the new line with the current one's indentation
+    pub(crate) color: bool, // Off for NO_COLOR and --no-color, emphasis falls back to bold/underline/reverse
+    pub(crate) backups: usize, // Rotated copies kept on save (file.md.~1~ is the newest), 0 keeps none
+    pub(crate) patch_save: bool, // Save big files with few changes by writing just the changed parts in place
+    pub(crate) undo_levels: usize, // Undo steps kept, the oldest are dropped past this
+    pub(crate) number_mode: NumberMode,
+    pub(crate) auto_list: bool, // Enter on a list item or quote starts the next one
+    pub(crate) auto_pair: bool, // Typing ( [ " * also types the closer, typing the closer steps over it
+    pub(crate) alt_esc: bool, // Alt+key outside the command line is taken as Esc then key
+    pub(crate) incsearch: bool, // / and ? jump to the nearest match while the pattern is typed
+    pub(crate) regex: bool, // s/old/new and :find take their pattern as a regex instead of plain text
+    pub(crate) gutter_separator: String, // Between the line numbers and the text, at most one single-width char
+    pub(crate) gutter_padding: usize, // Spaces between the numbers and the separator
+    pub(crate) gutter_show_on_wrap: bool, // Draw the separator next to wrapped continuation rows too
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            indent_warnings: true,
+            indent_skip_fences: false,
+            indent_style: None,
+            tabstop: 4,
+            textwidth: 0,
+            long_line: 10_000,
+            emacs_keys: false,
+            smart_paste: false,
+            profile: false,
+            typewriter: false,
+            soft_tabs: false,
+            expand_tab: true,
+            auto_indent: true,
+            backups: 0,
+            undo_levels: 1000,
+            patch_save: false,
+            color: true,
+            page_break: "<div style=\"page-break-after: always\"></div>".to_string(),
+            number_mode: NumberMode::Always,
+            auto_list: true,
+            auto_pair: true,
+            alt_esc: true,
+            incsearch: true,
+            regex: false,
+            gutter_separator: "|".to_string(),
+            gutter_padding: 1,
+            gutter_show_on_wrap: false,
+        }
+    }
+}
+
+impl Options {
+    // Parses a single `:set` argument, vim style:
+    // `name` turns a flag on, `noname` turns it off, `name=value` sets a value
+    // Returns the message to show in the status bar
+    pub(crate) fn set(&mut self, arg: &str) -> Result<String, String> {
+        if let Some((name, value)) = arg.split_once('=') {
+            return self.set_value(name, value);
+        }
+
+        let (name, enabled) = match arg.strip_prefix("no") {
+            Some(name) if self.flag(name).is_some() => (name, false),
+            _ => (arg, true),
+        };
+
+        match self.flag(name) {
+            Some(flag) => {
+                *flag = enabled;
+                Ok(format!("{}: {}", name, enabled))
+            }
+            None => Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    fn flag(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "indentwarn" => Some(&mut self.indent_warnings),
+            "indentskipfences" => Some(&mut self.indent_skip_fences),
+            "emacskeys" => Some(&mut self.emacs_keys),
+            "smartpaste" => Some(&mut self.smart_paste),
+            "profile" => Some(&mut self.profile),
+            "typewriter" => Some(&mut self.typewriter),
+            "softtabs" => Some(&mut self.soft_tabs),
+            "expandtab" => Some(&mut self.expand_tab),
+            "autoindent" => Some(&mut self.auto_indent),
+            "color" => Some(&mut self.color),
+            "patchsave" => Some(&mut self.patch_save),
+            "gutterwrap" => Some(&mut self.gutter_show_on_wrap),
+            "altesc" => Some(&mut self.alt_esc),
+            "autopair" => Some(&mut self.auto_pair),
+            "autolist" => Some(&mut self.auto_list),
+            "incsearch" => Some(&mut self.incsearch),
+            "regex" => Some(&mut self.regex),
+            _ => None,
+        }
+    }
+
+    fn set_value(&mut self, name: &str, value: &str) -> Result<String, String> {
+        match name {
+            "tabstop" => {
+                let width = value.parse::<usize>().ok().filter(|w| *w > 0)
+                    .ok_or_else(|| format!("Invalid tabstop: {}", value))?;
+                self.tabstop = width;
+            }
+            "textwidth" => {
+                self.textwidth = value.parse::<usize>().map_err(|_| format!("Invalid textwidth: {}", value))?;
+            }
+            "longline" => {
+                self.long_line = value.parse::<usize>().map_err(|_| format!("Invalid longline: {}", value))?;
+            }
+            "backups" => {
+                self.backups = value.parse::<usize>().map_err(|_| format!("Invalid backups: {}", value))?;
+            }
+            "undolevels" => {
+                self.undo_levels = value.parse::<usize>().ok().filter(|n| *n > 0)
+                    .ok_or_else(|| format!("Invalid undolevels: {}", value))?;
+            }
+            "pagebreak" => self.page_break = value.to_string(),
+            "gutterseparator" => {
+                // The gutter is measured in chars, so anything not exactly one column wide (CJK,
+                // emoji, combining marks) would push the text out of line
+                let mut chars = value.chars();
+                if chars.next().is_some_and(|c| c.is_control() || c.width() != Some(1)) || chars.next().is_some() {
+                    return Err(format!("Invalid gutterseparator: {} (one single-width character, or nothing)", value));
+                }
+                self.gutter_separator = value.to_string();
+            }
+            "gutterpadding" => {
+                self.gutter_padding = value.parse::<usize>().ok().filter(|n| *n <= 8)
+                    .ok_or_else(|| format!("Invalid gutterpadding: {} (0 to 8)", value))?;
+            }
+            "numbermode" => {
+                self.number_mode = match value {
+                    "always" => NumberMode::Always,
+                    "normal" => NumberMode::Normal,
+                    "never" => NumberMode::Never,
+                    _ => return Err(format!("Invalid numbermode: {} (always, normal, never)", value)),
+                };
+            }
+            "indentstyle" => {
+                self.indent_style = match value {
+                    "tabs" => Some(IndentStyle::Tabs),
+                    "spaces" => Some(IndentStyle::Spaces),
+                    "auto" => None,
+                    _ => return Err(format!("Invalid indentstyle: {} (tabs, spaces, auto)", value)),
+                };
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+        Ok(format!("{}={}", name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn separator(value: &str) -> Result<String, String> {
+        let mut options = Options::default();
+        options.set(&format!("gutterseparator={}", value)).map(|_| options.gutter_separator)
+    }
+
+    #[test]
+    fn gutter_separator_takes_one_narrow_char() {
+        assert_eq!(separator("│"), Ok("│".to_string()));
+        assert_eq!(separator(":"), Ok(":".to_string()));
+        assert_eq!(separator(""), Ok(String::new()));
+        assert!(separator("||").is_err());
+        assert!(separator("\u{7}").is_err());
+    }
+
+    #[test]
+    fn gutter_separator_rejects_wide_and_zero_width_chars() {
+        // CJK, fullwidth forms, emoji from all over the table, including the ranges a short
+        // list of blocks tends to miss (Hiragana extensions, supplementary ideographs)
+        for wide in ["中", "ｌ", "가", "🦀", "🗿", "🛹", "🫠", "⌚", "〇", "\u{30000}", "\u{1B000}"] {
+            assert!(separator(wide).is_err(), "{:?} should be rejected", wide);
+        }
+        // Combining marks and zero-width joiners take no column at all
+        for narrow in ["\u{301}", "\u{200D}", "\u{FE0F}"] {
+            assert!(separator(narrow).is_err(), "{:?} should be rejected", narrow);
+        }
+        // A rejected value leaves the old one in place
+        let mut options = Options::default();
+        assert!(options.set("gutterseparator=中").is_err());
+        assert_eq!(options.gutter_separator, "|");
+    }
+}
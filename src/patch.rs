@@ -0,0 +1,233 @@
+// Patch saving (`:set patchsave`): for big files where only a little changed, write just
+// the changed blocks into the existing file instead of rewriting all of it. Anything that
+// doesn't line up exactly (the file changed on disk, too much changed, an error halfway)
+// means the caller does a normal full save instead
+use ropey::Rope;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::SystemTime;
+
+const BLOCK: usize = 64 * 1024; // Unit of comparison and of writing
+const MAX_SHARE: usize = 4; // Patch only when at most 1/MAX_SHARE of the file gets written
+const SAMPLES: usize = 16; // Unchanged blocks read back from disk to check it's what we think
+
+// What the file held after the last load or save, and how it looked on disk then. Ropes
+// share their unchanged parts, so keeping the old text around is cheap
+pub struct OnDisk {
+    path: String,
+    text: Rope,
+    modified: SystemTime,
+    len: u64,
+}
+
+impl OnDisk {
+    // None if the file can't be looked at (then the next save is a full one)
+    pub fn record(path: &str, text: &Rope) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        Some(Self { path: path.to_string(), text: text.clone(), modified: meta.modified().ok()?, len: meta.len() })
+    }
+
+    // Whether `text` is what was last loaded or saved
+    pub fn holds(&self, text: &Rope) -> bool {
+        self.text == *text
+    }
+}
+
+// Writes `current` over the file `on_disk` was recorded from, touching only what changed.
+// Returns the number of bytes written, or None when a full save is needed instead (in
+// which case the file may have been partly patched, the full save puts that right)
+pub fn save(on_disk: &OnDisk, path: &str, current: &Rope) -> Option<usize> {
+    if on_disk.path != path { return None; }
+    let meta = fs::metadata(path).ok()?;
+    if meta.modified().ok()? != on_disk.modified || meta.len() != on_disk.len
+        || on_disk.len != on_disk.text.len_bytes() as u64
+    {
+        return None;
+    }
+
+    let old_len = on_disk.text.len_bytes();
+    let new_len = current.len_bytes();
+    let changed = changed_blocks(&on_disk.text, current);
+
+    // Same length: just the changed blocks. Otherwise everything after the first change
+    // has moved, so it's rewritten from there on
+    let writes: Vec<(usize, usize)> = if old_len == new_len {
+        changed.iter().map(|&b| (b * BLOCK, ((b + 1) * BLOCK).min(new_len))).collect()
+    } else {
+        let from = changed.first().map_or(old_len.min(new_len), |&b| b * BLOCK);
+        if from < new_len { vec![(from, new_len)] } else { Vec::new() }
+    };
+    let written: usize = writes.iter().map(|(start, end)| end - start).sum();
+    if written * MAX_SHARE > new_len.max(old_len) { return None; }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path).ok()?;
+    if !unchanged_on_disk(&mut file, &on_disk.text, &changed, old_len.min(new_len)) { return None; }
+
+    for &(start, end) in &writes {
+        file.seek(SeekFrom::Start(start as u64)).ok()?;
+        file.write_all(&bytes(current, start, end)).ok()?;
+    }
+    if old_len != new_len { file.set_len(new_len as u64).ok()?; }
+    file.flush().ok()?;
+    Some(written)
+}
+
+// Indices of the BLOCK-sized blocks that differ, over the length both texts share
+fn changed_blocks(old: &Rope, new: &Rope) -> Vec<usize> {
+    let mut changed = Vec::new();
+    let (mut old_chunks, mut new_chunks) = (old.chunks(), new.chunks());
+    let (mut a, mut b): (&[u8], &[u8]) = (&[], &[]);
+    let mut offset = 0;
+    loop {
+        if a.is_empty() { match old_chunks.next() { Some(chunk) => a = chunk.as_bytes(), None => break } }
+        if b.is_empty() { match new_chunks.next() { Some(chunk) => b = chunk.as_bytes(), None => break } }
+        if a.is_empty() || b.is_empty() { continue; }
+
+        // Never straddle a block boundary, so each comparison belongs to one block
+        let n = a.len().min(b.len()).min(BLOCK - offset % BLOCK);
+        let block = offset / BLOCK;
+        if a[..n] != b[..n] && changed.last() != Some(&block) { changed.push(block); }
+        a = &a[n..];
+        b = &b[n..];
+        offset += n;
+    }
+    changed
+}
+
+// Reads a spread of blocks we're not going to write back from disk, plus the ones right
+// next to each change, and checks they hold what we think the file holds
+fn unchanged_on_disk(file: &mut File, old: &Rope, changed: &[usize], shared_len: usize) -> bool {
+    let blocks = shared_len.div_ceil(BLOCK);
+    if blocks == 0 { return true; }
+    let mut samples: Vec<usize> = (0..SAMPLES).map(|i| i * blocks / SAMPLES).collect();
+    for &b in changed {
+        samples.extend([b.wrapping_sub(1), b + 1]);
+    }
+    samples.sort();
+    samples.dedup();
+
+    let mut buf = vec![0; BLOCK];
+    for b in samples.into_iter().filter(|b| *b < blocks && changed.binary_search(b).is_err()) {
+        let (start, end) = (b * BLOCK, ((b + 1) * BLOCK).min(shared_len));
+        let buf = &mut buf[..end - start];
+        if file.seek(SeekFrom::Start(start as u64)).is_err() || file.read_exact(buf).is_err() {
+            return false;
+        }
+        if *buf != bytes(old, start, end)[..] { return false; }
+    }
+    true
+}
+
+fn bytes(text: &Rope, start: usize, end: usize) -> Vec<u8> {
+    text.bytes_at(start).take(end - start).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Small deterministic generator, so a failure can be replayed from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() % n.max(1)
+        }
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vellum-patch-{}-{}", std::process::id(), name))
+    }
+
+    fn random_text(rng: &mut Rng, len: usize) -> String {
+        let words = ["alpha", "beta", "gamma", "ünïcode", "漢字", "", "- [ ] task", "#"];
+        let mut text = String::new();
+        while text.len() < len {
+            text.push_str(words[rng.below(words.len())]);
+            text.push(if rng.below(6) == 0 { '\n' } else { ' ' });
+        }
+        text
+    }
+
+    // A handful of small inserts and removes, always on char boundaries. Those that move the
+    // rest of the file mostly land near the end, or nothing would be small enough to patch
+    fn random_edits(rng: &mut Rng, text: &Rope) -> Rope {
+        let mut text = text.clone();
+        for _ in 0..1 + rng.below(4) {
+            let kind = rng.below(3);
+            let len = text.len_chars();
+            let at = if kind < 2 && rng.below(4) > 0 { len - rng.below(len / 6) } else { rng.below(len) };
+            match kind {
+                0 => {
+                    let len = rng.below(40);
+                    text.insert(at, &random_text(rng, len));
+                }
+                1 => text.remove(at..(at + rng.below(40)).min(text.len_chars())),
+                // Same length, so only the touched blocks get written
+                _ if at < text.len_chars() && text.char(at).is_ascii() => {
+                    text.remove(at..at + 1);
+                    text.insert(at, "x");
+                }
+                _ => {}
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn patched_save_matches_full_rewrite() {
+        let path = temp_file("random");
+        let name = path.to_str().unwrap();
+        let mut patched_saves = 0;
+        for seed in 1..=40 {
+            let mut rng = Rng(seed);
+            let len = 4 * BLOCK + rng.below(3 * BLOCK);
+            let old = Rope::from_str(&random_text(&mut rng, len));
+            fs::write(&path, old.to_string()).unwrap();
+            let on_disk = OnDisk::record(name, &old).unwrap();
+
+            let new = random_edits(&mut rng, &old);
+            if save(&on_disk, name, &new).is_some() {
+                patched_saves += 1;
+                assert_eq!(fs::read(&path).unwrap(), new.to_string().into_bytes(), "seed {}", seed);
+            }
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(patched_saves >= 10, "only {} of 40 saves were patched", patched_saves);
+    }
+
+    #[test]
+    fn gives_up_when_file_changed_on_disk() {
+        let path = temp_file("changed");
+        let name = path.to_str().unwrap();
+        let old = Rope::from_str(&"line\n".repeat(BLOCK));
+        fs::write(&path, old.to_string()).unwrap();
+        let on_disk = OnDisk::record(name, &old).unwrap();
+        fs::write(&path, "someone else's text\n").unwrap();
+
+        let mut new = old.clone();
+        new.insert(0, "x");
+        assert_eq!(save(&on_disk, name, &new), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_blocks_spot_each_block() {
+        let old = Rope::from_str(&"a".repeat(3 * BLOCK));
+        let mut new = old.clone();
+        new.remove(BLOCK + 5..BLOCK + 6);
+        new.insert(BLOCK + 5, "b");
+        assert_eq!(changed_blocks(&old, &new), vec![1]);
+        new.remove(3 * BLOCK - 1..3 * BLOCK);
+        new.insert(3 * BLOCK - 1, "b");
+        assert_eq!(changed_blocks(&old, &new), vec![1, 2]);
+    }
+}
@@ -0,0 +1,52 @@
+// Timing samples for `:set profile` / `:profile`
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const SAMPLES: usize = 100; // Kept per operation, older ones fall off
+
+#[derive(Default)]
+pub(crate) struct Profiler {
+    pub(crate) enabled: bool,
+    samples: Vec<(&'static str, VecDeque<Duration>)>,
+}
+
+impl Profiler {
+    // Start of a timed section, None (and nothing else) when profiling is off
+    pub(crate) fn start(&self) -> Option<Instant> {
+        if self.enabled { Some(Instant::now()) } else { None }
+    }
+
+    pub(crate) fn record(&mut self, operation: &'static str, start: Option<Instant>) {
+        let Some(start) = start else { return; };
+        let elapsed = start.elapsed();
+
+        let index = match self.samples.iter().position(|(name, _)| *name == operation) {
+            Some(i) => i,
+            None => {
+                self.samples.push((operation, VecDeque::with_capacity(SAMPLES)));
+                self.samples.len() - 1
+            }
+        };
+        let samples = &mut self.samples[index].1;
+        if samples.len() == SAMPLES { samples.pop_front(); }
+        samples.push_back(elapsed);
+    }
+
+    // One line per operation: name, sample count, average and worst time
+    pub(crate) fn report(&self) -> String {
+        if self.samples.is_empty() {
+            let hint = if self.enabled { "" } else { " (turn it on with :set profile)" };
+            return format!("No samples yet{}", hint);
+        }
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mut lines = vec![format!("{:<12} {:>6} {:>9} {:>9}", "operation", "count", "avg ms", "worst ms")];
+        for (name, samples) in &self.samples {
+            let total: Duration = samples.iter().sum();
+            let worst = samples.iter().max().copied().unwrap_or_default();
+            let avg = total / samples.len().max(1) as u32;
+            lines.push(format!("{:<12} {:>6} {:>9.2} {:>9.2}", name, samples.len(), ms(avg), ms(worst)));
+        }
+        lines.join("\n")
+    }
+}
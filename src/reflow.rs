@@ -0,0 +1,292 @@
+// Word breaking shared by auto-wrap while typing and paragraph reflow (`:wrap`, `gq`)
+
+// Splits off the markdown structure at the start of a line
+// Returns (length in chars of the prefix on this line, prefix to use on continuation lines)
+// e.g. "  > - item" gives (6, "  >   ") so wrapped text hangs under the item
+pub fn prefixes(line: &str) -> (usize, String) {
+    let chars: Vec<char> = line.chars().collect();
+    let (i, mut cont) = quote_prefix(&chars);
+
+    // List markers turn into spaces of the same width
+    let marker_len = list_marker_len(&chars[i..]);
+    cont.push_str(&" ".repeat(marker_len));
+    (i + marker_len, cont)
+}
+
+// Indentation and any number of blockquote markers, they carry over as-is
+// Returns (length in chars, the prefix itself)
+fn quote_prefix(chars: &[char]) -> (usize, String) {
+    let mut i = 0;
+    let mut cont = String::new();
+
+    loop {
+        while i < chars.len() && (chars[i] == ' ' || chars[i] == '\t') {
+            cont.push(chars[i]);
+            i += 1;
+        }
+        if i < chars.len() && chars[i] == '>' {
+            cont.push('>');
+            i += 1;
+            if i < chars.len() && chars[i] == ' ' {
+                cont.push(' ');
+                i += 1;
+            }
+        } else {
+            break;
+        }
+    }
+    (i, cont)
+}
+
+// What the next line starts with to carry on this line's list item or quote: the same
+// indentation and quote markers, the same bullet or the next number, and an unchecked box
+// for task items. Returns (length in chars of this line's prefix, the new prefix), None when
+// the line isn't a list item or quote
+// e.g. "  3. [x] done" gives (9, "  4. [ ] "), or "  3. [ ] " with `same_number` for lists
+// numbered 1. 1. 1.
+pub fn next_item(line: &str, same_number: bool) -> Option<(usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let (quote_len, mut next) = quote_prefix(&chars);
+    let marker_len = list_marker_len(&chars[quote_len..]);
+    if marker_len == 0 && !next.contains('>') { return None; }
+
+    let marker = &chars[quote_len..quote_len + marker_len];
+    let digits = marker.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let number: usize = marker[..digits].iter().collect::<String>().parse().ok()?;
+        next.push_str(&format!("{}{} ", if same_number { number } else { number + 1 }, marker[digits]));
+    } else if let Some(bullet) = marker.first() {
+        next.push_str(&format!("{} ", bullet));
+    }
+    if marker.contains(&'[') { next.push_str("[ ] "); }
+    Some((quote_len + marker_len, next))
+}
+
+// Length of a list marker ("- ", "12. ", "* [ ] ") at the start of the slice, 0 if there isn't one
+pub fn list_marker_len(chars: &[char]) -> usize {
+    let mut i = 0;
+    match chars.first() {
+        Some('-') | Some('*') | Some('+') => i = 1,
+        Some(c) if c.is_ascii_digit() => {
+            while i < chars.len() && chars[i].is_ascii_digit() { i += 1; }
+            if i < chars.len() && (chars[i] == '.' || chars[i] == ')') {
+                i += 1;
+            } else {
+                return 0;
+            }
+        }
+        _ => return 0,
+    }
+
+    // Marker needs a space after it to count
+    if chars.get(i) != Some(&' ') { return 0; }
+    i += 1;
+
+    // Task list checkbox
+    if chars.len() >= i + 4 && chars[i] == '[' && chars[i + 2] == ']' && chars[i + 3] == ' ' {
+        i += 4;
+    }
+    i
+}
+
+// Finds where to break a line so the first part fits in `width` chars
+// Returns the index of the first space in the run to break on, or None when
+// there's no space after the prefix (a single long word like a URL stays whole)
+pub fn break_point(chars: &[char], width: usize, prefix_len: usize) -> Option<usize> {
+    if chars.len() <= width { return None; }
+
+    let mut i = std::cmp::min(width, chars.len() - 1);
+    while i > prefix_len {
+        if chars[i] == ' ' && chars[i - 1] != ' ' {
+            return Some(i);
+        }
+        i -= 1;
+    }
+    None
+}
+
+// Whether the line starts a list item, inside any quote markers
+pub fn is_list_item(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().collect();
+    list_marker_len(&chars[quote_prefix(&chars).0..]) > 0
+}
+
+// Where each word of a paragraph starts, as (line, char column), in reading order. The
+// first line's prefix (list marker, quote) and the later lines' indentation and quote
+// markers aren't words
+pub fn word_starts(lines: &[&str]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    for (y, line) in lines.iter().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let skip = if y == 0 { prefixes(line).0 } else { quote_prefix(&chars).0 };
+        for x in skip..chars.len() {
+            if !chars[x].is_whitespace() && (x == skip || chars[x - 1].is_whitespace()) {
+                starts.push((y, x));
+            }
+        }
+    }
+    starts
+}
+
+// Re-breaks a paragraph so no line is longer than `width` chars, a single longer word gets
+// a line to itself. The first line keeps its list marker or quote prefix, the lines after
+// it hang under it with the continuation prefix. Returns the new lines and where each word
+// starts in them (as word_starts)
+pub fn fill(lines: &[&str], width: usize) -> (Vec<String>, Vec<(usize, usize)>) {
+    let Some(first) = lines.first() else { return (Vec::new(), Vec::new()); };
+    let (prefix_len, cont) = prefixes(first);
+    let mut words = Vec::new();
+    for (y, line) in lines.iter().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let skip = if y == 0 { prefix_len } else { quote_prefix(&chars).0 };
+        let rest: String = chars[skip..].iter().collect();
+        words.extend(rest.split_whitespace().map(str::to_string));
+    }
+
+    let mut current: String = first.chars().take(prefix_len).collect();
+    let (mut filled, mut starts) = (Vec::new(), Vec::new());
+    let mut empty = true; // No word on the current line yet
+    for word in words {
+        let len = current.chars().count();
+        if !empty && len + 1 + word.chars().count() > width {
+            filled.push(std::mem::replace(&mut current, cont.clone()));
+            empty = true;
+        }
+        if !empty { current.push(' '); }
+        starts.push((filled.len(), current.chars().count()));
+        current.push_str(&word);
+        empty = false;
+    }
+    filled.push(current);
+    (filled, starts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(line: &str) -> Vec<char> {
+        line.chars().collect()
+    }
+
+    // The word starting at each (line, column)
+    fn words_at(lines: &[&str], starts: &[(usize, usize)]) -> Vec<String> {
+        starts.iter().map(|&(y, x)| lines[y].chars().skip(x).take_while(|c| !c.is_whitespace()).collect()).collect()
+    }
+
+    #[test]
+    fn prefixes_hang_under_markers_and_quotes() {
+        for (line, expected_len, expected_cont) in [
+            ("plain text", 0, ""),
+            ("  indented", 2, "  "),
+            ("- item", 2, "  "),
+            ("12. item", 4, "    "),
+            ("3) item", 3, "   "),
+            ("* [x] done", 6, "      "),
+            ("> quoted", 2, "> "),
+            ("> > nested", 4, "> > "),
+            ("  > - item", 6, "  >   "),
+            ("\t- tabbed", 3, "\t  "),
+            // Not markers: no space after, or a number without . or )
+            ("-dash", 0, ""),
+            ("2024 was", 0, ""),
+            ("*emphasis*", 0, ""),
+        ] {
+            assert_eq!(prefixes(line), (expected_len, expected_cont.to_string()), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn next_item_carries_on_the_list() {
+        for (line, same_number, expected) in [
+            ("- item", false, Some((2, "- "))),
+            ("+ item", false, Some((2, "+ "))),
+            ("  3. [x] done", false, Some((9, "  4. [ ] "))),
+            ("  3. [x] done", true, Some((9, "  3. [ ] "))),
+            ("9) nine", false, Some((3, "10) "))),
+            ("1. one", true, Some((3, "1. "))),
+            ("> quote", false, Some((2, "> "))),
+            ("> - quoted item", false, Some((4, "> - "))),
+            (">", false, Some((1, ">"))),
+            ("plain", false, None),
+            ("  indented", false, None),
+            ("-nospace", false, None),
+        ] {
+            let expected = expected.map(|(len, next)| (len, next.to_string()));
+            assert_eq!(next_item(line, same_number), expected, "{:?} same_number={}", line, same_number);
+        }
+    }
+
+    #[test]
+    fn break_point_finds_the_last_space_that_fits() {
+        for (line, width, prefix_len, expected) in [
+            ("short", 10, 0, None),
+            ("one two three", 10, 0, Some(7)),
+            ("one two three", 7, 0, Some(7)),
+            ("one two three", 6, 0, Some(3)),
+            // The first space of a run, so no spaces are left at the end of the line
+            ("one   two three", 8, 0, Some(3)),
+            // A single long word stays whole
+            ("https://example.com/a/long/path", 10, 0, None),
+            ("- https://example.com/a/long/path", 10, 2, None),
+            // The space after the marker isn't somewhere to break
+            ("- word another", 4, 2, None),
+            ("- word another", 8, 2, Some(6)),
+            // A width below the prefix never breaks inside it
+            ("  > - item text", 3, 6, None),
+            ("  > - item text here", 3, 6, None),
+        ] {
+            assert_eq!(break_point(&chars(line), width, prefix_len), expected, "{:?} at {}", line, width);
+        }
+    }
+
+    #[test]
+    fn fill_hangs_lines_under_the_first_prefix() {
+        for (lines, width, expected) in [
+            (vec!["one two three four five"], 9, vec!["one two", "three", "four five"]),
+            (vec!["one", "two", "three"], 80, vec!["one two three"]),
+            (vec!["- a list item that wraps"], 12, vec!["- a list", "  item that", "  wraps"]),
+            (vec!["3. [ ] a task that wraps"], 14, vec!["3. [ ] a task", "       that", "       wraps"]),
+            // Later lines' quote markers come off and go back on as the continuation
+            (vec!["> quoted text", "> more of it"], 10, vec!["> quoted", "> text", "> more of", "> it"]),
+            (vec!["  > - nested item", "  >   goes on"], 14, vec!["  > - nested", "  >   item", "  >   goes on"]),
+            // A word longer than the width gets a line of its own
+            (vec!["see https://example.com/long/path now"], 10, vec!["see", "https://example.com/long/path", "now"]),
+            (vec!["- https://example.com/long/path"], 10, vec!["- https://example.com/long/path"]),
+            // Below the prefix's width every word is on its own line, still under the prefix
+            (vec!["  > - a b c"], 3, vec!["  > - a", "  >   b", "  >   c"]),
+            (vec!["- "], 10, vec!["- "]),
+        ] {
+            assert_eq!(fill(&lines, width).0, expected, "{:?} at {}", lines, width);
+        }
+        assert_eq!(fill(&[], 10), (Vec::<String>::new(), Vec::new()));
+    }
+
+    #[test]
+    fn word_starts_skip_prefixes() {
+        let lines = ["> - first  word", ">   then more", ">"];
+        assert_eq!(word_starts(&lines), [(0, 4), (0, 11), (1, 4), (1, 9)]);
+        assert_eq!(words_at(&lines, &word_starts(&lines)), ["first", "word", "then", "more"]);
+        assert_eq!(word_starts(&["", "  "]), []);
+    }
+
+    // The editor keeps the cursor on its word by position in word_starts, so the nth start
+    // before and after a fill has to be the same word
+    #[test]
+    fn fill_tracks_each_word() {
+        for (lines, width) in [
+            (vec!["one two three four five"], 9),
+            (vec!["- a list item", "  that wraps  oddly"], 10),
+            (vec!["> quoted text", "> more of it"], 10),
+            (vec!["  > - nested item", "  >   goes on and on"], 14),
+            (vec!["see https://example.com/long/path now"], 10),
+            (vec!["  > - a b c"], 3),
+        ] {
+            let (filled, starts) = fill(&lines, width);
+            let filled: Vec<&str> = filled.iter().map(String::as_str).collect();
+            let before = words_at(&lines, &word_starts(&lines));
+            assert_eq!(words_at(&filled, &starts), before, "{:?} at {}", lines, width);
+            assert_eq!(word_starts(&filled), starts, "{:?} at {}", lines, width);
+        }
+    }
+}
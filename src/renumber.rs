@@ -0,0 +1,190 @@
+// Ordered list renumbering for `:renumber` (and after autolist adds an item)
+// Works on plain lines (no newlines) like tasks.rs, callers put the result back into the document
+use crate::document::fence_marker;
+use crate::export::heading;
+use crate::reflow::list_marker_len;
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn indent_of(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+// (indent, number, digit count) of a numbered item like "  3. text", None for anything else
+fn numbered(line: &str) -> Option<(usize, usize, usize)> {
+    let indent = indent_of(line);
+    let chars: Vec<char> = line.chars().skip(indent).collect();
+    if list_marker_len(&chars) == 0 { return None; }
+    let digits = chars.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 { return None; }
+    let number = chars[..digits].iter().collect::<String>().parse().ok()?;
+    Some((indent, number, digits))
+}
+
+// A numbered item and the list it's in, lists are numbered from 0 in block order
+struct Item {
+    list: usize,
+    number: usize,
+    indent: usize,
+    digits: usize,
+}
+
+// The numbered items in `lines`. Nested lists are told apart by their indentation, a bullet
+// item at the same indentation ends a numbered list, and code fences are skipped
+fn items(lines: &[&str]) -> Vec<Option<Item>> {
+    let mut counters: Vec<(usize, usize)> = Vec::new(); // (indent, list), innermost last
+    let mut lists = 0;
+    let mut fence: Option<(char, usize)> = None;
+    let mut found = Vec::with_capacity(lines.len());
+
+    for line in lines {
+        if let Some(marker) = fence_marker(line) {
+            fence = match fence {
+                None => Some(marker),
+                Some((c, len)) if c == marker.0 && marker.1 >= len => None,
+                open => open,
+            };
+            found.push(None);
+            continue;
+        }
+
+        let indent = indent_of(line);
+        let is_item = list_marker_len(&line.chars().skip(indent).collect::<Vec<_>>()) > 0;
+        if fence.is_some() || !is_item {
+            found.push(None);
+            continue;
+        }
+        // Lists nested deeper than this item are over
+        while counters.last().is_some_and(|(i, _)| *i > indent) { counters.pop(); }
+        match numbered(line) {
+            Some((_, number, digits)) => {
+                let list = match counters.last() {
+                    Some((i, list)) if *i == indent => *list,
+                    _ => {
+                        lists += 1;
+                        counters.push((indent, lists - 1));
+                        lists - 1
+                    }
+                };
+                found.push(Some(Item { list, number, indent, digits }));
+            }
+            // A bullet at the same level ends the numbered list there
+            None => {
+                if counters.last().is_some_and(|(i, _)| *i == indent) { counters.pop(); }
+                found.push(None);
+            }
+        }
+    }
+    found
+}
+
+// Lists that give every item the same number ("1." all the way down, which markdown counts
+// up by itself). Those are numbered that way on purpose and get left alone
+fn lazy_lists(items: &[Option<Item>]) -> Vec<bool> {
+    let lists = items.iter().flatten().map(|item| item.list + 1).max().unwrap_or(0);
+    let mut first: Vec<Option<usize>> = vec![None; lists];
+    let mut count = vec![0; lists];
+    let mut lazy = vec![true; lists];
+    for item in items.iter().flatten() {
+        count[item.list] += 1;
+        match first[item.list] {
+            None => first[item.list] = Some(item.number),
+            Some(number) if number != item.number => lazy[item.list] = false,
+            _ => (),
+        }
+    }
+    lazy.iter().zip(count).map(|(lazy, count)| *lazy && count > 1).collect()
+}
+
+// First and last line of the block around y, which ends at blank lines and headings
+fn block(lines: &[&str], y: usize) -> (usize, usize) {
+    let in_block = |line: &str| !is_blank(line) && heading(line).is_none();
+    let mut first = y;
+    while first > 0 && in_block(lines[first - 1]) { first -= 1; }
+    let mut last = y;
+    while last + 1 < lines.len() && in_block(lines[last + 1]) { last += 1; }
+    (first, last)
+}
+
+// Renumbers the lists in the block of lines around y (up to blank lines and headings) so
+// each one counts up by one from its first item. Nested lists are counted apart by their
+// indentation, a bullet item at the same indentation ends a numbered list, and anything
+// else (continuation lines, code fences) is left as it is, as are lazy lists (1. 1. 1.)
+// Returns (first line, last line, replacement text, number of lines changed), None when
+// the block has no numbered items
+pub fn renumber(lines: &[&str], y: usize) -> Option<(usize, usize, String, usize)> {
+    if y >= lines.len() || is_blank(lines[y]) { return None; }
+    let (first, last) = block(lines, y);
+    let items = items(&lines[first..=last]);
+    if items.iter().all(Option::is_none) { return None; }
+    let lazy = lazy_lists(&items);
+
+    let mut next: Vec<Option<usize>> = vec![None; lazy.len()];
+    let mut changed = 0;
+    let mut text = String::new();
+    for (line, item) in lines[first..=last].iter().zip(&items) {
+        match item {
+            Some(item) if !lazy[item.list] => {
+                let expected = *next[item.list].get_or_insert(item.number);
+                next[item.list] = Some(expected + 1);
+                if expected != item.number {
+                    let rest: String = line.chars().skip(item.indent + item.digits).collect();
+                    text.push_str(&format!("{}{}{}", &line[..item.indent], expected, rest));
+                    changed += 1;
+                } else {
+                    text.push_str(line);
+                }
+            }
+            _ => text.push_str(line),
+        }
+        text.push('\n');
+    }
+    Some((first, last, text, changed))
+}
+
+// Whether the numbered item on line y is in a lazy list, so the next item gets the same number
+pub fn is_lazy(lines: &[&str], y: usize) -> bool {
+    if y >= lines.len() || is_blank(lines[y]) { return false; }
+    let (first, last) = block(lines, y);
+    let items = items(&lines[first..=last]);
+    let lazy = lazy_lists(&items);
+    items[y - first].as_ref().is_some_and(|item| lazy[item.list])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renumbered(text: &str, y: usize) -> Option<(String, usize)> {
+        let lines: Vec<&str> = text.lines().collect();
+        renumber(&lines, y).map(|(_, _, text, changed)| (text, changed))
+    }
+
+    #[test]
+    fn counts_up_from_the_first_item() {
+        assert_eq!(renumbered("3. a\n3. b\n7. c\n", 0), Some(("3. a\n4. b\n5. c\n".to_string(), 2)));
+        assert_eq!(renumbered("1. a\n   1. x\n   5. y\n9. b\n", 0), Some(("1. a\n   1. x\n   2. y\n2. b\n".to_string(), 2)));
+        assert_eq!(renumbered("- a\n- b\n", 0), None);
+    }
+
+    #[test]
+    fn leaves_lazy_lists_alone() {
+        assert_eq!(renumbered("1. a\n1. b\n1. c\n", 1), Some(("1. a\n1. b\n1. c\n".to_string(), 0)));
+        // Only the lazy one is skipped
+        assert_eq!(renumbered("1. a\n   1. x\n   1. y\n1. b\n4. c\n", 0), Some(("1. a\n   1. x\n   1. y\n2. b\n3. c\n".to_string(), 2)));
+        // A list with a second number in it isn't lazy
+        assert_eq!(renumbered("1. a\n1. b\n2. c\n", 0), Some(("1. a\n2. b\n3. c\n".to_string(), 2)));
+    }
+
+    #[test]
+    fn lazy_needs_two_items() {
+        let lines = ["1. a", "1. b", "", "1. c", "   1. x", "2. d"];
+        assert!(is_lazy(&lines, 0));
+        assert!(is_lazy(&lines, 1));
+        assert!(!is_lazy(&lines, 3));
+        assert!(!is_lazy(&lines, 4));
+        assert!(!is_lazy(&lines, 2));
+    }
+}
@@ -0,0 +1,195 @@
+// Searching shared by :find, / and ?, n/N, match highlighting and the match counter
+use crate::document::Document;
+use regex::Regex;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+#[derive(Clone)]
+pub struct Query {
+    pub pattern: String,
+    pub backward: bool,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    pub regex: bool, // Otherwise the pattern is matched literally
+}
+
+impl Query {
+    // Case sensitive only when the pattern has a capital in it (vim's smartcase)
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            backward: false,
+            case_sensitive: pattern.chars().any(char::is_uppercase),
+            whole_word: false,
+            regex: false,
+        }
+    }
+}
+
+// One hit, in chars: line and [start, end) columns
+#[derive(Clone, Copy, PartialEq)]
+pub struct Match {
+    pub y: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+// A match to substitute: where it is in the line (bytes, and its start in chars for the
+// cursor) and the text that goes in its place
+pub struct Replacement {
+    pub bytes: Range<usize>,
+    pub start: usize,
+    pub text: String,
+}
+
+// A query compiled once and reused by every consumer
+#[derive(Clone)]
+pub struct Search {
+    pub query: Query,
+    regex: Regex,
+    matches: RefCell<Option<(u64, Rc<Vec<Match>>)>>, // all_matches for a document revision, so n doesn't rescan the file
+}
+
+impl Search {
+    pub fn new(query: Query) -> Result<Self, String> {
+        if query.pattern.is_empty() { return Err("Empty search".to_string()); }
+
+        let mut pattern = if query.regex { query.pattern.clone() } else { regex::escape(&query.pattern) };
+        if query.whole_word { pattern = format!(r"\b(?:{})\b", pattern); }
+        if !query.case_sensitive { pattern = format!("(?i){}", pattern); }
+
+        let regex = Regex::new(&pattern).map_err(|e| format!("Bad pattern: {}", e))?;
+        Ok(Self { query, regex, matches: RefCell::default() })
+    }
+
+    // Non-empty matches on one line as char column ranges
+    pub fn line_matches(&self, line: &str) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        let mut chars_before = 0;
+        let mut last_byte = 0;
+        for m in self.regex.find_iter(line) {
+            if m.start() == m.end() { continue; }
+            chars_before += line[last_byte..m.start()].chars().count();
+            let len = m.as_str().chars().count();
+            found.push((chars_before, chars_before + len));
+            chars_before += len;
+            last_byte = m.end();
+        }
+        found
+    }
+
+    // Matches on line y, read straight from the rope (a line inside one chunk isn't copied)
+    pub fn matches_on(&self, doc: &Document, y: usize) -> Vec<(usize, usize)> {
+        let line: Cow<str> = doc.rope.line(y).into();
+        self.line_matches(line.trim_end_matches(['\n', '\r']))
+    }
+
+    // Each match on one line with what `replacement` turns it into (a regex query can use $1
+    // or ${name} for its groups). Unlike searching, empty matches count here so s/^/> / works
+    pub fn replacements(&self, line: &str, replacement: &str) -> Vec<Replacement> {
+        let mut found = Vec::new();
+        let mut chars_before = 0;
+        let mut last_byte = 0;
+        for caps in self.regex.captures_iter(line) {
+            let Some(m) = caps.get(0) else { continue; };
+            chars_before += line[last_byte..m.start()].chars().count();
+            let mut text = String::new();
+            if self.query.regex { caps.expand(replacement, &mut text); } else { text.push_str(replacement); }
+            found.push(Replacement { bytes: m.range(), start: chars_before, text });
+            chars_before += m.as_str().chars().count();
+            last_byte = m.end();
+        }
+        found
+    }
+
+    // Every match in the document, in order. Kept until the document changes
+    pub fn all_matches(&self, doc: &Document) -> Rc<Vec<Match>> {
+        let mut cache = self.matches.borrow_mut();
+        if let Some((revision, matches)) = cache.as_ref() && *revision == doc.revision() {
+            return matches.clone();
+        }
+        let mut found = Vec::new();
+        for y in 0..doc.len() {
+            found.extend(self.matches_on(doc, y).into_iter().map(|(start, end)| Match { y, start, end }));
+        }
+        let found = Rc::new(found);
+        *cache = Some((doc.revision(), found.clone()));
+        found
+    }
+
+    // The first match after (x, y), or before it going backward, wrapping around. Stops at
+    // the first line with a hit instead of collecting the whole document, so incremental
+    // search can run it on every keystroke
+    pub fn nearest(&self, doc: &Document, x: usize, y: usize, backward: bool) -> Option<Match> {
+        let len = doc.len();
+        if len == 0 { return None; }
+        // The start line comes round twice: first for the part past x, last for the rest
+        for i in 0..=len {
+            let line = if backward { (y + len - i % len) % len } else { (y + i) % len };
+            let mut found = self.matches_on(doc, line).into_iter().filter(|&(start, _)| match (i == 0, i == len) {
+                (true, _) => if backward { start < x } else { start > x },
+                (_, true) => if backward { start >= x } else { start <= x },
+                _ => true,
+            });
+            let found = if backward { found.next_back() } else { found.next() };
+            if let Some((start, end)) = found { return Some(Match { y: line, start, end }); }
+        }
+        None
+    }
+
+    // The `count`th match from (x, y) in the query's direction (reversed if asked), wrapping
+    // around the document. Returns the match, its 1-based index and the total
+    // Steps with `nearest`, so n/N land exactly where incremental search does
+    pub fn find(&self, doc: &Document, x: usize, y: usize, count: usize, reverse: bool) -> Option<(Match, usize, usize)> {
+        let backward = self.query.backward != reverse;
+        let mut found = self.nearest(doc, x, y, backward)?;
+        for _ in 1..count.max(1) {
+            found = self.nearest(doc, found.start, found.y, backward)?;
+        }
+        let all = self.all_matches(doc);
+        let index = all.binary_search_by_key(&(found.y, found.start), |m| (m.y, m.start)).ok()?;
+        Some((found, index + 1, all.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::Position;
+
+    #[test]
+    fn matches_are_kept_until_the_text_changes() {
+        let mut doc = Document::from_text("cat dog\ndog cat cat\n");
+        let search = Search::new(Query::new("cat")).unwrap();
+        let first = search.all_matches(&doc);
+        assert_eq!(first.len(), 3);
+        assert!(Rc::ptr_eq(&first, &search.all_matches(&doc)));
+
+        doc.insert_str(&Position { x: 0, y: 0 }, "cat ");
+        let after = search.all_matches(&doc);
+        assert!(!Rc::ptr_eq(&first, &after));
+        assert_eq!(after.len(), 4);
+
+        // Another document with the same text isn't taken for this one
+        let other = Document::from_text("cat\n");
+        assert_eq!(search.all_matches(&other).len(), 1);
+    }
+
+    #[test]
+    fn find_counts_from_the_cursor() {
+        let doc = Document::from_text("cat dog\ndog cat cat\n");
+        let search = Search::new(Query::new("cat")).unwrap();
+        let (found, index, total) = search.find(&doc, 0, 0, 1, false).unwrap();
+        assert_eq!((found.y, found.start, index, total), (1, 4, 2, 3));
+        let (found, index, _) = search.find(&doc, 0, 0, 2, false).unwrap();
+        assert_eq!((found.y, found.start, index), (1, 8, 3));
+        // Wraps around, and N goes the other way
+        let (found, index, _) = search.find(&doc, 8, 1, 1, false).unwrap();
+        assert_eq!((found.y, found.start, index), (0, 0, 1));
+        let (found, index, _) = search.find(&doc, 0, 0, 1, true).unwrap();
+        assert_eq!((found.y, found.start, index), (1, 8, 3));
+        assert!(Search::new(Query::new("bird")).unwrap().find(&doc, 0, 0, 1, false).is_none());
+    }
+}
@@ -0,0 +1,45 @@
+// :!cmd runs a shell command on the real terminal, like vim's. The editor steps aside while
+// it runs, its output stays on screen until Enter, then the editor comes back
+use std::io::{self, BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+
+// Runs `command` with $SHELL (sh if that isn't set) and waits for it and then for Enter. The
+// caller hands over the terminal first and takes it back afterwards
+pub fn run(command: &str) -> io::Result<ExitStatus> {
+    let shell = std::env::var("SHELL").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| "sh".to_string());
+    let mut child = Command::new(shell);
+    child.arg("-c").arg(command);
+
+    // Ctrl+C and Ctrl+\ go to the whole foreground process group, they're meant for the
+    // command and mustn't take the editor down with it. They're ignored before the spawn so
+    // there's no moment where the command is running and the editor isn't covered yet
+    // SAFETY: signal() with SIG_IGN installs no handler of ours, and nothing else in the
+    // editor touches these two signals, so swapping them out and back can't race anything
+    let (int, quit) = unsafe { (libc::signal(libc::SIGINT, libc::SIG_IGN), libc::signal(libc::SIGQUIT, libc::SIG_IGN)) };
+
+    // Ignored signals stay ignored across exec, so the command puts them back to the default
+    // for itself, otherwise Ctrl+C couldn't stop it
+    // SAFETY: this runs in the forked child before exec, where only async-signal-safe calls
+    // are allowed. signal() is one, and the closure neither allocates nor takes locks
+    unsafe {
+        child.pre_exec(|| {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+            Ok(())
+        });
+    }
+
+    let status = child.spawn().and_then(|mut child| child.wait()).and_then(|status| {
+        print!("\r\n[{}] Press Enter to go back to vellum", status);
+        io::stdout().flush()?;
+        io::stdin().lock().read_line(&mut String::new())?;
+        Ok(status)
+    });
+    // SAFETY: puts back exactly what the first signal() calls returned
+    unsafe {
+        libc::signal(libc::SIGINT, int);
+        libc::signal(libc::SIGQUIT, quit);
+    }
+    status
+}
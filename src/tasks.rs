@@ -0,0 +1,246 @@
+// Task list reordering for `:tasks sort` and `:tasks archive`
+// Works on plain lines (no newlines), callers put the result back into the document
+use crate::document::fence_marker;
+use crate::export::heading;
+use crate::reflow::list_marker_len;
+
+const ARCHIVE_HEADING: &str = "## Archive";
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+fn indent_of(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+fn is_item(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().skip(indent_of(line)).collect();
+    list_marker_len(&chars) > 0
+}
+
+// Some(true) for "- [x]", Some(false) for "- [ ]", None for anything that isn't a task
+fn checkbox(line: &str) -> Option<bool> {
+    let chars: Vec<char> = line.chars().skip(indent_of(line)).collect();
+    if list_marker_len(&chars) == 0 { return None; }
+
+    // The box sits right after the marker and its space
+    let m = chars.iter().position(|c| *c == ' ')? + 1;
+    if chars.len() < m + 3 || chars[m] != '[' || chars[m + 2] != ']' { return None; }
+    if chars.get(m + 3).is_some_and(|c| *c != ' ') { return None; }
+    match chars[m + 1] {
+        'x' | 'X' => Some(true),
+        ' ' => Some(false),
+        _ => None,
+    }
+}
+
+// End (exclusive) of the item starting at line i: its continuation lines and
+// anything indented deeper (sub-items) come along with it
+fn item_end(lines: &[&str], i: usize) -> usize {
+    let indent = indent_of(lines[i]);
+    let mut j = i + 1;
+    while j < lines.len() && !is_blank(lines[j]) && heading(lines[j]).is_none() {
+        let deeper = indent_of(lines[j]) > indent;
+        // A fence that isn't indented under the item starts a code block of its own
+        if !deeper && (is_item(lines[j]) || fence_marker(lines[j]).is_some()) { break; }
+        j += 1;
+    }
+    j
+}
+
+// The fence still open after `line`, given the one open before it
+fn next_fence(fence: Option<(char, usize)>, line: &str) -> Option<(char, usize)> {
+    match (fence, fence_marker(line)) {
+        (None, marker) => marker,
+        (Some((c, len)), Some((mc, mlen))) if c == mc && mlen >= len => None,
+        (open, _) => open,
+    }
+}
+
+// Whether line y is in a code block, its fence lines included
+fn in_fence(lines: &[&str], y: usize) -> bool {
+    let fence = lines[..y].iter().fold(None, |fence, line| next_fence(fence, line));
+    fence.is_some() || fence_marker(lines[y]).is_some()
+}
+
+// Items of the list around line y, as line ranges, all at the same indent
+fn list_items(lines: &[&str], y: usize) -> Result<Vec<(usize, usize)>, String> {
+    let not_a_list = || "No task list under cursor".to_string();
+    if y >= lines.len() || is_blank(lines[y]) || in_fence(lines, y) { return Err(not_a_list()); }
+
+    // Walk up to the start of the paragraph, then down to its first item
+    let mut start = y;
+    while start > 0 && !is_blank(lines[start - 1]) && heading(lines[start - 1]).is_none() && fence_marker(lines[start - 1]).is_none() {
+        start -= 1;
+    }
+    let start = (start..=y).find(|i| is_item(lines[*i])).ok_or_else(not_a_list)?;
+    let base = indent_of(lines[start]);
+
+    let mut items = Vec::new();
+    let mut i = start;
+    while i < lines.len() && is_item(lines[i]) && indent_of(lines[i]) == base {
+        let end = item_end(lines, i);
+        items.push((i, end));
+        i = end;
+    }
+    if i <= y { return Err(not_a_list()); }
+    Ok(items)
+}
+
+// Unchecked items first, checked ones last, each group keeping its order
+// Returns (first line, last line, replacement text, number of items, number checked)
+pub fn sort(lines: &[&str], y: usize) -> Result<(usize, usize, String, usize, usize), String> {
+    let items = list_items(lines, y)?;
+    if items.iter().all(|(i, _)| checkbox(lines[*i]).is_none()) {
+        return Err("No task list under cursor".to_string());
+    }
+
+    let (done, open): (Vec<_>, Vec<_>) = items.iter().partition(|(i, _)| checkbox(lines[*i]) == Some(true));
+    let mut text = String::new();
+    for (from, to) in open.iter().chain(done.iter()) {
+        for line in &lines[*from..*to] {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+
+    let (first, last) = (items[0].0, items[items.len() - 1].1 - 1);
+    Ok((first, last, text, items.len(), done.len()))
+}
+
+// Moves every checked item (with its children) under the archive heading at the end,
+// returns the whole new document and how many items moved
+pub fn archive(lines: &[&str]) -> (String, usize) {
+    // An existing archive section runs until the next heading of the same or higher level
+    let section_start = lines.iter().position(|line| line.trim_end() == ARCHIVE_HEADING);
+    let section_end = section_start.map(|s| {
+        (s + 1..lines.len())
+            .find(|i| heading(lines[*i]).is_some_and(|(level, _)| level <= 2))
+            .unwrap_or(lines.len())
+    });
+    let in_section = |i: usize| section_start.is_some_and(|s| i >= s && i < section_end.unwrap_or(0));
+
+    let mut kept: Vec<&str> = Vec::new();
+    let mut moved: Vec<String> = Vec::new();
+    let mut count = 0;
+    let mut fence: Option<(char, usize)> = None;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        fence = next_fence(fence, line);
+        if fence.is_none() && !in_section(i) && checkbox(line) == Some(true) {
+            // Children keep their nesting relative to the item
+            let indent = indent_of(line);
+            let end = item_end(lines, i);
+            for child in &lines[i..end] {
+                let strip = std::cmp::min(indent, indent_of(child));
+                moved.push(child.chars().skip(strip).collect());
+            }
+            count += 1;
+            i = end;
+            continue;
+        }
+        kept.push(line);
+        i += 1;
+    }
+
+    if count == 0 {
+        return (lines.iter().map(|line| format!("{}\n", line)).collect(), 0);
+    }
+
+    let mut out: Vec<String> = kept.iter().map(|line| line.to_string()).collect();
+    match kept.iter().position(|line| line.trim_end() == ARCHIVE_HEADING) {
+        Some(s) => {
+            // Append after the last non-blank line of the section
+            let mut at = (s + 1..out.len())
+                .find(|i| heading(&out[*i]).is_some_and(|(level, _)| level <= 2))
+                .unwrap_or(out.len());
+            while at > s + 1 && is_blank(&out[at - 1]) { at -= 1; }
+            if at == s + 1 {
+                out.insert(at, String::new());
+                at += 1;
+            }
+            for (offset, line) in moved.into_iter().enumerate() {
+                out.insert(at + offset, line);
+            }
+        }
+        None => {
+            while out.last().is_some_and(|line| is_blank(line)) { out.pop(); }
+            if !out.is_empty() { out.push(String::new()); }
+            out.push(ARCHIVE_HEADING.to_string());
+            out.push(String::new());
+            out.extend(moved);
+        }
+    }
+    (out.iter().map(|line| format!("{}\n", line)).collect(), count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.lines().collect()
+    }
+
+    #[test]
+    fn sort_moves_items_with_their_children() {
+        let text = "# Todo\n- [x] done\n  - sub of done\n  continuation\n- [ ] open\n  - [x] child\n- [ ] two\n\nafter\n";
+        let sorted = "- [ ] open\n  - [x] child\n- [ ] two\n- [x] done\n  - sub of done\n  continuation\n";
+        // From any line of the list, sub-items included, it's the top level that's sorted
+        for y in [1, 2, 3, 5, 6] {
+            assert_eq!(sort(&lines(text), y), Ok((1, 6, sorted.to_string(), 3, 1)), "from line {}", y);
+        }
+        for y in [0, 7, 8] {
+            assert_eq!(sort(&lines(text), y), Err("No task list under cursor".to_string()), "from line {}", y);
+        }
+        assert!(sort(&lines("- plain\n- list\n"), 0).is_err());
+    }
+
+    #[test]
+    fn sort_leaves_code_blocks_alone() {
+        let text = "```\n- [x] in code\n- [ ] too\n```\n- [x] a\n- [ ] b\n```\n- [ ] after\n```\n";
+        for y in [0, 1, 2, 3, 6, 7, 8] {
+            assert!(sort(&lines(text), y).is_err(), "from line {}", y);
+        }
+        // The list between the fences, without either of them
+        assert_eq!(sort(&lines(text), 5), Ok((4, 5, "- [ ] b\n- [x] a\n".to_string(), 2, 1)));
+    }
+
+    #[test]
+    fn archive_creates_the_section() {
+        let text = "# Todo\n- [x] done\n  - sub\n- [ ] open\n  - [x] nested\n    detail\n\n\n";
+        let expected = "# Todo\n- [ ] open\n\n## Archive\n\n- [x] done\n  - sub\n- [x] nested\n  detail\n";
+        assert_eq!(archive(&lines(text)), (expected.to_string(), 2));
+
+        // Nothing but done tasks
+        assert_eq!(archive(&lines("- [x] a\n")), ("## Archive\n\n- [x] a\n".to_string(), 1));
+        let nothing = "- [ ] a\n- b\n";
+        assert_eq!(archive(&lines(nothing)), (nothing.to_string(), 0));
+    }
+
+    #[test]
+    fn archive_appends_to_an_existing_section() {
+        let text = "# T\n- [x] new\n\n## Archive\n\n- [x] old\n\n## Later\nx\n";
+        let expected = "# T\n\n## Archive\n\n- [x] old\n- [x] new\n\n## Later\nx\n";
+        assert_eq!(archive(&lines(text)), (expected.to_string(), 1));
+
+        // An empty one, and one at the end
+        assert_eq!(archive(&lines("- [x] a\n## Archive\n")), ("## Archive\n\n- [x] a\n".to_string(), 1));
+        let text = "- [X] b\n- [ ] c\n\n## Archive\n\n- [x] a\n\n";
+        assert_eq!(archive(&lines(text)), ("- [ ] c\n\n## Archive\n\n- [x] a\n- [X] b\n\n".to_string(), 1));
+    }
+
+    #[test]
+    fn archive_skips_fenced_tasks() {
+        let text = "```\n- [x] code\n```\n- [x] real\n~~~~\n- [x] more code\n~~~\n- [x] still code\n~~~~\n";
+        let expected = "```\n- [x] code\n```\n~~~~\n- [x] more code\n~~~\n- [x] still code\n~~~~\n\n## Archive\n\n- [x] real\n";
+        assert_eq!(archive(&lines(text)), (expected.to_string(), 1));
+
+        // A fence straight after a done item isn't part of it
+        let text = "- [x] done\n```\n- [ ] code\n```\n";
+        assert_eq!(archive(&lines(text)), ("```\n- [ ] code\n```\n\n## Archive\n\n- [x] done\n".to_string(), 1));
+    }
+}
@@ -1,49 +1,145 @@
 use crossterm::{
     cursor,
-    event::{read, Event, KeyEvent, KeyEventKind},
-    queue,
-    style::{Print, Color, SetBackgroundColor, SetForegroundColor},
-    terminal::{enable_raw_mode, size, Clear, ClearType},
+    event::{poll, read, DisableBracketedPaste, EnableBracketedPaste, Event, KeyEvent, KeyEventKind},
+    execute, queue,
+    style::{Attribute, Colored, Print, Color, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{disable_raw_mode, enable_raw_mode, size, Clear, ClearType},
 };
-use std::io::{self, stdout, Write};
+use std::io::{stdout, Write};
+use std::time::Duration;
 
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
 
+// What the terminal hands over: a keypress, or a whole paste at once. With bracketed paste
+// the terminal marks pasted text, so it doesn't arrive as one keypress per char
+#[derive(Clone, PartialEq)]
+pub enum Input {
+    Key(KeyEvent),
+    Paste(String),
+}
+
 pub struct Terminal {
     size: Size,
-    stdout: io::Stdout,
+    stdout: Box<dyn Write>, // The real stdout, or a buffer when tests render into one
+    color: bool, // Off for NO_COLOR, --no-color and :set nocolor
+    #[cfg(test)]
+    script: Option<capture::Script>, // Where a test terminal's input comes from, None for the real one
 }
 
 impl Terminal {
     pub fn default() -> Result<Self, std::io::Error> {
         enable_raw_mode()?;
+        execute!(stdout(), EnableBracketedPaste)?;
         Ok(Self {
             size: Size {
                 width: size()?.0,
                 height: size()?.1,
             },
-            stdout: stdout(),
+            stdout: Box::new(stdout()),
+            color: true,
+            #[cfg(test)]
+            script: None,
         })
     }
 
+    // A terminal that draws into `out` instead of the screen, without touching raw mode.
+    // `color` is only this terminal's, crossterm's own switch is left to set_color. Its
+    // input is whatever `script` feeds it
+    #[cfg(test)]
+    pub fn to_writer(out: Box<dyn Write>, width: u16, height: u16, color: bool) -> Self {
+        Self { size: Size { width, height }, stdout: out, color, script: Some(capture::Script::default()) }
+    }
+
+    // Queues input for a terminal made by to_writer, after whatever is queued already
+    #[cfg(test)]
+    pub(crate) fn script(&mut self, steps: impl IntoIterator<Item = capture::Scripted>) {
+        self.script.as_mut().expect("only a test terminal takes a script").steps.extend(steps);
+    }
+
     pub fn size(&self) -> &Size {
         &self.size
     }
 
-    pub fn read_key() -> Result<KeyEvent, std::io::Error> {
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
+    // crossterm checks NO_COLOR on its own, so it's told too or `:set color` couldn't win
+    pub fn set_color(&mut self, on: bool) {
+        self.color = on;
+        Colored::set_ansi_color_disabled(!on);
+    }
+
+    // Blocks for the next keypress (presses only, not releases) or paste. Returns None when
+    // the window is resized so it can be redrawn
+    pub fn read_input(&mut self) -> Result<Option<Input>, std::io::Error> {
+        #[cfg(test)]
+        if let Some(script) = &mut self.script { return script.read(); }
         loop {
-            if let Event::Key(event) = read()? {
-                // FILTER: Only return the event if it is a keypress, not release
-                if event.kind == KeyEventKind::Press {
-                    return Ok(event);
-                }
+            match read()? {
+                Event::Key(event) if event.kind == KeyEventKind::Press => return Ok(Some(Input::Key(event))),
+                Event::Paste(text) => return Ok(Some(Input::Paste(text))),
+                Event::Resize(..) => return Ok(None),
+                _ => (),
             }
         }
     }
 
+    // The size is cached, this picks up resizes. True when it changed
+    pub fn update_size(&mut self) -> bool {
+        #[cfg(test)]
+        if let Some(script) = &mut self.script {
+            let Some((width, height)) = script.resized.take() else { return false; };
+            let changed = (width, height) != (self.size.width, self.size.height);
+            self.size = Size { width, height };
+            return changed;
+        }
+        let Ok((width, height)) = size() else { return false; };
+        let changed = (width, height) != (self.size.width, self.size.height);
+        self.size = Size { width, height };
+        changed
+    }
+
+    // Hands the terminal back in a usable state (on quit, and after a crash)
+    pub fn restore(&mut self) {
+        #[cfg(test)]
+        if self.script.is_some() { return; }
+        let _ = execute!(self.stdout, DisableBracketedPaste);
+        let _ = disable_raw_mode();
+        let _ = queue!(self.stdout, cursor::Show, SetForegroundColor(Color::Reset), SetBackgroundColor(Color::Reset));
+        let _ = self.stdout.flush();
+    }
+
+    // Takes the terminal back after restore() handed it to another program (:tutor, :!), the
+    // screen is redrawn from scratch on the next refresh. Colors or attributes the program
+    // left switched on are reset first
+    pub fn resume(&mut self) -> Result<(), std::io::Error> {
+        #[cfg(test)]
+        if self.script.is_some() { return Ok(()); }
+        enable_raw_mode()?;
+        execute!(self.stdout, EnableBracketedPaste, SetAttribute(Attribute::Reset), SetForegroundColor(Color::Reset),
+            SetBackgroundColor(Color::Reset), Clear(ClearType::All))?;
+        self.update_size();
+        Ok(())
+    }
+
+    // Returns a keypress or paste only if one is already waiting, never blocks
+    pub fn poll_input(&mut self) -> Result<Option<Input>, std::io::Error> {
+        #[cfg(test)]
+        if let Some(script) = &mut self.script { return Ok(script.poll()); }
+        while poll(Duration::ZERO)? {
+            match read()? {
+                Event::Key(event) if event.kind == KeyEventKind::Press => return Ok(Some(Input::Key(event))),
+                Event::Paste(text) => return Ok(Some(Input::Paste(text))),
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+
     // --- BUFFERED COMMANDS (These don't happen until flush is called) ---
 
     pub fn clear_screen(&mut self) {
@@ -71,21 +167,311 @@ impl Terminal {
         queue!(self.stdout, Print(string)).unwrap();
     }
 
+    // Without colors a background turns into reverse video, so the status bar, selections
+    // and matches still stand out. Foreground colors are dropped
     pub fn set_bg_color(&mut self, color: Color) {
+        if !self.color {
+            self.set_attribute(Attribute::Reverse);
+            return;
+        }
         queue!(self.stdout, SetBackgroundColor(color)).unwrap();
     }
 
     pub fn set_fg_color(&mut self, color: Color) {
+        if !self.color { return; }
         queue!(self.stdout, SetForegroundColor(color)).unwrap();
     }
 
     pub fn reset_colors(&mut self) {
+        if !self.color {
+            self.reset_attributes();
+            return;
+        }
         queue!(self.stdout, SetForegroundColor(Color::Reset)).unwrap();
         queue!(self.stdout, SetBackgroundColor(Color::Reset)).unwrap();
     }
 
+    pub fn set_attribute(&mut self, attribute: Attribute) {
+        queue!(self.stdout, SetAttribute(attribute)).unwrap();
+    }
+
+    pub fn reset_attributes(&mut self) {
+        queue!(self.stdout, SetAttribute(Attribute::Reset)).unwrap();
+    }
+
     // Send all queued changes to the screen at once
     pub fn flush(&mut self) -> Result<(), std::io::Error> {
         self.stdout.flush()
     }
-}
\ No newline at end of file
+}
+// Lets tests render into memory and read back what would have reached the screen
+#[cfg(test)]
+pub(crate) mod capture {
+    use super::Input;
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    // One thing happening at a test terminal, in order
+    #[derive(Clone)]
+    pub(crate) enum Scripted {
+        Typed(Input), // Already waiting when the editor looks: poll_input sees it, like type-ahead
+        Pressed(Input), // Only arrives once the editor blocks for input
+        Resize(u16, u16), // The window changes size while the editor waits
+    }
+
+    // Keys pressed one at a time, each once the editor is waiting for it. Named keys go in
+    // angle brackets, vim style: "<Esc>", "<CR>", "<BS>", "<C-r>", "<A-j>"
+    pub(crate) fn keys(typed: &str) -> Vec<Scripted> {
+        key_events(typed).into_iter().map(|key| Scripted::Pressed(Input::Key(key))).collect()
+    }
+
+    // The same keys, but all typed ahead before the editor gets to them
+    pub(crate) fn typed(typed: &str) -> Vec<Scripted> {
+        key_events(typed).into_iter().map(|key| Scripted::Typed(Input::Key(key))).collect()
+    }
+
+    pub(crate) fn key_events(typed: &str) -> Vec<KeyEvent> {
+        let mut keys = Vec::new();
+        let mut rest = typed;
+        while let Some(c) = rest.chars().next() {
+            let named = rest.strip_prefix('<').and_then(|r| r.split_once('>')).filter(|(name, _)| name.len() > 1);
+            let Some((name, after)) = named else {
+                keys.push(KeyEvent::from(KeyCode::Char(c)));
+                rest = &rest[c.len_utf8()..];
+                continue;
+            };
+            keys.push(match name {
+                "Esc" => KeyEvent::from(KeyCode::Esc),
+                "CR" => KeyEvent::from(KeyCode::Enter),
+                "BS" => KeyEvent::from(KeyCode::Backspace),
+                "Del" => KeyEvent::from(KeyCode::Delete),
+                "Tab" => KeyEvent::from(KeyCode::Tab),
+                "Up" => KeyEvent::from(KeyCode::Up),
+                "Down" => KeyEvent::from(KeyCode::Down),
+                "Left" => KeyEvent::from(KeyCode::Left),
+                "Right" => KeyEvent::from(KeyCode::Right),
+                "Home" => KeyEvent::from(KeyCode::Home),
+                "End" => KeyEvent::from(KeyCode::End),
+                _ => match (name.strip_prefix("C-"), name.strip_prefix("A-")) {
+                    (Some(c), _) => KeyEvent::new(KeyCode::Char(c.chars().next().unwrap()), KeyModifiers::CONTROL),
+                    (_, Some(c)) => alt(c.chars().next().unwrap()),
+                    _ => panic!("unknown key <{}> in {:?}", name, typed),
+                },
+            });
+            rest = after;
+        }
+        keys
+    }
+
+    pub(crate) fn alt(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::ALT)
+    }
+
+    #[derive(Default)]
+    pub(crate) struct Script {
+        pub(crate) steps: VecDeque<Scripted>,
+        pub(crate) resized: Option<(u16, u16)>, // Picked up by the next update_size
+    }
+
+    impl Script {
+        // Whatever comes next. Running out is an error, so a test waiting for more input
+        // than it scripted ends instead of hanging
+        pub(crate) fn read(&mut self) -> io::Result<Option<Input>> {
+            match self.steps.pop_front() {
+                Some(Scripted::Typed(input) | Scripted::Pressed(input)) => Ok(Some(input)),
+                Some(Scripted::Resize(width, height)) => {
+                    self.resized = Some((width, height));
+                    Ok(None)
+                }
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the script ran out")),
+            }
+        }
+
+        // Only input that was typed ahead
+        pub(crate) fn poll(&mut self) -> Option<Input> {
+            match self.steps.front() {
+                Some(Scripted::Typed(_)) => match self.steps.pop_front() {
+                    Some(Scripted::Typed(input)) => Some(input),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+    }
+
+    // What the terminal wrote, shared with the test that reads it back
+    #[derive(Clone, Default)]
+    pub(crate) struct Screen(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Screen {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Screen {
+        // Everything written since the last take
+        pub(crate) fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.borrow_mut())).unwrap()
+        }
+    }
+
+    // What a real terminal would show after the output: the text in each cell and where the
+    // cursor was left. Understands the little crossterm sends (MoveTo, the Clears, SGR and
+    // cursor show/hide are skipped), wide chars take two cells, combining ones join the last
+    pub(crate) struct Grid {
+        cells: Vec<Vec<String>>, // A wide char's second cell is empty
+        pub(crate) cursor: (usize, usize), // Column, row
+    }
+
+    impl Grid {
+        pub(crate) fn new(width: u16, height: u16) -> Self {
+            Self { cells: vec![vec![" ".to_string(); width as usize]; height as usize], cursor: (0, 0) }
+        }
+
+        pub(crate) fn feed(&mut self, output: &str) {
+            let mut chars = output.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '\x1b' && chars.peek() == Some(&'[') {
+                    chars.next();
+                    let mut params = String::new();
+                    let Some(end) = chars.by_ref().find(|c| if ('@'..='~').contains(c) { true } else { params.push(*c); false }) else { break; };
+                    self.escape(&params, end);
+                } else {
+                    self.print(c);
+                }
+            }
+        }
+
+        fn escape(&mut self, params: &str, end: char) {
+            let (x, y) = self.cursor;
+            let width = self.cells.first().map_or(0, Vec::len);
+            match (end, params) {
+                ('H', _) => {
+                    let mut numbers = params.split(';').map(|n| n.parse::<usize>().unwrap_or(1).max(1) - 1);
+                    let row = numbers.next().unwrap_or(0);
+                    self.cursor = (numbers.next().unwrap_or(0), row);
+                }
+                ('J', "2") => self.cells.iter_mut().flatten().for_each(|cell| *cell = " ".to_string()),
+                ('K', "2") => self.clear(y, 0..width),
+                ('K', "" | "0") => self.clear(y, x..width),
+                _ => (),
+            }
+        }
+
+        fn clear(&mut self, y: usize, range: std::ops::Range<usize>) {
+            if let Some(row) = self.cells.get_mut(y) {
+                let len = row.len();
+                row[range.start.min(len)..range.end.min(len)].iter_mut().for_each(|cell| *cell = " ".to_string());
+            }
+        }
+
+        fn print(&mut self, c: char) {
+            use unicode_width::UnicodeWidthChar;
+            let width = self.cells.first().map_or(0, Vec::len);
+            match c {
+                '\r' => self.cursor.0 = 0,
+                '\n' => self.cursor.1 += 1,
+                _ => match c.width().unwrap_or(0) {
+                    0 => {
+                        let (x, y) = self.cursor;
+                        let last = (0..x).rev().find(|&x| self.cells.get(y).is_some_and(|row| !row[x].is_empty()));
+                        if let Some(cell) = last.and_then(|x| self.cells.get_mut(y).map(|row| &mut row[x])) { cell.push(c); }
+                    }
+                    w => {
+                        // Like a terminal, text past the last column carries on on the next row
+                        if self.cursor.0 + w > width { self.cursor = (0, self.cursor.1 + 1); }
+                        let (x, y) = self.cursor;
+                        let Some(row) = self.cells.get_mut(y) else { return; };
+                        row[x] = c.to_string();
+                        if w == 2 { row[x + 1] = String::new(); }
+                        self.cursor.0 += w;
+                    }
+                },
+            }
+        }
+
+        // Row y as text, trailing blanks trimmed
+        pub(crate) fn row(&self, y: usize) -> String {
+            self.cells[y].concat().trim_end().to_string()
+        }
+
+        // What's in the cell under the cursor
+        pub(crate) fn under_cursor(&self) -> &str {
+            let (x, y) = self.cursor;
+            self.cells.get(y).and_then(|row| row.get(x)).map_or("<off screen>", String::as_str)
+        }
+
+        pub(crate) fn height(&self) -> usize {
+            self.cells.len()
+        }
+    }
+
+    // The parameters of every SGR sequence (ESC [ ... m) in the output, "7" for reverse
+    pub(crate) fn sgr(output: &str) -> Vec<&str> {
+        output.match_indices("\x1b[").filter_map(|(i, _)| {
+            let rest = &output[i + 2..];
+            let end = rest.find(|c: char| c.is_ascii_alphabetic())?;
+            (rest.as_bytes()[end] == b'm').then(|| &rest[..end])
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capture::{sgr, Screen};
+    use super::*;
+    use crate::highlighting::Type;
+
+    // Draws a status bar like ui does: a background, some foreground text, reset
+    fn status_bar(terminal: &mut Terminal) {
+        terminal.set_bg_color(Color::White);
+        terminal.set_fg_color(Color::Black);
+        terminal.print("notes.md - 3 lines");
+        terminal.reset_colors();
+    }
+
+    // Colors are a process-wide crossterm switch, so both modes are checked in one test
+    #[test]
+    fn monochrome_drops_colors_and_keeps_emphasis() {
+        let screen = Screen::default();
+        let mut terminal = Terminal::to_writer(Box::new(screen.clone()), 80, 24, true);
+        // crossterm would have gone by NO_COLOR in the environment the tests run in
+        terminal.set_color(true);
+
+        status_bar(&mut terminal);
+        let colored = screen.take();
+        assert!(sgr(&colored).iter().any(|s| s.starts_with("48;")), "{:?}", colored);
+        assert!(sgr(&colored).iter().any(|s| s.starts_with("38;")), "{:?}", colored);
+
+        terminal.set_color(false);
+        assert!(!terminal.color());
+        status_bar(&mut terminal);
+        let mono = screen.take();
+        // The background turns into reverse video and the reset ends it, no color codes at all
+        assert_eq!(sgr(&mono), vec!["7", "0"], "{:?}", mono);
+        assert!(mono.contains("notes.md - 3 lines"));
+
+        // Headers and bold still stand out, with attributes
+        for (kind, code) in [(Type::Header, "1"), (Type::Bold, "1"), (Type::Italic, "3")] {
+            terminal.set_fg_color(kind.to_color());
+            terminal.set_attribute(kind.to_attribute().unwrap());
+            terminal.print("text");
+            terminal.reset_colors();
+            assert_eq!(sgr(&screen.take()), vec![code, "0"]);
+        }
+        assert!(Type::None.to_attribute().is_none());
+
+        terminal.set_color(true);
+        status_bar(&mut terminal);
+        assert!(sgr(&screen.take()).iter().any(|s| s.starts_with("48;")));
+    }
+}
@@ -0,0 +1,212 @@
+// Text objects for operators (diw, ci*): ranges around the cursor, found on its line only
+// Ranges are [start, end) like the other operator ranges
+use crate::document::Document;
+use crate::editor::Position;
+
+fn line_chars(doc: &Document, y: usize) -> Option<Vec<char>> {
+    if y >= doc.len() { return None; }
+    Some(doc.rope.line(y).chars().filter(|c| *c != '\n' && *c != '\r').collect())
+}
+
+// The word under the cursor wherever in it the cursor is, or the run of spaces or
+// punctuation it's on
+pub fn inner_word(doc: &Document, at: Position) -> Option<(Position, Position)> {
+    let chars = line_chars(doc, at.y)?;
+    if at.x >= chars.len() { return None; }
+
+    let class = |c: char| if c.is_alphanumeric() || c == '_' { 0 } else if c.is_whitespace() { 1 } else { 2 };
+    let kind = class(chars[at.x]);
+    let mut start = at.x;
+    while start > 0 && class(chars[start - 1]) == kind { start -= 1; }
+    let mut end = at.x + 1;
+    while end < chars.len() && class(chars[end]) == kind { end += 1; }
+    Some((Position { x: start, y: at.y }, Position { x: end, y: at.y }))
+}
+
+// What's between the pair of delimiters the cursor is in (or on), without them
+// Brackets can be given by either side, ( and ) mean the same thing
+pub fn inner_delimited(doc: &Document, at: Position, delimiter: char) -> Option<(Position, Position)> {
+    let chars = line_chars(doc, at.y)?;
+    if at.x >= chars.len() { return None; }
+
+    let (start, end) = match delimiter {
+        '(' | ')' => bracketed(&chars, at.x, '(', ')')?,
+        '[' | ']' => bracketed(&chars, at.x, '[', ']')?,
+        c => quoted(&chars, at.x, c)?,
+    };
+    Some((Position { x: start, y: at.y }, Position { x: end, y: at.y }))
+}
+
+// Same char on both sides, paired up in order from the start of the line
+// A run of * or ` counts as one delimiter so **bold** and ``code`` pair up as a whole, closed
+// by the next run as long as it (``a ` b`` is the code "a ` b")
+fn quoted(chars: &[char], x: usize, delimiter: char) -> Option<(usize, usize)> {
+    let runs_collapse = matches!(delimiter, '*' | '`');
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != delimiter {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while runs_collapse && i < chars.len() && chars[i] == delimiter { i += 1; }
+        runs.push((start, i));
+    }
+
+    let mut open: Option<(usize, usize)> = None;
+    for run in runs {
+        match open {
+            Some(first) if first.1 - first.0 == run.1 - run.0 => {
+                if first.0 <= x && x < run.1 { return Some((first.1, run.0)); }
+                open = None;
+            }
+            Some(_) => (),
+            None => open = Some(run),
+        }
+    }
+    None
+}
+
+// Nesting aware: back to the opener that isn't closed before the cursor, then on to the
+// closer that matches it
+fn bracketed(chars: &[char], x: usize, open: char, close: char) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    let mut start = None;
+    for i in (0..=x).rev() {
+        if chars[i] == close && i != x {
+            depth += 1;
+        } else if chars[i] == open {
+            if depth == 0 {
+                start = Some(i);
+                break;
+            }
+            depth -= 1;
+        }
+    }
+    let start = start?;
+
+    let mut depth = 0;
+    for (i, c) in chars.iter().enumerate().skip(start + 1) {
+        if *c == open {
+            depth += 1;
+        } else if *c == close {
+            if depth == 0 { return Some((start + 1, i)); }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As (start, end) columns, Position can't be compared in an assert_eq
+    fn columns(range: Option<(Position, Position)>) -> Option<(usize, usize)> {
+        range.map(|(start, end)| (start.x, end.x))
+    }
+
+    fn word(line: &str, x: usize) -> Option<(usize, usize)> {
+        columns(inner_word(&Document::from_text(line), Position { x, y: 0 }))
+    }
+
+    fn inside(line: &str, x: usize, delimiter: char) -> Option<(usize, usize)> {
+        columns(inner_delimited(&Document::from_text(line), Position { x, y: 0 }, delimiter))
+    }
+
+    #[test]
+    fn inner_word_takes_the_run_under_the_cursor() {
+        for (line, x, expected) in [
+            ("hello, world", 0, Some((0, 5))),
+            ("hello, world", 4, Some((0, 5))),
+            ("hello, world", 5, Some((5, 6))),
+            ("hello, world", 6, Some((6, 7))),
+            ("hello, world", 11, Some((7, 12))),
+            ("foo_bar9 baz", 3, Some((0, 8))),
+            ("a   b", 2, Some((1, 4))),
+            ("see (--) it", 5, Some((4, 8))),
+            ("café au lait", 3, Some((0, 4))),
+            // Past the end of the line, and on an empty one
+            ("hello, world", 12, None),
+            ("hello, world", 40, None),
+            ("", 0, None),
+            ("crlf\r\n", 4, None),
+        ] {
+            assert_eq!(word(line, x), expected, "{:?} at {}", line, x);
+        }
+        assert!(inner_word(&Document::from_text("one\n"), Position { x: 0, y: 5 }).is_none());
+    }
+
+    #[test]
+    fn quotes_pair_up_from_the_start_of_the_line() {
+        let line = "say \"hi\" and \"bye\"";
+        for (x, expected) in [
+            (4, Some((5, 7))), // On the opening quote
+            (6, Some((5, 7))),
+            (7, Some((5, 7))), // On the closing one
+            (8, None), // Between two quoted strings isn't inside either
+            (15, Some((14, 17))),
+            (0, None),
+            (30, None),
+        ] {
+            assert_eq!(inside(line, x, '"'), expected, "at {}", x);
+        }
+        assert_eq!(inside("'' empty", 0, '\''), Some((1, 1)));
+
+        // A quote without a partner pairs with nothing
+        let unpaired = "a \"b\" \"c";
+        for (x, expected) in [(3, Some((3, 4))), (6, None), (7, None)] {
+            assert_eq!(inside(unpaired, x, '"'), expected, "at {}", x);
+        }
+    }
+
+    #[test]
+    fn emphasis_and_code_runs_are_one_delimiter() {
+        let line = "**bold** and *it*";
+        for (x, expected) in [(0, Some((2, 6))), (1, Some((2, 6))), (3, Some((2, 6))), (7, Some((2, 6))), (10, None), (15, Some((14, 16)))] {
+            assert_eq!(inside(line, x, '*'), expected, "at {}", x);
+        }
+        // A shorter run inside doesn't close a longer one
+        assert_eq!(inside("**bold *it* more**", 3, '*'), Some((2, 16)));
+        assert_eq!(inside("``a ` b`` x", 3, '`'), Some((2, 7)));
+        assert_eq!(inside("``a ` b`` x", 10, '`'), None);
+        assert_eq!(inside("`one` and `two`", 12, '`'), Some((11, 14)));
+        assert_eq!(inside("``never closed` x", 5, '`'), None);
+        // Quotes don't collapse, "" is an empty pair
+        assert_eq!(inside("\"\"x\"\"", 2, '"'), None);
+        assert_eq!(inside("\"\"x\"\"", 0, '"'), Some((1, 1)));
+    }
+
+    #[test]
+    fn brackets_nest() {
+        let line = "f(a, (b), c)";
+        for (x, expected) in [
+            (0, None),
+            (1, Some((2, 11))), // On the outer opener
+            (2, Some((2, 11))),
+            (5, Some((6, 7))), // On the inner opener
+            (6, Some((6, 7))),
+            (7, Some((6, 7))), // On the inner closer
+            (8, Some((2, 11))),
+            (11, Some((2, 11))), // On the outer closer
+            (12, None),
+            (40, None),
+        ] {
+            assert_eq!(inside(line, x, '('), expected, "at {}", x);
+            assert_eq!(inside(line, x, ')'), expected, "at {} by the closer", x);
+        }
+        assert_eq!(inside("[[x] y]", 1, '['), Some((2, 3)));
+        assert_eq!(inside("[[x] y]", 5, ']'), Some((1, 6)));
+        assert_eq!(inside("() x", 1, '('), Some((1, 1)));
+        // Only the kind asked for counts
+        assert_eq!(inside("(a [b) c]", 4, '('), Some((1, 5)));
+        assert_eq!(inside("(a [b) c]", 4, '['), Some((4, 8)));
+
+        // Unbalanced
+        assert_eq!(inside("(a (b", 4, '('), None);
+        assert_eq!(inside("a) b", 0, '('), None);
+        assert_eq!(inside("(a) (b)", 3, '('), None);
+    }
+}
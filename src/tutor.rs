@@ -0,0 +1,110 @@
+// :tutor, a hands-on lesson in the style of vimtutor. The lesson ships inside the binary
+// and is copied somewhere temporary, then edited in a second vellum so the session that
+// asked for it is untouched and comes back exactly as it was after :q
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+const LESSON: &str = include_str!("tutor.md");
+
+// A fresh copy every time, it's removed again once the tutor exits
+fn write_lesson() -> io::Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!("vellum-tutor-{}.md", std::process::id()));
+    std::fs::write(&path, LESSON)?;
+    Ok(path)
+}
+
+// Runs the tutor and waits for it. The caller hands over the terminal first and takes it
+// back afterwards
+pub fn run(color: bool) -> io::Result<()> {
+    let path = write_lesson()?;
+    let mut tutor = Command::new(std::env::current_exe()?);
+    tutor.arg(&path);
+    if !color { tutor.arg("--no-color"); }
+    let status = tutor.status();
+    let _ = std::fs::remove_file(&path);
+    let status = status?;
+    if !status.success() {
+        return Err(io::Error::other(format!("tutor exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::editor::Editor;
+    use crate::terminal::capture::{keys, Screen};
+    use crate::terminal::Terminal;
+
+    // Each **Before:** with the **After:** that follows it, as the text after the label or,
+    // when that's empty, the lines up to the next blank one
+    fn exercises(text: &str) -> Vec<(String, String)> {
+        let lines: Vec<&str> = text.lines().collect();
+        let body = |label: &str, from: usize| -> Option<(usize, String)> {
+            let at = (from..lines.len()).find(|&y| lines[y].starts_with(label))?;
+            let rest = lines[at][label.len()..].trim();
+            if !rest.is_empty() { return Some((at, rest.to_string())); }
+            let block: Vec<&str> = lines[at + 1..].iter().take_while(|l| !l.is_empty()).copied().collect();
+            Some((at, block.join("\n")))
+        };
+        let mut pairs = Vec::new();
+        let mut from = 0;
+        while let Some((at, before)) = body("**Before:**", from) {
+            let Some((after_at, after)) = body("**After:**", at + 1) else { break; };
+            pairs.push((before, after));
+            from = after_at + 1;
+        }
+        pairs
+    }
+
+    // Types `script` and returns the cursor line after it
+    fn run(editor: &mut Editor, script: &str) -> String {
+        editor.terminal.script(keys(script));
+        editor.run_script().unwrap();
+        editor.document.line_text(editor.cursor_position.y).trim_end().to_string()
+    }
+
+    // The lesson worked through with the keys it teaches, so it breaks when they do
+    #[test]
+    fn the_lesson_can_be_done_as_written() {
+        let path = write_lesson().unwrap();
+        let screen = Screen::default();
+        let terminal = Terminal::to_writer(Box::new(screen.clone()), 80, 24, false);
+        let mut editor = Editor::for_test(terminal, Document::open(path.to_str().unwrap()).unwrap());
+
+        // 1: moving around
+        run(&mut editor, "/one two<CR>$bbb");
+        assert_eq!(editor.document.line_text(editor.cursor_position.y).chars().nth(editor.cursor_position.x), Some('X'));
+        run(&mut editor, "0wwwww");
+        assert_eq!(editor.document.line_text(editor.cursor_position.y).chars().nth(editor.cursor_position.x), Some('X'));
+        // 2: x
+        assert_eq!(run(&mut editor, "/Thhe<CR>lxwxw6lxw4lxwwx"), "**Before:** The cow jumped over the moon.");
+        // 3: i and o
+        run(&mut editor, "/The brown<CR>wiquick <Esc>/jumped the<CR>ifox <Esc>/the fence<CR>iover <Esc>");
+        run(&mut editor, "/- apples<CR>o- bananas<Esc>");
+        // 4: dd and u
+        run(&mut editor, "/this line should go<CR>dd/so should this one<CR>dd");
+        run(&mut editor, "kddu");
+        // 5: operators
+        run(&mut editor, "/extra extra<CR>dw/cat sat<CR>cwdog<Esc>/red mat<CR>cwblue<Esc>");
+        // 6: yank and put
+        run(&mut editor, "/3. Save<CR>ddp");
+        // 7: search
+        assert_eq!(run(&mut editor, "gg/treasure<CR>nnncwgold<Esc>"), "    Keep looking, the gold is close.");
+
+        let pairs = exercises(&editor.document.rope.to_string());
+        assert_eq!(pairs.len(), 7);
+        for (before, after) in pairs {
+            assert_eq!(before, after);
+        }
+
+        // 8: save and quit
+        run(&mut editor, ":w<CR>:q<CR>");
+        assert!(editor.should_quit);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("The quick brown fox jumped over the fence."));
+        drop(editor);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -2,8 +2,22 @@ use crate::editor::{Editor, Mode};
 use crossterm::style::Color;
 use std::time::{Duration, Instant};
 
+const HARD_BREAK: &str = "↵"; // Drawn after lines ending in two spaces
+const MIN_WIDTH: u16 = 20; // Below this (or MIN_HEIGHT) only a warning is drawn
+const MIN_HEIGHT: u16 = 5;
+
 // Renders the TUI
 pub fn refresh_screen(editor: &mut Editor) -> Result<(), std::io::Error> {
+    // Measured against the old size, so the cursor can be put back at the same height
+    let (row, height) = (editor.cursor_screen_row(), editor.text_area_height());
+    if editor.terminal.update_size() {
+        editor.keep_cursor_row(row, height);
+    }
+    if too_small(editor) {
+        draw_too_small(editor);
+        return editor.terminal.flush();
+    }
+
     editor.scroll();
 
     // 1. Hide the cursor so it doesn't jump around while being drawn
@@ -16,21 +30,34 @@ pub fn refresh_screen(editor: &mut Editor) -> Result<(), std::io::Error> {
     if editor.should_quit {
         editor.terminal.clear_screen();
         editor.terminal.print("Goodbye.\r\n");
+    } else if editor.picker.is_some() {
+        draw_picker(editor);
+        draw_status_bar(editor);
+        draw_message_bar(editor);
+        editor.terminal.cursor_hide();
+        return editor.terminal.flush();
     } else {
         draw_rows(editor);
         draw_status_bar(editor);
         draw_message_bar(editor);
         
         // 4. Put the cursor back where it belongs and with offset (updated)
-        let gutter = editor.gutter_width();
-        let text_width = (editor.terminal.size().width as usize).saturating_sub(gutter);
+        let expanded = expanded_message(editor);
+        if let Some(last) = expanded.last() {
+            // Park the cursor on the "press any key" line
+            let height = editor.terminal.size().height;
+            editor.terminal.cursor_position(last.chars().count() as u16, height.saturating_sub(1));
+        } else {
+            let gutter = editor.gutter_width();
+            let text_width = (editor.terminal.size().width as usize).saturating_sub(gutter);
 
-        let (visual_x, visual_y) = get_visual_cursor(editor, text_width);
+            let (visual_x, visual_y) = get_visual_cursor(editor, text_width);
 
-        editor.terminal.cursor_position(
-            visual_x + gutter as u16,
-            visual_y
-        );
+            editor.terminal.cursor_position(
+                visual_x + gutter as u16,
+                visual_y
+            );
+        }
     }
 
     // 5. Show the cursor again
@@ -40,36 +67,40 @@ pub fn refresh_screen(editor: &mut Editor) -> Result<(), std::io::Error> {
     editor.terminal.flush()
 }
 
+// Everything else would be clipped to nothing (or worse), so just say so until a resize fixes it
+fn too_small(editor: &Editor) -> bool {
+    let size = editor.terminal.size();
+    size.width < MIN_WIDTH || size.height < MIN_HEIGHT
+}
+
+fn draw_too_small(editor: &mut Editor) {
+    let (width, height) = (editor.terminal.size().width as usize, editor.terminal.size().height);
+    let message = format!("Window too small (need at least {}x{})", MIN_WIDTH, MIN_HEIGHT);
+    let text = truncate_chars(&message, width);
+    let x = width.saturating_sub(text.chars().count()) / 2;
+
+    editor.terminal.cursor_hide();
+    editor.terminal.clear_screen();
+    editor.terminal.cursor_position(x as u16, height / 2);
+    editor.terminal.print(&text);
+}
+
+// Cuts by chars rather than bytes, so multi-byte text can't split mid-character
+fn truncate_chars(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
 fn get_visual_cursor(editor: &mut Editor, text_width: usize) -> (u16, u16) {
     if text_width == 0 { return (0, 0); }
     let mut visual_y = 0;
 
     // Calc how many visual lines are taken up by rows above the cursor
     for doc_y in editor.row_offset..editor.cursor_position.y {
-        if doc_y < editor.document.len() {
-            let line_slice = editor.document.rope.line(doc_y);
-            let mut len = line_slice.len_chars();
-
-            // Ignore newline chars in len calc
-            if line_slice.chars().last() == Some('\n') { len = len.saturating_sub(1); }
-            if line_slice.chars().nth(len.saturating_sub(1)) == Some('\r') { len = len.saturating_sub(1); }
-
-            if len <= text_width {
-                visual_y += 1;
-            } else {
-                let remaining = len.saturating_sub(text_width);
-                let wrap_width = text_width.saturating_sub(crate::editor::WRAP_PREFIX.len());
-                let safe_wrap_width = std::cmp::max(1, wrap_width);
-                let extra_lines = (remaining as f32 / safe_wrap_width as f32).ceil() as usize;
-                visual_y += 1 + extra_lines;
-            }
-        } else {
-            visual_y += 1;
-        }
+        visual_y += editor.visual_height(doc_y, text_width);
     }
 
     // Calc x offset and remaining y offset for current row
-    let mut visual_x = editor.cursor_position.x;
+    let mut visual_x = editor.display_col(editor.cursor_position.y, editor.cursor_position.x);
     if visual_x >= text_width {
         let remaining_x = visual_x.saturating_sub(text_width);
         let wrap_width = text_width.saturating_sub(crate::editor::WRAP_PREFIX.len());
@@ -85,48 +116,176 @@ fn get_visual_cursor(editor: &mut Editor, text_width: usize) -> (u16, u16) {
 }
 
 
-fn draw_gutter(terminal: &mut crate::terminal::Terminal, show_line_numbers: bool, gutter: usize, doc_row: usize, is_wrapped: bool) {
-    if !show_line_numbers { return; }
-    terminal.set_fg_color(Color::DarkGrey);
-    
-    if !is_wrapped {
-        let num_str = format!("{:>w$} |", doc_row + 1, w = gutter.saturating_sub(2));
-        terminal.print(&num_str);
+// Which wrapped row of its line column x lands on
+pub(crate) fn cursor_wrap_row(x: usize, text_width: usize) -> usize {
+    if x < text_width { return 0; }
+    let wrap_width = std::cmp::max(1, text_width.saturating_sub(crate::editor::WRAP_PREFIX.len()));
+    1 + (x - text_width) / wrap_width
+}
+
+// How the line number gutter looks, taken from the options once per frame so every kind
+// of row (numbered, wrapped, ~ filler) is drawn the same width
+struct Gutter {
+    shown: bool,
+    width: usize, // Whole gutter, as Editor::gutter_width
+    padding: usize,
+    separator: String,
+    show_on_wrap: bool,
+}
+
+impl Gutter {
+    fn new(editor: &Editor) -> Self {
+        Self {
+            shown: editor.show_line_numbers(),
+            width: editor.gutter_width(),
+            padding: editor.options.gutter_padding,
+            separator: editor.options.gutter_separator.clone(),
+            show_on_wrap: editor.options.gutter_show_on_wrap,
+        }
+    }
+
+    // `label` right-aligned in the number column, then the padding and separator
+    fn text(&self, label: &str, separator: bool) -> String {
+        let separator = if separator { self.separator.as_str() } else { "" };
+        let label_width = self.width.saturating_sub(self.padding + self.separator.chars().count());
+        format!("{:>w$}{}{:<s$}", label, " ".repeat(self.padding), separator, w = label_width, s = self.separator.chars().count())
+    }
+
+    fn draw(&self, terminal: &mut crate::terminal::Terminal, label: &str, separator: bool) {
+        if !self.shown { return; }
+        terminal.set_fg_color(Color::DarkGrey);
+        terminal.print(&self.text(label, separator));
+        terminal.reset_colors();
+    }
+
+    fn draw_row(&self, terminal: &mut crate::terminal::Terminal, doc_row: usize, is_wrapped: bool) {
+        if is_wrapped {
+            self.draw(terminal, "", self.show_on_wrap);
+        } else {
+            self.draw(terminal, &(doc_row + 1).to_string(), true);
+        }
+    }
+}
+
+// Draws the picker over the text area: title on the first row, then a page of items
+// scrolled to keep the selection in view
+fn draw_picker(editor: &mut Editor) {
+    let Some(picker) = &editor.picker else { return; };
+    let height = editor.text_area_height();
+    let width = editor.terminal.size().width as usize;
+    let rows = height.saturating_sub(1);
+    let first = (picker.selected + 1).saturating_sub(rows);
+
+    let title = if picker.filter.is_empty() {
+        format!("{} ({})", picker.title, picker.items.len())
     } else {
-        let empty_str = format!("{:>w$} |", "", w = gutter.saturating_sub(2));
-        terminal.print(&empty_str);
+        format!("{} ({}/{}): {}", picker.title, picker.matches.len(), picker.items.len(), picker.filter)
+    };
+    let mut lines = vec![(title, false)];
+    for (i, item) in picker.matches.iter().map(|&m| &picker.items[m]).enumerate().skip(first).take(rows) {
+        lines.push((format!(" {}", item), i == picker.selected));
+    }
+
+    for row in 0..height {
+        editor.terminal.clear_current_line();
+        if let Some((text, selected)) = lines.get(row) {
+            let text: String = text.chars().take(width).collect();
+            if row == 0 || *selected {
+                editor.terminal.set_bg_color(if row == 0 { Color::DarkGrey } else { Color::Blue });
+                editor.terminal.print(&format!("{:<w$}", text, w = width));
+                editor.terminal.reset_colors();
+            } else {
+                editor.terminal.print(&text);
+            }
+        }
+        editor.terminal.print("\r\n");
     }
-    terminal.reset_colors();
 }
 
 // Draws each row
 fn draw_rows(editor: &mut Editor) {
-    let height = editor.terminal.size().height as usize;
-    let visible_height = height.saturating_sub(2);
+    let visible_height = editor.text_area_height();
     let width = editor.terminal.size().width as usize;
-    let gutter = editor.gutter_width();
-    let text_width = width.saturating_sub(gutter);
+    let gutter = Gutter::new(editor);
+    let text_width = width.saturating_sub(gutter.width);
+    let selection = match editor.mode {
+        Mode::Visual { anchor } => Some(editor.document.selection_bounds(&anchor, &editor.cursor_position)),
+        Mode::VisualLine { anchor } => {
+            let (first, last) = (anchor.min(editor.cursor_position.y), anchor.max(editor.cursor_position.y));
+            let rope = &editor.document.rope;
+            let end = if last + 1 < rope.len_lines() { rope.line_to_char(last + 1) } else { rope.len_chars() };
+            Some((rope.line_to_char(first), end))
+        }
+        _ => None,
+    };
+    let line_mode = matches!(editor.mode, Mode::VisualLine { .. });
 
     let mut terminal_row = 0;
     let mut doc_row = editor.row_offset;
+    let indent_style = editor.indent_style();
+    // Needed for hard-break markers too, trailing spaces inside fences are literal
+    let fences = editor.document.fence_lines();
 
     while terminal_row < visible_height && doc_row < editor.document.len() { // subtracting 2 allows for the status and message bar
         if doc_row < editor.document.len() {
             let line_slice = editor.document.rope.line(doc_row);
-            let mut line_str = line_slice.to_string();
-
-            // Ropey includes newline char at the end of a string (need to get rid of it)
-            if line_str.ends_with("\n") { line_str.pop(); }
-            if line_str.ends_with("\r") { line_str.pop(); }
-            
-            let highlights = editor.document.get_highlights(doc_row);
-            let row_len = line_str.chars().count();
-            let mut char_index = 0;
+            // Ropey includes newline char at the end of a line, line_length leaves it out
+            let row_len = editor.line_length(doc_row);
+
+            // Very long lines (minified files) are drawn plain, highlighting them is too slow
+            let mut highlights = Vec::new();
+            if row_len <= editor.options.long_line {
+                let start = editor.profiler.start();
+                highlights = editor.document.get_highlights(doc_row);
+                editor.profiler.record("highlight", start);
+                if editor.options.indent_warnings && !(editor.options.indent_skip_fences && fences.get(doc_row) == Some(&true)) {
+                    crate::indent::mark_warnings(line_slice, indent_style, &mut highlights);
+                }
+            }
+            if editor.highlight_search && let Some(search) = &editor.search {
+                for (start, end) in search.matches_on(&editor.document, doc_row) {
+                    if highlights.len() < end { highlights.resize(end, crate::highlighting::Type::None); }
+                    highlights[start..end].fill(crate::highlighting::Type::Match);
+                }
+            }
+            let line_start = editor.document.rope.line_to_char(doc_row);
+            if let Some((start, end)) = selection {
+                let from = start.saturating_sub(line_start);
+                let to = std::cmp::min(end.saturating_sub(line_start), row_len);
+                if from < to {
+                    if highlights.len() < to { highlights.resize(to, crate::highlighting::Type::None); }
+                    highlights[from..to].fill(crate::highlighting::Type::Selection);
+                }
+            }
+            // The line begins inside the selection, so an empty one still shows as selected
+            let start_selected = selection.is_some_and(|(start, end)| start <= line_start && line_start < end);
+
+            // Each char as (char, index) screen cells, a tab spread over the columns up to the
+            // next tab stop. Left as None (one cell per char) without tabs or on very long lines
+            let expanded = (row_len <= editor.options.long_line && line_slice.chars().any(|c| c == '\t')).then(|| {
+                let mut cells = Vec::with_capacity(row_len);
+                for (i, c) in line_slice.chars().take(row_len).enumerate() {
+                    let width = if c == '\t' { crate::indent::next_tab_stop(cells.len(), editor.options.tabstop) - cells.len() } else { 1 };
+                    cells.extend(std::iter::repeat_n((c, i), width));
+                }
+                cells
+            });
+            let row_width = expanded.as_ref().map_or(row_len, Vec::len);
+
+            let mut char_index = 0; // In screen columns
             let mut is_wrapped = false;
+            let hard_break = fences.get(doc_row) != Some(&true) && crate::document::has_hard_break(line_slice);
 
             if row_len == 0 {
                 editor.terminal.clear_current_line();
-                draw_gutter(&mut editor.terminal, editor.show_line_numbers, gutter, doc_row, is_wrapped);
+                gutter.draw_row(&mut editor.terminal, doc_row, is_wrapped);
+                if start_selected {
+                    // Whole lines are highlighted across the full width
+                    let fill = if line_mode { text_width } else { 1 };
+                    editor.terminal.set_bg_color(crate::highlighting::Type::Selection.to_color());
+                    editor.terminal.print(&" ".repeat(fill));
+                    editor.terminal.reset_colors();
+                }
                 editor.terminal.print("\r\n");
                 terminal_row += 1;
                 doc_row += 1;
@@ -134,19 +293,22 @@ fn draw_rows(editor: &mut Editor) {
             }
 
             // Chunk text to fit screen
-            while char_index < row_len && terminal_row < visible_height {
+            while char_index < row_width && terminal_row < visible_height {
                 let current_width = if is_wrapped {
                     text_width.saturating_sub(crate::editor::WRAP_PREFIX.len())
                 } else {
                     text_width
                 };
 
-                let end_index = std::cmp::min(char_index + current_width, row_len);
-                // Substring helper, row.render is broken
-                let chunk = line_str.chars().skip(char_index).take(end_index - char_index).collect::<String>();
+                let end_index = std::cmp::min(char_index + current_width, row_width);
+                // Only the visible slice of the line is ever materialized
+                let chunk: Vec<(char, usize)> = match &expanded {
+                    Some(cells) => cells[char_index..end_index].to_vec(),
+                    None => line_slice.slice(char_index..end_index).chars().zip(char_index..).collect(),
+                };
 
                 editor.terminal.clear_current_line();
-                draw_gutter(&mut editor.terminal, editor.show_line_numbers, gutter, doc_row, is_wrapped);
+                gutter.draw_row(&mut editor.terminal, doc_row, is_wrapped);
 
 
                 if is_wrapped {
@@ -156,11 +318,36 @@ fn draw_rows(editor: &mut Editor) {
                 }
 
                 // Render colored chars
-                for (i, c) in chunk.chars().enumerate() {
-                    let hl_type = highlights.get(char_index + i).unwrap_or(&crate::highlighting::Type::None);
+                for (c, i) in chunk {
+                    let hl_type = highlights.get(i).unwrap_or(&crate::highlighting::Type::None);
+                    let c = if c == '\t' { ' ' } else { c };
+                    if hl_type.is_background() {
+                        // Warnings are often on whitespace, which has no glyph to color
+                        editor.terminal.set_bg_color(hl_type.to_color());
+                        editor.terminal.set_fg_color(Color::Black);
+                        editor.terminal.print(&c.to_string());
+                        editor.terminal.reset_colors();
+                        continue;
+                    }
                     editor.terminal.set_fg_color(hl_type.to_color());
+                    if !editor.terminal.color() {
+                        editor.terminal.reset_attributes();
+                        if let Some(attribute) = hl_type.to_attribute() { editor.terminal.set_attribute(attribute); }
+                    }
                     editor.terminal.print(&c.to_string());
                 }
+
+                // Only drawn when it fits on the last row, it's never part of the text
+                let mut drawn = end_index - char_index;
+                if hard_break && end_index == row_width && drawn < current_width {
+                    editor.terminal.set_fg_color(Color::DarkGrey);
+                    editor.terminal.print(HARD_BREAK);
+                    drawn += 1;
+                }
+                if line_mode && start_selected {
+                    editor.terminal.set_bg_color(crate::highlighting::Type::Selection.to_color());
+                    editor.terminal.print(&" ".repeat(current_width.saturating_sub(drawn)));
+                }
                 
                 editor.terminal.reset_colors();
                 editor.terminal.print("\r\n");
@@ -176,11 +363,8 @@ fn draw_rows(editor: &mut Editor) {
     // Fill empty screen with ~, thank you vim
     while terminal_row < visible_height {
         editor.terminal.clear_current_line();
-        if editor.show_line_numbers {
-            let empty_str = format!("{:>w$} |", "~", w = gutter.saturating_sub(2));
-            editor.terminal.set_fg_color(Color::DarkGrey);
-            editor.terminal.print(&empty_str);
-            editor.terminal.reset_colors();
+        if gutter.shown {
+            gutter.draw(&mut editor.terminal, "~", true);
         } else {
             editor.terminal.print("~");
         }
@@ -199,17 +383,49 @@ fn draw_status_bar(editor: &mut Editor) {
     } else {
         let filename = editor.document.filename.clone().unwrap_or_else(|| "[No Name]".to_string());
         status = format!("{} - {} lines {}", filename, editor.document.len(), modified_indicator);
+
+        if editor.document.read_only {
+            status.push_str(" [readonly]");
+        }
+
+        let conflicts = crate::conflict::count(&editor.document);
+        if conflicts > 0 {
+            status.push_str(&format!(" [{} conflicts]", conflicts));
+        }
+    }
+    if let Some((register, _)) = &editor.recording {
+        status.push_str(&format!(" recording @{}", register));
     }
     
-    let line_indicator = format!("{}/{}", editor.cursor_position.y + 1, editor.document.len());
-    let len = status.len() + line_indicator.len();
+    // Half-typed normal mode command (a count and/or prefix key), like vim's showcmd
+    let mut pending = editor.pending_register.map(|r| format!("\"{}", r)).unwrap_or_default();
+    if let Some(operator) = editor.pending_operator {
+        pending.extend(operator.count.map(|n| n.to_string()));
+        pending.push(operator.op);
+    }
+    pending.extend(editor.pending_count.map(|n| n.to_string()));
+    pending.extend(editor.pending_prefix);
+    let line_indicator = if pending.is_empty() {
+        format!("{}/{}", editor.cursor_position.y + 1, editor.document.len())
+    } else {
+        format!("{}   {}/{}", pending, editor.cursor_position.y + 1, editor.document.len())
+    };
+    // The block chain is the first thing to go when the bar is short on room
+    if !matches!(editor.mode, Mode::Command(_)) {
+        let context = editor.block_context();
+        let used = status.chars().count() + line_indicator.chars().count();
+        if !context.is_empty() && used + context.chars().count() + 4 <= width {
+            status.push_str("  ");
+            status.push_str(context);
+        }
+    }
+    let len = status.chars().count() + line_indicator.chars().count();
     
     if width > len {
         status.push_str(&" ".repeat(width - len));
     }
     // Truncation if line is too long
-    status = format!("{}{}", status, line_indicator);
-    status.truncate(width);
+    status = truncate_chars(&format!("{}{}", status, line_indicator), width);
 
     // Styling for status
     editor.terminal.set_bg_color(Color::White);
@@ -223,12 +439,278 @@ fn draw_status_bar(editor: &mut Editor) {
 
 }
 
+// Splits a message that won't fit on one row into screen-width lines, ending with the dismiss hint
+// Returns nothing for messages that fit (or are prompts), so callers can treat empty as "normal bar"
+pub(crate) fn expanded_message(editor: &Editor) -> Vec<String> {
+    let msg = &editor.status_message;
+    let width = editor.terminal.size().width as usize;
+    // A window too small for anything but its notice can't show it, and mustn't wait for
+    // a key nobody knows to press
+    if !msg.expand || too_small(editor) { return Vec::new(); }
+    if !msg.text.contains('\n') && msg.text.chars().count() <= width { return Vec::new(); }
+
+    let mut lines = Vec::new();
+    for line in msg.text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+
+    // Leave room for the status bar and at least one row of text
+    let max_lines = (editor.terminal.size().height as usize).saturating_sub(3);
+    lines.truncate(max_lines);
+    lines.push("-- press any key --".to_string());
+    lines
+}
+
 fn draw_message_bar(editor: &mut Editor) {
+    let expanded = expanded_message(editor);
+    if !expanded.is_empty() {
+        let width = editor.terminal.size().width as usize;
+        for (i, line) in expanded.iter().enumerate() {
+            if i > 0 { editor.terminal.print("\r\n"); }
+            editor.terminal.clear_current_line();
+            editor.terminal.set_bg_color(Color::DarkGrey);
+            editor.terminal.print(&format!("{:<w$}", line, w = width));
+            editor.terminal.reset_colors();
+        }
+        return;
+    }
+
     editor.terminal.clear_current_line();
     let msg = &editor.status_message;
-    if Instant::now() - msg.time < Duration::from_secs(5) {
-        let mut text = msg.text.clone();
-        text.truncate(editor.terminal.size().width as usize);
+    // A pending confirm() stays up for as long as it takes to answer
+    if Instant::now() - msg.time < Duration::from_secs(5) || !msg.choices.is_empty() {
+        let text = truncate_chars(&msg.text, editor.terminal.size().width as usize);
         editor.terminal.print(&text);
     }
-}
\ No newline at end of file
+
+    // Choices print as " [y]es"-style hints with the key highlighted
+    let choices = editor.status_message.choices.clone();
+    for (key, label) in choices {
+        editor.terminal.print(" ");
+        editor.terminal.set_bg_color(Color::DarkGrey);
+        editor.terminal.print(&key.to_string());
+        editor.terminal.reset_colors();
+        editor.terminal.print(&format!(" {}", label));
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unicode_width::UnicodeWidthStr;
+
+    fn gutter(separator: &str, padding: usize, show_on_wrap: bool) -> Gutter {
+        // A 100+ line document, as gutter_width would size it
+        let width = 3 + padding + separator.chars().count();
+        Gutter { shown: true, width, padding, separator: separator.to_string(), show_on_wrap }
+    }
+
+    #[test]
+    fn gutter_text() {
+        let g = gutter("|", 1, false);
+        assert_eq!(g.text("7", true), "  7 |");
+        assert_eq!(g.text("123", true), "123 |");
+        assert_eq!(g.text("", false), "     ");
+        assert_eq!(gutter("│", 0, false).text("42", true), " 42│");
+        assert_eq!(gutter("", 2, false).text("42", true), " 42  ");
+    }
+
+    #[test]
+    fn every_kind_of_row_is_as_wide_as_the_gutter() {
+        for (separator, padding, show_on_wrap) in [("|", 1, false), ("│", 0, true), ("", 3, false), ("┃", 2, true)] {
+            let g = gutter(separator, padding, show_on_wrap);
+            let rows = [
+                g.text("1", true),
+                g.text("100", true),
+                g.text("", g.show_on_wrap), // Wrapped continuation
+                g.text("~", true), // Filler past the end
+            ];
+            for row in &rows {
+                assert_eq!(row.width(), g.width, "{:?} with separator {:?}", row, separator);
+            }
+        }
+    }
+
+    #[test]
+    fn monochrome_gutter_has_no_colors() {
+        use crate::terminal::capture::{sgr, Screen};
+        let screen = Screen::default();
+        let mut terminal = crate::terminal::Terminal::to_writer(Box::new(screen.clone()), 80, 24, false);
+        let g = gutter("|", 1, true);
+        g.draw_row(&mut terminal, 6, false);
+        g.draw_row(&mut terminal, 6, true);
+        g.draw(&mut terminal, "~", true);
+        let output = screen.take();
+        // Only the resets after each row, the numbers are plain text
+        assert_eq!(sgr(&output), vec!["0", "0", "0"], "{:?}", output);
+        assert!(output.contains("  7 |") && output.contains("  ~ |"));
+    }
+
+    #[test]
+    fn separators_the_options_accept_keep_the_gutter_in_line() {
+        // Whatever gutterseparator lets through has to take exactly the one column the
+        // gutter counts it as
+        for candidate in ["|", "│", "┆", ":", "▏", "中", "🦀", "ｌ", "\u{301}"] {
+            let mut options = crate::options::Options::default();
+            if options.set(&format!("gutterseparator={}", candidate)).is_ok() {
+                let g = gutter(&options.gutter_separator, 1, true);
+                assert_eq!(g.text("9", true).width(), g.width, "{:?}", candidate);
+            }
+        }
+    }
+
+    // An editor in a width x height test terminal, what it draws comes back from the Screen
+    fn sized_editor(text: &str, width: u16, height: u16) -> (Editor, crate::terminal::capture::Screen) {
+        let screen = crate::terminal::capture::Screen::default();
+        let terminal = crate::terminal::Terminal::to_writer(Box::new(screen.clone()), width, height, false);
+        (Editor::for_test(terminal, crate::document::Document::from_text(text)), screen)
+    }
+
+    fn rows(output: &str, width: u16, height: u16) -> Vec<String> {
+        let mut grid = crate::terminal::capture::Grid::new(width, height);
+        grid.feed(output);
+        (0..grid.height()).map(|y| grid.row(y)).collect()
+    }
+
+    #[test]
+    fn too_small_draws_only_the_notice() {
+        let text = "# Title\n\n".to_string() + &"a line of text that goes on a while\n".repeat(50);
+        for (width, height, notice_row, notice) in [
+            (1, 1, 0, "W"),
+            (19, 4, 2, "Window too small (n"),
+            (60, 3, 1, "           Window too small (need at least 20x5)"),
+            (19, 40, 20, "Window too small (n"),
+            (0, 0, 0, ""),
+        ] {
+            let (mut editor, screen) = sized_editor(&text, width, height);
+            editor.cursor_position = crate::editor::Position { x: 5, y: 30 };
+            refresh_screen(&mut editor).unwrap();
+            let shown = rows(&screen.take(), width, height);
+            for (y, row) in shown.iter().enumerate() {
+                let expected = if y == notice_row { notice } else { "" };
+                assert_eq!(row, expected, "row {} at {}x{}: {:?}", y, width, height, shown);
+            }
+        }
+    }
+
+    // Keys still work while the window is too small, and the text comes back once it's big
+    // enough again
+    #[test]
+    fn too_small_survives_a_session() {
+        use crate::terminal::capture::{keys, Scripted};
+        let (mut editor, screen) = sized_editor("one\ntwo\nthree", 80, 24);
+        editor.terminal.script([Scripted::Resize(1, 1)]);
+        editor.terminal.script(keys("jjiabc<Esc>"));
+        editor.run_script().unwrap();
+        // No key went to dismissing a message that couldn't be shown
+        assert_eq!(editor.document.rope.to_string(), "one\ntwo\nabcthree");
+        editor.terminal.script([Scripted::Resize(19, 4)]);
+        editor.terminal.script(keys("v$:"));
+        editor.terminal.script([Scripted::Resize(0, 0), Scripted::Resize(1, 1)]);
+        editor.terminal.script(keys("<Esc><Esc>uG"));
+        editor.run_script().unwrap();
+        assert_eq!(rows(&screen.take(), 1, 1), ["W"]);
+
+        editor.terminal.script([Scripted::Resize(20, 5)]);
+        editor.run_script().unwrap();
+        let shown = rows(&screen.take(), 20, 5);
+        assert!(shown.iter().any(|row| row.ends_with("three")), "{:?}", shown);
+        assert_eq!(editor.document.rope.to_string(), "one\ntwo\nthree");
+    }
+
+    // The runs of text drawn in reverse video, which is how a monochrome terminal shows
+    // selections and matches
+    fn reversed(output: &str) -> Vec<String> {
+        let (mut runs, mut run, mut on) = (Vec::new(), String::new(), false);
+        let mut chars = output.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                let sequence: String = chars.by_ref().take_while(|c| !c.is_ascii_alphabetic()).collect();
+                for param in sequence.trim_start_matches('[').split(';') {
+                    match param {
+                        "7" => on = true,
+                        "0" => on = false,
+                        _ => (),
+                    }
+                }
+            } else if on {
+                run.push(c);
+            } else if !run.is_empty() {
+                runs.push(std::mem::take(&mut run));
+            }
+        }
+        runs.extend((!run.is_empty()).then_some(run));
+        runs
+    }
+
+    // Just the text rows, drawn on their own
+    fn text_rows(editor: &mut Editor, screen: &crate::terminal::capture::Screen) -> (Vec<String>, String) {
+        draw_rows(editor);
+        let output = screen.take();
+        let size = editor.terminal.size();
+        let mut shown = rows(&output, size.width, size.height);
+        shown.truncate(editor.text_area_height());
+        (shown, output)
+    }
+
+    #[test]
+    fn wide_and_combining_chars_are_drawn_whole() {
+        let text = "# 日本語のメモ\n🦀 crab, 🐍 snake\ncafe\u{301} re\u{301}sume\u{301}\nmixed é中🦀x\n";
+        let (mut editor, screen) = sized_editor(text, 40, 8);
+        editor.options.set("numbermode=never").unwrap();
+        let (shown, _) = text_rows(&mut editor, &screen);
+        assert_eq!(shown, [
+            "# 日本語のメモ",
+            "🦀 crab, 🐍 snake",
+            "cafe\u{301} re\u{301}sume\u{301}",
+            "mixed é中🦀x",
+            "",
+            "~",
+        ]);
+    }
+
+    #[test]
+    fn wrapped_lines_split_between_chars() {
+        // Two-byte chars all through, a byte slice would land inside one on every row
+        let text = "intro\nαβγδεζηθικλμνξοπρστυφχψω\nжзийклмн оп\nend";
+        for (width, height, top, expected) in [
+            (12, 8, 0, vec!["intro", "αβγδεζηθικλμ", " >νξοπρστυφχ", " >ψω", "жзийклмн оп", "end"]),
+            (14, 8, 1, vec!["αβγδεζηθικλμνξ", " >οπρστυφχψω", "жзийклмн оп", "end", "~", "~"]),
+            // Cut off partway into the wrapped line
+            (12, 4, 1, vec!["αβγδεζηθικλμ", " >νξοπρστυφχ"]),
+            (6, 6, 2, vec!["жзийкл", " >мн о", " >п", "end"]),
+        ] {
+            let (mut editor, screen) = sized_editor(text, width, height);
+            editor.options.set("numbermode=never").unwrap();
+            editor.row_offset = top;
+            let (shown, _) = text_rows(&mut editor, &screen);
+            assert_eq!(shown, expected, "{}x{} from line {}", width, height, top);
+        }
+    }
+
+    #[test]
+    fn highlights_line_up_with_the_chars() {
+        use crate::editor::Position;
+        let text = "ab🦀日e\u{301}zq\nαβγδεζηθικλμνξοπ\n";
+        for (anchor, cursor, width, expected) in [
+            // Starting on the crab, through the accented e (two chars, one cell)
+            (Position { x: 2, y: 0 }, Position { x: 5, y: 0 }, 20, vec!["🦀日e\u{301}"]),
+            (Position { x: 6, y: 0 }, Position { x: 6, y: 0 }, 20, vec!["z"]),
+            // Across the wrap: each row gets its own part, the prefix isn't selected
+            (Position { x: 7, y: 1 }, Position { x: 12, y: 1 }, 10, vec!["θικ", "λμν"]),
+            (Position { x: 0, y: 1 }, Position { x: 0, y: 1 }, 10, vec!["α"]),
+        ] {
+            let (mut editor, screen) = sized_editor(text, width, 8);
+            editor.options.set("numbermode=never").unwrap();
+            editor.mode = Mode::Visual { anchor };
+            editor.cursor_position = cursor;
+            let (_, output) = text_rows(&mut editor, &screen);
+            assert_eq!(reversed(&output), expected, "at width {}", width);
+        }
+    }
+}